@@ -0,0 +1,47 @@
+/*
+ * C interface of osm-construction-extractor.
+ *
+ * Link against libfast_osm_extraction_capi (cdylib or staticlib), built with
+ * `cargo build --release -p fast-osm-extraction-capi`.
+ *
+ *     if (foe_extract("germany-latest.osm.pbf", "highway=* and construction=*",
+ *                     NULL, "construction.gpkg") < 0) {
+ *         fprintf(stderr, "extraction failed: %s\n", foe_last_error());
+ *     }
+ */
+
+#ifndef FAST_OSM_EXTRACTION_H
+#define FAST_OSM_EXTRACTION_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Extracts the ways matching `filter` from the OSM file at `path` (PBF, o5m
+ * or OSM XML) and writes them to `out_path`.
+ *
+ * `filter` uses the syntax of the command line tool and `out_format` takes
+ * the names of its --format option (geojson, geojsonseq, geoparquet, arrow,
+ * fgb, gpkg, csv, tsv, shp, pbf, spatialite, duckdb, index). Either may be
+ * NULL, for the default `highway=* and construction=*` filter and a format
+ * guessed from the extension of `out_path`. All strings are UTF-8.
+ *
+ * Returns the number of features written (of OSM objects for PBF output),
+ * or -1 on failure, with the reason in foe_last_error().
+ */
+int64_t foe_extract(const char *path, const char *filter, const char *out_format, const char *out_path);
+
+/*
+ * The message of the last failed call on the calling thread, or NULL if it
+ * succeeded. Owned by the library and valid until the next call.
+ */
+const char *foe_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
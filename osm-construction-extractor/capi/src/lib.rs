@@ -0,0 +1,112 @@
+//! C interface of the extractor, declared in `include/fast_osm_extraction.h`,
+//! so plugins of QGIS, R and other hosts can extract in-process instead of
+//! running the command line tool.
+//!
+//! Errors, including panics, never cross the boundary: functions return -1
+//! and leave a message for [`foe_last_error`].
+
+use anyhow::{anyhow, bail, Context, Result};
+use osm_construction_extractor::output::{self, OutputFormat, WriteOptions};
+use osm_construction_extractor::Extractor;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Extracts the ways matching `filter` from the OSM file at `path` and
+/// writes them to `out_path`.
+///
+/// `filter` is in the syntax of the command line tool, e.g.
+/// `"highway=* and construction=*"`, and `out_format` a name `--format`
+/// takes, e.g. `"gpkg"`; either may be null for the default filter and a
+/// format guessed from the extension of `out_path`. Strings are UTF-8.
+///
+/// Returns the number of features written (of OSM objects for PBF output),
+/// or -1 on failure.
+///
+/// # Safety
+///
+/// `path` and `out_path` must be valid NUL-terminated strings, as must
+/// `filter` and `out_format` unless they are null.
+#[no_mangle]
+pub unsafe extern "C" fn foe_extract(
+    path: *const c_char,
+    filter: *const c_char,
+    out_format: *const c_char,
+    out_path: *const c_char,
+) -> i64 {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (path, out_path) = (str_arg(path, "path")?, str_arg(out_path, "out_path")?);
+        extract(path, opt_str_arg(filter, "filter")?, opt_str_arg(out_format, "out_format")?, out_path)
+    }));
+    let result = result.unwrap_or_else(|panic| {
+        let message = match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        Err(anyhow!("extraction panicked: {}", message))
+    });
+    match result {
+        Ok(count) => {
+            set_last_error(None);
+            count as i64
+        }
+        Err(e) => {
+            set_last_error(Some(format!("{:#}", e)));
+            -1
+        }
+    }
+}
+
+/// The message of the last failed call on this thread, or null if the last
+/// call succeeded. The string stays valid until the next call.
+#[no_mangle]
+pub extern "C" fn foe_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+fn extract(path: &str, filter: Option<&str>, out_format: Option<&str>, out_path: &str) -> Result<usize> {
+    let out_path = Path::new(out_path);
+    let format = match out_format {
+        Some(name) => name.parse()?,
+        None => match OutputFormat::from_path(out_path) {
+            Some(format) => format,
+            None => bail!("cannot guess the format of {:?}, pass out_format", out_path),
+        },
+    };
+    let mut extractor = Extractor::new(path);
+    if let Some(filter) = filter {
+        extractor = extractor.filter(filter.parse()?);
+    }
+    if format == OutputFormat::Pbf {
+        return output::pbf::write(out_path, extractor.objects()?.into_values());
+    }
+    let features = extractor.run()?;
+    output::write(out_path, format, &features, &WriteOptions::default())?;
+    Ok(features.len())
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    match opt_str_arg(s, name)? {
+        Some(s) => Ok(s),
+        None => bail!("{} must not be null", name),
+    }
+}
+
+unsafe fn opt_str_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s = CStr::from_ptr(s).to_str().with_context(|| format!("{} is not valid UTF-8", name))?;
+    Ok(Some(s))
+}
+
+fn set_last_error(message: Option<String>) {
+    let message = message.map(|m| CString::new(m.replace('\0', "")).unwrap_or_default());
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
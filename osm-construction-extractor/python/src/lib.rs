@@ -0,0 +1,195 @@
+//! Python bindings of the extractor, published as the `fast_osm_extraction`
+//! module.
+//!
+//! Features come back as a GeoJSON FeatureCollection, which
+//! `geopandas.GeoDataFrame.from_features` reads directly, or as columns with
+//! WKB geometries for `geopandas.GeoSeries.from_wkb`; either way the
+//! extraction itself runs without holding the GIL.
+
+use pyo3::prelude::*;
+
+#[pymodule]
+mod fast_osm_extraction {
+    use osm_construction_extractor::output::{self, geojson, wkb, OutputFormat, WriteOptions};
+    use osm_construction_extractor::{spatial, ExtractedFeature, Filter, OsmType};
+    use pyo3::exceptions::{PyRuntimeError, PyValueError};
+    use pyo3::prelude::*;
+    use pyo3::types::{PyBytes, PyDict, PyList};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// Extracts the OSM objects matching a tag filter from a PBF, o5m or
+    /// OSM XML file.
+    ///
+    ///     extractor = Extractor("germany-latest.osm.pbf",
+    ///                           filter="highway=* and construction=*",
+    ///                           bbox=(13.0, 52.3, 13.8, 52.7))
+    ///     gdf = geopandas.GeoDataFrame.from_features(extractor.features(),
+    ///                                                crs="EPSG:4326")
+    #[pyclass(frozen)]
+    struct Extractor {
+        inner: osm_construction_extractor::Extractor,
+    }
+
+    #[pymethods]
+    impl Extractor {
+        #[new]
+        #[pyo3(signature = (
+            path,
+            *,
+            filter = None,
+            types = None,
+            bbox = None,
+            streaming = false,
+            threads = 0,
+            with_metadata = false,
+            allow_incomplete = false,
+            verbose = false,
+        ))]
+        #[allow(clippy::too_many_arguments)]
+        fn new(
+            path: PathBuf,
+            filter: Option<&str>,
+            types: Option<Vec<String>>,
+            bbox: Option<(f64, f64, f64, f64)>,
+            streaming: bool,
+            threads: usize,
+            with_metadata: bool,
+            allow_incomplete: bool,
+            verbose: bool,
+        ) -> PyResult<Self> {
+            let mut inner = osm_construction_extractor::Extractor::new(path)
+                .streaming(streaming)
+                .threads(threads)
+                .with_metadata(with_metadata)
+                .allow_incomplete(allow_incomplete)
+                .verbose(verbose);
+            if let Some(filter) = filter {
+                inner = inner.filter(filter.parse::<Filter>().map_err(value_error)?);
+            }
+            if let Some(types) = types {
+                inner = inner.types(&types.iter().map(|t| t.parse()).collect::<anyhow::Result<Vec<OsmType>>>().map_err(value_error)?);
+            }
+            if let Some((min_lon, min_lat, max_lon, max_lat)) = bbox {
+                let bbox = format!("{},{},{},{}", min_lon, min_lat, max_lon, max_lat);
+                inner = inner.bbox(spatial::parse_bbox(&bbox).map_err(value_error)?);
+            }
+            Ok(Extractor { inner })
+        }
+
+        /// Runs the extraction and returns a GeoJSON FeatureCollection as a
+        /// dict, whose features carry the OSM tags as properties
+        fn features<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+            let features = self.run(py)?;
+            let list = PyList::empty(py);
+            for feature in &features {
+                list.append(to_python(py, &geojson::feature(feature))?)?;
+            }
+            let collection = PyDict::new(py);
+            collection.set_item("type", "FeatureCollection")?;
+            collection.set_item("features", list)?;
+            Ok(collection)
+        }
+
+        /// Runs the extraction and returns a dict of equally long lists:
+        /// `id`, `osm_type`, `osm_id`, `tags` (dicts) and `geometry` (WKB
+        /// bytes), ready for `pandas.DataFrame` and `GeoSeries.from_wkb`
+        fn columns<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+            let features = self.run(py)?;
+            let (ids, osm_types, osm_ids, tags, geometries) =
+                (PyList::empty(py), PyList::empty(py), PyList::empty(py), PyList::empty(py), PyList::empty(py));
+            for feature in &features {
+                ids.append(feature.unique_id())?;
+                osm_types.append(feature.osm_type().to_string())?;
+                osm_ids.append(feature.id())?;
+                let feature_tags: HashMap<&str, &str> =
+                    feature.tags().iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                tags.append(feature_tags)?;
+                geometries.append(PyBytes::new(py, &wkb::to_wkb(feature.geometry())))?;
+            }
+            let columns = PyDict::new(py);
+            columns.set_item("id", ids)?;
+            columns.set_item("osm_type", osm_types)?;
+            columns.set_item("osm_id", osm_ids)?;
+            columns.set_item("tags", tags)?;
+            columns.set_item("geometry", geometries)?;
+            Ok(columns)
+        }
+
+        /// Runs the extraction and writes the features to `path`, in the
+        /// format named by `format` (e.g. `"geoparquet"`) or else guessed
+        /// from the extension; returns the number of features written
+        #[pyo3(signature = (path, format = None))]
+        fn write(&self, py: Python<'_>, path: PathBuf, format: Option<&str>) -> PyResult<usize> {
+            let format = match format {
+                Some(name) => name.parse().map_err(value_error)?,
+                None => OutputFormat::from_path(&path)
+                    .ok_or_else(|| PyValueError::new_err(format!("cannot guess the format of {:?}", path)))?,
+            };
+            if format == OutputFormat::Pbf {
+                return Err(PyValueError::new_err("PBF output is only available from the command line"));
+            }
+            py.detach(|| {
+                let features = self.inner.run()?;
+                output::write(&path, format, &features, &WriteOptions::default())?;
+                Ok(features.len())
+            })
+            .map_err(runtime_error)
+        }
+    }
+
+    impl Extractor {
+        fn run(&self, py: Python<'_>) -> PyResult<Vec<ExtractedFeature>> {
+            py.detach(|| self.inner.run()).map_err(runtime_error)
+        }
+    }
+
+    /// Runs an extraction with the same arguments as `Extractor` and
+    /// returns its GeoJSON FeatureCollection
+    #[pyfunction]
+    #[pyo3(signature = (path, *, filter = None, types = None, bbox = None))]
+    fn extract<'py>(
+        py: Python<'py>,
+        path: PathBuf,
+        filter: Option<&str>,
+        types: Option<Vec<String>>,
+        bbox: Option<(f64, f64, f64, f64)>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        Extractor::new(path, filter, types, bbox, false, 0, false, false, false)?.features(py)
+    }
+
+    fn to_python<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+        Ok(match value {
+            Value::Null => py.None().into_bound(py),
+            Value::Bool(b) => b.into_pyobject(py)?.to_owned().into_any(),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => i.into_pyobject(py)?.into_any(),
+                None => n.as_f64().unwrap_or(f64::NAN).into_pyobject(py)?.into_any(),
+            },
+            Value::String(s) => s.into_pyobject(py)?.into_any(),
+            Value::Array(values) => {
+                let list = PyList::empty(py);
+                for value in values {
+                    list.append(to_python(py, value)?)?;
+                }
+                list.into_any()
+            }
+            Value::Object(members) => {
+                let dict = PyDict::new(py);
+                for (key, value) in members {
+                    dict.set_item(key, to_python(py, value)?)?;
+                }
+                dict.into_any()
+            }
+        })
+    }
+
+    fn value_error(e: anyhow::Error) -> PyErr {
+        PyValueError::new_err(format!("{:#}", e))
+    }
+
+    fn runtime_error(e: anyhow::Error) -> PyErr {
+        PyRuntimeError::new_err(format!("{:#}", e))
+    }
+}
@@ -0,0 +1,90 @@
+//! Address extraction: everything with a house number, normalized into one
+//! point per address for geocoding.
+
+use crate::feature::ExtractedFeature;
+use geo::{Centroid, Geometry};
+use osmpbfreader::{OsmId, OsmObj, Relation, Tags};
+use std::collections::{BTreeMap, HashMap};
+
+/// Selects the objects read for [`collect`]: anything with a house number,
+/// plus the `associatedStreet` relations that may name their street
+pub const FILTER: &str = "addr:housenumber or type=associatedStreet";
+
+/// The properties of the features [`collect`] returns, in column order
+pub const COLUMNS: [&str; 4] = ["street", "housenumber", "postcode", "city"];
+
+// What an associatedStreet relation contributes to its houses
+#[derive(Default)]
+struct StreetInfo {
+    street: Option<String>,
+    postcode: Option<String>,
+    city: Option<String>,
+}
+
+/// Turns every object with an `addr:housenumber` among `objects`, as
+/// returned by [`Extractor::objects`](crate::Extractor::objects), into a
+/// point feature with the [`COLUMNS`] as its only properties.
+///
+/// The point is the centroid of the object's geometry. The street comes from
+/// `addr:street`, else the `name` of an `associatedStreet` relation the
+/// object is a `house` of, else `addr:place` (for addresses named after a
+/// hamlet or square instead of a street); postcode and city fall back to
+/// the relation's `addr:postcode` and `addr:city` the same way. Whitespace
+/// in values is trimmed and collapsed.
+pub fn collect(objects: &BTreeMap<OsmId, OsmObj>) -> Vec<ExtractedFeature> {
+    let mut streets: HashMap<OsmId, &Relation> = HashMap::new();
+    for obj in objects.values() {
+        if let OsmObj::Relation(relation) = obj {
+            if relation.tags.get("type").is_some_and(|t| t == "associatedStreet") {
+                for member in relation.refs.iter().filter(|m| m.role == "house") {
+                    streets.entry(member.member).or_insert(relation);
+                }
+            }
+        }
+    }
+
+    let mut addresses = Vec::new();
+    for obj in objects.values() {
+        let Some(housenumber) = tag(obj.tags(), "addr:housenumber") else {
+            continue;
+        };
+        let relation = streets.get(&obj.id()).map(|r| street_info(r)).unwrap_or_default();
+        let Some(mut feature) = ExtractedFeature::build(obj, objects) else {
+            continue;
+        };
+        let Some(centroid) = feature.geometry().centroid() else {
+            continue;
+        };
+        let tags = obj.tags();
+        let values = [
+            tag(tags, "addr:street").or(relation.street).or_else(|| tag(tags, "addr:place")),
+            Some(housenumber),
+            tag(tags, "addr:postcode").or(relation.postcode),
+            tag(tags, "addr:city").or(relation.city),
+        ];
+        let properties = feature.tags_mut();
+        properties.clear();
+        for (key, value) in COLUMNS.iter().zip(values) {
+            if let Some(value) = value {
+                properties.insert((*key).into(), value.into());
+            }
+        }
+        feature.set_geometry(Geometry::Point(centroid));
+        addresses.push(feature);
+    }
+    addresses
+}
+
+fn street_info(relation: &Relation) -> StreetInfo {
+    StreetInfo {
+        street: tag(&relation.tags, "name"),
+        postcode: tag(&relation.tags, "addr:postcode").or_else(|| tag(&relation.tags, "postal_code")),
+        city: tag(&relation.tags, "addr:city"),
+    }
+}
+
+// A tag value with its whitespace normalized; blank values count as missing
+fn tag(tags: &Tags, key: &str) -> Option<String> {
+    let value = tags.get(key)?.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!value.is_empty()).then_some(value)
+}
@@ -0,0 +1,81 @@
+//! Administrative boundaries with their hierarchy: which boundary of a
+//! lower `admin_level` each one lies in, and which lie in it.
+
+use crate::feature::ExtractedFeature;
+use crate::validity::{self, INVALID_REASON_TAG, VALID_TAG};
+use geo::{Area, BoundingRect, Contains, InteriorPoint};
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::RTree;
+use std::collections::BTreeMap;
+
+/// Selects the relations read for [`link`]
+pub const FILTER: &str = "boundary=administrative and admin_level=*";
+
+/// Property holding the id of the boundary a boundary lies in, e.g. `r62422`
+pub const PARENT_TAG: &str = "@parent";
+/// Property holding the ids of the boundaries lying directly in a
+/// boundary, as a JSON array
+pub const CHILDREN_TAG: &str = "@children";
+
+/// The `admin_level` of a boundary, 1 for supranational areas to 11 for
+/// the smallest subdivisions
+pub fn admin_level(feature: &ExtractedFeature) -> Option<u8> {
+    feature.tags().get("admin_level")?.trim().parse().ok()
+}
+
+/// Adds the hierarchy and validity properties to the boundaries, the
+/// latter as [`VALID_TAG`] and [`INVALID_REASON_TAG`].
+///
+/// The parent of a boundary is, of the boundaries of a lower admin level
+/// containing a point on its surface, the one of the highest level, and of
+/// those the smallest. Boundaries without a numeric `admin_level` or a
+/// polygon get no parent or children.
+pub fn link(boundaries: &mut [ExtractedFeature]) {
+    let levels: Vec<Option<u8>> = boundaries.iter().map(admin_level).collect();
+    let entries: Vec<GeomWithData<Rectangle<[f64; 2]>, usize>> = boundaries
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| levels[*i].is_some())
+        .filter_map(|(i, feature)| {
+            let rect = feature.geometry().bounding_rect()?;
+            Some(GeomWithData::new(Rectangle::from_corners(rect.min().into(), rect.max().into()), i))
+        })
+        .collect();
+    let tree = RTree::bulk_load(entries);
+
+    let areas: Vec<f64> = boundaries.iter().map(|feature| feature.geometry().unsigned_area()).collect();
+    let mut parents: Vec<Option<usize>> = vec![None; boundaries.len()];
+    for (i, feature) in boundaries.iter().enumerate() {
+        let (Some(level), Some(point)) = (levels[i], feature.geometry().interior_point()) else {
+            continue;
+        };
+        parents[i] = tree
+            .locate_all_at_point(&[point.x(), point.y()])
+            .map(|entry| entry.data)
+            .filter(|&j| levels[j].is_some_and(|l| l < level) && boundaries[j].geometry().contains(&point))
+            .max_by(|&a, &b| levels[a].cmp(&levels[b]).then(areas[b].total_cmp(&areas[a])));
+    }
+
+    let mut children: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for (i, parent) in parents.iter().enumerate() {
+        if let Some(parent) = parent {
+            children.entry(*parent).or_default().push(boundaries[i].unique_id());
+        }
+    }
+    let parent_ids: Vec<Option<String>> = parents.iter().map(|p| p.map(|p| boundaries[p].unique_id())).collect();
+    for (i, feature) in boundaries.iter_mut().enumerate() {
+        let problem = validity::check(feature.geometry());
+        let tags = feature.tags_mut();
+        if let Some(parent) = &parent_ids[i] {
+            tags.insert(PARENT_TAG.into(), parent.into());
+        }
+        if let Some(children) = children.get_mut(&i) {
+            children.sort();
+            tags.insert(CHILDREN_TAG.into(), serde_json::Value::from(children.clone()).to_string().into());
+        }
+        tags.insert(VALID_TAG.into(), problem.is_none().to_string().into());
+        if let Some(problem) = problem {
+            tags.insert(INVALID_REASON_TAG.into(), problem.to_string().into());
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Decides whether a closed way describes an area or a closed line.
+
+use crate::intern::{Tag, TagMap};
+use osmpbfreader::Tags;
+
+// Which values of a key imply an area
+enum Rule {
+    Any,
+    AnyExcept(&'static [&'static str]),
+    Only(&'static [&'static str]),
+}
+
+// Area keys in the spirit of the osm2pgsql and osmium defaults: a closed way
+// with one of these tags is a polygon unless tagged `area=no`
+const AREA_KEYS: &[(&str, Rule)] = &[
+    ("building", Rule::Any),
+    ("building:part", Rule::Any),
+    ("landuse", Rule::Any),
+    ("landcover", Rule::Any),
+    ("natural", Rule::AnyExcept(&["coastline", "cliff", "ridge", "arete", "tree_row", "earth_bank"])),
+    ("amenity", Rule::Any),
+    ("leisure", Rule::AnyExcept(&["track", "slipway"])),
+    ("shop", Rule::Any),
+    ("tourism", Rule::Any),
+    ("office", Rule::Any),
+    ("craft", Rule::Any),
+    ("historic", Rule::Any),
+    ("military", Rule::Any),
+    ("place", Rule::Any),
+    ("water", Rule::Any),
+    ("wetland", Rule::Any),
+    ("area:highway", Rule::Any),
+    ("aeroway", Rule::AnyExcept(&["runway", "taxiway", "taxilane", "parking_position", "jet_bridge"])),
+    ("man_made", Rule::AnyExcept(&["cutline", "embankment", "pipeline", "dyke", "groyne", "breakwater"])),
+    ("power", Rule::AnyExcept(&["line", "minor_line", "cable"])),
+    ("highway", Rule::Only(&["rest_area", "services", "platform"])),
+    ("railway", Rule::Only(&["platform", "station", "turntable", "roundhouse"])),
+    ("public_transport", Rule::Only(&["platform", "station"])),
+    ("waterway", Rule::Only(&["riverbank", "dock", "boatyard", "dam", "fuel"])),
+];
+
+/// Whether a closed way with these tags should become a polygon.
+///
+/// `area=yes` and `area=no` always win; otherwise the way is an area if any
+/// of its tags is listed in the area key table. Closed ways without such
+/// tags, like roundabouts or closed fences, stay linestrings.
+pub fn is_area(tags: &Tags) -> bool {
+    is_area_by(|key| tags.get(key).map(|v| v.as_str()))
+}
+
+/// [`is_area`] for the tags of an extracted feature
+pub fn is_area_properties(tags: &TagMap) -> bool {
+    is_area_by(|key| tags.get(key).map(Tag::as_str))
+}
+
+fn is_area_by<'a>(get: impl Fn(&str) -> Option<&'a str>) -> bool {
+    match get("area") {
+        Some("yes") => return true,
+        Some("no") => return false,
+        _ => {}
+    }
+    AREA_KEYS.iter().any(|(key, rule)| {
+        get(key).is_some_and(|value| match rule {
+            Rule::Any => value != "no",
+            Rule::AnyExcept(values) => value != "no" && !values.contains(&value),
+            Rule::Only(values) => values.contains(&value),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in pairs {
+            tags.insert((*key).into(), (*value).into());
+        }
+        tags
+    }
+
+    fn area(pairs: &[(&str, &str)]) -> bool {
+        is_area(&tags(pairs))
+    }
+
+    #[test]
+    fn area_keys() {
+        assert!(area(&[("building", "yes")]));
+        assert!(area(&[("landuse", "residential"), ("name", "Park")]));
+        assert!(area(&[("natural", "wood")]));
+        assert!(!area(&[("natural", "coastline")]));
+        assert!(!area(&[("building", "no")]));
+        assert!(area(&[("highway", "rest_area")]));
+        assert!(!area(&[("highway", "residential"), ("junction", "roundabout")]));
+        assert!(!area(&[("barrier", "fence")]));
+        assert!(!area(&[]));
+    }
+
+    #[test]
+    fn area_tag_overrides() {
+        assert!(!area(&[("building", "yes"), ("area", "no")]));
+        assert!(!area(&[("leisure", "track")]));
+        assert!(area(&[("leisure", "track"), ("area", "yes")]));
+        assert!(area(&[("highway", "pedestrian"), ("area", "yes")]));
+        assert!(!area(&[("highway", "pedestrian")]));
+    }
+}
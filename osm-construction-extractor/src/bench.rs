@@ -0,0 +1,147 @@
+//! Repeated timing of an extraction, for tracking performance across
+//! releases.
+
+use crate::extractor::Extractor;
+use crate::memory;
+use crate::output::{self, OutputFormat, WriteOptions};
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What to run and how often
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Measured runs
+    pub runs: usize,
+    /// Unmeasured runs before the measured ones, e.g. to warm the page cache
+    pub warmup: usize,
+    /// Also time writing the features in this format, to a temporary file
+    pub format: Option<OutputFormat>,
+    pub write_options: WriteOptions,
+}
+
+/// Shortest, median and longest duration of one phase over all runs
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PhaseTimes {
+    pub min_ms: f64,
+    pub median_ms: f64,
+    pub max_ms: f64,
+}
+
+impl PhaseTimes {
+    fn of(durations: &mut [Duration]) -> PhaseTimes {
+        durations.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mid = durations.len() / 2;
+        let median = if durations.len().is_multiple_of(2) {
+            (ms(durations[mid - 1]) + ms(durations[mid])) / 2.0
+        } else {
+            ms(durations[mid])
+        };
+        PhaseTimes {
+            min_ms: ms(durations[0]),
+            median_ms: median,
+            max_ms: ms(durations[durations.len() - 1]),
+        }
+    }
+}
+
+/// The results of [`run`], serialized as the JSON report
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    /// Version of this crate, to tell the reports of releases apart
+    pub version: &'static str,
+    pub runs: usize,
+    /// Features extracted per run
+    pub features: usize,
+    pub objects_read: usize,
+    pub input_bytes: u64,
+    /// Reading and dependency resolution
+    pub extraction: PhaseTimes,
+    /// Geometry assembly
+    pub processing: PhaseTimes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write: Option<PhaseTimes>,
+    pub total: PhaseTimes,
+    /// Estimated size of the node location index in streaming mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_bytes: Option<u64>,
+    /// Highest resident set size of the process, where the OS reports it
+    pub peak_rss_bytes: Option<u64>,
+}
+
+/// Runs the extraction `warmup + runs` times and summarizes the measured
+/// runs
+pub fn run(extractor: &Extractor, options: &BenchOptions) -> Result<BenchReport> {
+    anyhow::ensure!(options.runs > 0, "at least one run is needed");
+    anyhow::ensure!(options.format != Some(OutputFormat::Pbf), "PBF output cannot be benchmarked");
+    let dir = tempfile::tempdir()?;
+    let (mut extraction, mut processing, mut write, mut total) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let (mut features, mut objects_read, mut input_bytes, mut index_bytes) = (0, 0, 0, None);
+    for i in 0..options.warmup + options.runs {
+        let start_time = Instant::now();
+        let (extracted, stats) = extractor.run_with_stats()?;
+        let write_duration = match options.format {
+            Some(format) => {
+                let path = dir.path().join(format!("bench.{}", format.extension()));
+                let write_start_time = Instant::now();
+                output::write(&path, format, &extracted, &options.write_options)?;
+                Some(write_start_time.elapsed())
+            }
+            None => None,
+        };
+        let total_duration = start_time.elapsed();
+        if i < options.warmup {
+            continue;
+        }
+        extraction.push(stats.extraction_duration);
+        processing.push(stats.processing_duration);
+        write.extend(write_duration);
+        total.push(total_duration);
+        (features, objects_read, input_bytes) = (extracted.len(), stats.objects_read, stats.input_bytes);
+        index_bytes = stats.index_bytes;
+    }
+    Ok(BenchReport {
+        version: env!("CARGO_PKG_VERSION"),
+        runs: options.runs,
+        features,
+        objects_read,
+        input_bytes,
+        extraction: PhaseTimes::of(&mut extraction),
+        processing: PhaseTimes::of(&mut processing),
+        write: (!write.is_empty()).then(|| PhaseTimes::of(&mut write)),
+        total: PhaseTimes::of(&mut total),
+        index_bytes,
+        peak_rss_bytes: memory::peak_rss(),
+    })
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Runs: {}, features per run: {}", self.runs, self.features)?;
+        writeln!(f, "{:<12} {:>12} {:>12} {:>12}", "phase", "min", "median", "max")?;
+        let phases = [
+            ("extraction", Some(&self.extraction)),
+            ("processing", Some(&self.processing)),
+            ("write", self.write.as_ref()),
+            ("total", Some(&self.total)),
+        ];
+        for (name, times) in phases {
+            if let Some(t) = times {
+                writeln!(
+                    f,
+                    "{:<12} {:>9.1} ms {:>9.1} ms {:>9.1} ms",
+                    name, t.min_ms, t.median_ms, t.max_ms
+                )?;
+            }
+        }
+        if let Some(bytes) = self.index_bytes {
+            writeln!(f, "Node index: {:.1} MB (estimated)", bytes as f64 / 1e6)?;
+        }
+        if let Some(bytes) = self.peak_rss_bytes {
+            writeln!(f, "Peak RSS: {:.1} MB", bytes as f64 / 1e6)?;
+        }
+        Ok(())
+    }
+}
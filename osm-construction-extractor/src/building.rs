@@ -0,0 +1,69 @@
+//! Building heights as numeric properties, e.g. for extruding footprints in
+//! 3D map renderers.
+
+use crate::feature::ExtractedFeature;
+
+/// Property holding the height of the top of a building in meters
+pub const HEIGHT_TAG: &str = "@height";
+/// Property holding the height of its bottom, above 0 for parts that float
+/// over the ground such as bridges between buildings
+pub const MIN_HEIGHT_TAG: &str = "@min_height";
+/// Property holding the number of levels above ground
+pub const LEVELS_TAG: &str = "@levels";
+
+/// Height assumed per level where only `building:levels` is tagged
+pub const LEVEL_HEIGHT: f64 = 3.0;
+
+/// Adds [`HEIGHT_TAG`], [`MIN_HEIGHT_TAG`] and, if tagged, [`LEVELS_TAG`]
+/// to a building.
+///
+/// Heights come from `height` and `min_height`, else from `building:levels`
+/// and `building:min_level` at [`LEVEL_HEIGHT`] per level; a building
+/// without either gets a single level, so that every footprint can be
+/// extruded. Values are in meters, rounded to centimeters.
+pub fn add_heights(feature: &mut ExtractedFeature) {
+    let tags = feature.tags();
+    let levels = tags.get("building:levels").and_then(|v| parse_levels(v));
+    let min_level = tags.get("building:min_level").and_then(|v| parse_levels(v));
+    let min_height = tags
+        .get("min_height")
+        .and_then(|v| parse_height(v))
+        .or(min_level.map(|l| l * LEVEL_HEIGHT))
+        .unwrap_or(0.0);
+    let height = tags
+        .get("height")
+        .and_then(|v| parse_height(v))
+        .or(levels.map(|l| l * LEVEL_HEIGHT))
+        .unwrap_or(LEVEL_HEIGHT)
+        .max(min_height);
+
+    let tags = feature.tags_mut();
+    tags.insert(HEIGHT_TAG.into(), format!("{:.2}", height).into());
+    tags.insert(MIN_HEIGHT_TAG.into(), format!("{:.2}", min_height).into());
+    if let Some(levels) = levels {
+        tags.insert(LEVELS_TAG.into(), levels.to_string().into());
+    }
+}
+
+/// Parses a `height`-style value into meters: a number with an optional
+/// `m` suffix, or feet and inches as `ft`, `40'` or `10'6"`. A decimal
+/// comma is accepted too.
+pub fn parse_height(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    let meters = if let Some((feet, inches)) = value.split_once('\'') {
+        let inches = inches.trim().trim_end_matches('"').trim();
+        let inches: f64 = if inches.is_empty() { 0.0 } else { inches.parse().ok()? };
+        (feet.trim().parse::<f64>().ok()? * 12.0 + inches) * 0.0254
+    } else if let Some(feet) = value.strip_suffix("ft") {
+        feet.trim().parse::<f64>().ok()? * 0.3048
+    } else {
+        value.strip_suffix('m').unwrap_or(&value).trim().parse().ok()?
+    };
+    (meters.is_finite() && meters >= 0.0).then_some(meters)
+}
+
+// A level count such as `4` or `2.5` (for a roof floor)
+fn parse_levels(value: &str) -> Option<f64> {
+    let levels: f64 = value.trim().replace(',', ".").parse().ok()?;
+    (levels.is_finite() && levels >= 0.0).then_some(levels)
+}
@@ -0,0 +1,40 @@
+//! Stopping an extraction early, e.g. on Ctrl-C, without losing what it
+//! extracted so far.
+//!
+//! Each extraction watches the [`CancelToken`] given to
+//! [`Extractor::cancel_token`](crate::Extractor::cancel_token). Once it is
+//! cancelled, the passes over the input stop at the next batch of blocks as
+//! if the input ended there, and the extraction goes on to assemble and
+//! write the objects it already read, so outputs are finished and valid yet
+//! partial. What a dependency pass read since the checkpoint last saved
+//! within it is not saved, so `--resume` redoes it from there.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared by the extractions it is given to and whoever may stop
+/// them; clones share it
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Asks the extractions watching the token to stop; safe to call from a
+    /// signal handler
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`CancelToken::cancel`] was called on the token or a clone
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears the token, so the extractions it is given to next run in full
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
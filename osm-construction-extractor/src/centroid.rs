@@ -0,0 +1,30 @@
+//! Reduction of features to a single point, e.g. for heatmaps and
+//! clustering.
+
+use clap::ValueEnum;
+use geo::{Centroid, Geometry, InteriorPoint};
+
+/// The geometry written for each feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputGeometry {
+    /// The assembled point, line or polygon
+    #[default]
+    Full,
+    /// The center of mass, which may lie outside of concave polygons and
+    /// off curved lines
+    Centroid,
+    /// A point guaranteed to lie on the line or inside the polygon, close
+    /// to its center
+    PointOnSurface,
+}
+
+/// The point a geometry is reduced to, or `None` to keep it as it is:
+/// always for [`OutputGeometry::Full`], else if it is empty
+pub fn reduce(geom: &Geometry, output: OutputGeometry) -> Option<Geometry> {
+    let point = match output {
+        OutputGeometry::Full => return None,
+        OutputGeometry::Centroid => geom.centroid(),
+        OutputGeometry::PointOnSurface => geom.interior_point(),
+    };
+    point.map(Geometry::Point)
+}
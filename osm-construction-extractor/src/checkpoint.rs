@@ -0,0 +1,397 @@
+//! Checkpoints of dependency mode, so that a run over a large input that is
+//! interrupted can resume where it stopped instead of starting over.
+//!
+//! After every pass over the input, and every [`Checkpoint::interval`]
+//! blocks within one, the objects collected so far, matched ones included,
+//! the ids of the dependencies still missing, how deeply the relations
+//! among them are nested and the locations of the dependency nodes are
+//! written to a directory of their own, `pass-N-B` for `N` passes done and
+//! `B` blocks of the next, and `state.json` is then replaced to point at
+//! it, so a run stopped at any moment leaves the last complete save
+//! behind. The objects and locations are written as PBF files, the
+//! missing ids as a list [`ids::read_file`] reads and the depths as JSON.
+//!
+//! A checkpoint only applies to the input and selection it was written
+//! for; one for another input, a changed file or other filters is ignored
+//! and overwritten.
+
+use crate::ids;
+use crate::input::Input;
+use crate::pbf::writer::Writer;
+use crate::pbf::ParallelObjects;
+use crate::store::{NodeIndex, NodeIndexKind};
+use anyhow::{bail, Context, Result};
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, OsmPbfReader, RelationId, Tags};
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Bumped whenever the files of a checkpoint change
+const VERSION: u32 = 3;
+
+/// Blocks of a pass between two saves within it, by default
+pub const INTERVAL: usize = 1000;
+
+const STATE_FILE: &str = "state.json";
+const OBJECTS_FILE: &str = "objects.osm.pbf";
+const LOCATIONS_FILE: &str = "locations.osm.pbf";
+const MISSING_FILE: &str = "missing.txt";
+const DEPTHS_FILE: &str = "relation-depths.json";
+
+/// The checkpoint directory of one input
+#[derive(Debug)]
+pub struct Checkpoint {
+    dir: PathBuf,
+    state: State,
+    interval: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Source {
+    input: String,
+    // Size and modification time, see `Input::fingerprint`
+    fingerprint: [u64; 2],
+    selection: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    source: Source,
+    // Passes over the input that were completed, and blocks of the next
+    passes: usize,
+    block: usize,
+    // Whether the last pass, or the one under way so far, found no new
+    // dependencies
+    finished: bool,
+}
+
+/// What dependency mode had collected when it was last saved
+#[derive(Debug)]
+pub struct Progress {
+    pub objects: BTreeMap<OsmId, OsmObj>,
+    /// Dependencies not found yet
+    pub missing: BTreeSet<OsmId>,
+    /// How many levels below a matched relation each relation among the
+    /// objects or the missing ones is
+    pub depths: BTreeMap<RelationId, usize>,
+    /// Locations of the dependency nodes, if they were kept apart from
+    /// `objects`
+    pub nodes: NodeIndex,
+    /// Passes over the input completed
+    pub passes: usize,
+    /// Blocks of the next pass already read, where it resumes
+    pub block: usize,
+    /// Whether no pass is left to do, or within a pass, whether it found
+    /// no new dependencies so far
+    pub finished: bool,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint in `dir`, creating the directory if needed.
+    /// `selection` describes everything deciding which objects match, and
+    /// the saved progress is only used if it was written for the same one
+    /// and the same unchanged input; inputs that are not local files, whose
+    /// changes cannot be told, are not supported.
+    pub fn open(dir: &Path, input: &Input, selection: String) -> Result<Checkpoint> {
+        let Some(fingerprint) = input.fingerprint() else {
+            bail!("resuming requires a local input file, not {}", input);
+        };
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create the checkpoint directory {}", dir.display()))?;
+        let input = match input {
+            Input::Path(path) => fs::canonicalize(path)?.display().to_string(),
+            _ => input.to_string(),
+        };
+        let source = Source { input, fingerprint, selection };
+        let saved = match fs::read_to_string(dir.join(STATE_FILE)) {
+            Ok(text) => Some(serde_json::from_str::<State>(&text).context("invalid checkpoint state")?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let state = match saved {
+            Some(state) if state.version == VERSION && state.source == source => state,
+            saved => {
+                if saved.is_some() {
+                    warn!("Ignoring the checkpoint in {}, written for another input or selection", dir.display());
+                }
+                clear(dir)?;
+                State { version: VERSION, source, passes: 0, block: 0, finished: false }
+            }
+        };
+        Ok(Checkpoint { dir: dir.to_path_buf(), state, interval: INTERVAL })
+    }
+
+    /// Saves every `blocks` blocks within a pass, instead of [`INTERVAL`]
+    pub fn save_every(mut self, blocks: usize) -> Self {
+        self.interval = blocks.max(1);
+        self
+    }
+
+    /// Blocks of a pass between two saves within it
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// The passes over the input the saved progress covers
+    pub fn passes(&self) -> usize {
+        self.state.passes
+    }
+
+    /// The blocks of the pass after [`Checkpoint::passes`] the saved
+    /// progress covers
+    pub fn blocks(&self) -> usize {
+        self.state.block
+    }
+
+    /// Reads back the progress saved last, if any
+    pub fn load(&self, pool: &ThreadPool) -> Result<Option<Progress>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let pass = self.save_dir(self.state.passes, self.state.block);
+        let mut objects = BTreeMap::new();
+        read_pbf(&pass.join(OBJECTS_FILE), pool, |obj| {
+            objects.insert(obj.id(), obj);
+            Ok(())
+        })?;
+        let mut nodes = NodeIndex::new(NodeIndexKind::Sorted);
+        read_pbf(&pass.join(LOCATIONS_FILE), pool, |obj| match obj {
+            OsmObj::Node(node) => nodes.insert(&node),
+            _ => Ok(()),
+        })?;
+        nodes.prepare();
+        let missing = ids::read_file(&pass.join(MISSING_FILE))?.into_iter().collect();
+        let depths: BTreeMap<i64, usize> = serde_json::from_str(&fs::read_to_string(pass.join(DEPTHS_FILE))?)?;
+        let depths = depths.into_iter().map(|(id, depth)| (RelationId(id), depth)).collect();
+        let (passes, block, finished) = (self.state.passes, self.state.block, self.state.finished);
+        Ok(Some(Progress { objects, missing, depths, nodes, passes, block, finished }))
+    }
+
+    /// Saves the progress after a pass, replacing the one saved before
+    pub fn save(
+        &mut self,
+        objects: &BTreeMap<OsmId, OsmObj>,
+        missing: &BTreeSet<OsmId>,
+        depths: &BTreeMap<RelationId, usize>,
+        nodes: Option<&NodeIndex>,
+        finished: bool,
+    ) -> Result<()> {
+        self.write(objects, missing, depths, nodes, self.state.passes + 1, 0, finished)
+    }
+
+    /// Saves the progress within a pass, once the first `block` blocks of
+    /// it were read, replacing the one saved before
+    pub fn save_within(
+        &mut self,
+        objects: &BTreeMap<OsmId, OsmObj>,
+        missing: &BTreeSet<OsmId>,
+        depths: &BTreeMap<RelationId, usize>,
+        nodes: Option<&NodeIndex>,
+        block: usize,
+        finished: bool,
+    ) -> Result<()> {
+        self.write(objects, missing, depths, nodes, self.state.passes, block, finished)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.passes == 0 && self.state.block == 0
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &mut self,
+        objects: &BTreeMap<OsmId, OsmObj>,
+        missing: &BTreeSet<OsmId>,
+        depths: &BTreeMap<RelationId, usize>,
+        nodes: Option<&NodeIndex>,
+        passes: usize,
+        block: usize,
+        finished: bool,
+    ) -> Result<()> {
+        let pass = self.save_dir(passes, block);
+        fs::create_dir_all(&pass)?;
+
+        let mut writer = Writer::new(BufWriter::new(File::create(pass.join(OBJECTS_FILE))?))?;
+        for obj in objects.values() {
+            writer.write(obj.clone())?;
+        }
+        writer.finish()?;
+        let mut writer = Writer::new(BufWriter::new(File::create(pass.join(LOCATIONS_FILE))?))?;
+        if let Some(NodeIndex::Sorted { ids, locations, .. }) = nodes {
+            for (&id, &[lon, lat]) in ids.iter().zip(locations) {
+                let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: lat, decimicro_lon: lon };
+                writer.write(OsmObj::Node(node))?;
+            }
+        }
+        writer.finish()?;
+        write_ids(&pass.join(MISSING_FILE), missing.iter().copied())?;
+        let depths: BTreeMap<i64, usize> = depths.iter().map(|(id, &depth)| (id.0, depth)).collect();
+        fs::write(pass.join(DEPTHS_FILE), serde_json::to_string(&depths)?)?;
+
+        let previous = (!self.is_empty()).then(|| self.save_dir(self.state.passes, self.state.block));
+        (self.state.passes, self.state.block, self.state.finished) = (passes, block, finished);
+        self.write_state()?;
+        if let Some(previous) = previous {
+            fs::remove_dir_all(previous)?;
+        }
+        Ok(())
+    }
+
+    fn save_dir(&self, passes: usize, block: usize) -> PathBuf {
+        self.dir.join(format!("pass-{}-{}", passes, block))
+    }
+
+    // Replaces the state at once, so it never points to a save only partly
+    // written
+    fn write_state(&self) -> Result<()> {
+        let path = self.dir.join(STATE_FILE);
+        let temporary = self.dir.join(format!("{}.tmp", STATE_FILE));
+        fs::write(&temporary, serde_json::to_string_pretty(&self.state)?)?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+// Removes what a checkpoint for another input or selection left
+fn clear(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("pass-") {
+            fs::remove_dir_all(entry.path())?;
+        } else if name == STATE_FILE {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_pbf(path: &Path, pool: &ThreadPool, mut each: impl FnMut(OsmObj) -> Result<()>) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to read the checkpoint file {}", path.display()))?;
+    let mut reader = OsmPbfReader::new(BufReader::new(file));
+    ParallelObjects::new(&mut reader, pool).try_for_each(|obj| each(obj?))
+}
+
+fn write_ids(path: &Path, ids: impl Iterator<Item = OsmId>) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for id in ids {
+        match id {
+            OsmId::Node(id) => writeln!(out, "n{}", id.0)?,
+            OsmId::Way(id) => writeln!(out, "w{}", id.0)?,
+            OsmId::Relation(id) => writeln!(out, "r{}", id.0)?,
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::pbf;
+    use osmpbfreader::{Way, WayId};
+
+    fn node(id: i64) -> Node {
+        Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: id as i32, decimicro_lon: -id as i32 }
+    }
+
+    // A file with 40000 nodes, five blocks of them, then a block of ways
+    // joining the last nodes of each block
+    fn input(dir: &Path) -> Input {
+        let path = dir.join("input.osm.pbf");
+        let mut writer = Writer::new(BufWriter::new(File::create(&path).unwrap())).unwrap();
+        for id in 1..=40000 {
+            writer.write(OsmObj::Node(node(id))).unwrap();
+        }
+        for id in 1..=5 {
+            let mut tags = Tags::new();
+            tags.insert("highway".into(), "road".into());
+            let nodes = vec![NodeId(id * 8000 - 1), NodeId(id * 8000)];
+            writer.write(OsmObj::Way(Way { id: WayId(id), tags, nodes })).unwrap();
+        }
+        writer.finish().unwrap();
+        Input::Path(path)
+    }
+
+    #[test]
+    fn saved_progress_is_loaded_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = input(dir.path());
+        let pool = pbf::thread_pool(1).unwrap();
+        let objects: BTreeMap<OsmId, OsmObj> = [(OsmId::Node(NodeId(1)), OsmObj::Node(node(1)))].into();
+        let missing = BTreeSet::from([OsmId::Way(WayId(2)), OsmId::Relation(RelationId(3))]);
+        let depths = BTreeMap::from([(RelationId(3), 1)]);
+        let mut nodes = NodeIndex::new(NodeIndexKind::Sorted);
+        nodes.insert(&node(7)).unwrap();
+        nodes.prepare();
+
+        let mut checkpoint = Checkpoint::open(dir.path(), &input, "highway".into()).unwrap();
+        assert!(checkpoint.load(&pool).unwrap().is_none());
+        checkpoint.save(&objects, &missing, &depths, Some(&nodes), false).unwrap();
+        checkpoint.save_within(&objects, &missing, &depths, Some(&nodes), 12, true).unwrap();
+
+        let checkpoint = Checkpoint::open(dir.path(), &input, "highway".into()).unwrap();
+        assert_eq!((checkpoint.passes(), checkpoint.blocks()), (1, 12));
+        let progress = checkpoint.load(&pool).unwrap().unwrap();
+        assert_eq!(progress.objects, objects);
+        assert_eq!(progress.missing, missing);
+        assert_eq!(progress.depths, depths);
+        assert!(progress.nodes.get(NodeId(7)).is_some());
+        assert!(progress.nodes.get(NodeId(1)).is_none());
+        assert_eq!((progress.passes, progress.block, progress.finished), (1, 12, true));
+        // Only the last save is kept
+        assert!(!dir.path().join("pass-1-0").exists());
+    }
+
+    #[test]
+    fn checkpoints_of_another_selection_are_cleared() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = input(dir.path());
+        let mut checkpoint = Checkpoint::open(dir.path(), &input, "highway".into()).unwrap();
+        checkpoint.save(&BTreeMap::new(), &BTreeSet::new(), &BTreeMap::new(), None, true).unwrap();
+        assert!(dir.path().join("pass-1-0").exists());
+
+        let checkpoint = Checkpoint::open(dir.path(), &input, "building".into()).unwrap();
+        assert_eq!((checkpoint.passes(), checkpoint.blocks()), (0, 0));
+        assert!(!dir.path().join("pass-1-0").exists());
+        assert!(!dir.path().join(STATE_FILE).exists());
+    }
+
+    #[test]
+    fn passes_resume_within_from_the_last_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = input(dir.path());
+        let pool = pbf::thread_pool(1).unwrap();
+        let run = |cancel: &CancelToken, stop_at: Option<OsmId>| {
+            let mut checkpoint = Checkpoint::open(&dir.path().join("state"), &input, "highway".into()).unwrap().save_every(1);
+            let mut reader = OsmPbfReader::new(File::open(input.to_string()).unwrap());
+            let pred = |obj: &OsmObj| {
+                if Some(obj.id()) == stop_at {
+                    cancel.cancel();
+                }
+                Ok(obj.is_way())
+            };
+            let store = pbf::get_objs_and_slim_deps(&mut reader, &pool, cancel, false, 0, Some(&mut checkpoint), None, pred);
+            (store.unwrap(), checkpoint.passes(), checkpoint.blocks())
+        };
+
+        // A single thread reads four blocks at a time, so the pass stops
+        // after the four holding the node cancelling it, which are saved
+        let (_, passes, blocks) = run(&CancelToken::new(), Some(OsmId::Node(NodeId(1000))));
+        assert_eq!((passes, blocks), (0, 4));
+        let (resumed, _, _) = run(&CancelToken::new(), None);
+        assert_eq!(resumed.objects.len(), 5);
+        for id in 1..=5 {
+            assert!(resumed.nodes.get(NodeId(id * 8000 - 1)).is_some());
+            assert!(resumed.nodes.get(NodeId(id * 8000)).is_some());
+        }
+        assert!(resumed.nodes.get(NodeId(1)).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+//! Cleanup of sloppy geometries: repeated points, segments of no length
+//! and area ways whose ends were left just short of each other.
+
+use crate::area;
+use crate::feature::ExtractedFeature;
+use geo::orient::{Direction, Orient};
+use geo::{Coord, Geometry, HaversineDistance, LineString, Point, Polygon};
+use serde_json::{Map, Value};
+
+/// Property listing what was fixed in a feature's geometry, as a JSON
+/// object of counts, e.g. `{"duplicate_points":2,"closed_rings":1}`; set
+/// only on features that needed fixing
+pub const FIXES_TAG: &str = "@fixes";
+
+// OSM stores coordinates in steps of 1e-7°
+const PRECISION: f64 = 1e7;
+
+/// What was fixed in a geometry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fixes {
+    /// Points equal to the one before them, removed
+    pub duplicate_points: usize,
+    /// Points at the same position as the one before them at OSM's
+    /// precision, but not equal to it, removed
+    pub zero_length_segments: usize,
+    /// Lines of area ways whose ends were within the tolerance, closed
+    /// into polygons
+    pub closed_rings: usize,
+}
+
+impl Fixes {
+    pub fn is_empty(&self) -> bool {
+        *self == Fixes::default()
+    }
+
+    fn add(&mut self, other: Fixes) {
+        self.duplicate_points += other.duplicate_points;
+        self.zero_length_segments += other.zero_length_segments;
+        self.closed_rings += other.closed_rings;
+    }
+
+    /// The counts that are not 0, as a JSON object
+    pub fn to_json(&self) -> Value {
+        let counts = [
+            ("duplicate_points", self.duplicate_points),
+            ("zero_length_segments", self.zero_length_segments),
+            ("closed_rings", self.closed_rings),
+        ];
+        let map: Map<String, Value> =
+            counts.into_iter().filter(|(_, n)| *n > 0).map(|(k, n)| (k.to_string(), Value::from(n))).collect();
+        Value::Object(map)
+    }
+}
+
+/// Fixes the feature's geometry and records the fixes in [`FIXES_TAG`].
+///
+/// A line of an area way (see [`area::is_area_properties`]) whose ends are
+/// at most `close_tolerance` meters apart becomes a polygon. Parts that
+/// would be left with too few points to be a line or ring are kept as
+/// they are.
+pub fn fix(feature: &mut ExtractedFeature, close_tolerance: f64) -> Fixes {
+    let mut fixes = Fixes::default();
+    let closable = area::is_area_properties(feature.tags());
+    let geometry = match feature.geometry_mut() {
+        // A single line is all a way gives that could be a broken ring
+        Geometry::LineString(line) if closable => {
+            fix_line(line, false, &mut fixes);
+            close(line, close_tolerance, &mut fixes)
+        }
+        geometry => {
+            fix_geometry(geometry, &mut fixes);
+            None
+        }
+    };
+    if let Some(geometry) = geometry {
+        feature.set_geometry(geometry);
+    }
+    if !fixes.is_empty() {
+        feature.tags_mut().insert(FIXES_TAG.into(), fixes.to_json().to_string().into());
+    }
+    fixes
+}
+
+fn fix_geometry(geometry: &mut Geometry, fixes: &mut Fixes) {
+    match geometry {
+        Geometry::LineString(line) => fix_line(line, false, fixes),
+        Geometry::MultiLineString(lines) => lines.iter_mut().for_each(|line| fix_line(line, false, fixes)),
+        Geometry::Polygon(polygon) => fix_polygon(polygon, fixes),
+        Geometry::MultiPolygon(polygons) => polygons.iter_mut().for_each(|polygon| fix_polygon(polygon, fixes)),
+        Geometry::GeometryCollection(collection) => collection.iter_mut().for_each(|g| fix_geometry(g, fixes)),
+        _ => {}
+    }
+}
+
+fn fix_polygon(polygon: &mut Polygon, fixes: &mut Fixes) {
+    polygon.exterior_mut(|ring| fix_line(ring, true, fixes));
+    polygon.interiors_mut(|rings| rings.iter_mut().for_each(|ring| fix_line(ring, true, fixes)));
+}
+
+fn same_position(a: Coord, b: Coord) -> bool {
+    (a.x * PRECISION).round() == (b.x * PRECISION).round() && (a.y * PRECISION).round() == (b.y * PRECISION).round()
+}
+
+fn fix_line(line: &mut LineString, is_ring: bool, fixes: &mut Fixes) {
+    let mut found = Fixes::default();
+    let mut kept: Vec<Coord> = Vec::with_capacity(line.0.len());
+    for &coord in &line.0 {
+        match kept.last() {
+            Some(&last) if last == coord => found.duplicate_points += 1,
+            Some(&last) if same_position(last, coord) => found.zero_length_segments += 1,
+            _ => kept.push(coord),
+        }
+    }
+    if found.is_empty() {
+        return;
+    }
+    // The closing point may have been the one dropped
+    if is_ring && kept.len() > 1 && kept.first() != kept.last() {
+        let last = kept.len() - 1;
+        kept[last] = kept[0];
+    }
+    if kept.len() < if is_ring { 4 } else { 2 } {
+        return;
+    }
+    line.0 = kept;
+    fixes.add(found);
+}
+
+fn close(line: &LineString, tolerance: f64, fixes: &mut Fixes) -> Option<Geometry> {
+    let (&first, &last) = (line.0.first()?, line.0.last()?);
+    if first == last || line.0.len() < 3 || Point(first).haversine_distance(&Point(last)) > tolerance {
+        return None;
+    }
+    let mut ring = line.0.clone();
+    if same_position(first, last) {
+        ring.pop();
+    }
+    if ring.len() < 3 {
+        return None;
+    }
+    ring.push(first);
+    fixes.closed_rings += 1;
+    Some(Polygon::new(LineString(ring), vec![]).orient(Direction::Default).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature::OsmType;
+    use crate::intern::TagMap;
+
+    fn fixed(coords: &[(f64, f64)], is_ring: bool) -> (Vec<(f64, f64)>, Fixes) {
+        let mut line: LineString = coords.to_vec().into();
+        let mut fixes = Fixes::default();
+        fix_line(&mut line, is_ring, &mut fixes);
+        (line.coords().map(|c| (c.x, c.y)).collect(), fixes)
+    }
+
+    // An almost closed square building about 110 m wide, whose last point
+    // is `gap` degrees short of the first
+    fn building(gap: f64) -> ExtractedFeature {
+        let mut tags = TagMap::new();
+        tags.insert("building".into(), "yes".into());
+        let coords = vec![(0.0, 0.0), (0.001, 0.0), (0.001, 0.001), (0.0, 0.001), (0.0, gap)];
+        ExtractedFeature::new(OsmType::Way, 1, tags, Geometry::LineString(coords.into()))
+    }
+
+    #[test]
+    fn repeated_points_are_removed() {
+        let (coords, fixes) = fixed(&[(0.0, 0.0), (0.0, 0.0), (1.0, 0.0), (1.0, 0.0), (2.0, 0.0)], false);
+        assert_eq!(coords, [(0.0, 0.0), (1.0, 0.0), (2.0, 0.0)]);
+        assert_eq!(fixes, Fixes { duplicate_points: 2, ..Fixes::default() });
+    }
+
+    #[test]
+    fn points_apart_below_the_osm_precision_are_removed() {
+        let (coords, fixes) = fixed(&[(1.0, 0.0), (1.00000002, 0.0), (1.0000001, 0.0)], false);
+        // One step of 1e-7° is a segment of its own
+        assert_eq!(coords, [(1.0, 0.0), (1.0000001, 0.0)]);
+        assert_eq!(fixes, Fixes { zero_length_segments: 1, ..Fixes::default() });
+    }
+
+    #[test]
+    fn rings_stay_closed_when_their_closing_point_is_dropped() {
+        let ring = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.00000002, 0.0), (0.0, 0.0)];
+        let (coords, fixes) = fixed(&ring, true);
+        assert_eq!(coords, [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+        assert_eq!(fixes.zero_length_segments, 1);
+
+        // Too few points left for a ring
+        let (coords, fixes) = fixed(&[(0.0, 0.0), (1.0, 0.0), (1.0, 0.0), (0.0, 0.0)], true);
+        assert_eq!((coords.len(), fixes.is_empty()), (4, true));
+    }
+
+    #[test]
+    fn area_ways_are_closed_within_the_tolerance() {
+        // About 1.1 m short
+        let mut feature = building(0.00001);
+        assert_eq!(fix(&mut feature, 2.0).closed_rings, 1);
+        let Geometry::Polygon(polygon) = feature.geometry() else {
+            panic!("not closed: {:?}", feature.geometry());
+        };
+        assert_eq!(polygon.exterior().0.len(), 6);
+        assert!(polygon.exterior().is_closed());
+        assert_eq!(feature.tags().get(FIXES_TAG).map(|v| v.as_str()), Some(r#"{"closed_rings":1}"#));
+
+        let mut feature = building(0.00001);
+        assert!(fix(&mut feature, 1.0).is_empty());
+        assert!(matches!(feature.geometry(), Geometry::LineString(_)));
+        assert_eq!(feature.tags().get(FIXES_TAG), None);
+    }
+}
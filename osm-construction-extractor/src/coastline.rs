@@ -0,0 +1,245 @@
+//! Assembly of land polygons from `natural=coastline` ways, the problem
+//! solved by osmcoastline, optionally split into a grid of cells.
+//!
+//! Coastline ways run with the land on their left, so the joined rings of
+//! land are counterclockwise and the rings of water lying in land, such as
+//! lagoons, clockwise. Coastlines cut off at the edge of an extract, or
+//! broken in the data, stay open; open ends close to each other are joined
+//! and the rest is reported rather than guessed at.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::intern::TagMap;
+use geo::orient::{Direction, Orient};
+use geo::winding_order::Winding;
+use geo::{Area, BooleanOps, Contains, Coord, HaversineDistance, LineString, MultiPolygon, Point, Polygon, Rect};
+use std::collections::HashMap;
+
+/// Selects the ways read for [`assemble`]
+pub const FILTER: &str = "natural=coastline";
+
+/// Property holding the number of coastline ways a land polygon was
+/// joined from
+pub const WAY_COUNT_TAG: &str = "@way_count";
+/// Properties holding the column and row of the grid cell of a part of a
+/// land polygon, counted from -180° longitude and -90° latitude
+pub const GRID_X_TAG: &str = "@grid_x";
+pub const GRID_Y_TAG: &str = "@grid_y";
+
+/// The land polygons assembled from coastline ways, and what could not be
+/// assembled
+#[derive(Debug, Default)]
+pub struct Coastline {
+    /// Land polygons, with the water inside them as holes; their ids are
+    /// those of the first way of their outer ring
+    pub land: Vec<ExtractedFeature>,
+    /// Chains of ways that stay open, as lines with the id of their first way
+    pub open: Vec<ExtractedFeature>,
+    /// Rings of water lying in no land ring
+    pub stray_water: usize,
+}
+
+// A line joined from coastline ways
+struct Chain {
+    coords: Vec<Coord>,
+    first_id: i64,
+    ways: usize,
+}
+
+impl Chain {
+    fn is_closed(&self) -> bool {
+        self.coords.len() >= 4 && self.coords.first() == self.coords.last()
+    }
+}
+
+// Shared nodes have bit-identical coordinates
+fn key(coord: Coord) -> (u64, u64) {
+    (coord.x.to_bits(), coord.y.to_bits())
+}
+
+/// Joins the coastline ways into rings and builds land polygons from them.
+///
+/// Ways are joined where one starts at the node another ends at. An open
+/// chain is then closed, or joined to the chain starting next to its end,
+/// if the gap is at most `close_distance` meters; 0 joins nothing but
+/// shared nodes. Features other than lines are ignored.
+pub fn assemble(ways: &[ExtractedFeature], close_distance: f64) -> Coastline {
+    let mut chains = join_shared(ways);
+    if close_distance > 0.0 {
+        close_gaps(&mut chains, close_distance);
+    }
+
+    let (rings, open): (Vec<Chain>, Vec<Chain>) = chains.into_iter().partition(Chain::is_closed);
+    let mut outers: Vec<(Polygon, i64, usize)> = Vec::new();
+    let mut waters: Vec<LineString> = Vec::new();
+    for ring in rings {
+        let line = LineString(ring.coords);
+        if line.is_ccw() {
+            outers.push((Polygon::new(line, vec![]), ring.first_id, ring.ways));
+        } else {
+            waters.push(line);
+        }
+    }
+
+    // A water ring is a hole in the smallest land ring around it
+    let areas: Vec<f64> = outers.iter().map(|(polygon, _, _)| polygon.unsigned_area()).collect();
+    let mut holes: Vec<Vec<LineString>> = vec![Vec::new(); outers.len()];
+    let mut stray_water = 0;
+    for water in waters {
+        let point = Point(water.0[0]);
+        let around = (0..outers.len())
+            .filter(|&i| outers[i].0.contains(&point))
+            .min_by(|&a, &b| areas[a].total_cmp(&areas[b]));
+        match around {
+            Some(i) => holes[i].push(water),
+            None => stray_water += 1,
+        }
+    }
+
+    let land = outers
+        .into_iter()
+        .zip(holes)
+        .map(|((polygon, id, ways), holes)| {
+            let polygon = Polygon::new(polygon.exterior().clone(), holes).orient(Direction::Default);
+            let tags = TagMap::from([
+                ("natural".into(), "land".into()),
+                (WAY_COUNT_TAG.into(), ways.to_string().into()),
+            ]);
+            ExtractedFeature::new(OsmType::Way, id, tags, polygon.into())
+        })
+        .collect();
+    let open = open
+        .into_iter()
+        .map(|chain| {
+            let tags = TagMap::from([
+                ("natural".into(), "coastline".into()),
+                (WAY_COUNT_TAG.into(), chain.ways.to_string().into()),
+            ]);
+            ExtractedFeature::new(OsmType::Way, chain.first_id, tags, LineString(chain.coords).into())
+        })
+        .collect();
+    Coastline { land, open, stray_water }
+}
+
+// Follows each way to the one starting where it ends, as long as exactly
+// one does
+fn join_shared(ways: &[ExtractedFeature]) -> Vec<Chain> {
+    let lines: Vec<(i64, &LineString)> = ways
+        .iter()
+        .filter_map(|feature| match feature.geometry() {
+            geo::Geometry::LineString(line) if line.0.len() >= 2 => Some((feature.id(), line)),
+            _ => None,
+        })
+        .collect();
+    let mut starts: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for (i, (_, line)) in lines.iter().enumerate() {
+        starts.entry(key(line.0[0])).or_default().push(i);
+    }
+    let next = |i: usize| match starts.get(&key(*lines[i].1 .0.last().expect("lines have points"))) {
+        Some(following) if following.len() == 1 => Some(following[0]),
+        _ => None,
+    };
+    let mut has_previous = vec![false; lines.len()];
+    for i in 0..lines.len() {
+        if let Some(following) = next(i) {
+            has_previous[following] = true;
+        }
+    }
+
+    // Chains start at ways nothing leads to; whatever is left over after
+    // them forms rings, which start at their first way
+    let mut used = vec![false; lines.len()];
+    let mut chains = Vec::new();
+    let heads = (0..lines.len()).filter(|&i| !has_previous[i]);
+    for head in heads.chain(0..lines.len()) {
+        if used[head] {
+            continue;
+        }
+        let mut chain = Chain { coords: lines[head].1 .0.clone(), first_id: lines[head].0, ways: 1 };
+        used[head] = true;
+        let mut current = head;
+        while let Some(following) = next(current).filter(|&j| !used[j]) {
+            chain.coords.extend_from_slice(&lines[following].1 .0[1..]);
+            chain.ways += 1;
+            used[following] = true;
+            current = following;
+        }
+        chains.push(chain);
+    }
+    chains
+}
+
+// Closes or joins open chains whose ends are at most `max` meters apart,
+// nearest gaps first
+fn close_gaps(chains: &mut Vec<Chain>, max: f64) {
+    loop {
+        let open: Vec<usize> = (0..chains.len()).filter(|&i| !chains[i].is_closed()).collect();
+        let mut nearest: Option<(f64, usize, usize)> = None;
+        for &from in &open {
+            let end = Point(*chains[from].coords.last().expect("chains have points"));
+            // A ring needs at least three points besides the closing one
+            for &to in open.iter().filter(|&&to| to != from || chains[from].coords.len() >= 3) {
+                let distance = end.haversine_distance(&Point(chains[to].coords[0]));
+                if distance <= max && nearest.is_none_or(|(best, _, _)| distance < best) {
+                    nearest = Some((distance, from, to));
+                }
+            }
+        }
+        let Some((_, from, to)) = nearest else {
+            return;
+        };
+        if from == to {
+            let start = chains[from].coords[0];
+            chains[from].coords.push(start);
+        } else {
+            let following = chains.swap_remove(to);
+            let from = if from == chains.len() { to } else { from };
+            chains[from].coords.extend(following.coords);
+            chains[from].ways += following.ways;
+        }
+    }
+}
+
+/// Splits land polygons along a grid of `cell` degrees, giving a feature
+/// for each part in a cell with [`GRID_X_TAG`] and [`GRID_Y_TAG`] set.
+///
+/// Large polygons are cut into columns first and each column into cells,
+/// so a continent is not intersected with every cell of the world.
+pub fn split(land: &[ExtractedFeature], cell: f64) -> Vec<ExtractedFeature> {
+    let index = |value: f64, min: f64| ((value - min) / cell).floor() as i64;
+    let mut parts = Vec::new();
+    for feature in land {
+        let geo::Geometry::Polygon(polygon) = feature.geometry() else {
+            continue;
+        };
+        let Some(bbox) = geo::BoundingRect::bounding_rect(polygon) else {
+            continue;
+        };
+        let polygon = MultiPolygon(vec![polygon.clone()]);
+        let rows = index(bbox.min().y, -90.0)..=index(bbox.max().y, -90.0);
+        for x in index(bbox.min().x, -180.0)..=index(bbox.max().x, -180.0) {
+            let (west, east) = (-180.0 + x as f64 * cell, -180.0 + (x + 1) as f64 * cell);
+            let column = Rect::new((west, bbox.min().y), (east, bbox.max().y)).to_polygon();
+            let column = polygon.intersection(&MultiPolygon(vec![column]));
+            if column.0.is_empty() {
+                continue;
+            }
+            for y in rows.clone() {
+                let (south, north) = (-90.0 + y as f64 * cell, -90.0 + (y + 1) as f64 * cell);
+                let square = Rect::new((west, south), (east, north)).to_polygon();
+                let mut clipped = column.intersection(&MultiPolygon(vec![square]));
+                if clipped.0.is_empty() {
+                    continue;
+                }
+                let mut tags = feature.tags().clone();
+                tags.insert(GRID_X_TAG.into(), x.to_string().into());
+                tags.insert(GRID_Y_TAG.into(), y.to_string().into());
+                let geometry = match clipped.0.len() {
+                    1 => clipped.0.pop().expect("one polygon").into(),
+                    _ => clipped.into(),
+                };
+                parts.push(ExtractedFeature::new(feature.osm_type(), feature.id(), tags, geometry));
+            }
+        }
+    }
+    parts
+}
@@ -0,0 +1,405 @@
+//! Compact in-memory storage of assembled features, for collecting all of
+//! them while the objects they were built from are still held, without two
+//! f64 per coordinate; they are decoded once the extraction is done.
+//!
+//! Coordinates are stored as fixed-point steps of 1e-7°, the precision of
+//! a PBF, each as the zigzag varint of its difference to the previous one,
+//! which for OSM geometries mostly takes one to three bytes instead of
+//! eight. Geometries with coordinates off that grid, e.g. clipped,
+//! simplified or projected ones, are kept as they are, so decoding always
+//! gives back exactly the geometry stored.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::intern::TagMap;
+use geo::{Coord, Geometry, GeometryCollection, LineString, MultiLineString, MultiPoint, MultiPolygon, Point, Polygon};
+
+// Steps per degree
+const SCALE: f64 = 1e7;
+
+// The first byte of an encoded geometry
+const POINT: u8 = 0;
+const LINE_STRING: u8 = 1;
+const POLYGON: u8 = 2;
+const MULTI_POINT: u8 = 3;
+const MULTI_LINE_STRING: u8 = 4;
+const MULTI_POLYGON: u8 = 5;
+const COLLECTION: u8 = 6;
+
+/// Holds features compactly until they are taken out with
+/// [`FeatureStore::into_features`]
+#[derive(Debug, Default)]
+pub struct FeatureStore {
+    features: Vec<CompactFeature>,
+    encoder: Encoder,
+}
+
+#[derive(Debug)]
+struct CompactFeature {
+    osm_type: OsmType,
+    id: i64,
+    tags: TagMap,
+    geometry: CompactGeometry,
+}
+
+#[derive(Debug)]
+enum CompactGeometry {
+    Encoded(Box<[u8]>),
+    Full(Geometry),
+}
+
+impl FeatureStore {
+    pub fn new() -> FeatureStore {
+        FeatureStore::default()
+    }
+
+    pub fn push(&mut self, feature: ExtractedFeature) {
+        let (osm_type, id) = (feature.osm_type(), feature.id());
+        let (tags, geometry) = match feature {
+            ExtractedFeature::Node { tags, geometry, .. }
+            | ExtractedFeature::Way { tags, geometry, .. }
+            | ExtractedFeature::Relation { tags, geometry, .. } => (tags, geometry),
+        };
+        let geometry = match self.encoder.encode(&geometry) {
+            Some(bytes) => CompactGeometry::Encoded(bytes),
+            None => CompactGeometry::Full(geometry),
+        };
+        self.features.push(CompactFeature { osm_type, id, tags, geometry });
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Decodes the features, in the order they were pushed
+    pub fn into_features(self) -> Vec<ExtractedFeature> {
+        self.features
+            .into_iter()
+            .map(|feature| {
+                let geometry = match feature.geometry {
+                    CompactGeometry::Encoded(bytes) => Decoder::new(&bytes).geometry(),
+                    CompactGeometry::Full(geometry) => geometry,
+                };
+                ExtractedFeature::new(feature.osm_type, feature.id, feature.tags, geometry)
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<ExtractedFeature> for FeatureStore {
+    fn from_iter<I: IntoIterator<Item = ExtractedFeature>>(iter: I) -> FeatureStore {
+        let mut store = FeatureStore::new();
+        for feature in iter {
+            store.push(feature);
+        }
+        store
+    }
+}
+
+// Writes geometries into a buffer reused between them, so each encoded
+// one is allocated once at its final size
+#[derive(Debug, Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    last: (i64, i64),
+}
+
+impl Encoder {
+    // The geometry as bytes, or `None` if it has a coordinate that does not
+    // lie on the 1e-7° grid or a kind of geometry OSM features never have
+    fn encode(&mut self, geometry: &Geometry) -> Option<Box<[u8]>> {
+        self.bytes.clear();
+        self.last = (0, 0);
+        self.geometry(geometry)?;
+        Some(Box::from(&self.bytes[..]))
+    }
+
+    fn geometry(&mut self, geometry: &Geometry) -> Option<()> {
+        match geometry {
+            Geometry::Point(point) => {
+                self.bytes.push(POINT);
+                self.coord(point.0)
+            }
+            Geometry::LineString(line) => {
+                self.bytes.push(LINE_STRING);
+                self.line(line)
+            }
+            Geometry::Polygon(polygon) => {
+                self.bytes.push(POLYGON);
+                self.polygon(polygon)
+            }
+            Geometry::MultiPoint(points) => {
+                self.bytes.push(MULTI_POINT);
+                self.len(points.0.len());
+                points.iter().try_for_each(|point| self.coord(point.0))
+            }
+            Geometry::MultiLineString(lines) => {
+                self.bytes.push(MULTI_LINE_STRING);
+                self.len(lines.0.len());
+                lines.iter().try_for_each(|line| self.line(line))
+            }
+            Geometry::MultiPolygon(polygons) => {
+                self.bytes.push(MULTI_POLYGON);
+                self.len(polygons.0.len());
+                polygons.iter().try_for_each(|polygon| self.polygon(polygon))
+            }
+            Geometry::GeometryCollection(collection) => {
+                self.bytes.push(COLLECTION);
+                self.len(collection.0.len());
+                collection.iter().try_for_each(|geometry| self.geometry(geometry))
+            }
+            Geometry::Line(_) | Geometry::Rect(_) | Geometry::Triangle(_) => None,
+        }
+    }
+
+    fn polygon(&mut self, polygon: &Polygon) -> Option<()> {
+        self.len(polygon.interiors().len() + 1);
+        self.line(polygon.exterior())?;
+        polygon.interiors().iter().try_for_each(|ring| self.line(ring))
+    }
+
+    fn line(&mut self, line: &LineString) -> Option<()> {
+        self.len(line.0.len());
+        line.0.iter().try_for_each(|&coord| self.coord(coord))
+    }
+
+    fn coord(&mut self, coord: Coord) -> Option<()> {
+        let (x, y) = (steps(coord.x)?, steps(coord.y)?);
+        self.varint(zigzag(x - self.last.0));
+        self.varint(zigzag(y - self.last.1));
+        self.last = (x, y);
+        Some(())
+    }
+
+    fn len(&mut self, len: usize) {
+        self.varint(len as u64);
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+}
+
+// The whole number of 1e-7° steps of a coordinate, if it is one; decoding
+// computes degrees the way osmpbfreader does, so node locations round-trip
+fn steps(degrees: f64) -> Option<i64> {
+    let steps = (degrees * SCALE).round();
+    // Far from the range of degrees, where differences could overflow
+    if steps.is_nan() || steps.abs() >= 1e15 || steps as i64 as f64 * 1e-7 != degrees {
+        return None;
+    }
+    Some(steps as i64)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+// Reads back what an `Encoder` wrote, which is trusted to be well-formed
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    last: (i64, i64),
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, at: 0, last: (0, 0) }
+    }
+
+    fn geometry(&mut self) -> Geometry {
+        let kind = self.bytes[self.at];
+        self.at += 1;
+        match kind {
+            POINT => Geometry::Point(Point(self.coord())),
+            LINE_STRING => Geometry::LineString(self.line()),
+            POLYGON => Geometry::Polygon(self.polygon()),
+            MULTI_POINT => Geometry::MultiPoint(MultiPoint((0..self.len()).map(|_| Point(self.coord())).collect())),
+            MULTI_LINE_STRING => {
+                Geometry::MultiLineString(MultiLineString((0..self.len()).map(|_| self.line()).collect()))
+            }
+            MULTI_POLYGON => Geometry::MultiPolygon(MultiPolygon((0..self.len()).map(|_| self.polygon()).collect())),
+            COLLECTION => {
+                Geometry::GeometryCollection(GeometryCollection((0..self.len()).map(|_| self.geometry()).collect()))
+            }
+            _ => unreachable!("unknown geometry kind {} in a feature store", kind),
+        }
+    }
+
+    fn polygon(&mut self) -> Polygon {
+        let rings = self.len();
+        let exterior = self.line();
+        let interiors = (1..rings).map(|_| self.line()).collect();
+        Polygon::new(exterior, interiors)
+    }
+
+    fn line(&mut self) -> LineString {
+        LineString((0..self.len()).map(|_| self.coord()).collect())
+    }
+
+    fn coord(&mut self) -> Coord {
+        let x = self.last.0 + unzigzag(self.varint());
+        let y = self.last.1 + unzigzag(self.varint());
+        self.last = (x, y);
+        Coord { x: x as f64 * 1e-7, y: y as f64 * 1e-7 }
+    }
+
+    fn len(&mut self) -> usize {
+        self.varint() as usize
+    }
+
+    fn varint(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes[self.at];
+            self.at += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::{coord, line_string, point, polygon};
+
+    // A coordinate on the grid, from its steps, as decoding computes it
+    fn at(x: i64, y: i64) -> Coord {
+        Coord { x: x as f64 * 1e-7, y: y as f64 * 1e-7 }
+    }
+
+    fn ring(coords: &[(i64, i64)]) -> LineString {
+        LineString(coords.iter().map(|&(x, y)| at(x, y)).collect())
+    }
+
+    fn square(x: i64, y: i64, size: i64) -> LineString {
+        ring(&[(x, y), (x + size, y), (x + size, y + size), (x, y + size), (x, y)])
+    }
+
+    // Stores the geometry as a feature and takes it out again, telling
+    // whether it was encoded
+    fn store(geometry: &Geometry) -> (Geometry, bool) {
+        let mut store = FeatureStore::new();
+        store.push(ExtractedFeature::new(OsmType::Way, 1, TagMap::new(), geometry.clone()));
+        let encoded = matches!(store.features[0].geometry, CompactGeometry::Encoded(_));
+        let mut features = store.into_features();
+        (features.remove(0).geometry().clone(), encoded)
+    }
+
+    fn assert_encoded(geometry: Geometry) {
+        assert_eq!(store(&geometry), (geometry, true));
+    }
+
+    #[test]
+    fn geometries_round_trip() {
+        let polygon = Polygon::new(square(0, 0, 100), vec![square(10, 10, 20), square(50, 50, 20)]);
+        let line = ring(&[(74_000_000, 437_000_000), (74_000_123, 437_000_456), (73_999_999, 436_999_999)]);
+        assert_encoded(Geometry::Point(Point(at(74_212_345, 437_312_345))));
+        assert_encoded(Geometry::LineString(line.clone()));
+        assert_encoded(Geometry::Polygon(polygon.clone()));
+        assert_encoded(Geometry::MultiPoint(MultiPoint(vec![Point(at(1, 2)), Point(at(-3, 4))])));
+        assert_encoded(Geometry::MultiLineString(MultiLineString(vec![line.clone(), square(5, 5, 5)])));
+        assert_encoded(Geometry::MultiPolygon(MultiPolygon(vec![
+            polygon.clone(),
+            Polygon::new(square(1_000, 1_000, 7), vec![]),
+        ])));
+        let nested = GeometryCollection(vec![
+            Geometry::Point(Point(at(5, 6))),
+            Geometry::GeometryCollection(GeometryCollection(vec![
+                Geometry::LineString(line),
+                Geometry::GeometryCollection(GeometryCollection(vec![])),
+            ])),
+            Geometry::Polygon(polygon),
+        ]);
+        assert_encoded(Geometry::GeometryCollection(nested));
+        assert_encoded(Geometry::GeometryCollection(GeometryCollection(vec![])));
+        assert_encoded(Geometry::LineString(LineString(vec![])));
+    }
+
+    #[test]
+    fn extreme_coordinates_round_trip() {
+        let (lon, lat) = (1_800_000_000, 900_000_000);
+        assert_encoded(Geometry::LineString(ring(&[(-lon, -lat), (lon, lat), (-lon, lat), (lon, -lat), (-1, -1)])));
+        assert_encoded(Geometry::Point(Point(at(-lon, -lat))));
+        // As written in degrees rather than computed from steps
+        for geometry in [
+            Geometry::Point(point!(x: 180.0, y: 90.0)),
+            Geometry::Point(point!(x: -180.0, y: -90.0)),
+            Geometry::LineString(line_string![(x: -180.0, y: -90.0), (x: 180.0, y: 90.0), (x: -0.0000001, y: 0.0)]),
+        ] {
+            assert_eq!(store(&geometry).0, geometry);
+        }
+    }
+
+    #[test]
+    fn off_grid_geometries_are_kept_in_full() {
+        // Projected, simplified or clipped coordinates
+        let projected = Geometry::Point(point!(x: 826_115.123_456_78, y: 5_934_093.987_654_3));
+        let simplified = Geometry::LineString(line_string![(x: 7.4, y: 43.7), (x: 7.400000012345, y: 43.75)]);
+        let clipped = Geometry::Polygon(polygon![
+            (x: 0.0, y: 0.0),
+            (x: 1.0 / 3.0, y: 0.0),
+            (x: 0.0, y: 1.0),
+        ]);
+        // One coordinate off the grid is enough, however deep it is
+        let nested = Geometry::GeometryCollection(GeometryCollection(vec![
+            Geometry::Point(Point(at(1, 1))),
+            Geometry::MultiPoint(MultiPoint(vec![Point(at(2, 2)), point!(x: 2e-8, y: 0.0)])),
+        ]));
+        for geometry in [projected, simplified, clipped, nested, Geometry::Point(point!(x: f64::NAN, y: 0.0))] {
+            let (stored, encoded) = store(&geometry);
+            assert!(!encoded, "{:?} was encoded", geometry);
+            assert!(stored == geometry || matches!(stored, Geometry::Point(p) if p.x().is_nan()));
+        }
+        let line = Geometry::Line(geo::Line::new(coord! { x: 0.0, y: 0.0 }, coord! { x: 1.0, y: 1.0 }));
+        assert_eq!(store(&line), (line, false));
+    }
+
+    #[test]
+    fn feature_order_and_tags_are_kept() {
+        let features: Vec<ExtractedFeature> = (0..3)
+            .map(|i| {
+                let mut tags = TagMap::new();
+                tags.insert("ref".into(), i.to_string().into());
+                let osm_type = [OsmType::Node, OsmType::Way, OsmType::Relation][i];
+                ExtractedFeature::new(osm_type, i as i64 * 10, tags, Geometry::Point(Point(at(i as i64, 0))))
+            })
+            .collect();
+        let parts = |features: &[ExtractedFeature]| -> Vec<(OsmType, i64, TagMap, Geometry)> {
+            features.iter().map(|f| (f.osm_type(), f.id(), f.tags().clone(), f.geometry().clone())).collect()
+        };
+        let store: FeatureStore = features.clone().into_iter().collect();
+        assert_eq!(store.len(), 3);
+        assert_eq!(parts(&store.into_features()), parts(&features));
+    }
+
+    #[test]
+    fn deltas_and_zigzag() {
+        for value in [0, 1, -1, 63, -64, 1 << 40, -(1 << 40), i64::MAX >> 1, i64::MIN >> 1] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert_eq!([zigzag(0), zigzag(-1), zigzag(1), zigzag(-2)], [0, 1, 2, 3]);
+        // Nearby coordinates take a byte each after the first
+        let line = Geometry::LineString(ring(&[(74_000_000, 437_000_000), (74_000_010, 436_999_990)]));
+        let bytes = Encoder::default().encode(&line).unwrap();
+        assert_eq!(bytes[..2], [LINE_STRING, 2]);
+        assert_eq!(bytes[bytes.len() - 2..], [20, 19]);
+        assert_eq!(Decoder::new(&bytes).geometry(), line);
+    }
+}
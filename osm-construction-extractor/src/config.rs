@@ -0,0 +1,153 @@
+//! Options of an extraction kept in a TOML or YAML file, so a run can be
+//! defined once, versioned and repeated, e.g.
+//!
+//! ```toml
+//! input = "germany-latest.osm.pbf"
+//! filter = "building=*"
+//! types = ["way", "relation"]
+//! output = ["buildings.fgb", "buildings.parquet"]
+//! streaming = true
+//! threads = 8
+//! ```
+//!
+//! Keys are the long names of the command line options, with `-` or `_`
+//! between words. A list repeats an option, `true` sets a flag and a number
+//! repeats a counted flag such as `verbose`. Options also given on the
+//! command line take their values from there, whole lists included, and
+//! those it cannot be combined with, e.g. `quiet` with `-v`, are left out.
+
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn scalar(&self, key: &str) -> Result<String> {
+        Ok(match self {
+            Value::Bool(value) => value.to_string(),
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::String(value) => value.clone(),
+            Value::List(_) => bail!("`{}` takes single values, not nested lists", key),
+        })
+    }
+}
+
+/// Reads the options in `path` as arguments of `command`, leaving out those
+/// `matches`, the command line parsed, already sets; the arguments are meant
+/// to go before those of the command line
+pub fn args(command: &Command, matches: &ArgMatches, path: &Path) -> Result<Vec<OsString>> {
+    let text = fs::read_to_string(path).with_context(|| format!("cannot read config {:?}", path))?;
+    let is_toml = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"));
+    let options: BTreeMap<String, Value> = if is_toml {
+        toml::from_str(&text).with_context(|| format!("invalid config {:?}", path))?
+    } else {
+        serde_yaml_ng::from_str(&text).with_context(|| format!("invalid config {:?}", path))?
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in &options {
+        let long = key.replace('_', "-");
+        let arg = match long.as_str() {
+            "config" | "help" | "version" => None,
+            long => command.get_arguments().find(|arg| arg.get_long() == Some(long)),
+        };
+        let Some(arg) = arg else {
+            bail!("unknown option `{}` in config {:?}", key, path);
+        };
+        if overridden(command, matches, arg) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Bool(set)) => args.extend(set.then(|| flag.clone().into())),
+            (ArgAction::SetTrue, _) => bail!("`{}` in config {:?} is a flag, set to true or false", key, path),
+            (ArgAction::Count, Value::Bool(set)) => args.extend(set.then(|| flag.clone().into())),
+            (ArgAction::Count, Value::Integer(count)) => {
+                args.extend((0..*count).map(|_| OsString::from(&flag)));
+            }
+            (ArgAction::Count, _) => bail!("`{}` in config {:?} is a count, such as 2", key, path),
+            (_, Value::List(values)) => {
+                for value in values {
+                    args.push(format!("{}={}", flag, value.scalar(key)?).into());
+                }
+            }
+            // With `=`, values starting with `-` are not taken for options
+            (_, value) => args.push(format!("{}={}", flag, value.scalar(key)?).into()),
+        }
+    }
+    Ok(args)
+}
+
+// Whether the command line sets `arg` or an option conflicting with it
+fn overridden(command: &Command, matches: &ArgMatches, arg: &Arg) -> bool {
+    let given = |arg: &Arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let conflicts = |a: &Arg, b: &Arg| command.get_arg_conflicts_with(a).iter().any(|c| c.get_id() == b.get_id());
+    given(arg) || command.get_arguments().any(|other| given(other) && (conflicts(arg, other) || conflicts(other, arg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("extract")
+            .arg(Arg::new("input").long("input"))
+            .arg(Arg::new("output").long("output").action(ArgAction::Append))
+            .arg(Arg::new("streaming").long("streaming").action(ArgAction::SetTrue))
+            .arg(Arg::new("verbose").short('v').long("verbose").action(ArgAction::Count))
+            .arg(Arg::new("quiet").long("quiet").action(ArgAction::SetTrue).conflicts_with("verbose"))
+    }
+
+    // The arguments `config` adds before those of `cli`
+    fn layered(file: &str, config: &str, cli: &[&str]) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file);
+        fs::write(&path, config).unwrap();
+        let matches = command().try_get_matches_from([&["extract"], cli].concat()).unwrap();
+        let args = args(&command(), &matches, &path).unwrap();
+        args.into_iter().map(|arg| arg.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn options_become_arguments() {
+        let toml = "input = \"in.pbf\"\noutput = [\"a.fgb\", \"b.parquet\"]\nstreaming = true\nverbose = 2\n";
+        let expected = ["--input=in.pbf", "--output=a.fgb", "--output=b.parquet", "--streaming", "--verbose", "--verbose"];
+        assert_eq!(layered("run.toml", toml, &[]), expected);
+        let yaml = "input: in.pbf\noutput: [a.fgb, b.parquet]\nstreaming: true\nverbose: 2\n";
+        assert_eq!(layered("run.yaml", yaml, &[]), expected);
+        assert_eq!(layered("run.toml", "streaming = false\n", &[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_config() {
+        let toml = "input = \"in.pbf\"\noutput = [\"a.fgb\", \"b.parquet\"]\nquiet = true\n";
+        assert_eq!(layered("run.toml", toml, &["--output", "c.csv"]), ["--input=in.pbf", "--quiet"]);
+        assert_eq!(layered("run.toml", toml, &["-v"]), ["--input=in.pbf", "--output=a.fgb", "--output=b.parquet"]);
+    }
+
+    #[test]
+    fn unknown_options_and_wrong_kinds_of_values_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let matches = command().get_matches_from(["extract"]);
+        for config in ["filter = \"building=*\"\n", "streaming = \"yes\"\n", "verbose = \"loud\"\n"] {
+            let path = dir.path().join("run.toml");
+            fs::write(&path, config).unwrap();
+            assert!(args(&command(), &matches, &path).is_err());
+        }
+    }
+}
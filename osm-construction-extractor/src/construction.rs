@@ -0,0 +1,155 @@
+//! Construction monitoring: the dates of construction sites parsed from
+//! their tags, a status derived from them and a filter on when they are
+//! expected to open.
+//!
+//! The dates are read from `construction:start_date`, `opening_date` and
+//! `check_date`, which may be a year, a month or a day (`2026`, `2026-05`,
+//! `2026-05-14`), optionally marked as approximate with `~`. A partial date
+//! stands for the whole period: a road opening in `2026` is overdue only
+//! once 2026 has passed.
+
+use crate::feature::ExtractedFeature;
+use crate::metadata;
+use anyhow::{Context, Result};
+#[cfg(not(target_family = "wasm"))]
+use std::time::SystemTime;
+// std's clock panics in browsers
+#[cfg(target_family = "wasm")]
+use web_time::SystemTime;
+
+/// Property holding the status of a construction site, see [`Status`]
+pub const STATUS_TAG: &str = "@status";
+
+/// The date tags read, with the properties their parsed dates are written
+/// to as `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+const DATE_TAGS: [(&str, &str); 3] = [
+    ("construction:start_date", "@start_date"),
+    ("opening_date", "@opening_date"),
+    ("check_date", "@check_date"),
+];
+
+/// Where a construction site stands on the day it is judged at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Construction starts after the day
+    Planned,
+    /// Under construction, with no opening date or one yet to come
+    Active,
+    /// Still under construction after the opening date has passed
+    Overdue,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Planned => "planned",
+            Status::Active => "active",
+            Status::Overdue => "overdue",
+        }
+    }
+}
+
+/// A date of a tag: the days since the Unix epoch from its first up to,
+/// not including, its last day, and the date as written back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Period {
+    pub start: i64,
+    pub end: i64,
+    pub text: String,
+}
+
+/// Parses a date tag such as `2026`, `2026-05` or `~2026-05-14`; of a
+/// `;`-separated list only the first entry is used
+pub fn parse_date(value: &str) -> Option<Period> {
+    let value = value.split(';').next()?.trim();
+    let value = value.strip_prefix('~').unwrap_or(value).trim();
+    let parts: Vec<i64> = value.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (start, end, text) = match parts[..] {
+        [year] => (ymd(year, 1, 1)?, ymd(year + 1, 1, 1)?, format!("{:04}", year)),
+        [year, month] => {
+            let next = if month == 12 { ymd(year + 1, 1, 1) } else { ymd(year, month + 1, 1) };
+            (ymd(year, month, 1)?, next?, format!("{:04}-{:02}", year, month))
+        }
+        [year, month, day] => {
+            let start = ymd(year, month, day)?;
+            (start, start + 1, format!("{:04}-{:02}-{:02}", year, month, day))
+        }
+        _ => return None,
+    };
+    Some(Period { start, end, text })
+}
+
+/// Parses a date given on the command line like [`parse_date`], failing
+/// with an error instead
+pub fn parse_period(s: &str) -> Result<Period> {
+    parse_date(s).with_context(|| format!("invalid date {:?}, expected e.g. 2026, 2026-05 or 2026-05-14", s))
+}
+
+// The day of a valid date, e.g. not February 30
+fn ymd(year: i64, month: i64, day: i64) -> Option<i64> {
+    if !(1000..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = metadata::days_from_civil(year, month, day);
+    (metadata::civil_from_days(days) == (year, month, day)).then_some(days)
+}
+
+/// Classifies construction sites and keeps those expected to open in a
+/// window; days are counted since the Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Monitor {
+    /// The day the status is judged at
+    pub as_of: i64,
+    /// Keep only sites expected to open on or after this day
+    pub opening_from: Option<i64>,
+    /// Keep only sites expected to open before this day
+    pub opening_until: Option<i64>,
+}
+
+impl Monitor {
+    /// A monitor judging at today's date in UTC, without an opening window
+    pub fn today() -> Monitor {
+        let secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Monitor { as_of: secs as i64 / 86400, opening_from: None, opening_until: None }
+    }
+
+    fn has_window(&self) -> bool {
+        self.opening_from.is_some() || self.opening_until.is_some()
+    }
+
+    /// Adds the parsed dates and the [`STATUS_TAG`] to the feature; `false`
+    /// if it is to be dropped, because its opening date, or the lack of
+    /// one, is outside the window
+    pub fn apply(&self, feature: &mut ExtractedFeature) -> bool {
+        let dates: Vec<Option<Period>> =
+            DATE_TAGS.iter().map(|(key, _)| feature.tags().get(*key).and_then(|v| parse_date(v))).collect();
+        let (start, opening) = (&dates[0], &dates[1]);
+
+        if self.has_window() {
+            // A partial opening date is in the window if any of its days is
+            let Some(opening) = opening else {
+                return false;
+            };
+            if self.opening_from.is_some_and(|from| opening.end <= from)
+                || self.opening_until.is_some_and(|until| opening.start >= until)
+            {
+                return false;
+            }
+        }
+
+        let status = if start.as_ref().is_some_and(|start| start.start > self.as_of) {
+            Status::Planned
+        } else if opening.as_ref().is_some_and(|opening| opening.end <= self.as_of) {
+            Status::Overdue
+        } else {
+            Status::Active
+        };
+        for ((_, property), date) in DATE_TAGS.iter().zip(dates) {
+            if let Some(date) = date {
+                feature.tags_mut().insert((*property).into(), date.text.into());
+            }
+        }
+        feature.tags_mut().insert(STATUS_TAG.into(), status.as_str().into());
+        true
+    }
+}
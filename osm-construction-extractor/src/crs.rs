@@ -0,0 +1,98 @@
+//! Reprojection of extracted geometries from WGS84 into other CRSs.
+
+use anyhow::{bail, Result};
+use geo::{Coord, Geometry, MapCoordsInPlace};
+use std::fmt;
+use std::str::FromStr;
+
+// Semi-major axis of the WGS84 ellipsoid, used as sphere radius by Web Mercator
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+// Latitude at which Web Mercator becomes a square
+pub(crate) const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_59;
+
+// OGC WKT definitions of the natively supported CRSs, for output formats
+// that store one
+pub(crate) const WGS84_WKT: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]]"#;
+pub(crate) const WEB_MERCATOR_WKT: &str = r#"PROJCS["WGS 84 / Pseudo-Mercator",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]],PROJECTION["Mercator_1SP"],PARAMETER["central_meridian",0],PARAMETER["scale_factor",1],PARAMETER["false_easting",0],PARAMETER["false_northing",0],UNIT["metre",1,AUTHORITY["EPSG","9001"]],AXIS["Easting",EAST],AXIS["Northing",NORTH],EXTENSION["PROJ4","+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m +nadgrids=@null +wktext +no_defs"],AUTHORITY["EPSG","3857"]]"#;
+
+/// A target coordinate reference system: an `EPSG:<code>` identifier or any
+/// definition understood by PROJ, e.g. `+proj=utm +zone=32 +datum=WGS84`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crs {
+    pub definition: String,
+    /// The EPSG code, if the CRS was given as one; written into the output
+    /// metadata of formats that record a CRS
+    pub epsg: Option<u32>,
+}
+
+impl FromStr for Crs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            bail!("empty CRS definition");
+        }
+        let epsg = match s.split_once(':') {
+            Some((authority, code)) if authority.eq_ignore_ascii_case("epsg") => match code.parse() {
+                Ok(code) => Some(code),
+                Err(_) => bail!("invalid EPSG code {:?}", code),
+            },
+            _ => None,
+        };
+        Ok(Crs { definition: s.to_string(), epsg })
+    }
+}
+
+impl fmt::Display for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.epsg {
+            Some(code) => write!(f, "EPSG:{}", code),
+            None => f.write_str(&self.definition),
+        }
+    }
+}
+
+/// Converts lon/lat geometries into a target [`Crs`].
+///
+/// WGS84 itself and Web Mercator (EPSG:3857) are handled natively; every
+/// other CRS requires building with the `proj` feature, which links PROJ.
+pub enum Transformer {
+    Identity,
+    WebMercator,
+    #[cfg(feature = "proj")]
+    Proj(proj::Proj),
+}
+
+impl Transformer {
+    pub fn new(crs: &Crs) -> Result<Transformer> {
+        match crs.epsg {
+            Some(4326) => Ok(Transformer::Identity),
+            Some(3857) | Some(900913) => Ok(Transformer::WebMercator),
+            #[cfg(feature = "proj")]
+            _ => Ok(Transformer::Proj(proj::Proj::new_known_crs("EPSG:4326", &crs.definition, None)?)),
+            #[cfg(not(feature = "proj"))]
+            _ => bail!("reprojecting to {} requires building with the `proj` feature", crs),
+        }
+    }
+
+    /// Reprojects every coordinate of `geom` in place
+    pub fn transform(&self, geom: &mut Geometry) -> Result<()> {
+        match self {
+            Transformer::Identity => {}
+            Transformer::WebMercator => geom.map_coords_in_place(web_mercator),
+            #[cfg(feature = "proj")]
+            Transformer::Proj(proj) => geom.try_map_coords_in_place(|c| proj.convert(c))?,
+        }
+        Ok(())
+    }
+}
+
+fn web_mercator(c: Coord) -> Coord {
+    let lat = c.y.clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT).to_radians();
+    Coord {
+        x: EARTH_RADIUS * c.x.to_radians(),
+        y: EARTH_RADIUS * (std::f64::consts::FRAC_PI_4 + lat / 2.0).tan().ln(),
+    }
+}
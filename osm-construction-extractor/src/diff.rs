@@ -0,0 +1,87 @@
+//! Comparison of the features extracted from two versions of the same
+//! area, e.g. last month's extract and today's.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::intern::Tag;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Property of tag-changed features: a JSON array of the keys that were
+/// added, removed or given another value, e.g. `["construction", "name"]`
+pub const CHANGED_TAGS_TAG: &str = "@changed_tags";
+
+/// The differences between two extractions
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Only in the new extraction
+    pub added: Vec<ExtractedFeature>,
+    /// Only in the old extraction, as they were there
+    pub removed: Vec<ExtractedFeature>,
+    /// In both, with another geometry; as they are now
+    pub geometry_changed: Vec<ExtractedFeature>,
+    /// In both, with other tags, listed in [`CHANGED_TAGS_TAG`]; as they
+    /// are now
+    pub tags_changed: Vec<ExtractedFeature>,
+}
+
+impl Diff {
+    /// The four kinds of differences with their layer names: `added`,
+    /// `removed`, `geometry` and `tags`
+    pub fn layers(&self) -> [(&'static str, &[ExtractedFeature]); 4] {
+        [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("geometry", &self.geometry_changed),
+            ("tags", &self.tags_changed),
+        ]
+    }
+}
+
+/// Compares the features of two extractions by their OSM type and id.
+///
+/// A feature whose geometry and tags both changed is in both of these
+/// lists, and features keep the order they have in `new` (and `old` for
+/// the removed ones).
+pub fn compare(old: Vec<ExtractedFeature>, new: Vec<ExtractedFeature>) -> Diff {
+    let mut old: HashMap<(OsmType, i64), (usize, ExtractedFeature)> = old
+        .into_iter()
+        .enumerate()
+        .map(|(i, feature)| ((feature.osm_type(), feature.id()), (i, feature)))
+        .collect();
+    let mut diff = Diff::default();
+    for feature in new {
+        let Some((_, before)) = old.remove(&(feature.osm_type(), feature.id())) else {
+            diff.added.push(feature);
+            continue;
+        };
+        let keys = changed_keys(&before, &feature);
+        if before.geometry() != feature.geometry() {
+            diff.geometry_changed.push(feature.clone());
+        }
+        if !keys.is_empty() {
+            let mut feature = feature;
+            feature.tags_mut().insert(CHANGED_TAGS_TAG.into(), serde_json::Value::from(keys).to_string().into());
+            diff.tags_changed.push(feature);
+        }
+    }
+    let mut removed: Vec<(usize, ExtractedFeature)> = old.into_values().collect();
+    removed.sort_by_key(|(i, _)| *i);
+    diff.removed = removed.into_iter().map(|(_, feature)| feature).collect();
+    diff
+}
+
+// Sorted, so that the same change always gives the same list
+fn changed_keys(old: &ExtractedFeature, new: &ExtractedFeature) -> Vec<String> {
+    let (old, new) = (old.tags(), new.tags());
+    let removed_or_changed = old.iter().filter(|(key, value)| new.get(*key) != Some(value)).map(|(key, _)| key);
+    let added = new.keys().filter(|key| !old.contains_key(*key));
+    let keys: BTreeSet<&Tag> = removed_or_changed.chain(added).collect();
+    keys.into_iter().map(|key| key.to_string()).collect()
+}
+
+/// The file of a layer next to `path`, named after it with the layer name
+/// as suffix, e.g. `construction_added.geojson`
+pub fn layer_path(path: &Path, layer: &str, extension: &str) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("diff");
+    path.with_file_name(format!("{}_{}.{}", stem, layer, extension))
+}
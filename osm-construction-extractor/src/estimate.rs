@@ -0,0 +1,316 @@
+//! Estimates of what an extraction takes, from the header of its input and
+//! a sample of its blocks, for checking before committing to a long run.
+//!
+//! Every blob header of the file is read, with the data between them
+//! skipped, to count the blocks, and a few blocks spread evenly over the
+//! file are decoded. What matches in them is scaled up to the whole file,
+//! which works as PBF blocks hold about as many objects each. Ways and
+//! relations cannot be placed without the locations of their nodes, so
+//! with a bbox or boundary they are taken to lie inside it as often as the
+//! nodes of the sample do.
+
+use crate::feature::OsmType;
+use crate::info::{self, Header, TypeCounts};
+use crate::memory;
+use crate::metadata;
+use crate::spatial;
+use anyhow::Result;
+use geo::Rect;
+use osmpbfreader::fileformat::Blob;
+use osmpbfreader::{blocks, primitive_block_from_blob, OsmObj};
+use protobuf::Message;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
+#[cfg(target_family = "wasm")]
+use web_time::Instant;
+
+/// Blocks decoded by default, a fraction of a second of work
+pub const DEFAULT_SAMPLES: usize = 64;
+
+// An assembled feature written as GeoJSON: its type, id and empty
+// geometry and properties, then per coordinate `[lon,lat],`, whose degrees
+// often take more digits than the 7 of their precision, and per tag the
+// quotes, colon and comma around key and value
+const GEOJSON_FEATURE_BYTES: u64 = 100;
+const GEOJSON_COORD_BYTES: u64 = 28;
+const GEOJSON_TAG_BYTES: u64 = 6;
+
+// An object held in memory besides its tags and node ids, and a feature
+// held until the output is written, whose coordinates are delta-encoded
+// into a few bytes each (see `compact`)
+const OBJECT_BYTES: u64 = 100;
+const HELD_COORD_BYTES: u64 = 4;
+
+// A decoded block, of which every decoding thread holds a few at a time
+const DECODED_BLOCK_BYTES: u64 = 2 << 20;
+const BLOCKS_PER_THREAD: u64 = 4;
+
+/// The blocks of a PBF, a few of them decoded
+#[derive(Debug)]
+pub struct Sample {
+    pub header: Header,
+    /// Data blocks in the file
+    pub blocks: usize,
+    /// The objects of each decoded block, in file order
+    pub objects: Vec<Vec<OsmObj>>,
+    /// Compressed size of the data blocks and of the decoded ones
+    pub data_bytes: u64,
+    pub sampled_bytes: u64,
+    /// Time taken to read and decode the sampled blocks
+    pub duration: Duration,
+    /// Threads they were decoded on
+    pub threads: usize,
+}
+
+/// Reads the header and the blob headers of a PBF and decodes `count` of
+/// its blocks on `pool`, with their metadata as tags if asked to
+pub fn sample<R: Read + Seek>(reader: &mut R, pool: &ThreadPool, count: usize, with_metadata: bool) -> Result<Sample> {
+    let mut header = Header::default();
+    // Offset and size of the data of every data block
+    let mut blocks: Vec<(u64, u64)> = Vec::new();
+    // Kept here rather than asked for, as spooled stdin only seeks from
+    // the start reliably
+    let mut position = 0;
+    while let Some((blob_header, header_size)) = info::next_blob_header(reader)? {
+        let data_size = u64::try_from(blob_header.datasize())?;
+        position += header_size;
+        match blob_header.type_() {
+            "OSMHeader" => header = info::parse_header(&info::read_bytes(reader, data_size)?)?,
+            kind => {
+                if kind == "OSMData" {
+                    blocks.push((position, data_size));
+                }
+                reader.seek(SeekFrom::Start(position + data_size))?;
+            }
+        }
+        position += data_size;
+    }
+
+    let start_time = Instant::now();
+    let picked: Vec<(u64, u64)> = match blocks.len() {
+        0 => Vec::new(),
+        n if n <= count => blocks.clone(),
+        n => (0..count).map(|i| blocks[i * n / count]).collect(),
+    };
+    let mut blobs = Vec::with_capacity(picked.len());
+    for &(offset, size) in &picked {
+        reader.seek(SeekFrom::Start(offset))?;
+        blobs.push(info::read_bytes(reader, size)?);
+    }
+    let objects = pool.install(|| {
+        blobs
+            .into_par_iter()
+            .map(|bytes| {
+                let block = primitive_block_from_blob(&Blob::parse_from_bytes(&bytes)?)?;
+                let mut objects: Vec<OsmObj> = blocks::iter(&block).collect();
+                if with_metadata {
+                    metadata::attach(&block, &mut objects);
+                }
+                Ok(objects)
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(Sample {
+        header,
+        blocks: blocks.len(),
+        objects,
+        data_bytes: blocks.iter().map(|(_, size)| size).sum(),
+        sampled_bytes: picked.iter().map(|(_, size)| size).sum(),
+        duration: start_time.elapsed(),
+        threads: pool.current_num_threads(),
+    })
+}
+
+/// How an extraction goes about reading its input
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    /// Re-reading the file until every dependency of the matches is found
+    Dependencies,
+    /// Indexing every node location, with this many bytes per node held
+    /// in memory, or none with the index on disk
+    Streaming { bytes_per_node: u64 },
+    /// A single pass without resolving geometries
+    Unresolved,
+}
+
+/// What an extraction over one input is expected to find and take
+#[derive(Debug, Clone)]
+pub struct Estimate {
+    /// The input, as it is shown
+    pub input: String,
+    pub header: Header,
+    /// Size of the input in bytes, 0 if unknown
+    pub input_bytes: u64,
+    /// Data blocks in the file, and how many of them were decoded
+    pub blocks: usize,
+    pub sampled_blocks: usize,
+    /// Objects in the file
+    pub objects: TypeCounts,
+    /// Objects expected to match
+    pub matched: TypeCounts,
+    /// Passes over the input
+    pub passes: usize,
+    /// Size of the features written as GeoJSON
+    pub output_bytes: u64,
+    /// Memory held at the peak: the objects or node index read, and the
+    /// features kept until they are written
+    pub memory_bytes: u64,
+    /// Time taken by the passes over the input, before the output is
+    /// written
+    pub duration: Duration,
+}
+
+impl Estimate {
+    /// Scales what matches in the sample up to the whole input, reading it
+    /// in `mode`; `rect` is the box candidates must lie in, if any
+    pub fn new(
+        input: String,
+        input_bytes: u64,
+        sample: Sample,
+        mode: Mode,
+        rect: Option<Rect>,
+        is_match: impl Fn(&OsmObj) -> bool,
+    ) -> Estimate {
+        let start_time = Instant::now();
+        let mut objects = TypeCounts::default();
+        let mut matched = TypeCounts::default();
+        let (mut nodes_inside, mut way_refs) = (0u64, 0u64);
+        // Referenced nodes and member ways of the matches, bytes of their
+        // tags and of the objects held for dependency mode
+        let (mut matched_refs, mut member_ways, mut tag_bytes, mut held_bytes) = (0u64, 0u64, 0u64, 0u64);
+        for obj in sample.objects.iter().flatten() {
+            objects.add(OsmType::of(obj));
+            let inside = match (obj, &rect) {
+                (OsmObj::Node(node), Some(rect)) => spatial::bbox_contains(rect, node.lon(), node.lat()),
+                _ => true,
+            };
+            match obj {
+                OsmObj::Node(_) => nodes_inside += u64::from(inside),
+                OsmObj::Way(way) => way_refs += way.nodes.len() as u64,
+                OsmObj::Relation(_) => {}
+            }
+            if !inside || !is_match(obj) {
+                continue;
+            }
+            matched.add(OsmType::of(obj));
+            let tags: u64 = obj.tags().iter().map(|(k, v)| (k.len() + v.len()) as u64).sum();
+            tag_bytes += tags + obj.tags().len() as u64 * GEOJSON_TAG_BYTES;
+            held_bytes += OBJECT_BYTES + tags;
+            match obj {
+                OsmObj::Node(_) => {}
+                OsmObj::Way(way) => {
+                    matched_refs += way.nodes.len() as u64;
+                    held_bytes += way.nodes.len() as u64 * 8;
+                }
+                OsmObj::Relation(relation) => {
+                    member_ways += relation.refs.iter().filter(|m| m.member.is_way()).count() as u64;
+                    matched_refs += relation.refs.iter().filter(|m| m.member.is_node()).count() as u64;
+                    held_bytes += relation.refs.len() as u64 * 16;
+                }
+            }
+        }
+
+        let scale = |count: u64| match sample.objects.len() {
+            0 => 0,
+            sampled => (count as f64 * sample.blocks as f64 / sampled as f64).round() as u64,
+        };
+        // The share of ways and relations taken to lie within the box
+        let share = match (rect, objects.nodes) {
+            (Some(_), 0) => 0.0,
+            (Some(_), nodes) => nodes_inside as f64 / nodes as f64,
+            (None, _) => 1.0,
+        };
+        let placed = |count: u64| (scale(count) as f64 * share).round() as u64;
+        let refs_per_way = if objects.ways == 0 { 0.0 } else { way_refs as f64 / objects.ways as f64 };
+        let member_refs = (member_ways as f64 * refs_per_way) as u64;
+        let matched = TypeCounts {
+            nodes: scale(matched.nodes),
+            ways: placed(matched.ways),
+            relations: placed(matched.relations),
+        };
+        let objects = TypeCounts {
+            nodes: scale(objects.nodes),
+            ways: scale(objects.ways),
+            relations: scale(objects.relations),
+        };
+        let features = matched.total();
+        let coords = matched.nodes + placed(matched_refs + member_refs);
+        let tag_bytes = placed(tag_bytes);
+
+        let passes = match mode {
+            Mode::Unresolved => 1,
+            Mode::Streaming { .. } => 1 + usize::from(matched.relations > 0),
+            Mode::Dependencies => {
+                usize::from(rect.is_some())
+                    + 1
+                    + usize::from(matched.ways + matched.relations > 0)
+                    + usize::from(member_ways > 0)
+            }
+        };
+        let held_features = features * OBJECT_BYTES + coords * HELD_COORD_BYTES + tag_bytes;
+        let read_memory = match mode {
+            Mode::Unresolved => 0,
+            // Locations of the referenced nodes, and the member ways with
+            // their node ids
+            Mode::Dependencies => {
+                placed(held_bytes) + coords * 16 + placed(member_ways) * OBJECT_BYTES + placed(member_refs) * 8
+            }
+            Mode::Streaming { bytes_per_node } => objects.nodes * bytes_per_node + placed(member_refs) * 8,
+        };
+        let read_memory = read_memory + sample.threads as u64 * BLOCKS_PER_THREAD * DECODED_BLOCK_BYTES;
+        // Every pass takes about as long per byte as reading, decoding and
+        // matching the sample did
+        let seconds_per_byte = match sample.sampled_bytes {
+            0 => 0.0,
+            bytes => (sample.duration + start_time.elapsed()).as_secs_f64() / bytes as f64,
+        };
+        let duration = Duration::from_secs_f64(seconds_per_byte * sample.data_bytes as f64 * passes as f64);
+        Estimate {
+            input,
+            header: sample.header,
+            input_bytes,
+            blocks: sample.blocks,
+            sampled_blocks: sample.objects.len(),
+            objects,
+            matched,
+            passes,
+            output_bytes: features * GEOJSON_FEATURE_BYTES + coords * GEOJSON_COORD_BYTES + tag_bytes,
+            memory_bytes: read_memory + held_features,
+            duration,
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Input: {}", self.input)?;
+        if self.input_bytes > 0 {
+            write!(f, " ({})", memory::format_size(self.input_bytes))?;
+        }
+        writeln!(f)?;
+        if let Some(program) = &self.header.writing_program {
+            writeln!(f, "  written by {}", program)?;
+        }
+        if let Some(bbox) = &self.header.bbox {
+            let (min, max) = (bbox.min(), bbox.max());
+            writeln!(f, "  covering {:.7},{:.7},{:.7},{:.7}", min.x, min.y, max.x, max.y)?;
+        }
+        if let Some(timestamp) = self.header.replication_timestamp {
+            writeln!(f, "  replicated up to {}", metadata::format_timestamp(timestamp))?;
+        }
+        writeln!(f, "  {}sorted by type then id", if self.header.sorted() { "" } else { "not marked as " })?;
+        writeln!(f, "Blocks: {}, of which {} decoded", self.blocks, self.sampled_blocks)?;
+        let about = if self.sampled_blocks < self.blocks { "about " } else { "" };
+        writeln!(f, "Objects: {}{}", about, self.objects)?;
+        writeln!(f, "Matching: {}{}", about, self.matched)?;
+        writeln!(f, "Passes over the input: {}", self.passes)?;
+        writeln!(f, "GeoJSON output: about {}", memory::format_size(self.output_bytes))?;
+        writeln!(f, "Memory: about {}", memory::format_size(self.memory_bytes))?;
+        writeln!(f, "Runtime: about {:.1?}, before writing the output", self.duration)
+    }
+}
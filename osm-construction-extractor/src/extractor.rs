@@ -0,0 +1,1739 @@
+use crate::cancel::CancelToken;
+use crate::centroid::{self, OutputGeometry};
+use crate::checkpoint::Checkpoint;
+use crate::cleanup;
+use crate::compact::FeatureStore;
+use crate::construction::Monitor;
+use crate::crs::{Crs, Transformer};
+use crate::estimate::{self, Estimate};
+use crate::feature::{BuildOptions, ExtractedFeature, OsmType, SkipCause, Skipped, INCOMPLETE_TAG};
+use crate::filter::Filter;
+use crate::measure::{self, Measure};
+use crate::memory;
+use crate::merge;
+use crate::metadata;
+use crate::normalize;
+use crate::input::{Input, InputReader};
+use crate::intern::Interner;
+#[cfg(feature = "opening-hours")]
+use crate::hours;
+use crate::join::JoinLayer;
+use crate::logging;
+use crate::pbf::{self, BlockIndex, ParallelObjects};
+use crate::preset::Preset;
+use crate::simplify;
+use crate::spatial::{self, Boundary};
+use crate::store::{DependencyStore, FlatNodes, NodeIndex, NodeIndexKind, ObjectStore, StreamingStore};
+use crate::validity::{self, InvalidMode};
+use anyhow::{anyhow, bail, Context, Result};
+use geo::{Intersects, LineString, Rect};
+use glob::Pattern;
+use indicatif::{ProgressBar, ProgressBarIter, ProgressStyle};
+use osmpbfreader::{OsmId, OsmObj, OsmPbfReader, Way, WayId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
+// std's clock panics in browsers
+#[cfg(target_family = "wasm")]
+use web_time::Instant;
+use tracing::{debug, info, warn};
+
+// An input being read, with a progress bar tracking the bytes consumed
+type Reader = OsmPbfReader<ProgressBarIter<InputReader>>;
+
+// How often the counters next to a byte progress bar are refreshed
+const COUNTER_INTERVAL: u64 = 1 << 16;
+
+// Features assembled ahead of a consumer of [`Extractor::features`]
+const FEATURE_BUFFER: usize = 1024;
+
+// Features handed from the reading thread to the emitting one at a time,
+// and batches that may wait between them
+#[cfg(not(target_family = "wasm"))]
+const PIPELINE_BATCH: usize = 256;
+#[cfg(not(target_family = "wasm"))]
+const PIPELINE_DEPTH: usize = FEATURE_BUFFER / PIPELINE_BATCH;
+
+/// Timings and counters collected during a run
+#[derive(Debug, Clone, Default)]
+pub struct ExtractionStats {
+    /// Number of objects held for geometry assembly: matches plus their
+    /// dependencies, or indexed nodes and member ways in streaming mode
+    pub objects_read: usize,
+    /// Time spent reading the PBF and resolving dependencies (in streaming
+    /// mode this includes assembling nodes and ways)
+    pub extraction_duration: Duration,
+    /// Time spent assembling geometries (in streaming mode: relations)
+    pub processing_duration: Duration,
+    /// Total size of the inputs in bytes, where known (local files)
+    pub input_bytes: u64,
+    /// Estimated memory held by the node location index and member ways in
+    /// streaming mode, the largest at the end of the run
+    pub index_bytes: Option<u64>,
+    /// Matched objects left out because their geometry could not be built,
+    /// per cause
+    pub skipped: BTreeMap<SkipCause, usize>,
+}
+
+/// Configures and runs an extraction over one or more PBF files.
+///
+/// ```no_run
+/// use osm_construction_extractor::{Extractor, OsmType};
+///
+/// let features = Extractor::new("germany-latest.osm.pbf")
+///     .filter("building=*".parse()?)
+///     .types(&[OsmType::Way, OsmType::Relation])
+///     .run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Extractor {
+    inputs: Vec<Input>,
+    filter: Filter,
+    node_filter: Option<Filter>,
+    exclude_filter: Option<Filter>,
+    invert: bool,
+    types: Vec<OsmType>,
+    ids: Option<HashSet<OsmId>>,
+    preset: Option<Preset>,
+    construction: Option<Monitor>,
+    bbox: Option<Rect>,
+    boundary: Option<Boundary>,
+    streaming: bool,
+    mmap: bool,
+    node_index: NodeIndexKind,
+    node_cache: Option<PathBuf>,
+    resume: Option<PathBuf>,
+    max_memory: Option<u64>,
+    strict: bool,
+    warnings_file: Option<PathBuf>,
+    threads: usize,
+    build_options: BuildOptions,
+    resolve_geometry: bool,
+    with_metadata: bool,
+    modified_since: Option<i64>,
+    modified_before: Option<i64>,
+    #[cfg(feature = "opening-hours")]
+    open_at: Option<chrono::NaiveDateTime>,
+    fix_geometry: Option<f64>,
+    invalid: Option<InvalidMode>,
+    measures: Vec<Measure>,
+    normalize: bool,
+    keep_tags: Vec<Pattern>,
+    drop_tags: Vec<Pattern>,
+    simplify: Option<f64>,
+    output_geometry: OutputGeometry,
+    to_crs: Option<Crs>,
+    precision: Option<u8>,
+    join: Option<JoinLayer>,
+    merge_ways: bool,
+    verbose: bool,
+    cancel: CancelToken,
+}
+
+impl Extractor {
+    /// Creates an extractor with the default `highway=* and construction=*`
+    /// filter applied to ways.
+    pub fn new(input: impl Into<Input>) -> Self {
+        Extractor {
+            inputs: vec![input.into()],
+            filter: Filter::And(vec![
+                Filter::HasKey("highway".to_string()),
+                Filter::HasKey("construction".to_string()),
+            ]),
+            node_filter: None,
+            exclude_filter: None,
+            invert: false,
+            types: vec![OsmType::Way],
+            ids: None,
+            preset: None,
+            construction: None,
+            bbox: None,
+            boundary: None,
+            streaming: false,
+            mmap: false,
+            node_index: NodeIndexKind::default(),
+            node_cache: None,
+            resume: None,
+            max_memory: None,
+            strict: false,
+            warnings_file: None,
+            threads: 0,
+            build_options: BuildOptions::default(),
+            resolve_geometry: true,
+            with_metadata: false,
+            modified_since: None,
+            modified_before: None,
+            #[cfg(feature = "opening-hours")]
+            open_at: None,
+            fix_geometry: None,
+            invalid: None,
+            measures: Vec::new(),
+            normalize: false,
+            keep_tags: Vec::new(),
+            drop_tags: Vec::new(),
+            simplify: None,
+            output_geometry: OutputGeometry::Full,
+            to_crs: None,
+            precision: None,
+            join: None,
+            merge_ways: false,
+            verbose: false,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Replaces the inputs, e.g. to process several regional extracts in
+    /// one run. Paths of `-` read stdin and `http(s)://` URLs are streamed.
+    ///
+    /// Objects contained in more than one file, as along the borders of
+    /// overlapping extracts, are emitted only once, from the first file
+    /// they could be assembled from; with [`Extractor::allow_incomplete`],
+    /// from one they are complete in if there is any.
+    pub fn inputs<I: Into<Input>>(mut self, inputs: impl IntoIterator<Item = I>) -> Self {
+        self.inputs = inputs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the tag filter selecting the objects to extract
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Matches nodes on their own filter instead of [`Extractor::filter`],
+    /// whatever the [`Extractor::types`]: the nodes it selects are emitted
+    /// as points alongside the other matched objects
+    pub fn node_filter(mut self, filter: Filter) -> Self {
+        self.node_filter = Some(filter);
+        self
+    }
+
+    /// Drops objects matching `filter` that the other filters selected,
+    /// e.g. `access=private or disused=yes`
+    pub fn exclude_filter(mut self, filter: Filter) -> Self {
+        self.exclude_filter = Some(filter);
+        self
+    }
+
+    /// Selects the tagged objects of the [`Extractor::types`] that do *not*
+    /// match [`Extractor::filter`] instead of those that do, e.g. for a base
+    /// map without the construction sites. Untagged objects, such as the
+    /// nodes of ways, stay unselected; the node and exclude filters apply
+    /// as before.
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// Sets the OSM object types the filter is applied to
+    pub fn types(mut self, types: &[OsmType]) -> Self {
+        self.types = types.to_vec();
+        self
+    }
+
+    /// Extracts exactly the objects with these ids, with what they
+    /// reference, instead of those the filters select, whatever their tags;
+    /// the types are set to those of the ids
+    pub fn ids(mut self, ids: impl IntoIterator<Item = OsmId>) -> Self {
+        let ids: HashSet<OsmId> = ids.into_iter().collect();
+        self.types = [OsmType::Node, OsmType::Way, OsmType::Relation]
+            .into_iter()
+            .filter(|&osm_type| ids.iter().any(|&id| OsmType::of_id(id) == osm_type))
+            .collect();
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Extracts a built-in theme: sets the filter and types of the preset
+    /// and post-processes the matched features as it describes
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.filter = preset.filter();
+        self.types = preset.types().to_vec();
+        self.preset = Some(preset);
+        self
+    }
+
+    /// Judges the construction sites found by `monitor`: adds their dates
+    /// and status and keeps those expected to open in its window. The
+    /// [`Preset::Construction`] preset does so as of today without one.
+    pub fn construction(mut self, monitor: Monitor) -> Self {
+        self.construction = Some(monitor);
+        self
+    }
+
+    /// Restricts the extraction to features intersecting a lon/lat box.
+    ///
+    /// Matching nodes are selected by their location, and the assembled
+    /// geometries of ways and relations are tested against the box exactly,
+    /// so a way crossing the box without a node in it is kept. Node blocks
+    /// are scanned first to index the blocks of the file (see
+    /// [`pbf::BlockIndex`]), so the later passes skip decoding blocks of
+    /// nodes outside the box and any block without a missing dependency,
+    /// which on spatially sorted extracts leaves most of them.
+    pub fn bbox(mut self, bbox: Rect) -> Self {
+        self.bbox = Some(bbox);
+        self
+    }
+
+    /// Restricts the extraction to features intersecting a boundary polygon,
+    /// optionally clipping their geometries at it (see [`spatial::ClipMode`]).
+    ///
+    /// The boundary's bounding box is used for the same node pre-scan as
+    /// [`Extractor::bbox`].
+    pub fn boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = Some(boundary);
+        self
+    }
+
+    /// Reads the file in a single streaming pass instead of resolving
+    /// dependencies with repeated passes.
+    ///
+    /// Every node location is kept in a compact index, so memory grows with
+    /// the node count of the file instead of the number of matched objects
+    /// and their dependencies. Requires a PBF sorted by type then id. The
+    /// bbox node pre-scan is not needed in this mode.
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Memory-maps local input files instead of reading them through a
+    /// buffer, leaving caching to the OS page cache; inputs from stdin or
+    /// URLs are rejected
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Selects the node location index used in streaming mode
+    pub fn node_index(mut self, kind: NodeIndexKind) -> Self {
+        self.node_index = kind;
+        self
+    }
+
+    /// Keeps node locations in a file instead of memory (see [`FlatNodes`]),
+    /// for inputs too large for the in-memory indexes. Implies streaming
+    /// mode; an unchanged input reuses the locations of an earlier run.
+    pub fn node_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.node_cache = Some(path.into());
+        self
+    }
+
+    /// Keeps checkpoints in `dir`, so that a run that was interrupted, or
+    /// failed after reading the input, resumes from the last one saved
+    /// during or after a pass over it (see [`checkpoint`](crate::checkpoint))
+    /// instead of starting over. In streaming mode the node locations are kept there as by
+    /// [`Extractor::node_cache`], unless that is set, and reused once
+    /// complete.
+    ///
+    /// With several inputs each gets a subdirectory, `1`, `2` and so on.
+    /// The checkpoints are left for later runs until `dir` is removed.
+    pub fn resume(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.resume = Some(dir.into());
+        self
+    }
+
+    /// Limits the memory the extraction may allocate to `bytes`.
+    ///
+    /// In streaming mode, an in-memory node index estimated to take more
+    /// than half of the limit is replaced by node locations in a temporary
+    /// file; and a run using more memory than the limit all the same ends
+    /// with an error (see [`memory::check`]) rather than being killed by
+    /// the OS. Features handed to [`Extractor::for_each`] count as well
+    /// once collected by the caller.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Fails on the first matched object whose geometry cannot be built,
+    /// e.g. a way with nodes missing from the extract, instead of leaving
+    /// it out. Without it skipped objects are logged at debug level and
+    /// summed up in a single warning at the end.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Writes every skipped object to `path` as a JSON line with its type,
+    /// id, cause and details
+    pub fn warnings_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.warnings_file = Some(path.into());
+        self
+    }
+
+    /// Stops reading once `cancel` is cancelled and goes on with the
+    /// objects read so far, see [`cancel`](crate::cancel)
+    pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Sets the number of threads decoding PBF blocks; `0` (the default)
+    /// uses one thread per CPU core
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Keeps closed ways with area tags as linestrings instead of turning
+    /// them into polygons
+    pub fn force_linestring(mut self, force_linestring: bool) -> Self {
+        self.build_options.force_linestring = force_linestring;
+        self
+    }
+
+    /// Builds ways missing some of their nodes from the nodes that are there
+    /// instead of skipping them (see [`BuildOptions::allow_incomplete`])
+    pub fn allow_incomplete(mut self, allow_incomplete: bool) -> Self {
+        self.build_options.allow_incomplete = allow_incomplete;
+        self
+    }
+
+    /// Lists the members of matched relations with their roles (see
+    /// [`BuildOptions::with_members`])
+    pub fn with_members(mut self, with_members: bool) -> Self {
+        self.build_options.with_members = with_members;
+        self
+    }
+
+    /// Resolves relations that are members of matched relations, such as
+    /// the routes of a route master, down to `depth` levels (see
+    /// [`BuildOptions::relation_depth`]). Streaming mode does not keep
+    /// member relations and leaves them out regardless.
+    pub fn relation_depth(mut self, depth: usize) -> Self {
+        self.build_options.relation_depth = depth;
+        self
+    }
+
+    /// With `false`, nothing the matched objects reference is looked up:
+    /// they are emitted in a single pass as they are read, ways and
+    /// relations without a geometry but with the ids of their nodes and
+    /// members (see [`ExtractedFeature::unresolved`]), and [`objects`]
+    /// returns the matches alone. Much faster where ids and tags are all
+    /// that is needed; a bbox, boundary or preset needs the geometry and
+    /// makes the extraction fail.
+    ///
+    /// [`objects`]: Extractor::objects
+    pub fn resolve_geometry(mut self, resolve_geometry: bool) -> Self {
+        self.resolve_geometry = resolve_geometry;
+        self
+    }
+
+    /// Adds the version, timestamp, changeset and author of each matched
+    /// object as `@version`, `@timestamp`, `@changeset`, `@uid` and `@user`
+    /// tags (see [`metadata`](crate::metadata)), which the filter can then
+    /// match on as well
+    pub fn with_metadata(mut self, with_metadata: bool) -> Self {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    /// Only extracts objects last edited at or after `timestamp`, in seconds
+    /// since the Unix epoch (see [`metadata::parse_timestamp`]). Objects
+    /// without a timestamp never match.
+    pub fn modified_since(mut self, timestamp: i64) -> Self {
+        self.modified_since = Some(timestamp);
+        self
+    }
+
+    /// Only extracts objects last edited before `timestamp`, like
+    /// [`Extractor::modified_since`]
+    pub fn modified_before(mut self, timestamp: i64) -> Self {
+        self.modified_before = Some(timestamp);
+        self
+    }
+
+    /// Only emits features open at the local time `at` by their
+    /// `opening_hours` tag (see [`hours::keep_open`]), after the filter
+    /// matched them
+    #[cfg(feature = "opening-hours")]
+    pub fn open_at(mut self, at: chrono::NaiveDateTime) -> Self {
+        self.open_at = Some(at);
+        self
+    }
+
+    /// Removes repeated points and segments of no length, and closes area
+    /// ways whose ends are at most `close_tolerance` meters apart into
+    /// polygons (see [`cleanup::fix`]), before anything else is computed
+    pub fn fix_geometry(mut self, close_tolerance: f64) -> Self {
+        self.fix_geometry = Some(close_tolerance);
+        self
+    }
+
+    /// Checks the polygons for validity after [`Extractor::fix_geometry`]
+    /// and flags, drops or repairs the invalid ones (see
+    /// [`validity::apply`]); with [`Extractor::simplify`],
+    /// [`Extractor::to_crs`] or [`Extractor::precision`] once more after
+    /// them
+    pub fn invalid(mut self, mode: InvalidMode) -> Self {
+        self.invalid = Some(mode);
+        self
+    }
+
+    /// Adds geodesic lengths of lines and areas of polygons as properties
+    /// (see [`measure::add`]), computed before simplification and
+    /// reprojection, and bounding boxes, computed after them
+    pub fn compute(mut self, measures: &[Measure]) -> Self {
+        self.measures = measures.to_vec();
+        self
+    }
+
+    /// Adds the values of measurement tags such as `maxspeed` or `width`
+    /// in SI units (see [`normalize::add`]), before tags are dropped
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Keeps only the tags whose keys match one of the glob patterns, e.g.
+    /// `highway`, `name` or `name:*`, and the computed `@` properties in
+    /// the emitted features; all of them if empty. Filters still see all
+    /// tags. The others are not even copied into the features, unless the
+    /// preset, construction monitor, opening hours, normalization or
+    /// geometry fixes need them.
+    pub fn keep_tags(mut self, patterns: &[Pattern]) -> Self {
+        self.keep_tags = patterns.to_vec();
+        self
+    }
+
+    /// Removes tags whose keys match one of the glob patterns, e.g.
+    /// `created_by` or `source:*`, from the emitted features. Filters still
+    /// see all tags.
+    pub fn drop_tags(mut self, patterns: &[Pattern]) -> Self {
+        self.drop_tags = patterns.to_vec();
+        self
+    }
+
+    /// Simplifies lines and polygons with Douglas-Peucker, removing detail
+    /// below `tolerance` meters (see [`simplify::simplify`])
+    pub fn simplify(mut self, tolerance: f64) -> Self {
+        self.simplify = Some(tolerance);
+        self
+    }
+
+    /// Replaces the geometry of each feature by a point on it (see
+    /// [`centroid::reduce`]), after the measures were computed from the
+    /// full geometry
+    pub fn output_geometry(mut self, output: OutputGeometry) -> Self {
+        self.output_geometry = output;
+        self
+    }
+
+    /// Reprojects the emitted geometries from WGS84 into `crs`.
+    ///
+    /// Spatial filters still apply in WGS84, before reprojection.
+    pub fn to_crs(mut self, crs: Crs) -> Self {
+        self.to_crs = Some(crs);
+        self
+    }
+
+    /// Rounds the emitted coordinates to `decimals` decimals (see
+    /// [`simplify::round`]), after reprojection, so in the units of the CRS
+    pub fn precision(mut self, decimals: u8) -> Self {
+        self.precision = Some(decimals);
+        self
+    }
+
+    /// Tags each feature with attributes of the polygon of `layer` it falls
+    /// in (see [`JoinLayer::apply`]), judged from its full geometry in WGS84
+    pub fn join(mut self, layer: JoinLayer) -> Self {
+        self.join = Some(layer);
+        self
+    }
+
+    /// Joins ways split only to change a tag back into longer lines (see
+    /// [`merge::merge_ways`]), comparing the tags left after
+    /// [`drop_tags`](Extractor::drop_tags). The line ways are held back
+    /// until the extraction has finished, also when streaming; measures
+    /// are computed for the merged lines.
+    pub fn merge_ways(mut self, merge_ways: bool) -> Self {
+        self.merge_ways = merge_ways;
+        self
+    }
+
+    /// Logs phase messages at info level (see [`logging`]) and draws
+    /// progress bars while running
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Runs the extraction and returns all matched features
+    pub fn run(&self) -> Result<Vec<ExtractedFeature>> {
+        self.run_with_stats().map(|(features, _)| features)
+    }
+
+    /// Runs the extraction, additionally returning timings and counters
+    pub fn run_with_stats(&self) -> Result<(Vec<ExtractedFeature>, ExtractionStats)> {
+        // Kept compact while the objects read are still held
+        let mut features = FeatureStore::new();
+        let stats = self.for_each(|feature| {
+            features.push(feature);
+            Ok(())
+        })?;
+        Ok((features.into_features(), stats))
+    }
+
+    /// Runs the extraction on a background thread, yielding features as they
+    /// are assembled.
+    ///
+    /// Only a small buffer of features waits to be consumed, so in streaming
+    /// mode memory stays bounded however many features the extraction
+    /// yields. An error ends the iteration as its last item, and dropping
+    /// the iterator early stops the extraction.
+    ///
+    /// ```no_run
+    /// use osm_construction_extractor::Extractor;
+    ///
+    /// let extractor = Extractor::new("planet.osm.pbf").streaming(true);
+    /// for feature in extractor.features() {
+    ///     println!("{}", feature?.unique_id());
+    /// }
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn features(&self) -> impl Iterator<Item = Result<ExtractedFeature>> {
+        let (sender, receiver) = mpsc::sync_channel(FEATURE_BUFFER);
+        let extractor = self.clone();
+        let handle = thread::spawn(move || {
+            let result = extractor.for_each(|feature| {
+                sender.send(Ok(feature)).map_err(|_| anyhow!("the feature iterator was dropped"))
+            });
+            if let Err(e) = result {
+                // Fails only if nobody is listening anymore
+                let _ = sender.send(Err(e));
+            }
+        });
+        Features {
+            receiver,
+            handle: Some(handle),
+        }
+    }
+
+    /// Reads the matched objects plus everything they reference as raw OSM
+    /// objects instead of assembling features, merged over all inputs.
+    ///
+    /// This is for consumers that need more than the geometry, such as the
+    /// node ids of ways for [`graph`](crate::graph). It always resolves
+    /// dependencies, also when streaming is set, and with a bbox or
+    /// boundary ways are kept if their line crosses its extent.
+    pub fn objects(&self) -> Result<BTreeMap<OsmId, OsmObj>> {
+        let mut all = BTreeMap::new();
+        for (i, input) in self.inputs.iter().enumerate() {
+            if !self.resolve_geometry {
+                self.scan_matches(input, |obj| {
+                    all.insert(obj.id(), obj);
+                    Ok(())
+                })?;
+                continue;
+            }
+            let mut objects = self.read_with_deps(input, false, self.checkpoint_dir(i).as_deref())?.objects;
+            if let Some(rect) = self.prefilter_rect() {
+                let outside: Vec<OsmId> = objects
+                    .values()
+                    .filter(|obj| matches!(obj, OsmObj::Way(way) if self.is_match(obj) && !way_crosses(way, &objects, &rect)))
+                    .map(OsmObj::id)
+                    .collect();
+                for id in outside {
+                    objects.remove(&id);
+                }
+            }
+            all.extend(objects);
+        }
+        if self.verbose {
+            info!("   Found {} total objects (matches and their required members).", all.len());
+        }
+        Ok(all)
+    }
+
+    /// Estimates the matches, output size, memory and runtime of the
+    /// extraction over each input without running it, from the header and
+    /// `samples` blocks spread over the file; see [`estimate`](crate::estimate)
+    pub fn estimate(&self, samples: usize) -> Result<Vec<Estimate>> {
+        let pool = pbf::thread_pool(self.threads)?;
+        let mode = if !self.resolve_geometry {
+            estimate::Mode::Unresolved
+        } else if self.node_cache.is_some() {
+            estimate::Mode::Streaming { bytes_per_node: 0 }
+        } else if self.streaming {
+            estimate::Mode::Streaming { bytes_per_node: self.node_index.bytes_per_node() }
+        } else {
+            estimate::Mode::Dependencies
+        };
+        let mut estimates = Vec::with_capacity(self.inputs.len());
+        for input in &self.inputs {
+            let mut reader = if self.mmap { input.open_mmap()? } else { input.open()? };
+            let sample = estimate::sample(&mut reader, &pool, samples, self.reads_metadata())?;
+            let size = reader.source_size().unwrap_or(0);
+            estimates.push(Estimate::new(input.to_string(), size, sample, mode, self.prefilter_rect(), |obj| {
+                self.is_match(obj)
+            }));
+        }
+        Ok(estimates)
+    }
+
+    /// Runs the extraction, handing every feature to `emit` soon after its
+    /// geometry is assembled.
+    ///
+    /// The input is read on a background thread while `emit` runs on the
+    /// calling one, so writing the features overlaps with decoding. In
+    /// streaming mode nodes and ways are emitted while the file is still
+    /// being read; relations follow after a second pass over the file.
+    pub fn for_each(&self, mut emit: impl FnMut(ExtractedFeature) -> Result<()>) -> Result<ExtractionStats> {
+        let transformer = self.to_crs.as_ref().map(Transformer::new).transpose()?;
+        let mut finished: u64 = 0;
+        let mut finish = |mut feature: ExtractedFeature| {
+            finished += 1;
+            if let (Some(limit), true) = (self.max_memory, finished.is_multiple_of(COUNTER_INTERVAL)) {
+                memory::check(limit)?;
+            }
+            if let Some(tolerance) = self.fix_geometry {
+                cleanup::fix(&mut feature, tolerance);
+            }
+            if self.invalid.is_some_and(|mode| !validity::apply(&mut feature, mode)) {
+                return Ok(());
+            }
+            measure::add(&mut feature, &self.measures);
+            if self.normalize {
+                normalize::add(&mut feature);
+            }
+            if !self.keep_tags.is_empty() {
+                feature.tags_mut().retain(|key, _| key.starts_with('@') || self.keep_tags.iter().any(|p| p.matches(key)));
+            }
+            if !self.drop_tags.is_empty() {
+                feature.tags_mut().retain(|key, _| !self.drop_tags.iter().any(|p| p.matches(key)));
+            }
+            if let Some(layer) = &self.join {
+                layer.apply(&mut feature);
+            }
+            if let Some(point) = centroid::reduce(feature.geometry(), self.output_geometry) {
+                feature.set_geometry(point);
+            }
+            if let Some(tolerance) = self.simplify {
+                let simplified = simplify::simplify(feature.geometry(), tolerance);
+                feature.set_geometry(simplified);
+            }
+            if let Some(transformer) = &transformer {
+                transformer.transform(feature.geometry_mut())?;
+            }
+            if let Some(decimals) = self.precision {
+                simplify::round(feature.geometry_mut(), decimals);
+            }
+            // Simplifying, reprojecting and rounding can make valid polygons
+            // invalid again
+            let transformed = self.simplify.is_some() || transformer.is_some() || self.precision.is_some();
+            if transformed && self.invalid.is_some_and(|mode| !validity::apply(&mut feature, mode)) {
+                return Ok(());
+            }
+            if self.measures.contains(&Measure::Bbox) {
+                measure::add_bbox(&mut feature);
+            }
+            emit(feature)
+        };
+        let monitor = match (&self.construction, self.preset) {
+            (Some(monitor), _) => Some(monitor.clone()),
+            (None, Some(Preset::Construction)) => Some(Monitor::today()),
+            (None, _) => None,
+        };
+        let mut held_back = Vec::new();
+        let mut emit = |mut feature: ExtractedFeature| {
+            if self.preset.is_some_and(|preset| !preset.finish(&mut feature)) {
+                return Ok(());
+            }
+            if monitor.as_ref().is_some_and(|monitor| !monitor.apply(&mut feature)) {
+                return Ok(());
+            }
+            #[cfg(feature = "opening-hours")]
+            if self.open_at.is_some_and(|at| !hours::keep_open(&mut feature, at)) {
+                return Ok(());
+            }
+            // Metadata only read for the time range stays out of the output
+            if !self.with_metadata && self.reads_metadata() {
+                for key in metadata::TAGS {
+                    feature.tags_mut().remove(key);
+                }
+            }
+            if self.merge_ways && merge::is_mergeable(&feature) {
+                // Dropped tags must not keep ways apart
+                feature.tags_mut().retain(|key, _| !self.drop_tags.iter().any(|p| p.matches(key)));
+                held_back.push(feature);
+                return Ok(());
+            }
+            finish(feature)
+        };
+        let build_options = self.run_build_options();
+        let mut skips = SkipLog::create(self.warnings_file.as_deref())?;
+        let stats = match self.inputs.as_slice() {
+            [] => bail!("no input files given"),
+            [input] => self.for_each_in(input, self.checkpoint_dir(0).as_deref(), &build_options, emit, &mut skips),
+            inputs => {
+                let mut seen: HashSet<(OsmType, i64)> = HashSet::new();
+                // Incomplete features wait for the end, in case another
+                // input has all that they miss
+                let mut incomplete = Vec::new();
+                let mut stats = ExtractionStats::default();
+                for (i, input) in inputs.iter().enumerate() {
+                    if self.verbose {
+                        info!("-> Input {}/{}: {}", i + 1, inputs.len(), input);
+                    }
+                    let emit_unseen = |feature: ExtractedFeature| {
+                        let key = (feature.osm_type(), feature.id());
+                        if feature.tags().contains_key(INCOMPLETE_TAG) {
+                            if !seen.contains(&key) {
+                                incomplete.push(feature);
+                            }
+                            Ok(())
+                        } else if seen.insert(key) {
+                            emit(feature)
+                        } else {
+                            Ok(())
+                        }
+                    };
+                    let state = self.checkpoint_dir(i);
+                    let file_stats =
+                        self.for_each_in(input, state.as_deref(), &build_options, emit_unseen, &mut skips)?;
+                    stats.objects_read += file_stats.objects_read;
+                    stats.extraction_duration += file_stats.extraction_duration;
+                    stats.processing_duration += file_stats.processing_duration;
+                    stats.input_bytes += file_stats.input_bytes;
+                    stats.index_bytes = stats.index_bytes.max(file_stats.index_bytes);
+                }
+                for feature in incomplete {
+                    if seen.insert((feature.osm_type(), feature.id())) {
+                        emit(feature)?;
+                    }
+                }
+                Ok(stats)
+            }
+        };
+        let mut stats = stats?;
+        if !held_back.is_empty() {
+            let ways = held_back.len();
+            let merged = merge::merge_ways(held_back);
+            if self.verbose {
+                info!("   Merged {} ways into {} lines.", ways, merged.len());
+            }
+            for feature in merged {
+                finish(feature)?;
+            }
+        }
+        stats.skipped = skips.finish(self.warnings_file.as_deref())?;
+        Ok(stats)
+    }
+
+    // Opens the input with a progress bar of the bytes read from it, which
+    // restarts whenever a pass rewinds the file
+    fn open(&self, input: &Input) -> Result<(Reader, ProgressBar)> {
+        let file = if self.mmap { input.open_mmap()? } else { input.open()? };
+        let bar = match (self.verbose && logging::shows_progress(), file.total_size()) {
+            (false, _) => ProgressBar::hidden(),
+            (true, Some(size)) => ProgressBar::new(size).with_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes:>10}/{total_bytes:10} ({eta}) {msg}")?
+                    .progress_chars("#>-"),
+            ),
+            (true, None) => ProgressBar::new_spinner()
+                .with_style(ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {bytes} read {msg}")?),
+        };
+        Ok((OsmPbfReader::new(bar.wrap_read(file)), bar))
+    }
+
+    // Reading the file and assembling the features runs on a thread of its
+    // own, which hands the features over in batches through a bounded queue:
+    // the passes go on decoding while `emit` transforms and writes what was
+    // assembled so far, and memory stays bounded when writing is slower.
+    // Browsers have no threads to spare, so there it all runs in turn.
+    fn for_each_in(
+        &self,
+        input: &Input,
+        state: Option<&Path>,
+        options: &BuildOptions,
+        emit: impl FnMut(ExtractedFeature) -> Result<()>,
+        skips: &mut SkipLog,
+    ) -> Result<ExtractionStats> {
+        #[cfg(target_family = "wasm")]
+        return self.extract_in(input, state, options, emit, skips);
+
+        #[cfg(not(target_family = "wasm"))]
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel::<Vec<ExtractedFeature>>(PIPELINE_DEPTH);
+            let reading = scope.spawn(move || {
+                let mut batch = Vec::with_capacity(PIPELINE_BATCH);
+                let stopped = || anyhow!("stopped writing the features");
+                let stats = self.extract_in(
+                    input,
+                    state,
+                    options,
+                    |feature| {
+                        batch.push(feature);
+                        if batch.len() == PIPELINE_BATCH {
+                            let full = std::mem::replace(&mut batch, Vec::with_capacity(PIPELINE_BATCH));
+                            sender.send(full).map_err(|_| stopped())?;
+                        }
+                        Ok(())
+                    },
+                    skips,
+                )?;
+                if !batch.is_empty() {
+                    sender.send(batch).map_err(|_| stopped())?;
+                }
+                Ok(stats)
+            });
+            // Dropping the queue on an error stops the reading thread at its
+            // next batch
+            let emitted = receiver.into_iter().flatten().try_for_each(emit);
+            let stats = reading.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            emitted?;
+            stats
+        })
+    }
+
+    fn extract_in(
+        &self,
+        input: &Input,
+        state: Option<&Path>,
+        options: &BuildOptions,
+        emit: impl FnMut(ExtractedFeature) -> Result<()>,
+        skips: &mut SkipLog,
+    ) -> Result<ExtractionStats> {
+        if !self.resolve_geometry {
+            self.for_each_unresolved(input, emit)
+        } else if self.streaming || self.node_cache.is_some() {
+            self.for_each_streaming(input, state, options, emit, skips)
+        } else {
+            self.for_each_with_deps(input, state, options, emit, skips)
+        }
+    }
+
+    // Where the checkpoints of the `i`th input are kept
+    fn checkpoint_dir(&self, i: usize) -> Option<PathBuf> {
+        let dir = self.resume.as_ref()?;
+        Some(match self.inputs.len() {
+            1 => dir.clone(),
+            _ => dir.join((i + 1).to_string()),
+        })
+    }
+
+    // What decides which objects are candidates, for telling whether a
+    // checkpoint was written by the same selection
+    fn selection(&self, slim: bool) -> String {
+        let mut ids: Option<Vec<&OsmId>> = self.ids.as_ref().map(|ids| ids.iter().collect());
+        if let Some(ids) = &mut ids {
+            ids.sort();
+        }
+        format!(
+            "{:?}",
+            (
+                (&self.filter, &self.node_filter, &self.exclude_filter, self.invert, &self.types, ids),
+                (self.prefilter_rect(), self.modified_since, self.modified_before, self.reads_metadata(), slim),
+                self.build_options.relation_depth,
+            )
+        )
+    }
+
+    fn for_each_with_deps(
+        &self,
+        input: &Input,
+        state: Option<&Path>,
+        options: &BuildOptions,
+        mut emit: impl FnMut(ExtractedFeature) -> Result<()>,
+        skips: &mut SkipLog,
+    ) -> Result<ExtractionStats> {
+        let start_time = Instant::now();
+        let store = self.read_with_deps(input, true, state)?;
+        let extraction_duration = start_time.elapsed();
+        if self.verbose {
+            info!(
+                "   Found {} total objects (matches and their required members) in {:.2?}.",
+                store.len(),
+                extraction_duration
+            );
+            info!("-> Pass 2: Re-structuring extracted data into final format...");
+        }
+        let prefilter_rect = self.prefilter_rect();
+        let is_candidate = |obj: &OsmObj| self.is_match(obj) && in_prefilter(obj, prefilter_rect.as_ref());
+
+        let processing_start_time = Instant::now();
+
+        // Dependencies may include objects that do not match the filter themselves
+        let objects_to_process: Vec<&OsmObj> = store.objects.values().filter(|obj| is_candidate(obj)).collect();
+
+        let bar = if self.verbose && logging::shows_progress() {
+            ProgressBar::new(objects_to_process.len() as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta})")?
+            .progress_chars("#>-"));
+
+        for obj in objects_to_process {
+            self.emit_built(obj, &store, options, &mut emit, skips)?;
+            bar.inc(1);
+        }
+        bar.finish_with_message("Done processing features.");
+
+        Ok(ExtractionStats {
+            objects_read: store.len(),
+            extraction_duration,
+            processing_duration: processing_start_time.elapsed(),
+            input_bytes: input.size().unwrap_or(0),
+            index_bytes: None,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    fn for_each_unresolved(
+        &self,
+        input: &Input,
+        mut emit: impl FnMut(ExtractedFeature) -> Result<()>,
+    ) -> Result<ExtractionStats> {
+        if self.preset.is_some() {
+            bail!("presets select and finish features by their geometry, which is not resolved");
+        }
+        let start_time = Instant::now();
+        let seen = self.scan_matches(input, |obj| emit(ExtractedFeature::unresolved(&obj)))?;
+        Ok(ExtractionStats {
+            objects_read: seen as usize,
+            extraction_duration: start_time.elapsed(),
+            processing_duration: Duration::ZERO,
+            input_bytes: input.size().unwrap_or(0),
+            index_bytes: None,
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    // A single pass handing over the objects that match, none of what they
+    // reference; returns the number of objects read
+    fn scan_matches(&self, input: &Input, mut matched: impl FnMut(OsmObj) -> Result<()>) -> Result<u64> {
+        if self.prefilter_rect().is_some() {
+            bail!("a bbox or boundary needs the node locations, which are not resolved");
+        }
+        let (mut reader, read_bar) = self.open(input)?;
+        let pool = pbf::thread_pool(self.threads)?;
+        debug!("Decoding PBF blocks on {} threads", pool.current_num_threads());
+        if self.verbose {
+            info!("-> Pass 1: Finding objects without resolving their geometry...");
+        }
+        // Only full decoding reads metadata
+        let objects = if self.matches_nodes() || self.reads_metadata() {
+            ParallelObjects::new(&mut reader, &pool).with_metadata(self.reads_metadata())
+        } else {
+            ParallelObjects::without_nodes(&mut reader, &pool)
+        }
+        .cancel_token(&self.cancel);
+        let mut counter = PassCounter::new(self.max_memory);
+        for obj in objects {
+            let obj = obj?;
+            let is_match = self.is_match(&obj);
+            counter.count(is_match, &read_bar)?;
+            if is_match {
+                matched(obj)?;
+            }
+        }
+        read_bar.finish_and_clear();
+        if self.verbose {
+            info!("   Scanned {} objects, {} matched the filter.", counter.seen, counter.matched);
+        }
+        Ok(counter.seen)
+    }
+
+    // Passes 0 and 1 of dependency mode: the candidates with everything they
+    // reference, after indexing the blocks of the file if there is a
+    // pre-filter box. With `slim`, the referenced nodes are kept as
+    // locations only, which is all that assembling geometries needs.
+    fn read_with_deps(&self, input: &Input, slim: bool, state: Option<&Path>) -> Result<DependencyStore> {
+        let (mut reader, read_bar) = self.open(input)?;
+        let pool = pbf::thread_pool(self.threads)?;
+        debug!("Decoding PBF blocks on {} threads", pool.current_num_threads());
+        let mut checkpoint = state.map(|dir| Checkpoint::open(dir, input, self.selection(slim))).transpose()?;
+        let resumed = checkpoint.as_ref().map_or(0, Checkpoint::passes);
+        let resumed_blocks = checkpoint.as_ref().map_or(0, Checkpoint::blocks);
+
+        let prefilter_rect = self.prefilter_rect();
+        // The index is only built by a run that reads the whole file, not
+        // one resuming from a checkpoint after the first pass
+        let mut index = None;
+        if let (Some(rect), 0) = (&prefilter_rect, resumed) {
+            if self.verbose {
+                info!("-> Pass 0: Indexing the blocks of nodes inside the bounding box...");
+            }
+            let blocks = BlockIndex::build(&mut reader, &pool, &self.cancel, rect)?;
+            if self.verbose {
+                let (node_blocks, inside) = blocks.node_blocks();
+                info!("   Found nodes inside the bounding box in {} of {} blocks of nodes.", inside, node_blocks);
+            }
+            reader.rewind()?;
+            index = Some(blocks);
+        }
+        let is_candidate = |obj: &OsmObj| self.is_match(obj) && in_prefilter(obj, prefilter_rect.as_ref());
+
+        if let (true, Some(dir), true) = (self.verbose, state, resumed > 0 || resumed_blocks > 0) {
+            info!(
+                "-> Resuming from the checkpoint in {:?} (passes over the input done: {}, blocks of the next: {})",
+                dir, resumed, resumed_blocks
+            );
+        } else if self.verbose {
+            info!("-> Pass 1: Finding objects and collecting dependencies...");
+        }
+        let mut counter = PassCounter::new(self.max_memory);
+        let pred = |obj: &OsmObj| {
+            let matched = is_candidate(obj);
+            counter.count(matched, &read_bar)?;
+            Ok(matched)
+        };
+        let (metadata, depth) = (self.reads_metadata(), self.build_options.relation_depth);
+        let (checkpoint, index, cancel) = (checkpoint.as_mut(), index.as_ref(), &self.cancel);
+        let store = match slim {
+            true => pbf::get_objs_and_slim_deps(&mut reader, &pool, cancel, metadata, depth, checkpoint, index, pred)?,
+            false => DependencyStore {
+                objects: pbf::get_objs_and_deps(&mut reader, &pool, cancel, metadata, depth, checkpoint, index, pred)?,
+                nodes: NodeIndex::new(NodeIndexKind::Sorted),
+            },
+        };
+        read_bar.finish_and_clear();
+        if self.verbose && resumed == 0 && resumed_blocks == 0 {
+            info!("   Scanned {} objects, {} matched the filter.", counter.seen, counter.matched);
+        }
+        Ok(store)
+    }
+
+    // Single pass over a PBF sorted by type then id: all node locations go
+    // into a compact index, and matched nodes and ways are assembled as soon
+    // as they are read. Matched relations are kept until the end, when a
+    // second pass collects the node lists of their member ways.
+    fn for_each_streaming(
+        &self,
+        input: &Input,
+        state: Option<&Path>,
+        options: &BuildOptions,
+        mut emit: impl FnMut(ExtractedFeature) -> Result<()>,
+        skips: &mut SkipLog,
+    ) -> Result<ExtractionStats> {
+        let start_time = Instant::now();
+        let (mut reader, read_bar) = self.open(input)?;
+        let pool = pbf::thread_pool(self.threads)?;
+        debug!("Decoding PBF blocks on {} threads", pool.current_num_threads());
+        let prefilter_rect = self.prefilter_rect();
+
+        let source = input.fingerprint();
+        // Kept until the end of the run, when the file is removed with it
+        let mut spill_dir = None;
+        let cache = self.node_cache.clone().or_else(|| state.map(|dir| dir.join("nodes.cache")));
+        if let (Some(dir), None) = (state, &self.node_cache) {
+            fs::create_dir_all(dir)?;
+        }
+        let nodes = match (&cache, self.max_memory) {
+            (Some(path), _) => NodeIndex::Flat(FlatNodes::open(path, source)?),
+            (None, Some(limit)) if self.node_index.estimated_size(input.size().unwrap_or(0)) > limit / 2 => {
+                let dir = spill_dir.insert(tempfile::tempdir()?);
+                let path = dir.path().join("nodes.cache");
+                if self.verbose {
+                    info!(
+                        "-> A node index of about {} does not fit the memory limit of {}, keeping node locations in {:?}",
+                        memory::format_size(self.node_index.estimated_size(input.size().unwrap_or(0))),
+                        memory::format_size(limit),
+                        path
+                    );
+                }
+                NodeIndex::Flat(FlatNodes::open(&path, None)?)
+            }
+            (None, _) => NodeIndex::new(self.node_index),
+        };
+        let mut store = StreamingStore {
+            nodes,
+            ways: HashMap::new(),
+        };
+        let mut relations = Vec::new();
+
+        // With all locations cached already nodes are only needed if they
+        // can match
+        let cached = matches!(&store.nodes, NodeIndex::Flat(flat) if flat.is_complete());
+        let skip_nodes = cached && !self.matches_nodes() && !self.reads_metadata();
+        if self.verbose {
+            if cached {
+                info!("-> Reusing {} cached node locations", store.nodes.len());
+            }
+            info!("-> Pass 1: Indexing node locations and streaming matched features...");
+        }
+        let mut counter = PassCounter::new(self.max_memory);
+        let objects = if skip_nodes {
+            ParallelObjects::without_nodes(&mut reader, &pool)
+        } else {
+            ParallelObjects::new(&mut reader, &pool).with_metadata(self.reads_metadata())
+        }
+        .cancel_token(&self.cancel);
+        for obj in objects {
+            let obj = obj?;
+            let matched = match &obj {
+                OsmObj::Node(node) => {
+                    store.nodes.insert(node)?;
+                    let matched = self.is_match(&obj) && in_prefilter(&obj, prefilter_rect.as_ref());
+                    if matched {
+                        self.emit_built(&obj, &store, options, &mut emit, skips)?;
+                    }
+                    matched
+                }
+                OsmObj::Way(_) => {
+                    let matched = self.is_match(&obj);
+                    if matched {
+                        store.nodes.prepare();
+                        self.emit_built(&obj, &store, options, &mut emit, skips)?;
+                    }
+                    matched
+                }
+                OsmObj::Relation(relation) => {
+                    let matched = self.is_match(&obj);
+                    if matched {
+                        relations.push(relation.clone());
+                    }
+                    matched
+                }
+            };
+            counter.count(matched, &read_bar)?;
+        }
+        read_bar.finish_and_clear();
+        let index_bytes = store.heap_size();
+        // A cancelled pass leaves the locations of the nodes it did not
+        // read out, so the file is not marked complete for later runs
+        if let (NodeIndex::Flat(flat), false) = (&mut store.nodes, self.cancel.is_cancelled()) {
+            flat.finish(source)?;
+        }
+        let extraction_duration = start_time.elapsed();
+        if self.verbose {
+            info!("   Scanned {} objects, {} matched the filter.", counter.seen, counter.matched);
+            info!(
+                "   Indexed {} node locations in {:.2?}.",
+                store.nodes.len(),
+                extraction_duration
+            );
+        }
+
+        let processing_start_time = Instant::now();
+        if !relations.is_empty() {
+            if self.verbose {
+                info!("-> Pass 2: Collecting member ways of {} matched relations...", relations.len());
+            }
+            let member_ways: HashSet<WayId> = relations
+                .iter()
+                .flat_map(|r| r.refs.iter().filter_map(|m| m.member.way()))
+                .collect();
+            reader.rewind()?;
+            read_bar.reset();
+            let mut counter = PassCounter::new(self.max_memory);
+            for obj in ParallelObjects::ways(&mut reader, &pool).cancel_token(&self.cancel) {
+                if let OsmObj::Way(way) = obj? {
+                    let matched = member_ways.contains(&way.id);
+                    counter.count(matched, &read_bar)?;
+                    if matched {
+                        store.ways.insert(way.id, way.nodes);
+                    }
+                }
+            }
+            read_bar.finish_and_clear();
+            if self.verbose {
+                info!("   Scanned {} ways, {} are relation members.", counter.seen, counter.matched);
+            }
+            store.nodes.prepare();
+            for relation in relations {
+                self.emit_built(&OsmObj::Relation(relation), &store, options, &mut emit, skips)?;
+            }
+        }
+
+        Ok(ExtractionStats {
+            objects_read: store.nodes.len() + store.ways.len(),
+            extraction_duration,
+            processing_duration: processing_start_time.elapsed(),
+            input_bytes: input.size().unwrap_or(0),
+            index_bytes: Some(index_bytes.max(store.heap_size())),
+            skipped: BTreeMap::new(),
+        })
+    }
+
+    fn emit_built<S: ObjectStore + ?Sized>(
+        &self,
+        obj: &OsmObj,
+        store: &S,
+        options: &BuildOptions,
+        emit: &mut impl FnMut(ExtractedFeature) -> Result<()>,
+        skips: &mut SkipLog,
+    ) -> Result<()> {
+        match ExtractedFeature::try_build_with(obj, store, options) {
+            Ok(feature) => {
+                if let Some(feature) = self.apply_spatial_filters(feature) {
+                    emit(feature)?;
+                }
+            }
+            Err(skipped) if self.strict => bail!("cannot assemble {}", skipped),
+            Err(skipped) => skips.record(skipped)?,
+        }
+        Ok(())
+    }
+
+    // Tags left out by `keep_tags` are only dropped while building if
+    // nothing looks at them after that
+    fn run_build_options(&self) -> BuildOptions {
+        let mut options = self.build_options.clone();
+        options.interner = Interner::default();
+        #[cfg(feature = "opening-hours")]
+        let open_at = self.open_at.is_some();
+        #[cfg(not(feature = "opening-hours"))]
+        let open_at = false;
+        let reads_tags = self.preset.is_some()
+            || self.construction.is_some()
+            || open_at
+            || self.normalize
+            || self.fix_geometry.is_some();
+        if !reads_tags {
+            options.keep_tags = self.keep_tags.clone();
+        }
+        options
+    }
+
+    // Whether objects are decoded with their metadata, which the time range
+    // is checked against
+    fn reads_metadata(&self) -> bool {
+        self.with_metadata || self.modified_since.is_some() || self.modified_before.is_some()
+    }
+
+    fn matches_nodes(&self) -> bool {
+        self.node_filter.is_some() || self.types.contains(&OsmType::Node)
+    }
+
+    // Cheap type checks first, before the tag filter is evaluated
+    fn is_match(&self, obj: &OsmObj) -> bool {
+        if let Some(ids) = &self.ids {
+            return ids.contains(&obj.id());
+        }
+        let selected = match (obj, &self.node_filter) {
+            (OsmObj::Node(_), Some(node_filter)) => node_filter.matches(obj.tags()),
+            _ if !self.types.contains(&OsmType::of(obj)) => false,
+            (OsmObj::Way(way), _) if way.nodes.len() < 2 => false,
+            // Metadata tags alone do not make an object tagged
+            _ if self.invert => {
+                obj.tags().keys().any(|key| !metadata::TAGS.contains(&key.as_str())) && !self.filter.matches(obj.tags())
+            }
+            _ => self.filter.matches(obj.tags()),
+        };
+        selected
+            && !self.exclude_filter.as_ref().is_some_and(|exclude| exclude.matches(obj.tags()))
+            && self.in_time_range(obj)
+    }
+
+    fn in_time_range(&self, obj: &OsmObj) -> bool {
+        if self.modified_since.is_none() && self.modified_before.is_none() {
+            return true;
+        }
+        let timestamp = obj.tags().get(metadata::TIMESTAMP_TAG).and_then(|t| metadata::parse_timestamp(t).ok());
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+        self.modified_since.is_none_or(|since| timestamp >= since)
+            && self.modified_before.is_none_or(|before| timestamp < before)
+    }
+
+    // The box used for the node pre-scan: the bbox, the boundary's extent,
+    // or the overlap of both. Disjoint boxes can keep either one, as the
+    // exact tests will then drop every feature anyway.
+    fn prefilter_rect(&self) -> Option<Rect> {
+        let boundary_rect = self.boundary.as_ref().and_then(Boundary::bounding_rect);
+        match (self.bbox, boundary_rect) {
+            (Some(a), Some(b)) => Some(spatial::rect_overlap(&a, &b).unwrap_or(a)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // Exact tests against the assembled geometry
+    fn apply_spatial_filters(&self, mut feature: ExtractedFeature) -> Option<ExtractedFeature> {
+        if self.bbox.is_some_and(|bbox| !feature.geometry().intersects(&bbox)) {
+            return None;
+        }
+        if let Some(boundary) = &self.boundary {
+            let geometry = boundary.apply(feature.geometry())?;
+            feature.set_geometry(geometry);
+        }
+        Some(feature)
+    }
+}
+
+// The receiving end of `Extractor::features`
+struct Features {
+    receiver: Receiver<Result<ExtractedFeature>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Iterator for Features {
+    type Item = Result<ExtractedFeature>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.recv() {
+            Ok(item) => Some(item),
+            Err(_) => {
+                // The extraction is done; pass on a panic instead of ending
+                // as if it had succeeded
+                if let Some(Err(panic)) = self.handle.take().map(JoinHandle::join) {
+                    std::panic::resume_unwind(panic);
+                }
+                None
+            }
+        }
+    }
+}
+
+// Cheap spatial pre-selection of nodes; ways and relations are only
+// checked once their geometry has been assembled
+fn in_prefilter(obj: &OsmObj, rect: Option<&Rect>) -> bool {
+    match (obj, rect) {
+        (OsmObj::Node(node), Some(rect)) => spatial::bbox_contains(rect, node.lon(), node.lat()),
+        _ => true,
+    }
+}
+
+// Whether the line of a way, from the node locations in `store`, crosses
+// `rect`
+fn way_crosses(way: &Way, store: &BTreeMap<OsmId, OsmObj>, rect: &Rect) -> bool {
+    let line: LineString = way.nodes.iter().filter_map(|&id| store.node_location(id)).collect();
+    line.intersects(rect)
+}
+
+// The objects that could not be assembled, counted per cause and written
+// one JSON object per line to the warnings file if there is one
+struct SkipLog {
+    counts: BTreeMap<SkipCause, usize>,
+    file: Option<BufWriter<File>>,
+}
+
+impl SkipLog {
+    fn create(path: Option<&Path>) -> Result<SkipLog> {
+        let file = match path {
+            Some(path) => Some(BufWriter::new(
+                File::create(path).with_context(|| format!("cannot create the warnings file {:?}", path))?,
+            )),
+            None => None,
+        };
+        Ok(SkipLog { counts: BTreeMap::new(), file })
+    }
+
+    fn record(&mut self, skipped: Skipped) -> Result<()> {
+        debug!("{}. Skipping.", skipped);
+        *self.counts.entry(skipped.cause).or_default() += 1;
+        if let Some(file) = &mut self.file {
+            serde_json::to_writer(&mut *file, &skipped)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    // Logs a single warning summing up the skipped objects
+    fn finish(mut self, path: Option<&Path>) -> Result<BTreeMap<SkipCause, usize>> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        let total: usize = self.counts.values().sum();
+        if total > 0 {
+            let causes: Vec<String> =
+                self.counts.iter().map(|(cause, count)| format!("{}: {}", cause.description(), count)).collect();
+            match path {
+                Some(path) => warn!("Skipped {} incomplete objects ({}), details in {:?}", total, causes.join(", "), path),
+                None => warn!("Skipped {} incomplete objects ({})", total, causes.join(", ")),
+            }
+        }
+        Ok(self.counts)
+    }
+}
+
+// Objects seen and matched during a pass, shown next to its progress bar,
+// checking the memory limit along the way
+struct PassCounter {
+    seen: u64,
+    matched: u64,
+    max_memory: Option<u64>,
+}
+
+impl PassCounter {
+    fn new(max_memory: Option<u64>) -> PassCounter {
+        PassCounter { seen: 0, matched: 0, max_memory }
+    }
+
+    fn count(&mut self, matched: bool, bar: &ProgressBar) -> Result<()> {
+        self.seen += 1;
+        if matched {
+            self.matched += 1;
+        }
+        if self.seen.is_multiple_of(COUNTER_INTERVAL) {
+            bar.set_message(format!("{} objects seen, {} matched", self.seen, self.matched));
+            if let Some(limit) = self.max_memory {
+                memory::check(limit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pbf::writer::Writer;
+    use geo::Geometry;
+    use osmpbfreader::{Node, NodeId, Ref, Relation, RelationId, Tags};
+
+    // Nodes in a row of the grid of the fixture, 0.0001° apart
+    const SIDE: i64 = 30;
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in pairs {
+            tags.insert((*key).into(), (*value).into());
+        }
+        tags
+    }
+
+    // A grid of nodes with a road between every two neighbours in a row,
+    // many more than go into one batch of the queue, a road whose nodes are
+    // missing and a multipolygon of untagged ways, which only the
+    // dependency passes find
+    fn fixture() -> Input {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for i in 0..SIDE * SIDE {
+            let (lat, lon) = ((i / SIDE) as i32 * 1000, (i % SIDE) as i32 * 1000);
+            let node = Node { id: NodeId(i + 1), tags: Tags::new(), decimicro_lat: lat, decimicro_lon: lon };
+            writer.write(OsmObj::Node(node)).unwrap();
+        }
+        let mut ways = Vec::new();
+        for row in 0..SIDE {
+            for col in 1..SIDE {
+                let nodes = vec![NodeId(row * SIDE + col), NodeId(row * SIDE + col + 1)];
+                ways.push((tags(&[("highway", "residential")]), nodes));
+            }
+        }
+        ways.push((tags(&[("highway", "service")]), vec![NodeId(SIDE * SIDE + 1), NodeId(1)]));
+        ways.push((Tags::new(), vec![NodeId(1), NodeId(2), NodeId(SIDE + 2)]));
+        ways.push((Tags::new(), vec![NodeId(SIDE + 2), NodeId(SIDE + 1), NodeId(1)]));
+        let members = [ways.len() as i64 - 1, ways.len() as i64];
+        for (i, (tags, nodes)) in ways.into_iter().enumerate() {
+            writer.write(OsmObj::Way(Way { id: WayId(i as i64 + 1), tags, nodes })).unwrap();
+        }
+        let refs = members.iter().map(|&id| Ref { member: WayId(id).into(), role: "outer".into() }).collect();
+        let tags = tags(&[("type", "multipolygon"), ("landuse", "grass")]);
+        writer.write(OsmObj::Relation(Relation { id: RelationId(1), tags, refs })).unwrap();
+        Input::from(writer.finish().unwrap())
+    }
+
+    fn extractor(inputs: &[Input]) -> Extractor {
+        Extractor::new(inputs[0].clone())
+            .inputs(inputs.to_vec())
+            .filter("highway=* or landuse=*".parse().unwrap())
+            .types(&[OsmType::Way, OsmType::Relation])
+    }
+
+    // The features as emitted without the queue between reading and
+    // writing, as on wasm
+    fn sequential(extractor: &Extractor) -> Vec<ExtractedFeature> {
+        let mut features = Vec::new();
+        let mut skips = SkipLog::create(None).unwrap();
+        let options = extractor.run_build_options();
+        let emit = |feature| {
+            features.push(feature);
+            Ok(())
+        };
+        extractor.extract_in(&extractor.inputs[0], None, &options, emit, &mut skips).unwrap();
+        features
+    }
+
+    fn queued(extractor: &Extractor) -> Vec<ExtractedFeature> {
+        let mut features = Vec::new();
+        extractor
+            .for_each(|feature| {
+                features.push(feature);
+                Ok(())
+            })
+            .unwrap();
+        features
+    }
+
+    // What tells a feature apart: its id, geometry and sorted tags
+    type Key = (String, Geometry, Vec<(String, String)>);
+
+    // The keys of the features, in the order they were emitted
+    fn keys(features: &[ExtractedFeature]) -> Vec<Key> {
+        let key = |feature: &ExtractedFeature| {
+            let mut tags: Vec<_> = feature.tags().iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            tags.sort();
+            (feature.unique_id(), feature.geometry().clone(), tags)
+        };
+        features.iter().map(key).collect()
+    }
+
+    #[test]
+    fn queue_keeps_the_order_of_the_features() {
+        let extractor = extractor(&[fixture()]);
+        let expected = sequential(&extractor);
+        // Every road but the one missing its nodes, and the multipolygon
+        assert_eq!(expected.len(), (SIDE * (SIDE - 1)) as usize + 1);
+        assert!(expected.iter().any(|feature| feature.unique_id() == "r1"));
+        assert_eq!(keys(&queued(&extractor)), keys(&expected));
+
+        let extractor = extractor.bbox(Rect::new((0.0, 0.0), (0.00105, 0.00105)));
+        let expected = sequential(&extractor);
+        // The 11 rows inside, each with 10 roads inside and 1 leaving the box
+        assert_eq!(expected.len(), 11 * 11 + 1);
+        assert_eq!(keys(&queued(&extractor)), keys(&expected));
+    }
+
+    #[test]
+    fn ways_crossing_the_bbox_without_a_node_inside_are_kept() {
+        // Between two nodes of the sixth row, around the road joining them
+        let bbox = Rect::new((0.00052, 0.00048), (0.00058, 0.00052));
+        for streaming in [false, true] {
+            let extractor = extractor(&[fixture()]).bbox(bbox).streaming(streaming);
+            let ids: Vec<String> = sequential(&extractor).iter().map(ExtractedFeature::unique_id).collect();
+            assert_eq!(ids, ["w151"]);
+        }
+        let objects = extractor(&[fixture()]).bbox(bbox).objects().unwrap();
+        assert!(objects.contains_key(&WayId(151).into()));
+        assert!(!objects.contains_key(&WayId(150).into()) && !objects.contains_key(&WayId(152).into()));
+    }
+
+    #[test]
+    fn member_relations_are_only_collected_down_to_the_relation_depth() {
+        // A route master of a route, which has another route as a member
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for id in 1..=3 {
+            let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: 0, decimicro_lon: id as i32 * 1000 };
+            writer.write(OsmObj::Node(node)).unwrap();
+        }
+        for (id, nodes) in [(1, [1, 2]), (2, [2, 3])] {
+            let way = Way { id: WayId(id), tags: Tags::new(), nodes: nodes.into_iter().map(NodeId).collect() };
+            writer.write(OsmObj::Way(way)).unwrap();
+        }
+        let relations = [
+            (2, "route_master", vec![OsmId::Relation(RelationId(3))]),
+            (3, "route", vec![WayId(1).into(), RelationId(4).into()]),
+            (4, "route", vec![WayId(2).into()]),
+        ];
+        for (id, kind, members) in relations {
+            let refs = members.into_iter().map(|member| Ref { member, role: "".into() }).collect();
+            let relation = Relation { id: RelationId(id), tags: tags(&[("type", kind)]), refs };
+            writer.write(OsmObj::Relation(relation)).unwrap();
+        }
+        let input = Input::from(writer.finish().unwrap());
+
+        let objects = |depth| {
+            let extractor = Extractor::new(input.clone())
+                .filter("type=route_master".parse().unwrap())
+                .types(&[OsmType::Relation])
+                .relation_depth(depth);
+            extractor.objects().unwrap().into_keys().collect::<Vec<OsmId>>()
+        };
+        let (route_master, route, nested) = (RelationId(2).into(), RelationId(3).into(), RelationId(4).into());
+        assert_eq!(objects(0), [route_master]);
+        let nodes: [OsmId; 3] = [NodeId(1).into(), NodeId(2).into(), NodeId(3).into()];
+        assert_eq!(objects(1), [nodes[0], nodes[1], WayId(1).into(), route_master, route]);
+        let all = [nodes[0], nodes[1], nodes[2], WayId(1).into(), WayId(2).into(), route_master, route, nested];
+        assert_eq!(objects(2), all);
+    }
+
+    #[test]
+    fn polygons_are_checked_again_after_rounding() {
+        // A building 0.0002° wide, which rounding to 3 decimals collapses
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for (id, (lon, lat)) in [(1, (0, 0)), (2, (2000, 0)), (3, (2000, 2000)), (4, (0, 2000))] {
+            let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: lat, decimicro_lon: lon };
+            writer.write(OsmObj::Node(node)).unwrap();
+        }
+        let nodes = [1, 2, 3, 4, 1].into_iter().map(NodeId).collect();
+        writer.write(OsmObj::Way(Way { id: WayId(1), tags: tags(&[("building", "yes")]), nodes })).unwrap();
+        let input = Input::from(writer.finish().unwrap());
+
+        let extractor = Extractor::new(input).filter("building=*".parse().unwrap()).types(&[OsmType::Way]);
+        let extractor = extractor.invalid(InvalidMode::Drop);
+        assert_eq!(queued(&extractor).len(), 1);
+        assert!(queued(&extractor.precision(3)).is_empty());
+    }
+
+    #[test]
+    fn features_in_several_inputs_are_emitted_once() {
+        let expected = sequential(&extractor(&[fixture()]));
+        assert_eq!(keys(&queued(&extractor(&[fixture(), fixture()]))), keys(&expected));
+    }
+
+    #[test]
+    fn complete_features_win_over_incomplete_ones_of_other_inputs() {
+        // One road, whose last node only the second input has
+        let input = |nodes: i64| {
+            let mut writer = Writer::new(Vec::new()).unwrap();
+            for id in 1..=nodes {
+                let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: 0, decimicro_lon: id as i32 * 1000 };
+                writer.write(OsmObj::Node(node)).unwrap();
+            }
+            let nodes = vec![NodeId(1), NodeId(2), NodeId(3)];
+            writer.write(OsmObj::Way(Way { id: WayId(1), tags: tags(&[("highway", "residential")]), nodes })).unwrap();
+            Input::from(writer.finish().unwrap())
+        };
+        for inputs in [[input(2), input(3)], [input(3), input(2)]] {
+            let features = queued(&extractor(&inputs).allow_incomplete(true));
+            assert_eq!(features.len(), 1);
+            assert!(!features[0].tags().contains_key(INCOMPLETE_TAG));
+        }
+        let features = queued(&extractor(&[input(2), input(2)]).allow_incomplete(true));
+        assert_eq!(features.len(), 1);
+        assert!(features[0].tags().contains_key(INCOMPLETE_TAG));
+    }
+
+    #[test]
+    fn writing_errors_stop_the_reading() {
+        let mut written = 0;
+        let result = extractor(&[fixture()]).for_each(|_| {
+            if written == PIPELINE_BATCH + 1 {
+                bail!("disk full");
+            }
+            written += 1;
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "disk full");
+        assert_eq!(written, PIPELINE_BATCH + 1);
+    }
+
+    #[test]
+    fn cancelled_streaming_runs_leave_the_node_cache_incomplete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.osm.pbf");
+        let mut writer = Writer::new(std::io::BufWriter::new(File::create(&path).unwrap())).unwrap();
+        // Five blocks of nodes, more than one thread reads at once
+        for id in 1..=40000 {
+            let tags = if id == 1 { tags(&[("highway", "crossing")]) } else { Tags::new() };
+            writer.write(OsmObj::Node(Node { id: NodeId(id), tags, decimicro_lat: 0, decimicro_lon: 0 })).unwrap();
+        }
+        writer.finish().unwrap();
+        let input = Input::Path(path);
+        let cache = dir.path().join("nodes.cache");
+
+        let cancel = CancelToken::new();
+        let extractor = Extractor::new(input.clone())
+            .filter("highway=*".parse().unwrap())
+            .types(&[OsmType::Node])
+            .streaming(true)
+            .node_cache(&cache)
+            .threads(1)
+            .cancel_token(cancel.clone());
+        let mut skips = SkipLog::create(None).unwrap();
+        let options = extractor.run_build_options();
+        let emit = |_| {
+            cancel.cancel();
+            Ok(())
+        };
+        extractor.extract_in(&input, None, &options, emit, &mut skips).unwrap();
+        assert!(!FlatNodes::open(&cache, input.fingerprint()).unwrap().is_complete());
+
+        cancel.reset();
+        assert_eq!(sequential(&extractor).len(), 1);
+        assert!(FlatNodes::open(&cache, input.fingerprint()).unwrap().is_complete());
+    }
+}
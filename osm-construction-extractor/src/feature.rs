@@ -0,0 +1,501 @@
+use crate::area;
+use crate::intern::{Interner, Tag, TagMap};
+use crate::multipolygon;
+use crate::route;
+use crate::store::ObjectStore;
+use clap::ValueEnum;
+use geo::orient::Direction;
+use geo::{Coord, Geometry, GeometryCollection, LineString, Orient, Point, Polygon};
+use glob::Pattern;
+use osmpbfreader::{NodeId, OsmId, OsmObj, Relation, RelationId, Tags, Way, WayId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// The OSM object types that can be selected for extraction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsmType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl OsmType {
+    pub fn of(obj: &OsmObj) -> OsmType {
+        match obj {
+            OsmObj::Node(_) => OsmType::Node,
+            OsmObj::Way(_) => OsmType::Way,
+            OsmObj::Relation(_) => OsmType::Relation,
+        }
+    }
+
+    pub fn of_id(id: OsmId) -> OsmType {
+        match id {
+            OsmId::Node(_) => OsmType::Node,
+            OsmId::Way(_) => OsmType::Way,
+            OsmId::Relation(_) => OsmType::Relation,
+        }
+    }
+}
+
+impl fmt::Display for OsmType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OsmType::Node => "node",
+            OsmType::Way => "way",
+            OsmType::Relation => "relation",
+        })
+    }
+}
+
+impl FromStr for OsmType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match <OsmType as ValueEnum>::from_str(s, true) {
+            Ok(osm_type) => Ok(osm_type),
+            Err(_) => anyhow::bail!("unknown OSM type {:?}, expected node, way or relation", s),
+        }
+    }
+}
+
+/// Why an object could not be assembled into a feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipCause {
+    /// A way references a node the extract does not contain
+    MissingNode,
+    /// The rings of a multipolygon or boundary could not be built, e.g.
+    /// because member ways are missing or do not close
+    InvalidMultipolygon,
+    /// The member ways of a route could not be resolved
+    InvalidRoute,
+    /// None of the members of another relation could be resolved
+    NoMembers,
+}
+
+impl SkipCause {
+    /// What the skipped objects are, for summaries such as "ways with
+    /// missing nodes: 3"
+    pub fn description(&self) -> &'static str {
+        match self {
+            SkipCause::MissingNode => "ways with missing nodes",
+            SkipCause::InvalidMultipolygon => "multipolygons that could not be assembled",
+            SkipCause::InvalidRoute => "routes that could not be assembled",
+            SkipCause::NoMembers => "relations without resolvable members",
+        }
+    }
+}
+
+/// An object left out because its geometry could not be built, see
+/// [`ExtractedFeature::try_build_with`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub osm_type: OsmType,
+    pub osm_id: i64,
+    pub cause: SkipCause,
+    /// What exactly went wrong, e.g. `node 123 not found`
+    pub detail: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.osm_type, self.osm_id, self.detail)
+    }
+}
+
+/// Property of ways built from only some of their nodes, see
+/// [`BuildOptions::allow_incomplete`]
+pub const INCOMPLETE_TAG: &str = "@incomplete";
+
+/// Property of ways left unresolved (see [`ExtractedFeature::unresolved`]):
+/// the ids of their nodes as a JSON array, e.g. `[101,102,103]`
+pub const NODES_TAG: &str = "@nodes";
+
+/// Property of relations left unresolved or built with
+/// [`BuildOptions::with_members`]: their members in order as a JSON array
+/// of objects with `type`, `ref` and `role`, as in the OSM JSON format
+pub const MEMBERS_TAG: &str = "@members";
+
+/// Settings controlling how geometries are assembled
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Keep closed area ways as linestrings instead of polygons
+    pub force_linestring: bool,
+    /// Build ways with nodes missing from the extract, as clipped regional
+    /// extracts have at their border, from the nodes that are there, as
+    /// long as there are at least two. They are always lines, as an area
+    /// cannot be told to be closed, and get [`INCOMPLETE_TAG`] set to
+    /// `true`.
+    pub allow_incomplete: bool,
+    /// List the members of relations with their roles in [`MEMBERS_TAG`],
+    /// so that stops and platforms of routes or the inner and outer rings
+    /// of multipolygons can still be told apart
+    pub with_members: bool,
+    /// How many levels of relations that are members of other relations,
+    /// as the routes of a route master, are resolved into the geometry
+    /// collection of the relation (see [`ExtractedFeature`]); 0 leaves
+    /// them out. Each relation is resolved once, so cycles of relations
+    /// end, and one reached twice is left out the second time.
+    pub relation_depth: usize,
+    /// Copy only the tags whose keys match one of these glob patterns into
+    /// the features, and the `@` properties, leaving the others out before
+    /// they are allocated; all of them if empty
+    pub keep_tags: Vec<Pattern>,
+    /// The pool the tag strings of the features are interned in; runs of
+    /// an [`Extractor`](crate::Extractor) use a fresh one each
+    pub interner: Interner,
+}
+
+/// A matched OSM object together with its assembled geometry.
+///
+/// Nodes always carry a `Geometry::Point`, ways a `Geometry::LineString`,
+/// or a `Geometry::Polygon` if they are closed and tagged as an area (see
+/// [`area::is_area`]). Multipolygon and boundary relations are assembled into a
+/// `Geometry::MultiPolygon`, route relations into a `Geometry::MultiLineString`
+/// (with the member roles of its lines in the [`route::ROLES_TAG`] tag); all
+/// other relations become a `Geometry::GeometryCollection` of their resolved
+/// members, including those of member relations up to
+/// [`BuildOptions::relation_depth`].
+#[derive(Debug, Clone)]
+pub enum ExtractedFeature {
+    Node {
+        id: NodeId,
+        tags: TagMap,
+        geometry: Geometry,
+    },
+    Way {
+        id: WayId,
+        tags: TagMap,
+        geometry: Geometry,
+    },
+    Relation {
+        id: RelationId,
+        tags: TagMap,
+        geometry: Geometry,
+    },
+}
+
+impl ExtractedFeature {
+    /// A feature of the given type and id, e.g. read back from a file
+    pub fn new(osm_type: OsmType, id: i64, tags: TagMap, geometry: Geometry) -> ExtractedFeature {
+        match osm_type {
+            OsmType::Node => ExtractedFeature::Node { id: NodeId(id), tags, geometry },
+            OsmType::Way => ExtractedFeature::Way { id: WayId(id), tags, geometry },
+            OsmType::Relation => ExtractedFeature::Relation { id: RelationId(id), tags, geometry },
+        }
+    }
+
+    pub fn osm_type(&self) -> OsmType {
+        match self {
+            ExtractedFeature::Node { .. } => OsmType::Node,
+            ExtractedFeature::Way { .. } => OsmType::Way,
+            ExtractedFeature::Relation { .. } => OsmType::Relation,
+        }
+    }
+
+    /// The bare numeric OSM id; only unique together with `osm_type()`
+    pub fn id(&self) -> i64 {
+        match self {
+            ExtractedFeature::Node { id, .. } => id.0,
+            ExtractedFeature::Way { id, .. } => id.0,
+            ExtractedFeature::Relation { id, .. } => id.0,
+        }
+    }
+
+    /// Type-prefixed id in the usual osmium style, e.g. `w3358460`
+    pub fn unique_id(&self) -> String {
+        let prefix = match self.osm_type() {
+            OsmType::Node => 'n',
+            OsmType::Way => 'w',
+            OsmType::Relation => 'r',
+        };
+        format!("{}{}", prefix, self.id())
+    }
+
+    pub fn tags(&self) -> &TagMap {
+        match self {
+            ExtractedFeature::Node { tags, .. }
+            | ExtractedFeature::Way { tags, .. }
+            | ExtractedFeature::Relation { tags, .. } => tags,
+        }
+    }
+
+    pub fn tags_mut(&mut self) -> &mut TagMap {
+        match self {
+            ExtractedFeature::Node { tags, .. }
+            | ExtractedFeature::Way { tags, .. }
+            | ExtractedFeature::Relation { tags, .. } => tags,
+        }
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+        match self {
+            ExtractedFeature::Node { geometry, .. }
+            | ExtractedFeature::Way { geometry, .. }
+            | ExtractedFeature::Relation { geometry, .. } => geometry,
+        }
+    }
+
+    pub fn geometry_mut(&mut self) -> &mut Geometry {
+        match self {
+            ExtractedFeature::Node { geometry, .. }
+            | ExtractedFeature::Way { geometry, .. }
+            | ExtractedFeature::Relation { geometry, .. } => geometry,
+        }
+    }
+
+    /// Replaces the geometry, e.g. after clipping
+    pub fn set_geometry(&mut self, new_geometry: Geometry) {
+        match self {
+            ExtractedFeature::Node { geometry, .. }
+            | ExtractedFeature::Way { geometry, .. }
+            | ExtractedFeature::Relation { geometry, .. } => *geometry = new_geometry,
+        }
+    }
+
+    /// Assembles the feature for a matched object, resolving node locations
+    /// and relation members from `store`.
+    ///
+    /// Returns `None` (after logging a warning) if the geometry cannot be
+    /// built because required objects are missing from the extract.
+    pub fn build<S: ObjectStore + ?Sized>(obj: &OsmObj, store: &S) -> Option<ExtractedFeature> {
+        ExtractedFeature::build_with(obj, store, &BuildOptions::default())
+    }
+
+    /// Like [`ExtractedFeature::build`], with non-default [`BuildOptions`]
+    pub fn build_with<S: ObjectStore + ?Sized>(
+        obj: &OsmObj,
+        store: &S,
+        options: &BuildOptions,
+    ) -> Option<ExtractedFeature> {
+        match ExtractedFeature::try_build_with(obj, store, options) {
+            Ok(feature) => Some(feature),
+            Err(skipped) => {
+                warn!("{}. Skipping.", skipped);
+                None
+            }
+        }
+    }
+
+    /// The feature of an object without looking up what it references:
+    /// nodes keep their point, while ways and relations get an empty
+    /// geometry and list the ids of their nodes or members in [`NODES_TAG`]
+    /// or [`MEMBERS_TAG`], to be resolved later if at all
+    pub fn unresolved(obj: &OsmObj) -> ExtractedFeature {
+        let empty = Geometry::GeometryCollection(GeometryCollection::default());
+        match obj {
+            OsmObj::Node(node) => ExtractedFeature::Node {
+                id: node.id,
+                tags: copy_tags(&node.tags),
+                geometry: Geometry::Point(Point::new(node.lon(), node.lat())),
+            },
+            OsmObj::Way(way) => {
+                let mut tags = copy_tags(&way.tags);
+                let nodes: Vec<i64> = way.nodes.iter().map(|n| n.0).collect();
+                tags.insert(NODES_TAG.into(), serde_json::Value::from(nodes).to_string().into());
+                ExtractedFeature::Way { id: way.id, tags, geometry: empty }
+            }
+            OsmObj::Relation(relation) => {
+                let mut tags = copy_tags(&relation.tags);
+                tags.insert(MEMBERS_TAG.into(), members(relation).into());
+                ExtractedFeature::Relation { id: relation.id, tags, geometry: empty }
+            }
+        }
+    }
+
+    /// Like [`ExtractedFeature::build_with`], returning why the object was
+    /// skipped instead of logging it, for callers that summarize these
+    pub fn try_build_with<S: ObjectStore + ?Sized>(
+        obj: &OsmObj,
+        store: &S,
+        options: &BuildOptions,
+    ) -> Result<ExtractedFeature, Skipped> {
+        let skipped = |cause, detail: String| Skipped {
+            osm_type: OsmType::of(obj),
+            osm_id: obj.id().inner_id(),
+            cause,
+            detail,
+        };
+        let mut feature = match obj {
+            OsmObj::Node(node) => ExtractedFeature::Node {
+                id: node.id,
+                tags: convert_tags(&node.tags, options),
+                geometry: Geometry::Point(Point::new(node.lon(), node.lat())),
+            },
+            OsmObj::Way(way) => match way_or_area_geometry(way, store, options) {
+                Ok(geometry) => ExtractedFeature::Way {
+                    id: way.id,
+                    tags: convert_tags(&way.tags, options),
+                    geometry,
+                },
+                Err(node_id) => match options.allow_incomplete.then(|| available_line(&way.nodes, store)).flatten() {
+                    Some(line) => {
+                        let mut tags = convert_tags(&way.tags, options);
+                        tags.insert(INCOMPLETE_TAG.into(), "true".into());
+                        ExtractedFeature::Way {
+                            id: way.id,
+                            tags,
+                            geometry: Geometry::LineString(line),
+                        }
+                    }
+                    None => return Err(skipped(SkipCause::MissingNode, format!("node {} not found", node_id.0))),
+                },
+            },
+            OsmObj::Relation(relation) if route::is_route_relation(relation) => {
+                let route = route::assemble(relation, store).map_err(|e| skipped(SkipCause::InvalidRoute, e.to_string()))?;
+                let mut tags = convert_tags(&relation.tags, options);
+                tags.insert(route::ROLES_TAG.into(), serde_json::Value::from(route.roles).to_string().into());
+                ExtractedFeature::Relation {
+                    id: relation.id,
+                    tags,
+                    geometry: Geometry::MultiLineString(route.lines),
+                }
+            }
+            OsmObj::Relation(relation) => ExtractedFeature::Relation {
+                id: relation.id,
+                tags: convert_tags(&relation.tags, options),
+                geometry: relation_geometry(relation, store, options.relation_depth)
+                    .map_err(|(cause, detail)| skipped(cause, detail))?,
+            },
+        };
+        if let (OsmObj::Relation(relation), true) = (obj, options.with_members) {
+            feature.tags_mut().insert(MEMBERS_TAG.into(), members(relation).into());
+        }
+        Ok(feature)
+    }
+}
+
+// The value of MEMBERS_TAG
+fn members(relation: &Relation) -> String {
+    let members: Vec<serde_json::Value> = relation
+        .refs
+        .iter()
+        .map(|r| {
+            let osm_type = OsmType::of_id(r.member);
+            serde_json::json!({ "type": osm_type, "ref": r.member.inner_id(), "role": r.role.as_str() })
+        })
+        .collect();
+    serde_json::Value::from(members).to_string()
+}
+
+// Tags of objects left unresolved, which are not kept in large numbers
+fn copy_tags(tags: &Tags) -> TagMap {
+    tags.iter().map(|(k, v)| (Tag::from(k.as_str()), Tag::from(v.as_str()))).collect()
+}
+
+fn convert_tags(tags: &Tags, options: &BuildOptions) -> TagMap {
+    let keep = &options.keep_tags;
+    let intern = |s: &str| options.interner.intern(s);
+    if !keep.is_empty() {
+        return tags
+            .iter()
+            .filter(|(k, _)| k.starts_with('@') || keep.iter().any(|p| p.matches(k)))
+            .map(|(k, v)| (intern(k), intern(v)))
+            .collect();
+    }
+    // Pre-allocated conversion from the SmartString-based reader tags
+    let mut tags_map = TagMap::with_capacity(tags.len());
+    tags.iter().for_each(|(k, v)| {
+        tags_map.insert(intern(k), intern(v));
+    });
+    tags_map
+}
+
+// The line of a way, or the first of its nodes that is missing
+fn way_geometry<S: ObjectStore + ?Sized>(nodes: &[NodeId], store: &S) -> Result<LineString, NodeId> {
+    // Pre-allocate coordinate vector with exact capacity
+    let mut coords: Vec<Coord> = Vec::with_capacity(nodes.len());
+
+    for &node_id in nodes {
+        coords.push(store.node_location(node_id).ok_or(node_id)?);
+    }
+
+    Ok(LineString(coords))
+}
+
+// The line through the nodes of a way that are there, if at least two are
+fn available_line<S: ObjectStore + ?Sized>(nodes: &[NodeId], store: &S) -> Option<LineString> {
+    let coords: Vec<Coord> = nodes.iter().filter_map(|&node_id| store.node_location(node_id)).collect();
+    (coords.len() >= 2).then_some(LineString(coords))
+}
+
+// Closed ways with area tags become polygons, oriented like the assembled
+// multipolygons
+fn way_or_area_geometry<S: ObjectStore + ?Sized>(way: &Way, store: &S, options: &BuildOptions) -> Result<Geometry, NodeId> {
+    let line = way_geometry(&way.nodes, store)?;
+    if !options.force_linestring && is_closed(&way.nodes) && area::is_area(&way.tags) {
+        Ok(Geometry::Polygon(Polygon::new(line, vec![]).orient(Direction::Default)))
+    } else {
+        Ok(Geometry::LineString(line))
+    }
+}
+
+// A ring needs at least three distinct nodes
+fn is_closed(nodes: &[NodeId]) -> bool {
+    nodes.len() >= 4 && nodes.first() == nodes.last()
+}
+
+fn relation_geometry<S: ObjectStore + ?Sized>(
+    relation: &osmpbfreader::Relation,
+    store: &S,
+    depth: usize,
+) -> Result<Geometry, (SkipCause, String)> {
+    if multipolygon::is_area_relation(relation) {
+        return match multipolygon::assemble(relation, store) {
+            Ok(polygons) => Ok(Geometry::MultiPolygon(polygons)),
+            Err(e) => Err((SkipCause::InvalidMultipolygon, e.to_string())),
+        };
+    }
+
+    let mut members = Vec::new();
+    member_geometries(relation, store, depth, &mut HashSet::from([relation.id]), &mut members);
+    if members.is_empty() {
+        return Err((SkipCause::NoMembers, "no member could be resolved".to_string()));
+    }
+
+    Ok(Geometry::GeometryCollection(GeometryCollection(members)))
+}
+
+// Members missing from regional extracts are common, so they are skipped
+// as long as at least one member could be resolved. Member relations are
+// flattened into their own members while `depth` lasts, except those in
+// `seen`, the relations resolved before.
+fn member_geometries<S: ObjectStore + ?Sized>(
+    relation: &osmpbfreader::Relation,
+    store: &S,
+    depth: usize,
+    seen: &mut HashSet<RelationId>,
+    members: &mut Vec<Geometry>,
+) {
+    for r in &relation.refs {
+        match r.member {
+            OsmId::Node(node_id) => members.extend(store.node_location(node_id).map(|c| Geometry::Point(Point(c)))),
+            OsmId::Way(way_id) => {
+                if let Some(nodes) = store.way_nodes(way_id) {
+                    members.extend(way_geometry(nodes, store).ok().map(Geometry::LineString));
+                }
+            }
+            OsmId::Relation(relation_id) if depth > 0 => {
+                let Some(member) = store.relation(relation_id) else {
+                    continue;
+                };
+                if !seen.insert(relation_id) {
+                    debug!("Relation {} was resolved already, as in a cycle, leaving it out", relation_id.0);
+                    continue;
+                }
+                if multipolygon::is_area_relation(member) {
+                    if let Ok(polygons) = multipolygon::assemble(member, store) {
+                        members.push(Geometry::MultiPolygon(polygons));
+                    }
+                } else {
+                    member_geometries(member, store, depth - 1, seen, members);
+                }
+            }
+            OsmId::Relation(_) => {}
+        }
+    }
+}
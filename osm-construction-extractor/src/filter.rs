@@ -0,0 +1,730 @@
+use crate::intern::{Tag, TagMap};
+use anyhow::{bail, Result};
+use osmpbfreader::Tags;
+use regex::Regex;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+/// A parsed tag filter expression.
+///
+/// Grammar (keywords are case-insensitive):
+///
+/// ```text
+/// expr      := and ("or" and)*
+/// and       := unary ("and" unary)*
+/// unary     := "not" unary | "(" expr ")" | clause clause* | condition
+/// condition := key | key "=" values | key "!=" values
+///            | key "~" pattern | key "!~" pattern
+/// values    := "*" | value ("," value)*
+/// clause    := "[" key "]" | "[!" key "]" | "[" key ("=" | "!=") value "]"
+///            | "[" key ("~" | "!~") pattern ("," "i")? "]"
+/// ```
+///
+/// Keys and values may be double-quoted to include spaces or operator
+/// characters, e.g. `maxspeed="30 mph"`. A `pattern` is a regular expression
+/// that matches anywhere in the value unless anchored, e.g.
+/// `ref~"^A[0-9]+$"`; prefix it with `(?i)` to ignore case. Inside quotes,
+/// `\"` and `\\` are escapes and any other backslash is kept, so regex
+/// classes like `\d` can be written as is.
+///
+/// Clauses are the tag filters of Overpass QL, so that queries can be
+/// copied from there: consecutive ones must all hold, e.g.
+/// `[highway][!construction]`, and `,i` makes a pattern ignore case, e.g.
+/// `["name"~"park",i]`. Their keys and values may also be single-quoted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// `key=*` (or a bare `key`): the tag is present with any value
+    HasKey(String),
+    /// `key=a,b,c`: the tag is present with one of the listed values
+    OneOf(String, Vec<String>),
+    /// `key~pattern`: the tag is present with a value matching the regex
+    Regex(String, ValueRegex),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+/// A regular expression on tag values, compiled once when the filter is
+/// parsed; two are equal if their patterns are
+#[derive(Debug, Clone)]
+pub struct ValueRegex(Regex);
+
+impl ValueRegex {
+    pub fn new(pattern: &str) -> Result<ValueRegex> {
+        match Regex::new(pattern) {
+            Ok(regex) => Ok(ValueRegex(regex)),
+            Err(e) => bail!("invalid regular expression {:?}: {}", pattern, e),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl PartialEq for ValueRegex {
+    fn eq(&self, other: &ValueRegex) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+/// Tags a [`Filter`] can be evaluated against: those of a raw OSM object or
+/// of an [`ExtractedFeature`](crate::ExtractedFeature)
+pub trait TagLookup {
+    fn tag(&self, key: &str) -> Option<&str>;
+}
+
+impl TagLookup for Tags {
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.get(key).map(|v| v.as_str())
+    }
+}
+
+impl TagLookup for TagMap {
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.get(key).map(Tag::as_str)
+    }
+}
+
+impl Filter {
+    /// Evaluates the expression against an OSM object's tags.
+    pub fn matches<T: TagLookup + ?Sized>(&self, tags: &T) -> bool {
+        match self {
+            Filter::HasKey(key) => tags.tag(key).is_some(),
+            Filter::OneOf(key, values) => tags.tag(key).is_some_and(|v| values.iter().any(|value| value == v)),
+            Filter::Regex(key, regex) => tags.tag(key).is_some_and(|v| regex.is_match(v)),
+            Filter::Not(inner) => !inner.matches(tags),
+            Filter::And(parts) => parts.iter().all(|f| f.matches(tags)),
+            Filter::Or(parts) => parts.iter().any(|f| f.matches(tags)),
+        }
+    }
+
+    /// The tag keys the expression refers to, in order of first appearance
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        self.collect_keys(&mut keys);
+        keys
+    }
+
+    fn collect_keys<'a>(&'a self, keys: &mut Vec<&'a str>) {
+        match self {
+            Filter::HasKey(key) | Filter::OneOf(key, _) | Filter::Regex(key, _) => {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key);
+                }
+            }
+            Filter::Not(inner) => inner.collect_keys(keys),
+            Filter::And(parts) | Filter::Or(parts) => parts.iter().for_each(|f| f.collect_keys(keys)),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            bail!("unexpected {} in filter expression", token);
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::HasKey(key) => write!(f, "{}=*", quote(key)),
+            Filter::OneOf(key, values) => {
+                let values: Vec<String> = values.iter().map(|v| quote(v)).collect();
+                write!(f, "{}={}", quote(key), values.join(","))
+            }
+            Filter::Regex(key, regex) => write!(f, "{}~{}", quote(key), quote(regex.as_str())),
+            Filter::Not(inner) => write!(f, "not {}", inner),
+            Filter::And(parts) => write_joined(f, parts, " and "),
+            Filter::Or(parts) => write_joined(f, parts, " or "),
+        }
+    }
+}
+
+fn write_joined(f: &mut fmt::Formatter<'_>, parts: &[Filter], sep: &str) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}", sep)?;
+        }
+        write!(f, "{}", part)?;
+    }
+    write!(f, ")")
+}
+
+fn quote(s: &str) -> String {
+    // A leading `[` would start a clause
+    if s.is_empty() || s.starts_with('[') || s.chars().any(|c| c.is_whitespace() || is_special(c) || c == '\\') {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Eq,
+    NotEq,
+    Tilde,
+    NotTilde,
+    Comma,
+    LParen,
+    RParen,
+    /// The text between `[` and `]`
+    Clause(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "'{}'", w),
+            Token::Quoted(w) => write!(f, "{:?}", w),
+            Token::Eq => write!(f, "'='"),
+            Token::NotEq => write!(f, "'!='"),
+            Token::Tilde => write!(f, "'~'"),
+            Token::NotTilde => write!(f, "'!~'"),
+            Token::Comma => write!(f, "','"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::Clause(c) => write!(f, "'[{}]'", c),
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    matches!(c, '=' | '!' | '~' | ',' | '(' | ')' | '"')
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Eq);
+            }
+            '~' => {
+                chars.next();
+                tokens.push(Token::Tilde);
+            }
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some('=') => tokens.push(Token::NotEq),
+                    Some('~') => tokens.push(Token::NotTilde),
+                    _ => bail!("expected '=' or '~' after '!' in filter expression"),
+                }
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '[' => {
+                chars.next();
+                let mut clause = String::new();
+                let mut quote = None;
+                loop {
+                    match (chars.next(), quote) {
+                        (Some(']'), None) => break,
+                        (Some(c @ ('"' | '\'')), None) => {
+                            quote = Some(c);
+                            clause.push(c);
+                        }
+                        (Some(c), Some(q)) if c == q => {
+                            quote = None;
+                            clause.push(c);
+                        }
+                        (Some('\\'), Some(_)) => {
+                            clause.push('\\');
+                            clause.extend(chars.next());
+                        }
+                        (Some(c), _) => clause.push(c),
+                        (None, _) => bail!("missing ']' in filter expression"),
+                    }
+                }
+                tokens.push(Token::Clause(clause));
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped @ ('"' | '\\')) => word.push(escaped),
+                            // Kept for regex escapes such as `\d`
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("unterminated escape in filter expression"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quoted string in filter expression"),
+                    }
+                }
+                tokens.push(Token::Quoted(word));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !is_special(c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter> {
+        let mut parts = vec![self.parse_and()?];
+        while self.eat_keyword("or") {
+            parts.push(self.parse_and()?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Filter::Or(parts) })
+    }
+
+    fn parse_and(&mut self) -> Result<Filter> {
+        let mut parts = vec![self.parse_unary()?];
+        while self.eat_keyword("and") {
+            parts.push(self.parse_unary()?);
+        }
+        Ok(if parts.len() == 1 { parts.remove(0) } else { Filter::And(parts) })
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter> {
+        if self.eat_keyword("not") {
+            return Ok(Filter::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let inner = self.parse_or()?;
+            match self.next() {
+                Some(Token::RParen) => return Ok(inner),
+                Some(token) => bail!("expected ')' but found {} in filter expression", token),
+                None => bail!("missing ')' in filter expression"),
+            }
+        }
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<Filter> {
+        let key = match self.next() {
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) => w,
+            Some(Token::Clause(clause)) => {
+                let mut parts = vec![parse_clause(&clause)?];
+                while let Some(Token::Clause(clause)) = self.peek() {
+                    parts.push(parse_clause(clause)?);
+                    self.pos += 1;
+                }
+                return Ok(if parts.len() == 1 { parts.remove(0) } else { Filter::And(parts) });
+            }
+            Some(token) => bail!("expected a tag key but found {} in filter expression", token),
+            None => bail!("expected a tag key at end of filter expression"),
+        };
+
+        let (negate, regex) = match self.peek() {
+            Some(Token::Eq) => (false, false),
+            Some(Token::NotEq) => (true, false),
+            Some(Token::Tilde) => (false, true),
+            Some(Token::NotTilde) => (true, true),
+            // A bare key is shorthand for `key=*`
+            _ => return Ok(Filter::HasKey(key)),
+        };
+        self.pos += 1;
+
+        let condition = if regex { self.parse_pattern(key)? } else { self.parse_values(key)? };
+        Ok(if negate { Filter::Not(Box::new(condition)) } else { condition })
+    }
+
+    fn parse_pattern(&mut self, key: String) -> Result<Filter> {
+        match self.next() {
+            Some(Token::Word(w)) | Some(Token::Quoted(w)) => {
+                let regex = ValueRegex::new(&w)?;
+                Ok(Filter::Regex(key, regex))
+            }
+            Some(token) => bail!("expected a regular expression for '{}' but found {}", key, token),
+            None => bail!("expected a regular expression for '{}' at end of filter expression", key),
+        }
+    }
+
+    fn parse_values(&mut self, key: String) -> Result<Filter> {
+        let mut values = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Word(w)) if w == "*" && values.is_empty() => {
+                    return Ok(Filter::HasKey(key));
+                }
+                Some(Token::Word(w)) | Some(Token::Quoted(w)) => values.push(w),
+                Some(token) => bail!("expected a value for '{}' but found {}", key, token),
+                None => bail!("expected a value for '{}' at end of filter expression", key),
+            }
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(Filter::OneOf(key, values));
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+// One Overpass QL tag filter, the text between its brackets
+fn parse_clause(clause: &str) -> Result<Filter> {
+    let context = || format!("in [{}]", clause);
+    let mut chars = clause.trim().chars().peekable();
+    if chars.peek() == Some(&'~') {
+        bail!("regular expressions on keys are not supported, {}", context());
+    }
+    let lacks = chars.next_if_eq(&'!').is_some();
+    let key = clause_string(&mut chars, clause, |c| matches!(c, '=' | '!' | '~') || c.is_whitespace())?;
+    skip_whitespace(&mut chars);
+    if lacks || chars.peek().is_none() {
+        if chars.peek().is_some() {
+            bail!("expected ']' after the key {}", context());
+        }
+        let has = Filter::HasKey(key);
+        return Ok(if lacks { Filter::Not(Box::new(has)) } else { has });
+    }
+
+    let negate = chars.next_if_eq(&'!').is_some();
+    let regex = match chars.next() {
+        Some('=') => false,
+        Some('~') => true,
+        _ => bail!("expected '=', '!=', '~' or '!~' after the key {}", context()),
+    };
+    skip_whitespace(&mut chars);
+    let value = clause_string(&mut chars, clause, |c| regex && c == ',')?;
+    skip_whitespace(&mut chars);
+    let condition = if regex {
+        let ignore_case = chars.next_if_eq(&',').is_some();
+        if ignore_case {
+            skip_whitespace(&mut chars);
+            if !matches!(chars.next(), Some('i')) {
+                bail!("expected 'i' after ',' {}", context());
+            }
+            skip_whitespace(&mut chars);
+        }
+        let pattern = if ignore_case { format!("(?i){}", value) } else { value };
+        Filter::Regex(key, ValueRegex::new(&pattern)?)
+    } else {
+        Filter::OneOf(key, vec![value])
+    };
+    if chars.peek().is_some() {
+        bail!("expected ']' after the value {}", context());
+    }
+    Ok(if negate { Filter::Not(Box::new(condition)) } else { condition })
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+// A single- or double-quoted string, or the text up to a character ending
+// it, trimmed
+fn clause_string(chars: &mut Peekable<Chars>, clause: &str, ends: impl Fn(char) -> bool) -> Result<String> {
+    let mut s = String::new();
+    if let Some(quote) = chars.next_if(|&c| c == '"' || c == '\'') {
+        loop {
+            match chars.next() {
+                Some(c) if c == quote => return Ok(s),
+                Some('\\') => match chars.next() {
+                    Some(escaped @ ('"' | '\'' | '\\')) => s.push(escaped),
+                    Some(c) => {
+                        s.push('\\');
+                        s.push(c);
+                    }
+                    None => bail!("unterminated escape in [{}]", clause),
+                },
+                Some(c) => s.push(c),
+                None => bail!("unterminated quoted string in [{}]", clause),
+            }
+        }
+    }
+    while let Some(c) = chars.next_if(|&c| !ends(c)) {
+        s.push(c);
+    }
+    let s = s.trim_end().to_string();
+    if s.is_empty() {
+        bail!("expected a key or value in [{}]", clause);
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Filter {
+        s.parse().unwrap_or_else(|e| panic!("{:?} does not parse: {:#}", s, e))
+    }
+
+    fn error(s: &str) -> String {
+        match s.parse::<Filter>() {
+            Ok(filter) => panic!("{:?} parses as {}", s, filter),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in pairs {
+            tags.insert((*key).into(), (*value).into());
+        }
+        tags
+    }
+
+    fn has(key: &str) -> Filter {
+        Filter::HasKey(key.to_string())
+    }
+
+    fn one_of(key: &str, values: &[&str]) -> Filter {
+        Filter::OneOf(key.to_string(), values.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parse("a or b and c"), Filter::Or(vec![has("a"), Filter::And(vec![has("b"), has("c")])]));
+        assert_eq!(parse("a and b or c"), Filter::Or(vec![Filter::And(vec![has("a"), has("b")]), has("c")]));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert_eq!(parse("not a and b"), Filter::And(vec![Filter::Not(Box::new(has("a"))), has("b")]));
+        assert_eq!(parse("not not a"), Filter::Not(Box::new(Filter::Not(Box::new(has("a"))))));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(parse("(a or b) and c"), Filter::And(vec![Filter::Or(vec![has("a"), has("b")]), has("c")]));
+        assert_eq!(parse("not (a or b)"), Filter::Not(Box::new(Filter::Or(vec![has("a"), has("b")]))));
+    }
+
+    #[test]
+    fn keywords_ignore_case() {
+        assert_eq!(parse("a AND b Or NOT c"), parse("a and b or not c"));
+    }
+
+    #[test]
+    fn any_value() {
+        assert_eq!(parse("building=*"), has("building"));
+        assert_eq!(parse("building"), has("building"));
+        assert_eq!(parse("building!=*"), Filter::Not(Box::new(has("building"))));
+        assert!(parse("building=*").matches(&tags(&[("building", "yes")])));
+        assert!(!parse("building=*").matches(&tags(&[("highway", "path")])));
+    }
+
+    #[test]
+    fn value_lists() {
+        let filter = parse("amenity=cafe,bar");
+        assert_eq!(filter, one_of("amenity", &["cafe", "bar"]));
+        assert!(filter.matches(&tags(&[("amenity", "bar")])));
+        assert!(!filter.matches(&tags(&[("amenity", "pub")])));
+        assert_eq!(parse("amenity = cafe , bar"), filter);
+        // `*` only stands for any value on its own
+        assert_eq!(parse("a=b,*"), one_of("a", &["b", "*"]));
+    }
+
+    #[test]
+    fn negation() {
+        let filter = parse("highway!=motorway,trunk");
+        assert_eq!(filter, Filter::Not(Box::new(one_of("highway", &["motorway", "trunk"]))));
+        assert!(filter.matches(&tags(&[("highway", "residential")])));
+        assert!(filter.matches(&tags(&[])));
+        assert!(!filter.matches(&tags(&[("highway", "trunk")])));
+    }
+
+    #[test]
+    fn quoting_and_escapes() {
+        assert_eq!(parse(r#"maxspeed="30 mph""#), one_of("maxspeed", &["30 mph"]));
+        assert_eq!(parse(r#""addr:street"="a=b""#), one_of("addr:street", &["a=b"]));
+        assert_eq!(parse(r#"name="say \"hi\" \\ x""#), one_of("name", &[r#"say "hi" \ x"#]));
+        // Other backslashes are kept
+        assert_eq!(parse(r#"name="a\d""#), one_of("name", &[r"a\d"]));
+        assert_eq!(parse(r#"name="""#), one_of("name", &[""]));
+        // Keywords only count unquoted
+        assert_eq!(parse(r#""and" or "not""#), Filter::Or(vec![has("and"), has("not")]));
+    }
+
+    fn regex(key: &str, pattern: &str) -> Filter {
+        Filter::Regex(key.to_string(), ValueRegex::new(pattern).unwrap())
+    }
+
+    #[test]
+    fn regex_matches_anywhere_unless_anchored() {
+        let filter = parse(r#"ref~"^A[0-9]+$""#);
+        assert_eq!(filter, regex("ref", "^A[0-9]+$"));
+        assert!(filter.matches(&tags(&[("ref", "A7")])));
+        assert!(!filter.matches(&tags(&[("ref", "A7a")])));
+        assert!(!parse("name~park").matches(&tags(&[("name", "Central Park Lane")])));
+        assert!(parse("name~Park").matches(&tags(&[("name", "Central Park Lane")])));
+        assert!(parse(r#"name~"(?i)park""#).matches(&tags(&[("name", "Central Park Lane")])));
+        assert!(!parse("name~Park").matches(&tags(&[])));
+    }
+
+    #[test]
+    fn regex_negation() {
+        let filter = parse("name!~^The");
+        assert_eq!(filter, Filter::Not(Box::new(regex("name", "^The"))));
+        assert!(filter.matches(&tags(&[("name", "A Street")])));
+        assert!(filter.matches(&tags(&[])));
+        assert!(!filter.matches(&tags(&[("name", "The Mall")])));
+    }
+
+    #[test]
+    fn regex_escapes_are_kept() {
+        assert_eq!(parse(r#"ref~"\d+\.\d""#), regex("ref", r"\d+\.\d"));
+        assert!(parse(r#"ref~"^\d+$""#).matches(&tags(&[("ref", "42")])));
+        assert_eq!(parse(r#"name~"\"x\"""#), regex("name", r#""x""#));
+    }
+
+    #[test]
+    fn regex_errors() {
+        assert!(error("name~\"(\"").starts_with("invalid regular expression \"(\": "));
+        assert_eq!(error("name~"), "expected a regular expression for 'name' at end of filter expression");
+        assert_eq!(error("name~,"), "expected a regular expression for 'name' but found ','");
+    }
+
+    #[test]
+    fn overpass_key_clauses() {
+        assert_eq!(parse("[highway]"), has("highway"));
+        assert_eq!(parse("[!construction]"), Filter::Not(Box::new(has("construction"))));
+        assert_eq!(parse("[highway][!construction]"), Filter::And(vec![has("highway"), parse("[!construction]")]));
+        assert_eq!(parse(r#"["addr:street"]"#), has("addr:street"));
+    }
+
+    #[test]
+    fn overpass_value_clauses() {
+        assert_eq!(parse("[highway=primary]"), one_of("highway", &["primary"]));
+        assert_eq!(parse("[ highway = primary ]"), one_of("highway", &["primary"]));
+        assert_eq!(parse("[access!=private]"), Filter::Not(Box::new(one_of("access", &["private"]))));
+        assert_eq!(parse("['name'='St. John\\'s']"), one_of("name", &["St. John's"]));
+        assert_eq!(parse(r#"["name"="say \"hi\""]"#), one_of("name", &[r#"say "hi""#]));
+        // A comma only ends the value of a regex
+        assert_eq!(parse("[a=b,c]"), one_of("a", &["b,c"]));
+    }
+
+    #[test]
+    fn overpass_regex_clauses() {
+        assert_eq!(parse("[name~park]"), regex("name", "park"));
+        assert_eq!(parse(r#"["name"~"park",i]"#), regex("name", "(?i)park"));
+        assert_eq!(parse(r#"[name!~"^A" , i]"#), Filter::Not(Box::new(regex("name", "(?i)^A"))));
+        assert!(parse(r#"["name"~"park",i]"#).matches(&tags(&[("name", "Central Park")])));
+    }
+
+    #[test]
+    fn overpass_clauses_combine_with_expressions() {
+        assert_eq!(
+            parse("[highway][!area] and not access=private"),
+            Filter::And(vec![
+                Filter::And(vec![has("highway"), Filter::Not(Box::new(has("area")))]),
+                Filter::Not(Box::new(one_of("access", &["private"]))),
+            ])
+        );
+    }
+
+    #[test]
+    fn overpass_clause_errors() {
+        assert_eq!(error("[highway"), "missing ']' in filter expression");
+        assert_eq!(error(r#"[a="b]"#), "missing ']' in filter expression");
+        assert_eq!(error("[]"), "expected a key or value in []");
+        assert_eq!(error("[a=]"), "expected a key or value in [a=]");
+        assert_eq!(error("[~name~x]"), "regular expressions on keys are not supported, in [~name~x]");
+        assert_eq!(error("[!a=b]"), "expected ']' after the key in [!a=b]");
+        assert_eq!(error("[a b]"), "expected '=', '!=', '~' or '!~' after the key in [a b]");
+        assert_eq!(error("[a~x,j]"), "expected 'i' after ',' in [a~x,j]");
+        assert_eq!(error(r#"[a="b" c]"#), r#"expected ']' after the value in [a="b" c]"#);
+        assert_eq!(error(r#"[a="b\"]"#), "missing ']' in filter expression");
+        assert!(error("[a~\"(\"]").starts_with("invalid regular expression"));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        let cases = [
+            "a or b and not c",
+            r#"name="say \"hi\"",x"#,
+            "(a or b) and c!=d",
+            r#""[x]"=*"#,
+            r#"ref!~"^\d, x""#,
+        ];
+        for s in cases {
+            let filter = parse(s);
+            assert_eq!(parse(&filter.to_string()), filter, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn keys_in_order() {
+        assert_eq!(parse("b=1 or a and not b").keys(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error(""), "expected a tag key at end of filter expression");
+        assert_eq!(error("a and"), "expected a tag key at end of filter expression");
+        assert_eq!(error("(a or b"), "missing ')' in filter expression");
+        assert_eq!(error("(a b)"), "expected ')' but found 'b' in filter expression");
+        assert_eq!(error("a b"), "unexpected 'b' in filter expression");
+        assert_eq!(error("a="), "expected a value for 'a' at end of filter expression");
+        assert_eq!(error("a=b,"), "expected a value for 'a' at end of filter expression");
+        assert_eq!(error("a=)"), "expected a value for 'a' but found ')'");
+        assert_eq!(error("=b"), "expected a tag key but found '=' in filter expression");
+        assert_eq!(error("a!b"), "expected '=' or '~' after '!' in filter expression");
+        assert_eq!(error(r#"a="b"#), "unterminated quoted string in filter expression");
+        assert_eq!(error(r#"a="b\"#), "unterminated escape in filter expression");
+    }
+}
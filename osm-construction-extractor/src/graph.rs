@@ -0,0 +1,491 @@
+//! Routing graphs: the highway network as nodes and directed edges, with
+//! turn restrictions.
+//!
+//! Graph nodes are the OSM nodes where ways end or meet (and the via nodes
+//! of restrictions, which are such intersections anyway); the nodes in
+//! between only add to the length of the edge passing through them. Ways
+//! that are not one-way get an edge in each direction.
+//!
+//! The binary format written by [`write_binary`] is little-endian:
+//!
+//! ```text
+//! "OSMGRAPH" u32 version (1)
+//! u32 count, per highway value: u16 length, UTF-8 bytes
+//! u64 count, per node:          i64 osm_id, f64 lon, f64 lat
+//! u64 count, per edge:          u32 source, u32 target, i64 way_id,
+//!                               f32 length (m), f32 maxspeed (km/h, NaN if
+//!                               unknown), u16 highway value index
+//! u64 count, per restriction:   i64 relation_id, u8 kind (0 = no_*,
+//!                               1 = only_*), u32 from_edge, u32 to_edge
+//! ```
+//!
+//! Nodes and edges are referred to by their position; only restrictions
+//! whose edges could be resolved are included.
+
+use crate::filter::Filter;
+use crate::output::csv::push_field;
+use anyhow::Result;
+use clap::ValueEnum;
+use geo::{Coord, HaversineDistance, Point};
+use osmpbfreader::{NodeId, OsmId, OsmObj, Relation, Tags, Way};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The default selection of routable ways: all highways except those that
+/// cannot (yet, or any longer) be travelled and pedestrian areas
+pub const ROUTABLE_FILTER: &str = "highway=* and not highway=proposed,construction,abandoned,platform,raceway,\
+                                   bus_stop,services,rest_area,elevator,emergency_bay and not area=yes";
+
+const MAGIC: &[u8; 8] = b"OSMGRAPH";
+const VERSION: u32 = 1;
+
+/// The graph file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    /// `_nodes.csv`, `_edges.csv` and `_restrictions.csv` files
+    Csv,
+    /// A single compact binary file, see the module documentation
+    Bin,
+}
+
+impl GraphFormat {
+    /// Guesses the format from a file extension: `.bin` or `.graph` for
+    /// binary, `.csv` for CSV
+    pub fn from_path(path: &Path) -> Option<GraphFormat> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "csv" => Some(GraphFormat::Csv),
+            "bin" | "graph" => Some(GraphFormat::Bin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GraphNode {
+    pub osm_id: i64,
+    pub coord: Coord,
+}
+
+/// A directed edge along part of a way, between two graph nodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    /// Index into [`Graph::nodes`]
+    pub source: usize,
+    pub target: usize,
+    pub way_id: i64,
+    /// Along all nodes of the way in between, in meters
+    pub length: f64,
+    pub highway: String,
+    /// In km/h, from `maxspeed:forward`/`maxspeed:backward` or `maxspeed`
+    pub maxspeed: Option<f64>,
+}
+
+/// A `type=restriction` relation between two ways of the graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restriction {
+    pub relation_id: i64,
+    /// E.g. `no_left_turn` or `only_straight_on`
+    pub restriction: String,
+    pub from_way: i64,
+    pub via: Via,
+    pub to_way: i64,
+    /// The edge of `from_way` leading into the via node or ways, and the
+    /// one of `to_way` leaving them, where they could be found
+    pub from_edge: Option<usize>,
+    pub to_edge: Option<usize>,
+}
+
+impl Restriction {
+    /// Whether the restriction names the only allowed turn rather than a
+    /// forbidden one
+    pub fn is_mandatory(&self) -> bool {
+        self.restriction.starts_with("only_")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Via {
+    Node(i64),
+    Ways(Vec<i64>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<Edge>,
+    pub restrictions: Vec<Restriction>,
+}
+
+/// Which ways of a graph a way can be travelled along
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+    Both,
+}
+
+/// Builds the graph from the ways matching `routable` among `objects`, as
+/// returned by [`Extractor::objects`](crate::Extractor::objects), plus the
+/// restriction relations between them.
+///
+/// Nodes of a way that are missing from the input are skipped, leaving a
+/// straight segment across the gap.
+pub fn build(objects: &BTreeMap<OsmId, OsmObj>, routable: &Filter) -> Graph {
+    let location = |id: &NodeId| match objects.get(&OsmId::Node(*id)) {
+        Some(OsmObj::Node(node)) => Some(Coord { x: node.lon(), y: node.lat() }),
+        _ => None,
+    };
+    let ways: Vec<(&Way, Vec<(NodeId, Coord)>)> = objects
+        .values()
+        .filter_map(|obj| match obj {
+            OsmObj::Way(way) if routable.matches(&way.tags) => {
+                let nodes: Vec<(NodeId, Coord)> =
+                    way.nodes.iter().filter_map(|id| Some((*id, location(id)?))).collect();
+                (nodes.len() >= 2).then_some((way, nodes))
+            }
+            _ => None,
+        })
+        .collect();
+
+    // Way ends and nodes used more than once become graph nodes
+    let mut uses: HashMap<NodeId, u32> = HashMap::new();
+    for (_, nodes) in &ways {
+        for (id, _) in nodes {
+            *uses.entry(*id).or_default() += 1;
+        }
+        for end in [nodes[0].0, nodes[nodes.len() - 1].0] {
+            *uses.entry(end).or_default() += 2;
+        }
+    }
+
+    let mut graph = Graph::default();
+    let mut index: HashMap<NodeId, usize> = HashMap::new();
+    let mut node_index = |graph: &mut Graph, id: NodeId, coord: Coord| {
+        *index.entry(id).or_insert_with(|| {
+            graph.nodes.push(GraphNode { osm_id: id.0, coord });
+            graph.nodes.len() - 1
+        })
+    };
+    // For finding the edges of restrictions: (way, target) and (way, source)
+    let mut into: HashMap<(i64, NodeId), usize> = HashMap::new();
+    let mut out_of: HashMap<(i64, NodeId), usize> = HashMap::new();
+    for (way, nodes) in &ways {
+        let highway = way.tags.get("highway").map_or("", |v| v.as_str()).to_string();
+        let direction = direction(&way.tags);
+        let speed = |key: &str| way.tags.get(key).and_then(|v| parse_maxspeed(v));
+        let forward_speed = speed("maxspeed:forward").or_else(|| speed("maxspeed"));
+        let backward_speed = speed("maxspeed:backward").or_else(|| speed("maxspeed"));
+
+        let (mut start, mut length) = (0, 0.0);
+        for i in 1..nodes.len() {
+            length += Point(nodes[i - 1].1).haversine_distance(&Point(nodes[i].1));
+            if i < nodes.len() - 1 && uses[&nodes[i].0] < 2 {
+                continue;
+            }
+            let (a, b) = (nodes[start], nodes[i]);
+            let (source, target) = (node_index(&mut graph, a.0, a.1), node_index(&mut graph, b.0, b.1));
+            let mut add = |from: (usize, NodeId), to: (usize, NodeId), maxspeed: Option<f64>| {
+                out_of.entry((way.id.0, from.1)).or_insert(graph.edges.len());
+                into.entry((way.id.0, to.1)).or_insert(graph.edges.len());
+                graph.edges.push(Edge {
+                    source: from.0,
+                    target: to.0,
+                    way_id: way.id.0,
+                    length,
+                    highway: highway.clone(),
+                    maxspeed,
+                });
+            };
+            if direction != Direction::Backward {
+                add((source, a.0), (target, b.0), forward_speed);
+            }
+            if direction != Direction::Forward {
+                add((target, b.0), (source, a.0), backward_speed);
+            }
+            (start, length) = (i, 0.0);
+        }
+    }
+
+    let way_nodes: HashMap<i64, &[NodeId]> = ways.iter().map(|(way, _)| (way.id.0, way.nodes.as_slice())).collect();
+    for obj in objects.values() {
+        let OsmObj::Relation(relation) = obj else { continue };
+        let Some(mut restriction) = restriction(relation) else { continue };
+        let (Some(from), Some(to)) = (way_nodes.get(&restriction.from_way), way_nodes.get(&restriction.to_way)) else {
+            continue;
+        };
+        let (into_via, out_of_via) = match &restriction.via {
+            Via::Node(id) => (Some(NodeId(*id)), Some(NodeId(*id))),
+            Via::Ways(via) => (
+                via.first().and_then(|id| junction(from, objects, *id)),
+                via.last().and_then(|id| junction(to, objects, *id)),
+            ),
+        };
+        restriction.from_edge = into_via.and_then(|node| into.get(&(restriction.from_way, node)).copied());
+        restriction.to_edge = out_of_via.and_then(|node| out_of.get(&(restriction.to_way, node)).copied());
+        graph.restrictions.push(restriction);
+    }
+    graph
+}
+
+// The end of a via way that also lies on the from or to way
+fn junction(way: &[NodeId], objects: &BTreeMap<OsmId, OsmObj>, via: i64) -> Option<NodeId> {
+    let Some(OsmObj::Way(via)) = objects.get(&OsmId::Way(osmpbfreader::WayId(via))) else {
+        return None;
+    };
+    [via.nodes.first()?, via.nodes.last()?].into_iter().find(|end| way.contains(end)).copied()
+}
+
+// The members of a restriction relation, if it is one and well-formed
+fn restriction(relation: &Relation) -> Option<Restriction> {
+    if relation.tags.get("type").is_none_or(|t| t != "restriction") {
+        return None;
+    }
+    // Restrictions for one mode only, e.g. restriction:hgv, are kept too
+    let value = relation.tags.get("restriction").or_else(|| {
+        let mut keys: Vec<_> = relation.tags.keys().filter(|k| k.starts_with("restriction:")).collect();
+        keys.sort();
+        keys.first().and_then(|k| relation.tags.get(k.as_str()))
+    })?;
+    let (mut from, mut to, mut via_node, mut via_ways) = (None, None, None, Vec::new());
+    for member in &relation.refs {
+        match (member.role.as_str(), member.member) {
+            ("from", OsmId::Way(id)) => from = Some(id.0),
+            ("to", OsmId::Way(id)) => to = Some(id.0),
+            ("via", OsmId::Node(id)) => via_node = Some(id.0),
+            ("via", OsmId::Way(id)) => via_ways.push(id.0),
+            _ => {}
+        }
+    }
+    let via = match (via_node, via_ways.is_empty()) {
+        (Some(node), true) => Via::Node(node),
+        (None, false) => Via::Ways(via_ways),
+        _ => return None,
+    };
+    Some(Restriction {
+        relation_id: relation.id.0,
+        restriction: value.to_string(),
+        from_way: from?,
+        via,
+        to_way: to?,
+        from_edge: None,
+        to_edge: None,
+    })
+}
+
+// Roundabouts and motorways are one-way unless tagged otherwise
+fn direction(tags: &Tags) -> Direction {
+    match tags.get("oneway").map(|v| v.as_str()) {
+        Some("yes" | "true" | "1") => Direction::Forward,
+        Some("-1" | "reverse") => Direction::Backward,
+        Some("no" | "false" | "0") => Direction::Both,
+        _ if tags.get("junction").is_some_and(|j| j == "roundabout" || j == "circular") => Direction::Forward,
+        _ if tags.get("highway").is_some_and(|h| h == "motorway") => Direction::Forward,
+        _ => Direction::Both,
+    }
+}
+
+/// Parses a `maxspeed` value into km/h: a number, optionally followed by
+/// `mph` or `knots`. Symbolic values such as `none`, `walk` or `DE:urban`
+/// give `None`.
+pub fn parse_maxspeed(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (number, factor) = if let Some(mph) = value.strip_suffix("mph") {
+        (mph, 1.609344)
+    } else if let Some(knots) = value.strip_suffix("knots") {
+        (knots, 1.852)
+    } else {
+        (value, 1.0)
+    };
+    let speed: f64 = number.trim().parse().ok()?;
+    (speed.is_finite() && speed > 0.0).then_some(speed * factor)
+}
+
+/// Writes the graph as three CSV files next to `path`, named after it with
+/// a `_nodes`, `_edges` and `_restrictions` suffix, e.g. `roads_edges.csv`,
+/// and returns their paths
+pub fn write_csv(path: &Path, graph: &Graph) -> Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = ["nodes", "edges", "restrictions"].iter().map(|s| suffixed(path, s)).collect();
+
+    let mut out = BufWriter::new(File::create(&paths[0])?);
+    writeln!(out, "id,osm_id,lon,lat")?;
+    for (i, node) in graph.nodes.iter().enumerate() {
+        writeln!(out, "{},{},{},{}", i, node.osm_id, node.coord.x, node.coord.y)?;
+    }
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(&paths[1])?);
+    writeln!(out, "id,source,target,way_id,length,highway,maxspeed")?;
+    let mut line = String::new();
+    for (i, edge) in graph.edges.iter().enumerate() {
+        line.clear();
+        line.push_str(&format!("{},{},{},{},{:.2},", i, edge.source, edge.target, edge.way_id, edge.length));
+        push_field(&mut line, &edge.highway, ',');
+        line.push(',');
+        if let Some(maxspeed) = edge.maxspeed {
+            line.push_str(&format!("{:.1}", maxspeed));
+        }
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(&paths[2])?);
+    writeln!(out, "osm_id,restriction,from_way,via_node,via_ways,to_way,from_edge,to_edge")?;
+    let optional = |v: Option<usize>| v.map(|v| v.to_string()).unwrap_or_default();
+    for r in &graph.restrictions {
+        line.clear();
+        line.push_str(&format!("{},", r.relation_id));
+        push_field(&mut line, &r.restriction, ',');
+        let (via_node, via_ways) = match &r.via {
+            Via::Node(id) => (id.to_string(), String::new()),
+            Via::Ways(ids) => (String::new(), ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(";")),
+        };
+        line.push_str(&format!(
+            ",{},{},{},{},{},{}",
+            r.from_way,
+            via_node,
+            via_ways,
+            r.to_way,
+            optional(r.from_edge),
+            optional(r.to_edge)
+        ));
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+    Ok(paths)
+}
+
+/// Writes the graph in the binary format described in the module
+/// documentation
+pub fn write_binary(path: &Path, graph: &Graph) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+
+    let mut highways: Vec<&str> = graph.edges.iter().map(|e| e.highway.as_str()).collect();
+    highways.sort_unstable();
+    highways.dedup();
+    anyhow::ensure!(highways.len() <= u16::MAX as usize, "too many distinct highway values");
+    out.write_all(&(highways.len() as u32).to_le_bytes())?;
+    for highway in &highways {
+        let bytes = &highway.as_bytes()[..highway.len().min(u16::MAX as usize)];
+        out.write_all(&(bytes.len() as u16).to_le_bytes())?;
+        out.write_all(bytes)?;
+    }
+
+    anyhow::ensure!(graph.nodes.len() <= u32::MAX as usize, "too many nodes for the binary format");
+    out.write_all(&(graph.nodes.len() as u64).to_le_bytes())?;
+    for node in &graph.nodes {
+        out.write_all(&node.osm_id.to_le_bytes())?;
+        out.write_all(&node.coord.x.to_le_bytes())?;
+        out.write_all(&node.coord.y.to_le_bytes())?;
+    }
+
+    anyhow::ensure!(graph.edges.len() <= u32::MAX as usize, "too many edges for the binary format");
+    out.write_all(&(graph.edges.len() as u64).to_le_bytes())?;
+    for edge in &graph.edges {
+        let highway = highways.binary_search(&edge.highway.as_str()).unwrap_or(0) as u16;
+        out.write_all(&(edge.source as u32).to_le_bytes())?;
+        out.write_all(&(edge.target as u32).to_le_bytes())?;
+        out.write_all(&edge.way_id.to_le_bytes())?;
+        out.write_all(&(edge.length as f32).to_le_bytes())?;
+        out.write_all(&edge.maxspeed.map_or(f32::NAN, |s| s as f32).to_le_bytes())?;
+        out.write_all(&highway.to_le_bytes())?;
+    }
+
+    let resolved: Vec<(&Restriction, usize, usize)> = graph
+        .restrictions
+        .iter()
+        .filter_map(|r| Some((r, r.from_edge?, r.to_edge?)))
+        .collect();
+    out.write_all(&(resolved.len() as u64).to_le_bytes())?;
+    for (r, from_edge, to_edge) in resolved {
+        out.write_all(&r.relation_id.to_le_bytes())?;
+        out.write_all(&[r.is_mandatory() as u8])?;
+        out.write_all(&(from_edge as u32).to_le_bytes())?;
+        out.write_all(&(to_edge as u32).to_le_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("graph");
+    path.with_file_name(format!("{}_{}.csv", stem, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{Node, Ref, RelationId, WayId};
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in pairs {
+            tags.insert((*key).into(), (*value).into());
+        }
+        tags
+    }
+
+    // A crossroads at node 1: a two-way road from west (2) through node 6
+    // and the crossing to east (3), a one-way street coming in from south
+    // (4) and one drawn from the crossing to north (5) but only travelled
+    // towards it; turning left from south to west is forbidden
+    fn crossroads() -> BTreeMap<OsmId, OsmObj> {
+        let mut objects = BTreeMap::new();
+        for (id, x, y) in [(1, 0, 0), (2, -10, 0), (3, 10, 0), (4, 0, -10), (5, 0, 10), (6, -5, 0)] {
+            let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: y * 1000, decimicro_lon: x * 1000 };
+            objects.insert(node.id.into(), OsmObj::Node(node));
+        }
+        let ways = [
+            (10, &[2, 6, 1, 3][..], tags(&[("highway", "primary")])),
+            (11, &[4, 1][..], tags(&[("highway", "residential"), ("oneway", "yes")])),
+            (12, &[1, 5][..], tags(&[("highway", "residential"), ("oneway", "-1")])),
+        ];
+        for (id, nodes, tags) in ways {
+            let way = Way { id: WayId(id), tags, nodes: nodes.iter().map(|&n| NodeId(n)).collect() };
+            objects.insert(way.id.into(), OsmObj::Way(way));
+        }
+        let refs = vec![
+            Ref { member: WayId(11).into(), role: "from".into() },
+            Ref { member: NodeId(1).into(), role: "via".into() },
+            Ref { member: WayId(10).into(), role: "to".into() },
+        ];
+        let tags = tags(&[("type", "restriction"), ("restriction", "no_left_turn")]);
+        objects.insert(RelationId(1).into(), OsmObj::Relation(Relation { id: RelationId(1), tags, refs }));
+        objects
+    }
+
+    // The edges as (way, source, target) in OSM node ids
+    fn edges(graph: &Graph) -> Vec<(i64, i64, i64)> {
+        let osm_id = |index: usize| graph.nodes[index].osm_id;
+        graph.edges.iter().map(|e| (e.way_id, osm_id(e.source), osm_id(e.target))).collect()
+    }
+
+    #[test]
+    fn ways_are_split_where_they_meet() {
+        let graph = build(&crossroads(), &ROUTABLE_FILTER.parse().unwrap());
+        let mut nodes: Vec<i64> = graph.nodes.iter().map(|n| n.osm_id).collect();
+        nodes.sort();
+        // Node 6 only adds to the length of the edges through it
+        assert_eq!(nodes, [1, 2, 3, 4, 5]);
+        assert_eq!(edges(&graph), [(10, 2, 1), (10, 1, 2), (10, 1, 3), (10, 3, 1), (11, 4, 1), (12, 5, 1)]);
+        // Both halves of the road are 0.001° long
+        let west = &graph.edges[0];
+        assert!((west.length - graph.edges[2].length).abs() < 0.01);
+        assert!((west.length - 111.2).abs() < 0.1, "{}", west.length);
+    }
+
+    #[test]
+    fn restrictions_refer_to_the_edges_into_and_out_of_the_via_node() {
+        let graph = build(&crossroads(), &ROUTABLE_FILTER.parse().unwrap());
+        let restriction = &graph.restrictions[0];
+        assert!(!restriction.is_mandatory());
+        assert_eq!((restriction.from_way, &restriction.via, restriction.to_way), (11, &Via::Node(1), 10));
+        let edge = |index: Option<usize>| edges(&graph)[index.unwrap()];
+        // Coming from the south, turning into the road towards the west
+        assert_eq!(edge(restriction.from_edge), (11, 4, 1));
+        assert_eq!(edge(restriction.to_edge), (10, 1, 2));
+    }
+}
@@ -0,0 +1,60 @@
+//! Evaluation of `opening_hours` tags, to keep the features open at a given
+//! time and to add the tag in a normalized form.
+//!
+//! Times are local times of the features, without a time zone, as the tag
+//! gives them; public holidays (`PH`) are not known and never match.
+
+use crate::feature::ExtractedFeature;
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+use opening_hours::OpeningHours;
+
+/// The tag evaluated
+pub const OPENING_HOURS_KEY: &str = "opening_hours";
+/// Property holding the `opening_hours` tag normalized into an unambiguous
+/// expression, e.g. `Mo-Fr 08:00-18:00; Sa 08:00-12:00` for
+/// `Mo-Fr 8:00-18:00, Sa 8:00-12:00`
+pub const NORMALIZED_TAG: &str = "@opening_hours";
+/// Property holding the next time, after the time features are kept open
+/// at, that their opening hours change, e.g. when they close
+pub const NEXT_CHANGE_TAG: &str = "@next_change";
+
+/// Parses a local date and time such as `2025-06-01T10:00`, with or without
+/// seconds
+pub fn parse_datetime(s: &str) -> Result<NaiveDateTime> {
+    for format in ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(datetime);
+        }
+    }
+    bail!("invalid date and time {:?}, expected e.g. 2025-06-01T10:00", s)
+}
+
+fn opening_hours(feature: &ExtractedFeature) -> Option<OpeningHours> {
+    feature.tags().get(OPENING_HOURS_KEY)?.parse().ok()
+}
+
+/// Whether the feature is open at `at` by its `opening_hours` tag; features
+/// without one, with one that cannot be parsed or with an unknown state at
+/// that time count as closed. An open feature has [`NEXT_CHANGE_TAG`]
+/// added, if its hours change again at all.
+pub fn keep_open(feature: &mut ExtractedFeature, at: NaiveDateTime) -> bool {
+    let Some(hours) = opening_hours(feature) else {
+        return false;
+    };
+    if !hours.is_open(at) {
+        return false;
+    }
+    if let Some(next) = hours.next_change(at) {
+        feature.tags_mut().insert(NEXT_CHANGE_TAG.into(), next.format("%Y-%m-%dT%H:%M").to_string().into());
+    }
+    true
+}
+
+/// Adds the normalized `opening_hours` tag as [`NORMALIZED_TAG`], if it can
+/// be parsed
+pub fn add_normalized(feature: &mut ExtractedFeature) {
+    if let Some(hours) = opening_hours(feature) {
+        feature.tags_mut().insert(NORMALIZED_TAG.into(), hours.normalize().to_string().into());
+    }
+}
@@ -0,0 +1,45 @@
+//! Explicit lists of OSM object ids, such as `w123,n456,r789`, for
+//! extracting particular objects whatever their tags.
+//!
+//! Ids are written as the type letter followed by the number, the way
+//! features are identified in the output, or as `way/123` like in the URLs
+//! of openstreetmap.org.
+
+use anyhow::{bail, Context, Result};
+use osmpbfreader::{NodeId, OsmId, RelationId, WayId};
+use std::fs;
+use std::path::Path;
+
+/// Parses a single id, e.g. `w123` or `way/123`
+pub fn parse_id(s: &str) -> Result<OsmId> {
+    let s = s.trim();
+    let (kind, number) = match s.split_once('/') {
+        Some((kind, number)) => (kind, number),
+        None => s.split_at(s.chars().next().map_or(0, char::len_utf8)),
+    };
+    let number: i64 = number.parse().with_context(|| format!("invalid id {:?}, expected e.g. w123", s))?;
+    Ok(match kind {
+        "n" | "node" => OsmId::Node(NodeId(number)),
+        "w" | "way" => OsmId::Way(WayId(number)),
+        "r" | "relation" => OsmId::Relation(RelationId(number)),
+        _ => bail!("invalid id {:?}, expected it to start with n, w or r", s),
+    })
+}
+
+/// Reads the ids of a file, one per line with `#` starting a comment, or
+/// as a JSON array of strings, as written by `--ids-only`
+pub fn read_file(path: &Path) -> Result<Vec<OsmId>> {
+    let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if text.trim_start().starts_with('[') {
+        let ids: Vec<String> =
+            serde_json::from_str(&text).with_context(|| format!("invalid id list {}", path.display()))?;
+        return ids.iter().map(|id| parse_id(id)).collect();
+    }
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.split('#').next().unwrap_or("").trim();
+            (!line.is_empty()).then(|| parse_id(line).with_context(|| format!("line {} of {}", i + 1, path.display())))
+        })
+        .collect()
+}
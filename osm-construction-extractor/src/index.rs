@@ -0,0 +1,226 @@
+//! A local feature store with an R-tree over the features' bounding boxes,
+//! written as an output format and queried by box, point or radius with
+//! the `query` subcommand, so an extraction can be looked into repeatedly
+//! without rereading the PBF.
+//!
+//! The file starts with [`MAGIC`] and the number of features, followed by
+//! one entry per feature, its box (`min_x`, `min_y`, `max_x`, `max_y` as
+//! f64) and the offset and length (u64, u32) of its record, and then the
+//! records: the OSM type (u8), id (i64), length of the tags (u32), the tags
+//! as a JSON object and the geometry as WKB, all little-endian. Entries
+//! and records are in the leaf order of the R-tree bulk-loaded from the
+//! boxes, so features close to each other are close in the file.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::output::wkb;
+use crate::intern::TagMap;
+use anyhow::{bail, Context, Result};
+use geo::{
+    BoundingRect, Closest, Geometry, HaversineClosestPoint, HaversineDistance, Intersects, Point, Rect,
+};
+use memmap2::Mmap;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{RTree, AABB};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::warn;
+
+/// Property holding the distance in meters of the features a radius query
+/// finds ([`FeatureIndex::within`]) from its point
+pub const DISTANCE_TAG: &str = "@distance";
+
+/// The first bytes of an index file, ending in the version of the layout
+pub const MAGIC: &[u8; 8] = b"OSMFIDX1";
+
+// Bytes of the header and of each entry
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 44;
+// Mean earth radius in meters, as geo's haversine functions use it
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+type Entry = GeomWithData<Rectangle<[f64; 2]>, usize>;
+
+/// Writes the features to an index file at `path`; features with an empty
+/// geometry cannot be found by location and are left out
+pub fn write(path: &Path, features: &[ExtractedFeature]) -> Result<()> {
+    let entries: Vec<Entry> = features
+        .iter()
+        .enumerate()
+        .filter_map(|(i, feature)| {
+            let rect = feature.geometry().bounding_rect()?;
+            Some(GeomWithData::new(Rectangle::from_corners(rect.min().into(), rect.max().into()), i))
+        })
+        .collect();
+    if entries.len() < features.len() {
+        warn!("Left {} features with an empty geometry out of the index", features.len() - entries.len());
+    }
+    let tree = RTree::bulk_load(entries);
+
+    let mut records = Vec::new();
+    let mut index = Vec::with_capacity(HEADER_LEN + tree.size() * ENTRY_LEN);
+    index.extend_from_slice(MAGIC);
+    index.extend_from_slice(&(tree.size() as u64).to_le_bytes());
+    for entry in tree.iter() {
+        let start = records.len();
+        record(&mut records, &features[entry.data])?;
+        let (lower, upper) = (entry.geom().lower(), entry.geom().upper());
+        for value in [lower[0], lower[1], upper[0], upper[1]] {
+            index.extend_from_slice(&value.to_le_bytes());
+        }
+        index.extend_from_slice(&(start as u64).to_le_bytes());
+        index.extend_from_slice(&((records.len() - start) as u32).to_le_bytes());
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&index)?;
+    out.write_all(&records)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn record(out: &mut Vec<u8>, feature: &ExtractedFeature) -> Result<()> {
+    out.push(match feature.osm_type() {
+        OsmType::Node => 0,
+        OsmType::Way => 1,
+        OsmType::Relation => 2,
+    });
+    out.extend_from_slice(&feature.id().to_le_bytes());
+    let tags = serde_json::to_vec(feature.tags())?;
+    out.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+    out.extend_from_slice(&tags);
+    wkb::write_geometry(out, feature.geometry());
+    Ok(())
+}
+
+/// An index file opened for queries; its records stay on disk, mapped into
+/// memory, until a query reads them
+pub struct FeatureIndex {
+    data: Mmap,
+    tree: RTree<Entry>,
+    // Per entry, the start and length of its record in `data`
+    records: Vec<(usize, usize)>,
+}
+
+impl FeatureIndex {
+    /// Opens an index written by [`write`]
+    pub fn open(path: &Path) -> Result<FeatureIndex> {
+        let file = File::open(path).with_context(|| format!("cannot open index {:?}", path))?;
+        // SAFETY: the mapping is only read, and changing the index while it
+        // is queried is not supported
+        let data = unsafe { Mmap::map(&file)? };
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            bail!("{:?} is not a feature index", path);
+        }
+        let count = u64::from_le_bytes(data[8..16].try_into()?) as usize;
+        let records_start = count
+            .checked_mul(ENTRY_LEN)
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .filter(|&start| start <= data.len())
+            .with_context(|| format!("index {:?} is truncated", path))?;
+
+        let mut entries = Vec::with_capacity(count);
+        let mut records = Vec::with_capacity(count);
+        for (i, entry) in data[HEADER_LEN..records_start].chunks_exact(ENTRY_LEN).enumerate() {
+            let f = |at: usize| f64::from_le_bytes(entry[at..at + 8].try_into().unwrap());
+            let start = records_start + u64::from_le_bytes(entry[32..40].try_into()?) as usize;
+            let len = u32::from_le_bytes(entry[40..44].try_into()?) as usize;
+            if start + len > data.len() {
+                bail!("index {:?} is truncated", path);
+            }
+            entries.push(GeomWithData::new(Rectangle::from_corners([f(0), f(8)], [f(16), f(24)]), i));
+            records.push((start, len));
+        }
+        Ok(FeatureIndex { data, tree: RTree::bulk_load(entries), records })
+    }
+
+    /// The number of features in the index
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// All features, in the order of the file
+    pub fn all(&self) -> Result<Vec<ExtractedFeature>> {
+        (0..self.records.len()).map(|entry| self.feature(entry)).collect()
+    }
+
+    /// The features intersecting the box
+    pub fn bbox(&self, bbox: &Rect) -> Result<Vec<ExtractedFeature>> {
+        let envelope = AABB::from_corners(bbox.min().into(), bbox.max().into());
+        let mut found = Vec::new();
+        for entry in self.tree.locate_in_envelope_intersecting(&envelope) {
+            let feature = self.feature(entry.data)?;
+            if feature.geometry().intersects(bbox) {
+                found.push(feature);
+            }
+        }
+        Ok(found)
+    }
+
+    /// The features at the point: polygons containing it, lines passing
+    /// through it and points on it
+    pub fn at(&self, point: Point) -> Result<Vec<ExtractedFeature>> {
+        let mut found = Vec::new();
+        for entry in self.tree.locate_in_envelope_intersecting(&AABB::from_point(point.into())) {
+            let feature = self.feature(entry.data)?;
+            if feature.geometry().intersects(&point) {
+                found.push(feature);
+            }
+        }
+        Ok(found)
+    }
+
+    /// The features within `meters` of the point with their geodesic
+    /// distance to it, nearest first; 0 for polygons containing it
+    pub fn within(&self, point: Point, meters: f64) -> Result<Vec<(f64, ExtractedFeature)>> {
+        let mut found = Vec::new();
+        for entry in self.tree.locate_in_envelope_intersecting(&radius_envelope(point, meters)) {
+            let feature = self.feature(entry.data)?;
+            let distance = match feature.geometry().haversine_closest_point(&point) {
+                Closest::Intersection(_) => 0.0,
+                Closest::SinglePoint(closest) => closest.haversine_distance(&point),
+                Closest::Indeterminate => continue,
+            };
+            if distance <= meters {
+                found.push((distance, feature));
+            }
+        }
+        found.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Ok(found)
+    }
+
+    fn feature(&self, entry: usize) -> Result<ExtractedFeature> {
+        let (start, len) = self.records[entry];
+        let record = &self.data[start..start + len];
+        if record.len() < 13 {
+            bail!("truncated record in index");
+        }
+        let osm_type = match record[0] {
+            0 => OsmType::Node,
+            1 => OsmType::Way,
+            2 => OsmType::Relation,
+            other => bail!("invalid OSM type {} in index", other),
+        };
+        let id = i64::from_le_bytes(record[1..9].try_into()?);
+        let tags_len = u32::from_le_bytes(record[9..13].try_into()?) as usize;
+        let (tags, geometry) = record[13..].split_at_checked(tags_len).context("truncated record in index")?;
+        let tags: TagMap = serde_json::from_slice(tags).context("invalid tags in index")?;
+        let geometry: Geometry = wkb::from_wkb(geometry)?;
+        Ok(ExtractedFeature::new(osm_type, id, tags, geometry))
+    }
+}
+
+// A box in degrees around everything within `meters` of the point
+fn radius_envelope(point: Point, meters: f64) -> AABB<[f64; 2]> {
+    let dlat = (meters / EARTH_RADIUS).to_degrees();
+    let cos_lat = point.y().to_radians().cos();
+    let dlon = if cos_lat * 180.0 > dlat { dlat / cos_lat } else { 360.0 };
+    AABB::from_corners(
+        [(point.x() - dlon).max(-180.0), (point.y() - dlat).max(-90.0)],
+        [(point.x() + dlon).min(180.0), (point.y() + dlat).min(90.0)],
+    )
+}
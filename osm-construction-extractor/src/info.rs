@@ -0,0 +1,241 @@
+//! What a PBF says about itself in its header, and how many objects of each
+//! type it holds, as `osmium fileinfo` reports them.
+//!
+//! Objects are counted from the primitive groups of the blocks, which are
+//! decompressed and parsed but not turned into objects.
+//!
+//! o5m and OSM XML input is described by the PBF it is converted into, which
+//! takes over the extent and writing program the input gives; the size is
+//! that of the input itself.
+
+use crate::feature::OsmType;
+use crate::input::Input;
+use crate::memory;
+use crate::metadata;
+use crate::pbf;
+use anyhow::{bail, Result};
+use flate2::read::ZlibDecoder;
+use geo::Rect;
+use osmpbfreader::fileformat::{Blob, BlobHeader};
+use osmpbfreader::osmformat::HeaderBlock;
+use osmpbfreader::primitive_block_from_blob;
+use protobuf::Message;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Read};
+
+// The largest blob header the PBF format allows
+const MAX_BLOB_HEADER: u32 = 64 * 1024;
+
+/// What the header block of a PBF says about the file
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    /// The extent the file claims to cover
+    pub bbox: Option<Rect>,
+    /// Features a reader must support, e.g. `DenseNodes`
+    pub required_features: Vec<String>,
+    /// Features a reader may make use of, e.g. `Sort.Type_then_ID`
+    pub optional_features: Vec<String>,
+    pub writing_program: Option<String>,
+    pub source: Option<String>,
+    /// Up to when changes were replicated into it, in seconds since the
+    /// Unix epoch
+    pub replication_timestamp: Option<i64>,
+    pub replication_sequence: Option<i64>,
+    /// Where the change files to update it are published
+    pub replication_url: Option<String>,
+}
+
+impl Header {
+    /// Whether it is sorted by type then id, as streaming mode needs
+    pub fn sorted(&self) -> bool {
+        self.optional_features.iter().any(|f| f == "Sort.Type_then_ID")
+    }
+}
+
+/// Counts per type of object
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TypeCounts {
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+}
+
+impl TypeCounts {
+    pub fn total(&self) -> u64 {
+        self.nodes + self.ways + self.relations
+    }
+
+    pub fn add(&mut self, osm_type: OsmType) {
+        match osm_type {
+            OsmType::Node => self.nodes += 1,
+            OsmType::Way => self.ways += 1,
+            OsmType::Relation => self.relations += 1,
+        }
+    }
+}
+
+impl fmt::Display for TypeCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (nodes: {}, ways: {}, relations: {})", self.total(), self.nodes, self.ways, self.relations)
+    }
+}
+
+/// The header and contents of a PBF
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// The input, as it is shown
+    pub input: String,
+    /// Size of the input in bytes, 0 if unknown
+    pub input_bytes: u64,
+    pub header: Header,
+    /// Data blocks in the file
+    pub blocks: usize,
+    pub objects: TypeCounts,
+}
+
+/// Reads the header of `input` and counts its objects, decoding the blocks
+/// on `threads` threads (0 = one per CPU core)
+pub fn read(input: &Input, threads: usize) -> Result<FileInfo> {
+    let pool = pbf::thread_pool(threads)?;
+    let mut reader = input.open()?;
+    let input_bytes = reader.source_size().unwrap_or(0);
+    let mut header = Header::default();
+    let mut blocks = 0;
+    let mut objects = TypeCounts::default();
+    let batch_size = pool.current_num_threads() * 4;
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        let next = next_blob_header(&mut reader)?;
+        if let Some((blob_header, _)) = &next {
+            let data = read_bytes(&mut reader, u64::try_from(blob_header.datasize())?)?;
+            match blob_header.type_() {
+                "OSMHeader" => header = parse_header(&data)?,
+                "OSMData" => batch.push(data),
+                _ => {}
+            }
+        }
+        if batch.len() == batch_size || (next.is_none() && !batch.is_empty()) {
+            blocks += batch.len();
+            let counts = pool.install(|| batch.par_drain(..).map(|data| count(&data)).collect::<Result<Vec<_>>>())?;
+            for counts in counts {
+                objects.nodes += counts.nodes;
+                objects.ways += counts.ways;
+                objects.relations += counts.relations;
+            }
+        }
+        if next.is_none() {
+            break;
+        }
+    }
+    Ok(FileInfo { input: input.to_string(), input_bytes, header, blocks, objects })
+}
+
+// The objects of a data block, by the lengths of its groups
+fn count(data: &[u8]) -> Result<TypeCounts> {
+    let block = primitive_block_from_blob(&Blob::parse_from_bytes(data)?)?;
+    let mut counts = TypeCounts::default();
+    for group in &block.primitivegroup {
+        counts.nodes += (group.nodes.len() + group.dense.as_ref().map_or(0, |dense| dense.id.len())) as u64;
+        counts.ways += group.ways.len() as u64;
+        counts.relations += group.relations.len() as u64;
+    }
+    Ok(counts)
+}
+
+/// Reads the header of the next blob with the bytes it took, or `None` at
+/// the end of the file
+pub(crate) fn next_blob_header<R: Read>(reader: &mut R) -> Result<Option<(BlobHeader, u64)>> {
+    let mut size = [0; 4];
+    match reader.read_exact(&mut size) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let size = u32::from_be_bytes(size);
+    if size > MAX_BLOB_HEADER {
+        bail!("invalid PBF: a blob header of {} bytes", size);
+    }
+    let header = BlobHeader::parse_from_bytes(&read_bytes(reader, size.into())?)?;
+    Ok(Some((header, 4 + u64::from(size))))
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(size).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != size {
+        bail!("invalid PBF: the file ends within a blob");
+    }
+    Ok(bytes)
+}
+
+/// Parses the data of an `OSMHeader` blob
+pub(crate) fn parse_header(data: &[u8]) -> Result<Header> {
+    let blob = Blob::parse_from_bytes(data)?;
+    let block = if blob.has_raw() {
+        HeaderBlock::parse_from_bytes(blob.raw())?
+    } else if blob.has_zlib_data() {
+        HeaderBlock::parse_from_reader(&mut ZlibDecoder::new(blob.zlib_data()))?
+    } else {
+        bail!("unsupported compression of the PBF header");
+    };
+    // The header gives the box in nanodegrees
+    let bbox = block.bbox.as_ref().map(|bbox| {
+        let degrees = |nano: i64| nano as f64 * 1e-9;
+        Rect::new((degrees(bbox.left()), degrees(bbox.bottom())), (degrees(bbox.right()), degrees(bbox.top())))
+    });
+    Ok(Header {
+        bbox,
+        required_features: block.required_features.clone(),
+        optional_features: block.optional_features.clone(),
+        writing_program: block.writingprogram.clone(),
+        source: block.source.clone(),
+        replication_timestamp: block.osmosis_replication_timestamp.filter(|&t| t > 0),
+        replication_sequence: block.osmosis_replication_sequence_number,
+        replication_url: block.osmosis_replication_base_url.clone(),
+    })
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(bbox) = &self.bbox {
+            let (min, max) = (bbox.min(), bbox.max());
+            writeln!(f, "  bbox: {:.7},{:.7},{:.7},{:.7}", min.x, min.y, max.x, max.y)?;
+        }
+        writeln!(f, "  required features: {}", self.required_features.join(", "))?;
+        if !self.optional_features.is_empty() {
+            writeln!(f, "  optional features: {}", self.optional_features.join(", "))?;
+        }
+        if let Some(program) = &self.writing_program {
+            writeln!(f, "  writing program: {}", program)?;
+        }
+        if let Some(source) = &self.source {
+            writeln!(f, "  source: {}", source)?;
+        }
+        if let Some(timestamp) = self.replication_timestamp {
+            writeln!(f, "  replication timestamp: {}", metadata::format_timestamp(timestamp))?;
+        }
+        if let Some(sequence) = self.replication_sequence {
+            writeln!(f, "  replication sequence number: {}", sequence)?;
+        }
+        if let Some(url) = &self.replication_url {
+            writeln!(f, "  replication base URL: {}", url)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "File: {}", self.input)?;
+        if self.input_bytes > 0 {
+            write!(f, " ({})", memory::format_size(self.input_bytes))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Header:")?;
+        write!(f, "{}", self.header)?;
+        writeln!(f, "Blocks: {}", self.blocks)?;
+        writeln!(f, "Objects: {}", self.objects)
+    }
+}
@@ -0,0 +1,561 @@
+//! Input sources: local files, stdin, HTTP(S) URLs and bytes in memory.
+//!
+//! Extraction reads its input several times (dependency resolution and the
+//! second streaming pass both rewind), so every source is exposed as
+//! `Read + Seek`. Stdin is copied to a temporary file while the first pass
+//! reads it, and URLs are re-requested with HTTP range requests.
+//!
+//! o5m and OSM XML (plain or bzip2/gzip compressed) are told apart from PBF
+//! by the file extension or, failing that, the first bytes, and transcoded
+//! to a temporary PBF once. o5m is converted as it is read; OSM XML is
+//! loaded into memory first to sort it, so it is meant for small files such
+//! as hand-edited test data and JOSM exports.
+
+use anyhow::{bail, Result};
+use crate::info::Header;
+use crate::metadata::Metadata;
+use crate::pbf::writer::Writer;
+use memmap2::Mmap;
+use osmpbfreader::OsmObj;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Where an OSM file is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Path(PathBuf),
+    /// Standard input, given as `-`
+    Stdin,
+    /// An `http://` or `https://` URL
+    Url(String),
+    /// A whole file already in memory, e.g. one picked in a browser, where
+    /// there is no file system to read it from
+    Bytes(Arc<[u8]>),
+}
+
+/// The encoding of an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Pbf,
+    O5m,
+    Xml,
+    XmlGzip,
+    XmlBzip2,
+}
+
+impl InputFormat {
+    /// Tells the format from a file name, e.g. `map.osm.bz2`
+    pub fn from_path(path: &Path) -> Option<InputFormat> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let (stem, format) = if let Some(stem) = name.strip_suffix(".gz") {
+            (stem, InputFormat::XmlGzip)
+        } else if let Some(stem) = name.strip_suffix(".bz2") {
+            (stem, InputFormat::XmlBzip2)
+        } else if name.ends_with(".pbf") {
+            return Some(InputFormat::Pbf);
+        } else if name.ends_with(".o5m") {
+            return Some(InputFormat::O5m);
+        } else {
+            (name.as_str(), InputFormat::Xml)
+        };
+        (stem.ends_with(".osm") || stem.ends_with(".xml")).then_some(format)
+    }
+
+    /// Tells the format from the first bytes of a file
+    pub fn from_magic(head: &[u8]) -> InputFormat {
+        let text = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+        // A reset followed by the header dataset
+        if head.starts_with(b"\xff\xe0\x04o5m2") {
+            InputFormat::O5m
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            InputFormat::XmlGzip
+        } else if head.starts_with(b"BZh") {
+            InputFormat::XmlBzip2
+        } else if text.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'<') {
+            InputFormat::Xml
+        } else {
+            InputFormat::Pbf
+        }
+    }
+}
+
+// Bytes read to tell the format of an input without a telling extension
+const MAGIC_LEN: u64 = 16;
+
+impl Input {
+    /// Opens the input for reading from the start, transcoding OSM XML into
+    /// a temporary PBF
+    pub fn open(&self) -> Result<InputReader> {
+        let mut reader = self.open_raw()?;
+        let format = match self.format_from_name() {
+            Some(format) => format,
+            // Sniffing a download would cost another request
+            None if matches!(self, Input::Url(_)) => InputFormat::Pbf,
+            None => InputFormat::from_magic(&reader.peek(MAGIC_LEN)?),
+        };
+        match format {
+            InputFormat::Pbf => Ok(reader),
+            format => transcode(reader, format),
+        }
+    }
+
+    fn format_from_name(&self) -> Option<InputFormat> {
+        match self {
+            Input::Path(path) => InputFormat::from_path(path),
+            Input::Stdin => None,
+            Input::Url(url) => InputFormat::from_path(Path::new(url.split(['?', '#']).next()?)),
+            Input::Bytes(_) => None,
+        }
+    }
+
+    fn open_raw(&self) -> Result<InputReader> {
+        Ok(match self {
+            Input::Path(path) => InputReader::File(File::open(path)?),
+            Input::Stdin => InputReader::Stdin(SpooledStdin::new()?),
+            #[cfg(feature = "http")]
+            Input::Url(url) => InputReader::Http(HttpReader::open(url)?),
+            #[cfg(not(feature = "http"))]
+            Input::Url(_) => bail!("reading from URLs requires building with the `http` feature"),
+            Input::Bytes(bytes) => InputReader::Memory(Cursor::new(bytes.clone())),
+        })
+    }
+
+    /// Opens a local file by mapping it into memory, so reads are served
+    /// straight from the OS page cache without copying through a buffer
+    pub fn open_mmap(&self) -> Result<InputReader> {
+        let Input::Path(path) = self else {
+            bail!("{} cannot be memory-mapped; only local files can", self);
+        };
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only read, and changing the file while it is
+        // being extracted is not supported regardless of how it is read
+        let mmap = unsafe { Mmap::map(&file)? };
+        #[cfg(unix)]
+        mmap.advise(memmap2::Advice::Sequential)?;
+        let format = self.format_from_name().unwrap_or_else(|| InputFormat::from_magic(&mmap));
+        let reader = InputReader::Mmap(Cursor::new(mmap));
+        match format {
+            InputFormat::Pbf => Ok(reader),
+            format => transcode(reader, format),
+        }
+    }
+
+    /// The size of the input in bytes, if known before reading it
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Input::Path(path) => path.metadata().ok().map(|m| m.len()),
+            Input::Bytes(bytes) => Some(bytes.len() as u64),
+            _ => None,
+        }
+    }
+
+    /// Size and modification time (in nanoseconds since the Unix epoch) of
+    /// a local file, which change whenever the file does
+    pub fn fingerprint(&self) -> Option<[u64; 2]> {
+        let Input::Path(path) = self else {
+            return None;
+        };
+        let metadata = path.metadata().ok()?;
+        let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some([metadata.len(), modified.as_nanos() as u64])
+    }
+}
+
+impl From<&str> for Input {
+    fn from(s: &str) -> Input {
+        if s == "-" {
+            Input::Stdin
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Input::Url(s.to_string())
+        } else {
+            Input::Path(PathBuf::from(s))
+        }
+    }
+}
+
+impl From<&Path> for Input {
+    fn from(path: &Path) -> Input {
+        match path.to_str() {
+            Some(s) => Input::from(s),
+            None => Input::Path(path.to_path_buf()),
+        }
+    }
+}
+
+impl From<PathBuf> for Input {
+    fn from(path: PathBuf) -> Input {
+        Input::from(path.as_path())
+    }
+}
+
+impl From<&PathBuf> for Input {
+    fn from(path: &PathBuf) -> Input {
+        Input::from(path.as_path())
+    }
+}
+
+impl From<String> for Input {
+    fn from(s: String) -> Input {
+        Input::from(s.as_str())
+    }
+}
+
+impl From<Vec<u8>> for Input {
+    fn from(bytes: Vec<u8>) -> Input {
+        Input::Bytes(bytes.into())
+    }
+}
+
+impl From<&[u8]> for Input {
+    fn from(bytes: &[u8]) -> Input {
+        Input::Bytes(bytes.into())
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Path(path) => write!(f, "{}", path.display()),
+            Input::Stdin => f.write_str("<stdin>"),
+            Input::Url(url) => f.write_str(url),
+            Input::Bytes(bytes) => write!(f, "<{} bytes in memory>", bytes.len()),
+        }
+    }
+}
+
+/// An opened [`Input`]
+pub enum InputReader {
+    File(File),
+    Mmap(Cursor<Mmap>),
+    Memory(Cursor<Arc<[u8]>>),
+    Stdin(SpooledStdin),
+    #[cfg(feature = "http")]
+    Http(HttpReader),
+    /// The temporary PBF an o5m or OSM XML input was converted into, with
+    /// the size of the input if known
+    Transcoded(Box<InputReader>, Option<u64>),
+}
+
+impl InputReader {
+    /// The total size in bytes, if known: always for files, for downloads
+    /// only if the server sent a content length
+    pub fn total_size(&self) -> Option<u64> {
+        match self {
+            InputReader::File(f) => f.metadata().ok().map(|m| m.len()),
+            InputReader::Mmap(m) => Some(m.get_ref().len() as u64),
+            InputReader::Memory(m) => Some(m.get_ref().len() as u64),
+            InputReader::Stdin(_) => None,
+            #[cfg(feature = "http")]
+            InputReader::Http(h) => h.total_size(),
+            InputReader::Transcoded(pbf, _) => pbf.total_size(),
+        }
+    }
+
+    /// The size in bytes of the input as it is stored, before any
+    /// conversion to PBF, if known
+    pub fn source_size(&self) -> Option<u64> {
+        match self {
+            InputReader::Transcoded(_, size) => *size,
+            reader => reader.total_size(),
+        }
+    }
+}
+
+impl InputReader {
+    // Reads the first bytes and goes back to the start
+    fn peek(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut head = Vec::new();
+        match self {
+            InputReader::Stdin(s) => return Ok(s.peek(len)?),
+            reader => {
+                reader.take(len).read_to_end(&mut head)?;
+                reader.rewind()?;
+            }
+        }
+        Ok(head)
+    }
+}
+
+// Converts an o5m or OSM XML input into a temporary PBF, which every pass
+// then reads, with the extent and writing program the input gives in its
+// header. Inputs in memory are converted in memory, as where they come from
+// there may not be any file system.
+fn transcode(reader: InputReader, format: InputFormat) -> Result<InputReader> {
+    if format == InputFormat::Pbf {
+        return Ok(reader);
+    }
+    let source_size = reader.total_size();
+    let pbf = if matches!(reader, InputReader::Memory(_)) {
+        let pbf = transcode_into(reader, format, Vec::new())?;
+        InputReader::Memory(Cursor::new(pbf.into()))
+    } else {
+        let out = transcode_into(reader, format, BufWriter::new(tempfile::tempfile()?))?;
+        let mut file = out.into_inner().map_err(|e| e.into_error())?;
+        file.rewind()?;
+        InputReader::File(file)
+    };
+    Ok(InputReader::Transcoded(Box::new(pbf), source_size))
+}
+
+fn transcode_into<W: Write>(reader: InputReader, format: InputFormat, out: W) -> Result<W> {
+    match format {
+        InputFormat::O5m => {
+            let mut objects = crate::o5m::Reader::new(BufReader::new(reader));
+            // Its bounding box comes before the first object
+            let first = objects.next().transpose()?;
+            let header = Header { bbox: objects.bbox(), ..Header::default() };
+            let mut writer = Writer::with_header(out, &header)?;
+            for obj in first.map(Ok).into_iter().chain(objects) {
+                let (obj, metadata) = obj?;
+                writer.write_with_metadata(obj, metadata)?;
+            }
+            writer.finish()
+        }
+        format => {
+            let (header, objects) = read_xml(reader, format)?;
+            let mut writer = Writer::with_header(out, &header)?;
+            for (obj, metadata) in objects {
+                writer.write_with_metadata(obj, metadata)?;
+            }
+            writer.finish()
+        }
+    }
+}
+
+#[cfg(feature = "xml")]
+fn read_xml(reader: InputReader, format: InputFormat) -> Result<(Header, Vec<(OsmObj, Metadata)>)> {
+    use flate2::read::MultiGzDecoder;
+
+    match format {
+        InputFormat::XmlGzip => crate::xml::read(BufReader::new(MultiGzDecoder::new(reader))),
+        InputFormat::XmlBzip2 => crate::xml::read(BufReader::new(bzip2::read::MultiBzDecoder::new(reader))),
+        _ => crate::xml::read(BufReader::new(reader)),
+    }
+}
+
+#[cfg(not(feature = "xml"))]
+fn read_xml(_reader: InputReader, format: InputFormat) -> Result<(Header, Vec<(OsmObj, Metadata)>)> {
+    bail!("reading {:?} input requires building with the `xml` feature", format)
+}
+
+impl Read for InputReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            InputReader::File(f) => f.read(buf),
+            InputReader::Mmap(m) => m.read(buf),
+            InputReader::Memory(m) => m.read(buf),
+            InputReader::Stdin(s) => s.read(buf),
+            #[cfg(feature = "http")]
+            InputReader::Http(h) => h.read(buf),
+            InputReader::Transcoded(pbf, _) => pbf.read(buf),
+        }
+    }
+}
+
+impl Seek for InputReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            InputReader::File(f) => f.seek(pos),
+            InputReader::Mmap(m) => m.seek(pos),
+            InputReader::Memory(m) => m.seek(pos),
+            InputReader::Stdin(s) => s.seek(pos),
+            #[cfg(feature = "http")]
+            InputReader::Http(h) => h.seek(pos),
+            InputReader::Transcoded(pbf, _) => pbf.seek(pos),
+        }
+    }
+}
+
+/// Reads stdin while keeping a copy in a temporary file, which serves all
+/// reads after the first seek
+pub struct SpooledStdin {
+    stdin: Option<io::Stdin>,
+    spool: File,
+    // Bytes peeked at before the first read, already in the spool
+    head: Cursor<Vec<u8>>,
+}
+
+impl SpooledStdin {
+    fn new() -> Result<SpooledStdin> {
+        Ok(SpooledStdin {
+            stdin: Some(io::stdin()),
+            spool: tempfile::tempfile()?,
+            head: Cursor::new(Vec::new()),
+        })
+    }
+
+    fn peek(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let mut head = Vec::new();
+        if let Some(stdin) = &mut self.stdin {
+            stdin.take(len).read_to_end(&mut head)?;
+            self.spool.write_all(&head)?;
+        }
+        self.head = Cursor::new(head.clone());
+        Ok(head)
+    }
+}
+
+impl Read for SpooledStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.head.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+        match &mut self.stdin {
+            Some(stdin) => {
+                let n = stdin.read(buf)?;
+                self.spool.write_all(&buf[..n])?;
+                Ok(n)
+            }
+            None => self.spool.read(buf),
+        }
+    }
+}
+
+impl Seek for SpooledStdin {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if let Some(mut stdin) = self.stdin.take() {
+            // Whatever the first pass did not read is still needed later
+            io::copy(&mut stdin, &mut self.spool)?;
+        }
+        self.head = Cursor::new(Vec::new());
+        self.spool.seek(pos)
+    }
+}
+
+#[cfg(feature = "http")]
+pub use http::HttpReader;
+
+#[cfg(feature = "http")]
+mod http {
+    use anyhow::{bail, Result};
+    use std::io::{self, Read, Seek, SeekFrom};
+    use std::thread;
+    use std::time::Duration;
+    use tracing::warn;
+
+    // Reconnection attempts per lost connection before giving up
+    const MAX_RETRIES: u32 = 5;
+    // Forward seeks up to this far read on rather than start a new request,
+    // whose round trip costs about as long as reading that much; PBF blobs
+    // are mostly smaller, so skipping them, e.g. for `--dry-run`, keeps to
+    // one connection
+    const MAX_SKIP: u64 = 1 << 20;
+
+    /// Streams a file over HTTP(S), resuming with a range request whenever
+    /// the connection drops; seeks read on if they are short and forward,
+    /// and re-request from the offset otherwise
+    pub struct HttpReader {
+        agent: ureq::Agent,
+        url: String,
+        body: Option<ureq::BodyReader<'static>>,
+        position: u64,
+        length: Option<u64>,
+    }
+
+    impl HttpReader {
+        pub fn open(url: &str) -> Result<HttpReader> {
+            let mut reader = HttpReader {
+                agent: ureq::Agent::new_with_defaults(),
+                url: url.to_string(),
+                body: None,
+                position: 0,
+                length: None,
+            };
+            reader.connect()?;
+            Ok(reader)
+        }
+
+        pub fn total_size(&self) -> Option<u64> {
+            self.length
+        }
+
+        fn connect(&mut self) -> Result<()> {
+            let mut request = self.agent.get(&self.url);
+            if self.position > 0 {
+                request = request.header("Range", format!("bytes={}-", self.position));
+            }
+            let response = request.call()?;
+            if self.position > 0 && response.status() != 206 {
+                bail!("{} does not support range requests", self.url);
+            }
+            if self.position == 0 {
+                self.length = response
+                    .headers()
+                    .get("content-length")
+                    .and_then(|v| v.to_str().ok()?.parse().ok());
+            }
+            self.body = Some(response.into_body().into_reader());
+            Ok(())
+        }
+
+        fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(body) = &mut self.body else {
+                return Err(io::Error::other("no open connection"));
+            };
+            let n = body.read(buf)?;
+            // A body ending before the announced length is a dropped connection
+            if n == 0 && !buf.is_empty() && self.length.is_some_and(|len| self.position < len) {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.position += n as u64;
+            Ok(n)
+        }
+
+        fn reconnect(&mut self, error: &io::Error, attempt: u32) -> io::Result<()> {
+            self.body = None;
+            warn!(
+                "Download interrupted at byte {} ({}). Resuming (attempt {}/{})...",
+                self.position, error, attempt, MAX_RETRIES
+            );
+            thread::sleep(Duration::from_secs(1 << attempt.min(4)));
+            self.connect().map_err(io::Error::other)
+        }
+    }
+
+    impl Read for HttpReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut attempt = 0;
+            loop {
+                let mut error = match self.read_body(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(e) => e,
+                };
+                loop {
+                    attempt += 1;
+                    if attempt > MAX_RETRIES {
+                        return Err(error);
+                    }
+                    match self.reconnect(&error, attempt) {
+                        Ok(()) => break,
+                        Err(e) => error = e,
+                    }
+                }
+            }
+        }
+    }
+
+    impl Seek for HttpReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let position = match pos {
+                SeekFrom::Start(n) => n,
+                SeekFrom::Current(0) => return Ok(self.position),
+                _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "only absolute seeks are supported")),
+            };
+            let skip = position.wrapping_sub(self.position);
+            if self.body.is_some() && skip <= MAX_SKIP {
+                let skipped = io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+                if skipped < skip {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            } else if position != self.position {
+                self.position = position;
+                self.connect().map_err(io::Error::other)?;
+            }
+            Ok(self.position)
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Interned tag strings, so that the keys and values repeated across
+//! millions of features, such as `highway` and `residential`, share one
+//! allocation each instead of being copied into every feature.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The tags of a feature
+pub type TagMap = HashMap<Tag, Tag>;
+
+/// A tag key or value: a shared, immutable string that is cheap to clone
+/// and behaves like a `&str`, e.g. `tags.get("highway")` looks it up and
+/// `value == "yes"` compares it
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag(Arc<str>);
+
+impl Tag {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Tag {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Tag {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Tag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Tag {
+    fn from(s: &str) -> Tag {
+        Tag(Arc::from(s))
+    }
+}
+
+impl From<String> for Tag {
+    fn from(s: String) -> Tag {
+        Tag(Arc::from(s))
+    }
+}
+
+impl From<&String> for Tag {
+    fn from(s: &String) -> Tag {
+        Tag(Arc::from(s.as_str()))
+    }
+}
+
+impl From<Tag> for String {
+    fn from(tag: Tag) -> String {
+        tag.0.to_string()
+    }
+}
+
+impl PartialEq<str> for Tag {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Tag {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Tag {
+    fn eq(&self, other: &String) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Serialize for Tag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tag, D::Error> {
+        String::deserialize(deserializer).map(Tag::from)
+    }
+}
+
+/// A pool of the distinct tag strings of an extraction, handing out the
+/// same [`Tag`] for equal strings. Clones share the pool.
+///
+/// Strings no feature holds any more are swept out whenever the pool has
+/// doubled in size since the last sweep, so that streaming a planet does
+/// not keep every name it has seen.
+#[derive(Clone, Default)]
+pub struct Interner(Arc<Mutex<Pool>>);
+
+#[derive(Default)]
+struct Pool {
+    tags: HashSet<Tag>,
+    sweep_at: usize,
+}
+
+// The smallest pool that is swept
+const MIN_SWEEP: usize = 1 << 16;
+
+impl Interner {
+    pub fn intern(&self, s: &str) -> Tag {
+        let mut pool = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(tag) = pool.tags.get(s) {
+            return tag.clone();
+        }
+        if pool.tags.len() >= pool.sweep_at.max(MIN_SWEEP) {
+            pool.tags.retain(|tag| Arc::strong_count(&tag.0) > 1);
+            pool.sweep_at = pool.tags.len() * 2;
+        }
+        let tag = Tag::from(s);
+        pool.tags.insert(tag.clone());
+        tag
+    }
+
+    /// The number of distinct strings in the pool
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for Interner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interner").field("strings", &self.len()).finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! Spatial join of the extracted features against a polygon layer, e.g. to
+//! tag each construction site with the district it lies in.
+
+use crate::feature::ExtractedFeature;
+use crate::spatial;
+use anyhow::{bail, Context, Result};
+use geo::{Contains, Geometry, InteriorPoint, Point, Polygon};
+use rstar::primitives::GeomWithData;
+use rstar::{RTree, AABB};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Prefix of the properties [`JoinLayer::apply`] adds, followed by the key
+/// of the polygon attribute, e.g. `@join:name`
+pub const JOIN_TAG_PREFIX: &str = "@join:";
+
+/// The polygons of a GeoJSON layer with the attributes to copy, indexed in
+/// an in-memory R-tree
+#[derive(Debug, Clone)]
+pub struct JoinLayer {
+    tree: RTree<GeomWithData<Polygon, usize>>,
+    // Per feature of the layer, the copied attributes it has
+    attributes: Vec<Vec<(String, String)>>,
+}
+
+impl JoinLayer {
+    /// Reads the Polygon and MultiPolygon features of a GeoJSON file,
+    /// keeping their properties named in `keys`
+    pub fn from_file(path: &Path, keys: &[String]) -> Result<JoinLayer> {
+        let text = fs::read_to_string(path).with_context(|| format!("cannot read join layer {:?}", path))?;
+        let doc: Value = serde_json::from_str(&text).with_context(|| format!("invalid GeoJSON in join layer {:?}", path))?;
+        let features = match doc["type"].as_str() {
+            Some("FeatureCollection") => doc["features"].as_array().cloned().unwrap_or_default(),
+            Some("Feature") => vec![doc],
+            _ => bail!("join layer {:?} must be a GeoJSON Feature or FeatureCollection", path),
+        };
+
+        let mut polygons = Vec::new();
+        let mut attributes = Vec::new();
+        for feature in &features {
+            let geometry = spatial::parse_geojson_polygons(&feature["geometry"].to_string())?;
+            if geometry.0.is_empty() {
+                continue;
+            }
+            let values = keys
+                .iter()
+                .filter_map(|key| {
+                    let value = match &feature["properties"][key] {
+                        Value::Null => return None,
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    Some((format!("{}{}", JOIN_TAG_PREFIX, key), value))
+                })
+                .collect();
+            polygons.extend(geometry.0.into_iter().map(|polygon| GeomWithData::new(polygon, attributes.len())));
+            attributes.push(values);
+        }
+        if polygons.is_empty() {
+            bail!("join layer {:?} does not contain any polygons", path);
+        }
+        Ok(JoinLayer { tree: RTree::bulk_load(polygons), attributes })
+    }
+
+    /// Adds the attributes of the polygon the feature falls in, judged by
+    /// the feature's point or a point on its line or inside its polygon.
+    /// Where polygons overlap the first one in the layer wins; features
+    /// outside of all of them are left as they are.
+    pub fn apply(&self, feature: &mut ExtractedFeature) {
+        let Some(attributes) = self.attributes_at(feature.geometry()) else {
+            return;
+        };
+        let tags = feature.tags_mut();
+        for (key, value) in attributes {
+            tags.insert(key.into(), value.into());
+        }
+    }
+
+    /// The attributes [`JoinLayer::apply`] adds for a geometry, as
+    /// `(@join:key, value)` pairs, or `None` outside of all polygons
+    pub fn attributes_at(&self, geometry: &Geometry) -> Option<&[(String, String)]> {
+        let point = match geometry {
+            Geometry::Point(point) => Some(*point),
+            geometry => geometry.interior_point(),
+        };
+        point.and_then(|point| self.containing(point)).map(|found| self.attributes[found].as_slice())
+    }
+
+    // The first feature of the layer containing the point
+    fn containing(&self, point: Point) -> Option<usize> {
+        self.tree
+            .locate_in_envelope_intersecting(&AABB::from_point(point))
+            .filter(|polygon| polygon.geom().contains(&point))
+            .map(|polygon| polygon.data)
+            .min()
+    }
+}
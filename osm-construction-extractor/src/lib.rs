@@ -0,0 +1,72 @@
+//! Fast extraction of tagged OSM objects from `.osm.pbf` files.
+//!
+//! The [`Extractor`] reads a PBF file (or o5m or OSM XML, see [`input`]), selects objects with a tag
+//! [`Filter`] and assembles their geometries into [`ExtractedFeature`]s,
+//! which can then be written out with one of the [`output`] writers.
+
+pub mod address;
+pub mod admin;
+pub mod area;
+pub mod bench;
+pub mod building;
+pub mod cancel;
+pub mod centroid;
+pub mod checkpoint;
+pub mod cleanup;
+pub mod coastline;
+pub mod compact;
+pub mod config;
+pub mod construction;
+pub mod crs;
+pub mod diff;
+pub mod estimate;
+pub mod extractor;
+pub mod feature;
+pub mod filter;
+pub mod graph;
+#[cfg(feature = "opening-hours")]
+pub mod hours;
+pub mod ids;
+pub mod index;
+pub mod info;
+pub mod input;
+pub mod intern;
+pub mod join;
+pub mod logging;
+pub mod measure;
+pub mod memory;
+pub mod merge;
+pub mod metadata;
+pub mod multipolygon;
+pub mod normalize;
+pub mod o5m;
+pub mod output;
+pub mod overpass;
+pub mod pbf;
+pub mod preset;
+pub mod profile;
+pub mod route;
+pub mod schema;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod simplify;
+pub mod sort;
+pub mod spatial;
+pub mod split;
+pub mod stats;
+pub mod store;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod summary;
+#[cfg(feature = "tiles")]
+pub mod tiles;
+#[cfg(feature = "xml")]
+pub mod update;
+pub mod validity;
+#[cfg(feature = "xml")]
+pub mod xml;
+
+pub use extractor::{ExtractionStats, Extractor};
+pub use feature::{BuildOptions, ExtractedFeature, OsmType};
+pub use filter::Filter;
+pub use input::Input;
@@ -0,0 +1,128 @@
+//! Logging of progress messages and warnings through `tracing`, as plain
+//! lines for people or as JSON for schedulers and log collectors.
+//!
+//! The library only emits events; [`init`] installs the subscriber the
+//! command line tool writes them with. Messages go to stderr, leaving
+//! stdout to reports such as those of `stats` and `bench`. Warnings are
+//! also kept for the summary of a run, see [`warnings`].
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How log messages are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines, warnings and errors marked as such
+    #[default]
+    Text,
+    /// One JSON object per line with `timestamp`, `level`, `target` and
+    /// `message`
+    Json,
+}
+
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Whether progress bars are drawn: not next to JSON logs, whose lines
+/// they would break up, nor once info messages are filtered out
+pub fn shows_progress() -> bool {
+    PROGRESS.load(Ordering::Relaxed)
+}
+
+/// The messages of the warnings logged since [`init`], as far as the level
+/// let them through
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().map(|warnings| warnings.clone()).unwrap_or_default()
+}
+
+/// The level shown for a number of `-v` and `-q` flags: info by default,
+/// debug and trace with one or two `-v`, only warnings and errors with one
+/// `-q` and only errors with two
+pub fn level(verbose: u8, quiet: u8) -> LevelFilter {
+    match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Installs the global subscriber writing messages of this crate from
+/// `level` up to stderr; other crates only get to log warnings and errors
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<()> {
+    PROGRESS.store(format == LogFormat::Text && level >= LevelFilter::INFO, Ordering::Relaxed);
+    let filter = Targets::new()
+        .with_default(level.min(LevelFilter::WARN))
+        .with_target(env!("CARGO_CRATE_NAME"), level);
+    let registry = tracing_subscriber::registry().with(filter).with(Recorder);
+    let result = match format {
+        LogFormat::Text => registry
+            .with(tracing_subscriber::fmt::layer().with_writer(io::stderr).event_format(Plain))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json().flatten_event(true).with_writer(io::stderr))
+            .try_init(),
+    };
+    result.map_err(|e| anyhow!("cannot install the logger: {}", e))
+}
+
+// Keeps the message of every warning for `warnings`
+struct Recorder;
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() == Level::WARN {
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            if let Ok(mut warnings) = WARNINGS.lock() {
+                warnings.push(message.0);
+            }
+        }
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+// The message alone for info, as the tool always printed it, and prefixed
+// with its level otherwise
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "Error: ")?,
+            Level::WARN => write!(writer, "Warning: ")?,
+            Level::INFO => {}
+            Level::DEBUG => write!(writer, "Debug: ")?,
+            Level::TRACE => write!(writer, "Trace: ")?,
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
@@ -1,141 +1,2169 @@
-use anyhow::Result;
-use clap::Parser;
-use geo::{Coord, LineString};
-use indicatif::{ProgressBar, ProgressStyle};
-use osmpbfreader::{OsmId, OsmObj, OsmPbfReader, WayId};
-use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use geo::{Geometry, Intersects, Point, Rect};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use osm_construction_extractor::{address, admin, coastline};
+use osm_construction_extractor::bench::{self, BenchOptions};
+use osm_construction_extractor::cancel::CancelToken;
+use osm_construction_extractor::centroid::OutputGeometry;
+use osm_construction_extractor::compact::FeatureStore;
+use osm_construction_extractor::config;
+use osm_construction_extractor::construction::{self, Monitor, Period};
+use osm_construction_extractor::crs::Crs;
+use osm_construction_extractor::diff;
+use osm_construction_extractor::estimate;
+use osm_construction_extractor::measure::Measure;
+use osm_construction_extractor::logging::{self, LogFormat};
+use osm_construction_extractor::memory;
+use osm_construction_extractor::graph::{self, GraphFormat};
+#[cfg(feature = "opening-hours")]
+use osm_construction_extractor::hours;
+use osm_construction_extractor::ids;
+use osm_construction_extractor::index::{FeatureIndex, DISTANCE_TAG};
+use osm_construction_extractor::info;
+use osm_construction_extractor::join::JoinLayer;
+use osm_construction_extractor::metadata;
+use osm_construction_extractor::output::compress::Compression;
+use osm_construction_extractor::output::ids::IdWriter;
+use osm_construction_extractor::output::{self, geojson, OutputFormat, WriteOptions};
+#[cfg(feature = "arrow")]
+use osm_construction_extractor::output::arrow;
+#[cfg(feature = "postgis")]
+use osm_construction_extractor::output::postgis::{self, PostgisOptions, TagStorage};
+use osm_construction_extractor::preset::Preset;
+use osm_construction_extractor::profile::Profile;
+use osm_construction_extractor::schema::TagSchema;
+use osm_construction_extractor::sort::{self, SortOrder};
+use osm_construction_extractor::spatial::{self, Boundary, ClipMode};
+use osm_construction_extractor::split::{self, SplitBy, SplitMode};
+use osm_construction_extractor::stats::Report;
+use osm_construction_extractor::summary::{ExitStatus, Summary};
+use osm_construction_extractor::store::NodeIndexKind;
+#[cfg(feature = "tiles")]
+use osm_construction_extractor::tiles::{self, TileFormat, TileOptions};
+#[cfg(feature = "xml")]
+use osm_construction_extractor::update::{self, State};
+use osm_construction_extractor::validity::{self, InvalidMode};
+use osm_construction_extractor::{ExtractedFeature, ExtractionStats, Extractor, Filter, Input, OsmType};
+use osmpbfreader::OsmId;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::OnceLock;
 use std::time::Instant;
-
-// A simplified struct to hold our final extracted data in memory
-#[derive(Debug)]
-struct ConstructionWay {
-    id: WayId,
-    tags: HashMap<String, String>,
-    geometry: LineString,
-}
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, after_help = EXIT_CODES)]
 struct Args {
-    /// Path to the input OSM PBF file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    select: SelectArgs,
+
+    /// Read the options of the extraction from this TOML or YAML file,
+    /// keyed by their long names, e.g. `filter = "building=*"` or
+    /// `output = ["a.fgb", "a.parquet"]`; options also given on the command
+    /// line take their values from there
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Extract several layers in one pass, each with its own filter and
+    /// output file, as described by a YAML or TOML profile
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "format", "columns", "layer", "filter", "types", "preset", "invert", "ids", "id_file"]
+    )]
+    profile: Option<PathBuf>,
+
+    /// Write the extracted features to this file; repeat to write several
+    /// files, e.g. GeoJSON and GeoParquet, from the same extraction. `-`
+    /// writes GeoJSON, GeoJSONSeq, CSV, TSV or Arrow IPC to stdout
+    #[arg(short, long)]
+    output: Vec<PathBuf>,
+
+    /// Output format; guessed from the output file extension if omitted,
+    /// which it has to be for several outputs
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Only list the ids of the matched objects, e.g. `w123`, one per line,
+    /// or as a JSON array for an output ending in `.json`, on stdout without
+    /// --output; node locations and members are not looked up, so this
+    /// takes about as long as reading the file
+    #[arg(
+        long,
+        conflicts_with_all = ["profile", "format", "preset", "bbox", "clip_poly", "sort", "split_by", "split_by_polygons"]
+    )]
+    ids_only: bool,
+
+    /// Only estimate the matched features, output size, memory and runtime
+    /// from the header of the input and a sample of its blocks, without
+    /// extracting anything; takes a fraction of a second on any local file,
+    /// and for a URL about as long as downloading it
+    #[arg(long, conflicts_with_all = ["profile", "ids_only"])]
+    dry_run: bool,
+
+    /// Write the counts, durations, warnings and outputs of the extraction
+    /// and how it ended as JSON to this file, e.g. for CI to check; the exit
+    /// code tells the ending too, see below
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    summary_json: Option<PathBuf>,
+
+    /// Exit with 3 rather than 0 if nothing matched, and with 4 if matches
+    /// were left out as their geometry could not be built, e.g. ways with
+    /// nodes outside a regional extract
+    #[arg(long, conflicts_with = "dry_run")]
+    strict_exit: bool,
+
+    /// Write one file per part instead, named after the output with the
+    /// part appended: per web map tile of a zoom level, e.g. `tiles:z8`
+    /// giving `out_8_134_85.gpkg`, per cell of a grid of that many degrees,
+    /// e.g. `grid:0.5` or just `0.5`, or per value of a tag, e.g.
+    /// `tag:highway` giving `out_residential.gpkg` and `out_no_highway.gpkg`
+    #[arg(long, value_name = "SPLIT", conflicts_with = "profile")]
+    split_by: Option<SplitBy>,
+
+    /// Write one file per polygon of this GeoJSON file instead, e.g. per
+    /// country, named after its --split-key property; features go to the
+    /// polygon they lie in, judged as for --join, or to `out_no_KEY.gpkg`
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["profile", "split_by"], requires = "split_key")]
+    split_by_polygons: Option<PathBuf>,
+
+    /// Property of the --split-by-polygons polygons naming their files,
+    /// e.g. `iso_a2`
+    #[arg(long, alias = "key", value_name = "KEY", requires = "split_by_polygons")]
+    split_key: Option<String>,
+
+    /// Which parts of an area features crossing their edges go to:
+    /// `centroid` writes them only to the part their centroid lies in,
+    /// `clip` to every part they cross, cut at its edges
+    #[arg(long, value_enum, default_value_t = SplitMode::Centroid, requires = "split_by")]
+    split_mode: SplitMode,
+
+    /// Compress GeoJSON, GeoJSONSeq, CSV and TSV output while writing it:
+    /// `gzip` or `zstd`, optionally with a level, e.g. `zstd:19`; outputs
+    /// ending in `.gz` or `.zst` are compressed without it
+    #[arg(long, value_name = "METHOD[:LEVEL]")]
+    compress: Option<Compression>,
+
+    /// Remove repeated points and segments of no length, and close area ways
+    /// whose ends are at most this many meters apart (1 if left out) into
+    /// polygons; what was fixed is listed in `@fixes`
+    #[arg(long, value_name = "METERS", num_args = 0..=1, default_missing_value = "1")]
+    fix_geometry: Option<f64>,
+
+    /// Check polygons for unclosed, misoriented, crossing or touching rings
+    /// and overlapping parts: `flag` sets `@valid` and `@invalid_reason`,
+    /// `drop` leaves invalid ones out, `repair` rebuilds them from the area
+    /// they enclose, noting what was wrong in `@repaired`
+    #[arg(long, value_enum, value_name = "MODE")]
+    invalid: Option<InvalidMode>,
+
+    /// Add computed properties: `length` adds the geodesic length of lines
+    /// in meters as `@length`, `area` the area of polygons in m² as `@area`,
+    /// `bbox` the bounding box as `@bbox` (the `bbox` member in GeoJSON)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    compute: Vec<Measure>,
+
+    /// Add measurement tags such as `maxspeed=50 mph`, `width=3,5 m` or
+    /// `maxheight=12'` in SI units (m, m/s, kg) as `@si:maxspeed` etc.,
+    /// and `opening_hours` normalized as `@opening_hours`, keeping the tags
+    /// as they are
+    #[arg(long)]
+    normalize: bool,
+
+    /// Write only these tags, by key with `*` and `?` wildcards, e.g.
+    /// `highway,construction,name,ref`, and the computed `@` properties
+    #[arg(long, value_delimiter = ',', value_name = "KEYS", conflicts_with = "all_tags")]
+    keep_tags: Vec<glob::Pattern>,
+
+    /// Write all tags, the default
+    #[arg(long)]
+    all_tags: bool,
+
+    /// Remove tags from the output by key, with `*` and `?` wildcards, e.g.
+    /// `created_by,source:*`
+    #[arg(long, value_delimiter = ',', value_name = "KEYS")]
+    drop_tags: Vec<glob::Pattern>,
+
+    /// Join ways with identical tags that continue each other, as where a
+    /// road was split for a bridge, into one line; the tags compared are
+    /// those left after --drop-tags, and merged lines list their ways in
+    /// `@way_ids`
+    #[arg(long)]
+    merge_ways: bool,
+
+    /// Tag each feature with attributes of the polygon of this GeoJSON file
+    /// it lies in, e.g. the district, as `@join:<key>` properties
+    #[arg(long, value_name = "FILE", requires = "join_key")]
+    join: Option<PathBuf>,
+
+    /// Properties of the --join polygons copied to the features, e.g.
+    /// `name,admin_level`
+    #[arg(long, value_delimiter = ',', value_name = "KEYS", requires = "join")]
+    join_key: Vec<String>,
+
+    /// Order of the written features; anything but `none` holds all of them
+    /// in memory before writing, also for GeoJSONSeq
+    #[arg(long, value_enum, default_value_t = SortOrder::None)]
+    sort: SortOrder,
+
+    /// Geometry written for each feature: the full one, or a point for
+    /// heatmaps and clustering, either the centroid or a point guaranteed
+    /// to lie on the line or inside the polygon
+    #[arg(long, value_enum, default_value_t = OutputGeometry::Full)]
+    geometry: OutputGeometry,
+
+    /// Simplify lines and polygons, dropping detail below this many meters
+    #[arg(long, value_name = "METERS")]
+    simplify: Option<f64>,
+
+    /// Reproject the output from WGS84, e.g. `EPSG:3857` or a PROJ string
+    /// (anything but EPSG:4326/3857 requires the `proj` feature)
+    #[arg(long, value_name = "CRS")]
+    to_crs: Option<Crs>,
+
+    /// Round output coordinates to this many decimals, e.g. 6 (about 10 cm),
+    /// shrinking GeoJSON, CSV and WKT output; with --to-crs in its units
+    #[arg(long, value_name = "DECIMALS", value_parser = clap::value_parser!(u8).range(0..=15))]
+    precision: Option<u8>,
+
+    /// Tag keys written as separate CSV/TSV/GeoPackage/SpatiaLite/
+    /// GeoParquet/Arrow/DuckDB columns instead of a `tags` column; for shapefiles
+    /// the tags written, by default all
+    #[arg(long, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// YAML or TOML file mapping tags to typed columns, e.g. `lanes:int` or
+    /// `width:float_m`, for GeoParquet, Arrow, GeoPackage, SpatiaLite,
+    /// DuckDB, CSV/TSV and PostGIS
+    #[arg(long, value_name = "FILE", value_parser = parse_schema, conflicts_with = "columns")]
+    schema: Option<TagSchema>,
+
+    /// GeoPackage layer or SpatiaLite or DuckDB table name; defaults to the
+    /// output file name
+    #[arg(long, visible_alias = "table")]
+    layer: Option<String>,
+
+    /// Load the extracted features into PostGIS, e.g. `postgresql://user@localhost/gis`
+    #[cfg(feature = "postgis")]
+    #[arg(long, value_name = "URL")]
+    postgis: Option<String>,
+
+    /// Schema of the PostGIS table
+    #[cfg(feature = "postgis")]
+    #[arg(long, default_value = "public", requires = "postgis")]
+    postgis_schema: String,
+
+    /// Name of the PostGIS table to create
+    #[cfg(feature = "postgis")]
+    #[arg(long, default_value = "osm_features", requires = "postgis")]
+    postgis_table: String,
+
+    /// How tags are stored in the PostGIS table
+    #[cfg(feature = "postgis")]
+    #[arg(long, value_enum, default_value_t = TagStorage::Jsonb, requires = "postgis")]
+    postgis_tags: TagStorage,
+
+    /// Replace the PostGIS table if it already exists
+    #[cfg(feature = "postgis")]
+    #[arg(long, requires = "postgis")]
+    postgis_overwrite: bool,
+
+    /// Log more: debug messages with -v, trace messages with -vv
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less: only warnings and errors with -q, only errors with -qq
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Write log messages as plain text or as JSON lines, e.g. for
+    /// schedulers collecting the logs of jobs
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Report counts, tag values, way lengths and way sizes of the matched
+    /// objects instead of writing features, e.g. to size an extraction
+    Stats(StatsArgs),
+    /// Print what the header of a PBF says, its bbox, features, writing
+    /// program and replication state, and how many objects of each type
+    /// it holds
+    Info(InfoArgs),
+    /// Cut the extracted features into vector tiles and write them as an
+    /// MBTiles or PMTiles archive, ready to be shown on a web map
+    #[cfg(feature = "tiles")]
+    Tiles(TilesArgs),
+    /// Run the extraction several times and report the min, median and max
+    /// duration of each phase and the peak memory, optionally as JSON
+    Bench(BenchArgs),
+    /// Export the routable highway network as a graph of nodes and directed
+    /// edges, with oneway streets, maxspeeds and turn restrictions
+    Graph(GraphArgs),
+    /// Collect everything with a house number into a table of street,
+    /// house number, postcode, city and a point, e.g. for geocoding
+    Addresses(AddressesArgs),
+    /// Assemble the polygons of administrative boundaries and link each to
+    /// the boundary it lies in, with a flag telling whether it is valid
+    Boundaries(BoundariesArgs),
+    /// Join `natural=coastline` ways into land polygons, optionally split
+    /// into a grid of cells, and report the coastline left open
+    Coastline(CoastlineArgs),
+    /// Run the same filter on two versions of a file, e.g. last month's
+    /// and today's, and write the added, removed, geometry-changed and
+    /// tag-changed features as separate layers
+    Diff(DiffArgs),
+    /// Keep an extraction up to date with OSM change files (.osc, .osc.gz)
+    /// and write only the features they created, modified or deleted
+    #[cfg(feature = "xml")]
+    Update(UpdateArgs),
+    /// Report the matching features created, modified or deleted between
+    /// two dates according to OSM change files, with who changed them in
+    /// which changeset
+    #[cfg(feature = "xml")]
+    Changes(ChangesArgs),
+    /// Look up features in an index written by an extraction to a `.fidx`
+    /// file, by box, point or radius, without reading the PBF again
+    Query(QueryArgs),
+    /// Answer Overpass QL queries over HTTP, like the Overpass API at
+    /// `/api/interpreter`, from a local extract or index, and run
+    /// extractions POSTed to `/extract`
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+    /// Print the completion script of a shell, e.g. for bash to `source`
+    /// or to put in `~/.local/share/bash-completion/completions`
+    Completions(CompletionsArgs),
+    /// Print the man page, or write one per subcommand to a directory
+    Man(ManArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct StatsArgs {
+    #[command(flatten)]
+    select: SelectArgs,
+
+    /// Number of most common values listed per filter key
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// The files to describe; `-` reads stdin and `http(s)://` URLs are
+    /// streamed
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompletionsArgs {
+    /// The shell to complete the options in
+    #[arg(value_enum)]
+    shell: Shell,
+}
+
+#[derive(clap::Args, Debug)]
+struct ManArgs {
+    /// Write `osm-construction-extractor.1` and a page for each subcommand,
+    /// e.g. `osm-construction-extractor-stats.1`, to this directory instead
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchArgs {
+    #[command(flatten)]
+    select: SelectArgs,
+
+    /// Number of measured runs
+    #[arg(long, default_value_t = 5)]
+    runs: usize,
+
+    /// Number of unmeasured runs before them, e.g. to warm the page cache
+    #[arg(long, default_value_t = 1)]
+    warmup: usize,
+
+    /// Also time writing the features in this format, to a temporary file
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Write the results as JSON to this file, e.g. to compare releases
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct GraphArgs {
+    /// Path to the input file(s), as for extraction
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Tag filter selecting the routable ways, e.g. `highway=primary,secondary`
+    /// for a road network of major roads only
+    #[arg(short, long, default_value = graph::ROUTABLE_FILTER)]
+    filter: Filter,
+
+    /// Only include ways crossing this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Write the graph to this file; CSV output is split into `_nodes`,
+    /// `_edges` and `_restrictions` files named after it
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Graph format; guessed from the output file extension if omitted
+    #[arg(long, value_enum)]
+    format: Option<GraphFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct AddressesArgs {
+    /// Path to the input file(s), as for extraction
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Only include addresses inside this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Write the addresses to this file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output format; guessed from the output file extension, CSV if that
+    /// fails
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct BoundariesArgs {
+    /// Path to the input file(s), as for extraction
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Only include boundaries intersecting this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Only include these admin levels, e.g. `2,4,6`; parents are looked
+    /// for among the included boundaries only
+    #[arg(long, value_delimiter = ',')]
+    levels: Vec<u8>,
+
+    /// Write the boundaries to this file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output format; guessed from the output file extension, GeoJSON if
+    /// that fails
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct CoastlineArgs {
+    /// Path to the input file(s), as for extraction
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Only read coastline ways intersecting this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Join open ends of the coastline at most this many meters apart
+    /// (0 = only at shared nodes)
+    #[arg(long, default_value_t = 1000.0)]
+    close_distance: f64,
+
+    /// Split the land polygons into cells of this many degrees, e.g. 1
+    #[arg(long)]
+    grid: Option<f64>,
+
+    /// Write the land polygons to this file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Write the coastline left open to this file, as lines, to find where
+    /// it is broken
+    #[arg(long)]
+    open_output: Option<PathBuf>,
+
+    /// Output format; guessed from the output file extension, GeoJSON if
+    /// that fails
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// The older file
+    old: PathBuf,
+
+    /// The newer file
+    new: PathBuf,
+
+    /// Tag filter expression selecting the features to compare
+    #[arg(short, long, default_value = "highway=* and construction=*")]
+    filter: Filter,
+
+    /// OSM object types the filter is applied to
+    #[arg(short, long, value_delimiter = ',', default_value = "way")]
+    types: Vec<OsmType>,
+
+    /// Only compare features intersecting this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Name the layer files after this file, e.g. `diff.geojson` gives
+    /// `diff_added.geojson`, `diff_removed.geojson`, `diff_geometry.geojson`
+    /// and `diff_tags.geojson`
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Layer format; guessed from the output file extension if omitted
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[cfg(feature = "xml")]
+#[derive(clap::Args, Debug)]
+struct UpdateArgs {
+    /// Directory holding the matched objects between updates
+    #[arg(long, value_name = "DIR")]
+    state: PathBuf,
+
+    /// Create the state (replacing any in DIR) by extracting from this
+    /// file, which should be as recent as the first change file applied
+    #[arg(long, value_name = "FILE")]
+    init: Option<PathBuf>,
+
+    /// Tag filter of the state created with --init
+    #[arg(short, long, default_value = "highway=* and construction=*", requires = "init")]
+    filter: Filter,
+
+    /// OSM object types of the state created with --init
+    #[arg(short, long, value_delimiter = ',', default_value = "way", requires = "init")]
+    types: Vec<OsmType>,
+
+    /// Change files to apply, oldest first, e.g. a day of minutely diffs
+    #[arg(required_unless_present = "init")]
+    changes: Vec<PathBuf>,
+
+    /// Write the changed features to this file, each with an
+    /// `@change_type` of `create`, `modify` or `delete`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format; guessed from the output file extension if omitted
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[cfg(feature = "xml")]
+#[derive(clap::Args, Debug)]
+struct ChangesArgs {
+    /// Change files covering the time range, oldest first
+    #[arg(required = true)]
+    changes: Vec<PathBuf>,
+
+    /// OSM file from before the first change file, whose objects tell what
+    /// matched before and where the nodes of edited ways are
+    #[arg(long, value_name = "FILE")]
+    base: Option<PathBuf>,
+
+    /// Tag filter expression selecting the features to report
+    #[arg(short, long, default_value = "highway=* and construction=*")]
+    filter: Filter,
+
+    /// OSM object types the filter is applied to
+    #[arg(short, long, value_delimiter = ',', default_value = "way")]
+    types: Vec<OsmType>,
+
+    /// Only report changes made at or after this UTC date or time
+    #[arg(long, value_parser = metadata::parse_timestamp)]
+    since: Option<i64>,
+
+    /// Only report changes made before this UTC date or time
+    #[arg(long, value_parser = metadata::parse_timestamp)]
+    until: Option<i64>,
+
+    /// Changeset files (e.g. the changeset replication files) to add the
+    /// changeset comment of each feature from, as `@comment`
+    #[arg(long, value_name = "FILE", num_args = 1..)]
+    changesets: Vec<PathBuf>,
+
+    /// Write the changed features to this file, each with an
+    /// `@change_type` of `create`, `modify` or `delete` and the metadata of
+    /// its last edit
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output format; guessed from the output file extension if omitted
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct QueryArgs {
+    /// The index, written with `-o FILE.fidx` or `--format index`
+    index: PathBuf,
+
+    /// Find the features intersecting this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true, required_unless_present = "point", conflicts_with = "point")]
+    bbox: Option<Rect>,
+
+    /// Find the features at this point, `lon,lat`, e.g. the polygons
+    /// containing it, or with --radius those near it
+    #[arg(long, value_parser = spatial::parse_point, allow_hyphen_values = true)]
+    point: Option<Point>,
+
+    /// Find the features within this many meters of --point instead,
+    /// nearest first, with their distance as `@distance`
+    #[arg(long, value_name = "METERS", requires = "point")]
+    radius: Option<f64>,
+
+    /// Write the features to this file instead of printing them to stdout
+    /// as GeoJSONSeq
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format; guessed from the output file extension if omitted
+    #[arg(long, value_enum, requires = "output")]
+    format: Option<OutputFormat>,
+}
+
+#[cfg(feature = "serve")]
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Path to the input file(s), as for extraction, or an index written
+    /// with `-o FILE.fidx`, which is served as is but cannot be extracted from
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Tag filter expression selecting the objects Overpass QL is answered
+    /// from; all tagged objects if omitted
     #[arg(short, long)]
-    input: PathBuf,
+    filter: Option<Filter>,
+
+    /// OSM object types Overpass QL is answered from
+    #[arg(short, long, value_delimiter = ',', default_value = "node,way,relation")]
+    types: Vec<OsmType>,
+
+    /// Only answer Overpass QL from objects intersecting this box:
+    /// min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Address to listen on; 0.0.0.0 to accept queries from other machines
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    /// Port to listen on
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// Number of threads decoding PBF blocks and answering queries (0 =
+    /// one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[cfg(feature = "tiles")]
+#[derive(clap::Args, Debug)]
+struct TilesArgs {
+    #[command(flatten)]
+    select: SelectArgs,
+
+    /// Write the tile archive to this file
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Archive format; guessed from the output file extension if omitted
+    #[arg(long, value_enum)]
+    format: Option<TileFormat>,
+
+    /// Lowest zoom level to generate tiles for
+    #[arg(long, default_value_t = 0)]
+    min_zoom: u8,
+
+    /// Highest zoom level to generate tiles for; maps can overzoom beyond it
+    #[arg(long, default_value_t = 14)]
+    max_zoom: u8,
+
+    /// Name of the vector tile layer holding the features; defaults to the
+    /// output file name
+    #[arg(long)]
+    layer: Option<String>,
+}
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0    Success
+  1    Any other error
+  2    Invalid arguments
+  3    Nothing matched, with --strict-exit
+  4    Matches were left out as their geometry could not be built, with --strict-exit
+  5    Reading the input or writing an output failed
+  130  Interrupted by Ctrl-C, the outputs holding the features extracted before";
+
+// Selecting objects by id replaces what selects them by their tags
+const ID_CONFLICTS: [&str; 5] = ["filter", "types", "node_filter", "invert", "preset"];
+
+// Options selecting what is read and matched, shared by all commands
+#[derive(clap::Args, Debug)]
+struct SelectArgs {
+    /// Path to the input OSM PBF, o5m or OSM XML (`.osm`, `.osm.bz2`,
+    /// `.osm.gz`) file, `-` for stdin or an http(s) URL;
+    /// repeat it or pass a glob such as `'extracts/*.osm.pbf'` to process
+    /// several files, with objects that appear in more than one of them
+    /// emitted only once
+    #[arg(short, long, required = true, num_args = 1..)]
+    input: Vec<PathBuf>,
+
+    /// Tag filter expression selecting the objects to extract,
+    /// e.g. `building=*`, `amenity=cafe,bar`, `highway=* and not access=private`
+    /// or the regex match `ref~"^A[0-9]+$"`; the tag filters of Overpass QL
+    /// work too, e.g. `[highway][!construction]` or `["name"~"park",i]`
+    #[arg(short, long, default_value = "highway=* and construction=*")]
+    filter: Filter,
+
+    /// Separate tag filter for nodes, e.g. `highway=traffic_signals`, which
+    /// are then emitted as points alongside the objects matched by
+    /// `--filter`; with `--types node` only the nodes are extracted
+    #[arg(long)]
+    node_filter: Option<Filter>,
+
+    /// Tag filter for objects to leave out even though they match, e.g.
+    /// `access=private or disused=yes`
+    #[arg(long)]
+    exclude_filter: Option<Filter>,
+
+    /// Extract the tagged objects NOT matching `--filter` instead, e.g. a
+    /// base map without the construction sites
+    #[arg(long)]
+    invert: bool,
+
+    /// OSM object types the filter is applied to
+    #[arg(short, long, value_delimiter = ',', default_value = "way")]
+    types: Vec<OsmType>,
+
+    /// Extract the objects with these ids, e.g. `w123,n456,r789`, and what
+    /// they reference, whatever their tags, instead of those matching
+    /// `--filter`
+    #[arg(long, value_delimiter = ',', value_parser = ids::parse_id, conflicts_with_all = ID_CONFLICTS)]
+    ids: Vec<OsmId>,
+
+    /// Like --ids, reading the ids from this file, one per line or as the
+    /// JSON array written by --ids-only
+    #[arg(long, value_name = "FILE", conflicts_with_all = ID_CONFLICTS)]
+    id_file: Option<PathBuf>,
+
+    /// Extract a built-in theme instead of `--filter` and `--types`
+    #[arg(long, value_enum, conflicts_with_all = ["filter", "types", "invert"])]
+    preset: Option<Preset>,
+
+    /// Judge construction sites as of this date instead of today, e.g.
+    /// `2026-01-01`; like --opening-from and --opening-until it adds their
+    /// dates and `@status` also without `--preset construction`
+    #[arg(long, value_name = "DATE", value_parser = construction::parse_period)]
+    as_of: Option<Period>,
+
+    /// Only extract construction sites expected to open in or after this
+    /// year, month or day, by their `opening_date`
+    #[arg(long, value_name = "DATE", value_parser = construction::parse_period)]
+    opening_from: Option<Period>,
+
+    /// Only extract construction sites expected to open up to the end of
+    /// this year, month or day
+    #[arg(long, value_name = "DATE", value_parser = construction::parse_period)]
+    opening_until: Option<Period>,
+
+    /// Only extract features intersecting this box: min_lon,min_lat,max_lon,max_lat
+    #[arg(long, value_parser = spatial::parse_bbox, allow_hyphen_values = true)]
+    bbox: Option<Rect>,
+
+    /// Only extract features inside this boundary (GeoJSON or Osmosis .poly file)
+    #[arg(long)]
+    clip_poly: Option<PathBuf>,
+
+    /// Whether to keep intersecting features whole or cut them at the boundary
+    #[arg(long, value_enum, default_value_t = ClipMode::Intersect, requires = "clip_poly")]
+    clip_mode: ClipMode,
+
+    /// Emit closed ways with area tags (building, landuse, area=yes, ...) as
+    /// linestrings instead of polygons
+    #[arg(long)]
+    force_linestring: bool,
+
+    /// Keep ways with nodes missing from the extract, as at the border of
+    /// clipped regional extracts, as lines through the nodes that are
+    /// there (at least two), marked with `@incomplete=true`
+    #[arg(long)]
+    allow_incomplete: bool,
+
+    /// Add the members of relations with their ids and roles as a JSON
+    /// array, `@members`, e.g. `[{"type":"node","ref":1,"role":"stop"}]`
+    #[arg(long)]
+    with_members: bool,
+
+    /// Resolve relations that are members of matched relations, as the
+    /// routes of a route master, down to this many levels into the matched
+    /// relation's geometry, each relation once; by default they are left
+    /// out
+    #[arg(long, value_name = "N", default_value_t = 0, conflicts_with = "streaming")]
+    relation_depth: usize,
+
+    /// Skip looking up node locations and relation members: matches are
+    /// written as they are read, without geometry, ways with the ids of
+    /// their nodes as `@nodes` and relations with their members as
+    /// `@members`; PBF output gets the matches without what they reference
+    #[arg(long, conflicts_with_all = ["preset", "bbox", "clip_poly", "force_linestring", "allow_incomplete"])]
+    no_resolve_geometry: bool,
+
+    /// Add each object's version, timestamp, changeset, uid and user as
+    /// `@version`, `@timestamp`, `@changeset`, `@uid` and `@user`
+    /// properties, which the filter can match too, e.g. `@user=alice`
+    #[arg(long)]
+    with_metadata: bool,
+
+    /// Only extract objects last edited at or after this UTC date or time,
+    /// e.g. `2024-01-01` or `2024-01-01T12:00:00Z`
+    #[arg(long, value_parser = metadata::parse_timestamp)]
+    modified_since: Option<i64>,
+
+    /// Only extract objects last edited before this UTC date or time
+    #[arg(long, value_parser = metadata::parse_timestamp)]
+    modified_before: Option<i64>,
+
+    /// Only extract features open at this local date and time by their
+    /// `opening_hours` tag, e.g. `2025-06-01T10:00`, adding when that next
+    /// changes as `@next_change`
+    #[cfg(feature = "opening-hours")]
+    #[arg(long, value_name = "DATETIME", value_parser = hours::parse_datetime)]
+    open_at: Option<chrono::NaiveDateTime>,
+
+    /// Read the file in a single streaming pass with a node location index
+    /// instead of resolving dependencies (requires a PBF sorted by type and id)
+    #[arg(long)]
+    streaming: bool,
+
+    /// Memory-map the input files instead of reading them through a buffer
+    #[arg(long)]
+    mmap: bool,
+
+    /// Node location index used in streaming mode
+    #[arg(long, value_enum, default_value_t = NodeIndexKind::Sorted)]
+    node_index: NodeIndexKind,
+
+    /// Keep node locations in this file instead of memory, for planet-sized
+    /// inputs (implies --streaming); it is reused by later runs on the same
+    /// unchanged input
+    #[arg(long, value_name = "FILE")]
+    node_cache: Option<PathBuf>,
+
+    /// Keep checkpoints in this directory during and after every pass over
+    /// the input, so that an interrupted run started again with the same
+    /// arguments resumes from the last one saved; in streaming mode it
+    /// holds the node locations as --node-cache would
+    #[arg(long, value_name = "DIR")]
+    resume: Option<PathBuf>,
+
+    /// Stop with an error instead of being killed once the process uses
+    /// more memory than this, e.g. `8G`; in streaming mode node locations
+    /// go to a temporary file if they would take more than half of it
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    max_memory: Option<u64>,
+
+    /// Fail on the first matched object whose geometry cannot be built,
+    /// e.g. a way with nodes missing from the extract, instead of skipping
+    /// it with a warning
+    #[arg(long)]
+    strict: bool,
+
+    /// Write each skipped object with its id and the cause to this file,
+    /// as JSON lines, next to the single warning summing them up
+    #[arg(long, value_name = "FILE", conflicts_with = "strict")]
+    warnings_file: Option<PathBuf>,
+
+    /// Number of threads decoding PBF blocks (0 = one per CPU core)
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+impl SelectArgs {
+    fn extractor(&self) -> Result<Extractor> {
+        self.extractor_for(&self.filter(), &self.types())
+    }
+
+    // The filter and types in effect, which a preset replaces
+    fn filter(&self) -> Filter {
+        self.preset.map_or_else(|| self.filter.clone(), |preset| preset.filter())
+    }
+
+    fn types(&self) -> Vec<OsmType> {
+        if !self.ids.is_empty() || self.id_file.is_some() {
+            return vec![OsmType::Node, OsmType::Way, OsmType::Relation];
+        }
+        self.preset.map_or_else(|| self.types.clone(), |preset| preset.types().to_vec())
+    }
+
+    // Like `extractor`, but with the filter and types given by the caller
+    fn extractor_for(&self, filter: &Filter, types: &[OsmType]) -> Result<Extractor> {
+        let inputs = expand_inputs(&self.input)?;
+        if let [input] = inputs.as_slice() {
+            info!("-> Opening PBF file: {}", input);
+        } else {
+            info!("-> Opening {} PBF files", inputs.len());
+        }
+        let mut ids = self.ids.clone();
+        if let Some(path) = &self.id_file {
+            ids.extend(ids::read_file(path)?);
+        }
+        match self.preset {
+            _ if !ids.is_empty() || self.id_file.is_some() => info!("-> Ids: {} objects", ids.len()),
+            Some(preset) => info!("-> Preset: {}: {} (on {})", preset, filter, join_types(types)),
+            None if self.invert => info!("-> Filter: not ({}) (on {})", filter, join_types(types)),
+            None => info!("-> Filter: {} (on {})", filter, join_types(types)),
+        }
+        if let Some(node_filter) = &self.node_filter {
+            info!("-> Node filter: {}", node_filter);
+        }
+        if let Some(exclude_filter) = &self.exclude_filter {
+            info!("-> Excluding: {}", exclude_filter);
+        }
+
+        let mut extractor = Extractor::new(inputs[0].clone())
+            .inputs(inputs)
+            .filter(filter.clone())
+            .types(types)
+            .invert(self.invert)
+            .streaming(self.streaming)
+            .mmap(self.mmap)
+            .node_index(self.node_index)
+            .threads(self.threads)
+            .cancel_token(interrupted().clone())
+            .force_linestring(self.force_linestring)
+            .allow_incomplete(self.allow_incomplete)
+            .with_members(self.with_members)
+            .relation_depth(self.relation_depth)
+            .resolve_geometry(!self.no_resolve_geometry)
+            .with_metadata(self.with_metadata)
+            .strict(self.strict)
+            .verbose(true);
+        if let Some(preset) = self.preset {
+            extractor = extractor.preset(preset);
+        }
+        if !ids.is_empty() || self.id_file.is_some() {
+            extractor = extractor.ids(ids);
+        }
+        if self.as_of.is_some() || self.opening_from.is_some() || self.opening_until.is_some() {
+            let as_of = self.as_of.as_ref().map_or_else(|| Monitor::today().as_of, |date| date.start);
+            extractor = extractor.construction(Monitor {
+                as_of,
+                opening_from: self.opening_from.as_ref().map(|date| date.start),
+                opening_until: self.opening_until.as_ref().map(|date| date.end),
+            });
+        }
+        if let Some(node_filter) = &self.node_filter {
+            extractor = extractor.node_filter(node_filter.clone());
+        }
+        if let Some(path) = &self.node_cache {
+            extractor = extractor.node_cache(path);
+        }
+        if let Some(dir) = &self.resume {
+            extractor = extractor.resume(dir);
+        }
+        if let Some(bytes) = self.max_memory {
+            extractor = extractor.max_memory(bytes);
+        }
+        if let Some(path) = &self.warnings_file {
+            extractor = extractor.warnings_file(path);
+        }
+        if let Some(exclude_filter) = &self.exclude_filter {
+            extractor = extractor.exclude_filter(exclude_filter.clone());
+        }
+        if let Some(since) = self.modified_since {
+            extractor = extractor.modified_since(since);
+        }
+        if let Some(before) = self.modified_before {
+            extractor = extractor.modified_before(before);
+        }
+        #[cfg(feature = "opening-hours")]
+        if let Some(at) = self.open_at {
+            extractor = extractor.open_at(at);
+        }
+        if let Some(bbox) = self.bbox {
+            extractor = extractor.bbox(bbox);
+        }
+        if let Some(path) = &self.clip_poly {
+            extractor = extractor.boundary(Boundary::from_file(path, self.clip_mode)?);
+        }
+        Ok(extractor)
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = logging::init(args.log_format, logging::level(args.verbose, args.quiet)) {
+        eprintln!("Error: {:#}", e);
+        return ExitCode::FAILURE;
+    }
     let start_time = Instant::now();
+    let inputs: Vec<_> = args.select.input.iter().map(|input| input.display()).collect();
+    let mut summary = Summary::new(&inputs);
+    let result = run(&args, &mut summary);
+    let status = match &result {
+        Err(e) => {
+            error!("{:#}", e);
+            ExitStatus::of_error(e)
+        }
+        Ok(()) if interrupted().is_cancelled() => {
+            warn!("Interrupted: the outputs only hold the features extracted before");
+            ExitStatus::Interrupted
+        }
+        Ok(()) if args.command.is_none() && args.strict_exit => summary.status(),
+        Ok(()) => ExitStatus::Success,
+    };
+    if let (Some(path), None) = (&args.summary_json, &args.command) {
+        summary.finish(status, result.as_ref().err(), logging::warnings(), start_time.elapsed());
+        let written = serde_json::to_string_pretty(&summary).map_err(anyhow::Error::from).and_then(|json| {
+            std::fs::write(path, json).with_context(|| format!("failed to write the summary to {:?}", path))
+        });
+        if let Err(e) = written {
+            error!("{:#}", e);
+            return ExitCode::from(ExitStatus::of_error(&e).code());
+        }
+    }
+    ExitCode::from(status.code())
+}
+
+// Parses the command line, with the options of --config it does not set
+// put before it
+fn parse_args() -> Result<Args> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    // Missing required options may come from the config, other errors are
+    // left to report
+    let parsed = match Args::command().try_get_matches_from(&argv) {
+        Err(e) if e.kind() == ErrorKind::MissingRequiredArgument => {
+            Args::command().ignore_errors(true).try_get_matches_from(&argv)
+        }
+        parsed => parsed,
+    };
+    if let Ok(matches) = parsed {
+        if let (Some(path), None) = (matches.get_one::<PathBuf>("config"), matches.subcommand_name()) {
+            let options = config::args(&Args::command(), &matches, path)?;
+            argv.splice(1..1, options);
+        }
+    }
+    Ok(try_parse_from(argv).unwrap_or_else(|e| e.exit()))
+}
 
-    println!("-> Opening PBF file: {:?}", &args.input);
-    let f = File::open(&args.input)?;
-    let mut reader = OsmPbfReader::new(f);
+// `Args::try_parse_from`, but only the logging options may come before a
+// subcommand, not those of an extraction
+fn try_parse_from(argv: Vec<OsString>) -> Result<Args, clap::Error> {
+    let mut command = Args::command();
+    let matches = command.try_get_matches_from_mut(argv)?;
+    if let Some(name) = matches.subcommand_name() {
+        let extraction_option = |arg: &&Arg| {
+            !arg.is_global_set() && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        };
+        let given = command.get_arguments().find(extraction_option).map(Arg::to_string);
+        if let Some(arg) = given {
+            let message = format!("the subcommand '{}' cannot be used with '{}'", name, arg);
+            return Err(command.error(ErrorKind::ArgumentConflict, message));
+        }
+    }
+    Args::from_arg_matches(&matches).map_err(|e| e.format(&mut command))
+}
 
-    // Optimized predicate with early exits and string slice lookups
-    let predicate = |obj: &OsmObj| -> bool {
-        match obj.way() {
-            Some(way) if way.nodes.len() >= 2 => {
-                // Use string slices which work with SmartString's Borrow<str> implementation
-                way.tags.contains_key("highway") && way.tags.contains_key("construction")
+fn run(args: &Args, summary: &mut Summary) -> Result<()> {
+    // Those writing outputs finish them on Ctrl-C; the others, and the server
+    // until stopped, quit at once
+    let interruptible = match &args.command {
+        Some(Command::Info(_) | Command::Bench(_) | Command::Query(_) | Command::Completions(_) | Command::Man(_)) => {
+            false
+        }
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(_)) => false,
+        _ => !args.dry_run,
+    };
+    if interruptible {
+        handle_interrupts()?;
+    }
+    match &args.command {
+        Some(Command::Stats(stats_args)) => stats(stats_args),
+        Some(Command::Info(info_args)) => file_info(info_args),
+        #[cfg(feature = "tiles")]
+        Some(Command::Tiles(tiles_args)) => tiles(tiles_args),
+        Some(Command::Bench(bench_args)) => bench(bench_args),
+        Some(Command::Graph(graph_args)) => export_graph(graph_args),
+        Some(Command::Addresses(addresses_args)) => addresses(addresses_args),
+        Some(Command::Boundaries(boundaries_args)) => boundaries(boundaries_args),
+        Some(Command::Coastline(coastline_args)) => coastline(coastline_args),
+        Some(Command::Diff(diff_args)) => diff(diff_args),
+        #[cfg(feature = "xml")]
+        Some(Command::Update(update_args)) => update(update_args),
+        #[cfg(feature = "xml")]
+        Some(Command::Changes(changes_args)) => changes(changes_args),
+        Some(Command::Query(query_args)) => query(query_args),
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(serve_args)) => serve(serve_args),
+        Some(Command::Completions(completions_args)) => completions(completions_args),
+        Some(Command::Man(man_args)) => man(man_args),
+        None => extract(args, summary),
+    }
+}
+
+// Given to every extraction of the run and cancelled on Ctrl-C
+fn interrupted() -> &'static CancelToken {
+    static INTERRUPTED: OnceLock<CancelToken> = OnceLock::new();
+    INTERRUPTED.get_or_init(CancelToken::new)
+}
+
+// On a first Ctrl-C the extraction stops reading and finishes its outputs
+// with what it has, see `cancel`; a second one quits at once
+fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if interrupted().is_cancelled() {
+            std::process::exit(ExitStatus::Interrupted.code().into());
+        }
+        interrupted().cancel();
+        warn!("Interrupted, finishing the outputs with the features extracted so far; press Ctrl-C again to quit");
+    })?;
+    Ok(())
+}
+
+fn extract(args: &Args, summary: &mut Summary) -> Result<()> {
+    if args.dry_run {
+        return dry_run(args);
+    }
+    if args.ids_only {
+        return extract_ids(args, summary);
+    }
+    if let Some(path) = &args.profile {
+        return extract_profile(args, &Profile::from_file(path)?, summary);
+    }
+    if args.format.is_some() && args.output.len() > 1 {
+        bail!("--format applies to a single --output; for several, the formats are told by their extensions");
+    }
+    let outputs: Vec<(&Path, OutputFormat)> = args
+        .output
+        .iter()
+        .map(|output| Ok((output.as_path(), output_format(args, output)?)))
+        .collect::<Result<_>>()?;
+    let split_by = split_by(args)?;
+    if let [(output, OutputFormat::Pbf)] = outputs[..] {
+        if split_by.is_some() {
+            bail!("PBF output holds OSM objects rather than features and cannot be split");
+        }
+        return extract_pbf(args, output, summary);
+    }
+    if outputs.len() > 1 && outputs.iter().any(|(_, format)| *format == OutputFormat::Pbf) {
+        bail!("PBF output holds OSM objects rather than features and cannot be combined with other outputs");
+    }
+    if let [(output, format @ (OutputFormat::Geojsonseq | OutputFormat::Arrow))] = outputs[..] {
+        // Loading into PostGIS, sorting and splitting need all features at
+        // once anyway
+        if !loads_postgis(args) && args.sort == SortOrder::None && split_by.is_none() {
+            return extract_seq(args, output, format, summary);
+        }
+    }
+    let start_time = Instant::now();
+    let extractor = with_transforms(args, args.select.extractor()?)?;
+    let (mut final_features, stats) = extractor.run_with_stats()?;
+    sort::sort(&mut final_features, args.sort);
+    summary.record(&stats);
+    summary.features = final_features.len();
+    for feature in &final_features {
+        summary.types.add(feature.osm_type());
+    }
+
+    let total_duration = start_time.elapsed();
+
+    info!("--- BENCHMARK RESULTS ---");
+    info!("Total features extracted: {}", final_features.len());
+    for osm_type in &args.select.types() {
+        let count = final_features.iter().filter(|f| f.osm_type() == *osm_type).count();
+        info!("  {}s: {}", osm_type, count);
+    }
+    info!("Core extraction (PBF read & dependency resolution): {:.2?}", stats.extraction_duration);
+    info!("Data restructuring (geometry building, etc.):       {:.2?}", stats.processing_duration);
+    print_throughput(&args.select, &stats);
+    print_memory(&stats);
+    info!("----------------------------------------------------");
+    info!("Total runtime:                                      {:.2?}", total_duration);
+
+    // The parts take the features over, unless PostGIS loads them as well
+    let (parts, final_features) = match &split_by {
+        Some(by) if loads_postgis(args) => {
+            (Some(split::split(final_features.clone(), by, args.split_mode)), final_features)
+        }
+        Some(by) => (Some(split::split(final_features, by, args.split_mode)), Vec::new()),
+        None => (None, final_features),
+    };
+    let mut written = false;
+    for (output, format) in outputs {
+        info!("-> Writing {} to {:?}...", format, output);
+        let write_start_time = Instant::now();
+        let options = WriteOptions {
+            columns: args.columns.clone(),
+            layer: args.layer.clone(),
+            crs: args.to_crs.clone(),
+            schema: args.schema.clone(),
+            compression: compression(args, output),
+        };
+        match (&split_by, &parts) {
+            (Some(by), Some(parts)) => {
+                for (part, features) in parts {
+                    let path = split::part_path(output, part);
+                    output::write(&path, format, features, &options)?;
+                    summary.add_output(&path, &format_name(format), features.len());
+                }
+                info!("   Wrote {} parts by {} in {:.2?}.", parts.len(), by, write_start_time.elapsed());
+            }
+            _ => {
+                output::write(output, format, &final_features, &options)?;
+                summary.add_output(output, &format_name(format), final_features.len());
+                info!("   Wrote {} features in {:.2?}.", final_features.len(), write_start_time.elapsed());
+            }
+        }
+        written = true;
+    }
+
+    #[cfg(feature = "postgis")]
+    if let Some(url) = &args.postgis {
+        let options = PostgisOptions {
+            schema: args.postgis_schema.clone(),
+            table: args.postgis_table.clone(),
+            tags: args.postgis_tags,
+            columns: args.schema.as_ref().map(|s| s.columns.clone()).unwrap_or_default(),
+            overwrite: args.postgis_overwrite,
+            srid: args.to_crs.as_ref().map_or(4326, |crs| crs.epsg.unwrap_or(0)),
+        };
+        info!("-> Loading into PostGIS table {}.{}...", options.schema, options.table);
+        let load_start_time = Instant::now();
+        postgis::write(url, &options, &final_features)?;
+        let table = format!("{}.{}", options.schema, options.table);
+        summary.add_output(Path::new(&table), "postgis", final_features.len());
+        info!("   Loaded {} features in {:.2?}.", final_features.len(), load_start_time.elapsed());
+        written = true;
+    }
+
+    if written {
+        info!("✅ Success!");
+    } else {
+        info!("✅ Success! Data is held in an in-memory array.");
+
+        // We can even print one to prove it exists
+        if let Some(first_feature) = final_features.first() {
+            info!("Example of first extracted feature:\n{:#?}", first_feature);
+        }
+    }
+
+    Ok(())
+}
+
+// The split of --split-by or --split-by-polygons
+fn split_by(args: &Args) -> Result<Option<SplitBy>> {
+    match (&args.split_by_polygons, &args.split_key) {
+        (Some(path), Some(key)) => {
+            let layer = JoinLayer::from_file(path, std::slice::from_ref(key))?;
+            Ok(Some(SplitBy::Polygons { layer, key: key.clone() }))
+        }
+        _ => Ok(args.split_by.clone()),
+    }
+}
+
+// Writes the matched objects and their dependencies as they were read,
+// leaving out the feature assembly
+fn extract_pbf(args: &Args, output: &Path, summary: &mut Summary) -> Result<()> {
+    let transforms = !args.compute.is_empty()
+        || args.fix_geometry.is_some()
+        || args.invalid.is_some()
+        || args.normalize
+        || args.simplify.is_some()
+        || args.to_crs.is_some()
+        || args.precision.is_some();
+    if transforms || args.merge_ways || args.geometry != OutputGeometry::Full {
+        bail!(
+            "--fix-geometry, --invalid, --compute, --normalize, --simplify, --to-crs, --precision, --merge-ways and \
+             --geometry do not apply to PBF output"
+        );
+    }
+    if loads_postgis(args) {
+        bail!("PBF output cannot be combined with loading into PostGIS");
+    }
+    let start_time = Instant::now();
+    let extractor = args.select.extractor()?;
+    let objects = extractor.objects()?;
+    info!("-> Writing OSM PBF to {:?}...", output);
+    let write_start_time = Instant::now();
+    let written = output::pbf::write(output, objects.into_values())?;
+    summary.features = written;
+    summary.add_output(output, &format_name(OutputFormat::Pbf), written);
+    info!("   Wrote {} objects in {:.2?}.", written, write_start_time.elapsed());
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
+
+// Estimates the extraction instead of running it
+fn dry_run(args: &Args) -> Result<()> {
+    let extractor = args.select.extractor()?;
+    for (i, estimate) in extractor.estimate(estimate::DEFAULT_SAMPLES)?.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", estimate);
+    }
+    Ok(())
+}
+
+// Lists the ids of the matches as they are read
+fn extract_ids(args: &Args, summary: &mut Summary) -> Result<()> {
+    let output = match args.output.as_slice() {
+        [] => Path::new("-"),
+        [output] => output.as_path(),
+        _ => bail!("--ids-only writes a single --output"),
+    };
+    let start_time = Instant::now();
+    let extractor = args.select.extractor()?.resolve_geometry(false);
+    info!("-> Listing ids to {:?}", output);
+    let mut writer = IdWriter::create(output)?;
+    let stats = extractor.for_each(|feature| {
+        summary.types.add(feature.osm_type());
+        writer.write(&feature.unique_id())
+    })?;
+    let written = writer.finish()?;
+    summary.record(&stats);
+    summary.features = written;
+    summary.add_output(output, "ids", written);
+    info!("   Found {} matching objects in {:.2?}.", written, stats.extraction_duration);
+    print_throughput(&args.select, &stats);
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
+
+// The writers of the formats written while the features are extracted
+enum SeqWriter {
+    Geojson(geojson::SeqWriter),
+    #[cfg(feature = "arrow")]
+    Arrow(arrow::StreamWriter),
+}
+
+impl SeqWriter {
+    fn create(args: &Args, output: &Path, format: OutputFormat) -> Result<SeqWriter> {
+        match format {
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => {
+                let options = WriteOptions {
+                    columns: args.columns.clone(),
+                    schema: args.schema.clone(),
+                    ..WriteOptions::default()
+                };
+                let writer = arrow::StreamWriter::create(output, &options.tag_columns(), args.to_crs.as_ref())?;
+                Ok(SeqWriter::Arrow(writer))
+            }
+            #[cfg(not(feature = "arrow"))]
+            OutputFormat::Arrow => bail!("Arrow IPC output requires building with the `arrow` feature"),
+            _ => Ok(SeqWriter::Geojson(geojson::SeqWriter::create_compressed(output, compression(args, output))?)),
+        }
+    }
+
+    fn write(&mut self, feature: ExtractedFeature) -> Result<()> {
+        match self {
+            SeqWriter::Geojson(writer) => writer.write(&feature),
+            #[cfg(feature = "arrow")]
+            SeqWriter::Arrow(writer) => writer.write(feature),
+        }
+    }
+
+    fn finish(self) -> Result<usize> {
+        match self {
+            SeqWriter::Geojson(writer) => writer.finish(),
+            #[cfg(feature = "arrow")]
+            SeqWriter::Arrow(writer) => writer.finish(),
+        }
+    }
+}
+
+// Writes GeoJSONSeq or an Arrow IPC stream while the features are extracted
+// instead of collecting them first, so memory stays flat however many match
+fn extract_seq(args: &Args, output: &Path, format: OutputFormat, summary: &mut Summary) -> Result<()> {
+    let start_time = Instant::now();
+    let extractor = with_transforms(args, args.select.extractor()?)?;
+    info!("-> Streaming {} to {:?}", format, output);
+    let mut writer = SeqWriter::create(args, output, format)?;
+    let types = args.select.types();
+    let mut counts = vec![0usize; types.len()];
+    let stats = extractor.for_each(|feature| {
+        if let Some(i) = types.iter().position(|t| *t == feature.osm_type()) {
+            counts[i] += 1;
+        }
+        summary.types.add(feature.osm_type());
+        writer.write(feature)
+    })?;
+    let written = writer.finish()?;
+    summary.record(&stats);
+    summary.features = written;
+    summary.add_output(output, &format_name(format), written);
+
+    info!("--- BENCHMARK RESULTS ---");
+    info!("Total features extracted: {}", written);
+    for (osm_type, count) in types.iter().zip(&counts) {
+        info!("  {}s: {}", osm_type, count);
+    }
+    info!("Core extraction (PBF read & dependency resolution): {:.2?}", stats.extraction_duration);
+    info!("Data restructuring (geometry building, etc.):       {:.2?}", stats.processing_duration);
+    print_throughput(&args.select, &stats);
+    print_memory(&stats);
+    info!("----------------------------------------------------");
+    info!("Total runtime:                                      {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
+
+fn extract_profile(args: &Args, profile: &Profile, summary: &mut Summary) -> Result<()> {
+    #[cfg(feature = "postgis")]
+    if args.postgis.is_some() {
+        bail!("--postgis cannot be combined with --profile");
+    }
+    let start_time = Instant::now();
+    let names: Vec<&str> = profile.layers.iter().map(|l| l.name.as_str()).collect();
+    info!("-> Profile with {} layers: {}", names.len(), names.join(", "));
+    let extractor = with_transforms(args, args.select.extractor_for(&profile.filter(), &profile.types())?)?;
+
+    let mut layers: Vec<FeatureStore> = profile.layers.iter().map(|_| FeatureStore::new()).collect();
+    let stats = extractor.for_each(|feature| {
+        summary.features += 1;
+        summary.types.add(feature.osm_type());
+        let matching: Vec<usize> = (0..layers.len()).filter(|&i| profile.layers[i].matches(&feature)).collect();
+        if let Some((&last, rest)) = matching.split_last() {
+            for &i in rest {
+                layers[i].push(feature.clone());
             }
-            _ => false,
+            layers[last].push(feature);
         }
+        Ok(())
+    })?;
+    let mut layers: Vec<Vec<ExtractedFeature>> = layers.into_iter().map(FeatureStore::into_features).collect();
+    summary.record(&stats);
+
+    info!("--- BENCHMARK RESULTS ---");
+    for (layer, features) in profile.layers.iter().zip(&layers) {
+        info!("  {}: {}", layer.name, features.len());
+    }
+    info!("Core extraction (PBF read & dependency resolution): {:.2?}", stats.extraction_duration);
+    info!("Data restructuring (geometry building, etc.):       {:.2?}", stats.processing_duration);
+    print_throughput(&args.select, &stats);
+    print_memory(&stats);
+    info!("----------------------------------------------------");
+    info!("Total runtime:                                      {:.2?}", start_time.elapsed());
+
+    for (layer, features) in profile.layers.iter().zip(&mut layers) {
+        sort::sort(features, args.sort);
+        info!("-> Writing layer {} as {} to {:?}...", layer.name, layer.format, layer.output);
+        let write_start_time = Instant::now();
+        let options = WriteOptions {
+            columns: layer.columns.clone(),
+            layer: Some(layer.name.clone()),
+            crs: args.to_crs.clone(),
+            schema: args.schema.clone(),
+            compression: compression(args, &layer.output),
+        };
+        output::write(&layer.output, layer.format, features, &options)?;
+        summary.add_output(&layer.output, &format_name(layer.format), features.len());
+        info!("   Wrote {} features in {:.2?}.", features.len(), write_start_time.elapsed());
+    }
+    info!("✅ Success!");
+    Ok(())
+}
+
+// The name of a format as --format takes it
+fn format_name(format: OutputFormat) -> String {
+    format.to_possible_value().map_or_else(|| format.to_string(), |value| value.get_name().to_string())
+}
+
+fn compression(args: &Args, output: &Path) -> Option<Compression> {
+    args.compress.or_else(|| Compression::from_path(output))
+}
+
+// The format of --format, or else the one told by the extension of the
+// output; stdout gets GeoJSON, and only takes the formats written as a stream
+fn output_format(args: &Args, output: &Path) -> Result<OutputFormat> {
+    if output == Path::new("-") {
+        let format = args.format.unwrap_or(OutputFormat::Geojson);
+        let streamed = matches!(
+            format,
+            OutputFormat::Geojson | OutputFormat::Geojsonseq | OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Arrow
+        );
+        if !streamed {
+            bail!("{} output cannot be written to stdout (`-o -`); give a file name", format_name(format));
+        }
+        return Ok(format);
+    }
+    if let Some(format) = args.format.or_else(|| OutputFormat::from_path(output)) {
+        return Ok(format);
+    }
+    let extensions: Vec<String> =
+        OutputFormat::value_variants().iter().map(|format| format!(".{}", format.extension())).collect();
+    let extension = match output.extension() {
+        Some(extension) => format!("from its extension .{}", extension.to_string_lossy()),
+        None => "without an extension".to_string(),
     };
+    bail!("cannot tell the format of {:?} {}; use one of {} or set --format", output, extension, extensions.join(", "))
+}
+
+#[cfg(feature = "postgis")]
+fn loads_postgis(args: &Args) -> bool {
+    args.postgis.is_some()
+}
+
+#[cfg(not(feature = "postgis"))]
+fn loads_postgis(_args: &Args) -> bool {
+    false
+}
+
+// Applies the geometry options shared by plain and profile extraction
+fn with_transforms(args: &Args, mut extractor: Extractor) -> Result<Extractor> {
+    extractor = extractor
+        .compute(&args.compute)
+        .normalize(args.normalize)
+        .keep_tags(&args.keep_tags)
+        .drop_tags(&args.drop_tags)
+        .merge_ways(args.merge_ways)
+        .output_geometry(args.geometry);
+    if let Some(tolerance) = args.fix_geometry {
+        extractor = extractor.fix_geometry(tolerance);
+    }
+    if let Some(mode) = args.invalid {
+        extractor = extractor.invalid(mode);
+    }
+    if let Some(tolerance) = args.simplify {
+        extractor = extractor.simplify(tolerance);
+    }
+    if let Some(crs) = &args.to_crs {
+        extractor = extractor.to_crs(crs.clone());
+    }
+    if let Some(decimals) = args.precision {
+        extractor = extractor.precision(decimals);
+    }
+    if let Some(path) = &args.join {
+        info!("-> Joining attributes {} of {:?}", args.join_key.join(", "), path);
+        extractor = extractor.join(JoinLayer::from_file(path, &args.join_key)?);
+    }
+    Ok(extractor)
+}
+
+fn file_info(args: &InfoArgs) -> Result<()> {
+    for (i, input) in expand_inputs(&args.inputs)?.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", info::read(input, args.threads)?);
+    }
+    Ok(())
+}
 
-    println!("-> Pass 1: Finding ways and collecting dependencies...");
-    let objects: BTreeMap<OsmId, OsmObj> = reader.get_objs_and_deps(predicate)?;
-    let extraction_duration = start_time.elapsed();
-    println!(
-        "   Found {} total objects (ways and their required nodes) in {:.2?}.",
-        objects.len(),
-        extraction_duration
+fn completions(args: &CompletionsArgs) -> Result<()> {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(args.shell, &mut command, name, &mut std::io::stdout());
+    Ok(())
+}
+
+fn man(args: &ManArgs) -> Result<()> {
+    let command = Args::command();
+    match &args.dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            clap_mangen::generate_to(command, dir)?;
+            info!("-> Wrote the man pages to {:?}", dir);
+        }
+        None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+    }
+    Ok(())
+}
+
+fn stats(args: &StatsArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let mut report = Report::new(&args.select.filter().keys());
+    report.top_values = args.top;
+    args.select.extractor()?.for_each(|feature| {
+        report.add(&feature);
+        Ok(())
+    })?;
+
+    info!("--- STATISTICS ---");
+    print!("{}", report);
+    info!("----------------------------------------------------");
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    Ok(())
+}
+
+fn bench(args: &BenchArgs) -> Result<()> {
+    let extractor = args.select.extractor()?.verbose(false);
+    let options = BenchOptions {
+        runs: args.runs,
+        warmup: args.warmup,
+        format: args.format,
+        write_options: WriteOptions::default(),
+    };
+    info!("-> Timing {} runs after {} warmup runs...", options.runs, options.warmup);
+    let report = bench::run(&extractor, &options)?;
+
+    info!("--- BENCHMARK RESULTS ---");
+    print!("{}", report);
+    if let Some(path) = &args.json {
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+        info!("-> Wrote JSON results to {:?}", path);
+    }
+    Ok(())
+}
+
+fn export_graph(args: &GraphArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let inputs = expand_inputs(&args.input)?;
+    info!("-> Routable ways: {}", args.filter);
+    let restrictions: Filter = "type=restriction".parse()?;
+    let mut extractor = Extractor::new(inputs[0].clone())
+        .inputs(inputs)
+        .filter(Filter::Or(vec![args.filter.clone(), restrictions]))
+        .types(&[OsmType::Way, OsmType::Relation])
+        .threads(args.threads)
+        .cancel_token(interrupted().clone())
+        .verbose(true);
+    if let Some(bbox) = args.bbox {
+        extractor = extractor.bbox(bbox);
+    }
+    let objects = extractor.objects()?;
+    let graph = graph::build(&objects, &args.filter);
+    drop(objects);
+    let resolved = graph.restrictions.iter().filter(|r| r.from_edge.is_some() && r.to_edge.is_some()).count();
+    info!(
+        "   Built a graph of {} nodes and {} edges with {} turn restrictions ({} resolved to edges) in {:.2?}.",
+        graph.nodes.len(),
+        graph.edges.len(),
+        graph.restrictions.len(),
+        resolved,
+        start_time.elapsed()
     );
 
-    println!("-> Pass 2: Re-structuring extracted data into final format...");
-    let processing_start_time = Instant::now();
-
-    // Pre-filter and collect ways more efficiently
-    let ways_to_process: Vec<&osmpbfreader::Way> = objects
-        .values()
-        .filter_map(|obj| {
-            if let OsmObj::Way(way) = obj {
-                // Check for construction highway ways
-                if way.tags.contains_key("highway") && way.tags.contains_key("construction") {
-                    Some(way)
-                } else {
-                    None
-                }
-            } else {
-                None
+    let format = args
+        .format
+        .or_else(|| GraphFormat::from_path(&args.output))
+        .unwrap_or(GraphFormat::Csv);
+    match format {
+        GraphFormat::Csv => {
+            for path in graph::write_csv(&args.output, &graph)? {
+                info!("-> Wrote {:?}", path);
             }
-        })
-        .collect();
-
-    let bar = ProgressBar::new(ways_to_process.len() as u64);
-    bar.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta})")?
-        .progress_chars("#>-"));
-
-    // Pre-allocate with exact capacity
-    let mut final_ways: Vec<ConstructionWay> = Vec::with_capacity(ways_to_process.len());
-
-    for way in ways_to_process {
-        // Pre-allocate coordinate vector with exact capacity
-        let mut coords: Vec<Coord> = Vec::with_capacity(way.nodes.len());
-        let mut valid_way = true;
-        
-        // Process nodes in batch for better cache locality
-        for &node_id in &way.nodes {
-            match objects.get(&node_id.into()) {
-                Some(OsmObj::Node(node)) => {
-                    coords.push(Coord { x: node.lon(), y: node.lat() });
-                }
-                _ => {
-                    eprintln!("Warning: Node ID {:?} for Way ID {:?} not found. Skipping.", node_id, way.id);
-                    valid_way = false;
-                    break;
-                }
+        }
+        GraphFormat::Bin => {
+            graph::write_binary(&args.output, &graph)?;
+            info!("-> Wrote {:?}", args.output);
+        }
+    }
+    info!("✅ Success!");
+    Ok(())
+}
+
+fn addresses(args: &AddressesArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let inputs = expand_inputs(&args.input)?;
+    let mut extractor = Extractor::new(inputs[0].clone())
+        .inputs(inputs)
+        .filter(address::FILTER.parse()?)
+        .types(&[OsmType::Node, OsmType::Way, OsmType::Relation])
+        .threads(args.threads)
+        .cancel_token(interrupted().clone())
+        .verbose(true);
+    if let Some(bbox) = args.bbox {
+        extractor = extractor.bbox(bbox);
+    }
+    let mut addresses = address::collect(&extractor.objects()?);
+    if let Some(bbox) = args.bbox {
+        addresses.retain(|a| a.geometry().intersects(&bbox));
+    }
+    info!("   Collected {} addresses in {:.2?}.", addresses.len(), start_time.elapsed());
+
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&args.output))
+        .unwrap_or(OutputFormat::Csv);
+    info!("-> Writing {} to {:?}...", format, args.output);
+    let options = WriteOptions {
+        columns: address::COLUMNS.iter().map(|c| c.to_string()).collect(),
+        compression: Compression::from_path(&args.output),
+        ..WriteOptions::default()
+    };
+    output::write(&args.output, format, &addresses, &options)?;
+    info!("✅ Success!");
+    Ok(())
+}
+
+fn boundaries(args: &BoundariesArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let inputs = expand_inputs(&args.input)?;
+    let mut extractor = Extractor::new(inputs[0].clone())
+        .inputs(inputs)
+        .filter(admin::FILTER.parse()?)
+        .types(&[OsmType::Relation])
+        .threads(args.threads)
+        .cancel_token(interrupted().clone())
+        .verbose(true);
+    if let Some(bbox) = args.bbox {
+        extractor = extractor.bbox(bbox);
+    }
+    let mut boundaries = extractor.run()?;
+    boundaries.retain(|feature| {
+        matches!(feature.geometry(), Geometry::Polygon(_) | Geometry::MultiPolygon(_))
+            && (args.levels.is_empty() || admin::admin_level(feature).is_some_and(|l| args.levels.contains(&l)))
+    });
+    admin::link(&mut boundaries);
+    let invalid = boundaries.iter().filter(|f| f.tags().get(validity::VALID_TAG).is_some_and(|v| v == "false")).count();
+    info!(
+        "   Assembled {} boundaries, {} of them invalid, in {:.2?}.",
+        boundaries.len(),
+        invalid,
+        start_time.elapsed()
+    );
+
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&args.output))
+        .unwrap_or(OutputFormat::Geojson);
+    info!("-> Writing {} to {:?}...", format, args.output);
+    let options = WriteOptions { compression: Compression::from_path(&args.output), ..WriteOptions::default() };
+    output::write(&args.output, format, &boundaries, &options)?;
+    info!("✅ Success!");
+    Ok(())
+}
+
+fn coastline(args: &CoastlineArgs) -> Result<()> {
+    if let Some(cell) = args.grid {
+        if !(cell > 0.0 && cell <= 180.0) {
+            bail!("--grid must be more than 0 and at most 180 degrees, got {}", cell);
+        }
+    }
+    let start_time = Instant::now();
+    let inputs = expand_inputs(&args.input)?;
+    let mut extractor = Extractor::new(inputs[0].clone())
+        .inputs(inputs)
+        .filter(coastline::FILTER.parse()?)
+        .types(&[OsmType::Way])
+        .threads(args.threads)
+        .cancel_token(interrupted().clone())
+        .verbose(true);
+    if let Some(bbox) = args.bbox {
+        extractor = extractor.bbox(bbox);
+    }
+    let coastline = coastline::assemble(&extractor.run()?, args.close_distance);
+    info!(
+        "   Assembled {} land polygons in {:.2?}; {} chains of coastline are left open.",
+        coastline.land.len(),
+        start_time.elapsed(),
+        coastline.open.len()
+    );
+    if coastline.stray_water > 0 {
+        warn!("Dropped {} rings of water lying in no land polygon", coastline.stray_water);
+    }
+    let land = match args.grid {
+        Some(cell) => coastline::split(&coastline.land, cell),
+        None => coastline.land,
+    };
+
+    let format = |path: &Path| args.format.or_else(|| OutputFormat::from_path(path)).unwrap_or(OutputFormat::Geojson);
+    let write = |path: &Path, features: &[ExtractedFeature]| -> Result<()> {
+        info!("-> Writing {} to {:?}...", format(path), path);
+        let options = WriteOptions { compression: Compression::from_path(path), ..WriteOptions::default() };
+        output::write(path, format(path), features, &options)
+    };
+    write(&args.output, &land)?;
+    if let Some(path) = &args.open_output {
+        write(path, &coastline.open)?;
+    }
+    info!("✅ Success!");
+    Ok(())
+}
+
+fn query(args: &QueryArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let index = FeatureIndex::open(&args.index)?;
+    info!("-> Opened index {:?} with {} features in {:.2?}", args.index, index.len(), start_time.elapsed());
+    let features = match (args.bbox, args.point, args.radius) {
+        (Some(bbox), _, _) => index.bbox(&bbox)?,
+        (None, Some(point), Some(radius)) => index
+            .within(point, radius)?
+            .into_iter()
+            .map(|(distance, mut feature)| {
+                feature.tags_mut().insert(DISTANCE_TAG.into(), format!("{:.2}", distance).into());
+                feature
+            })
+            .collect(),
+        (None, Some(point), None) => index.at(point)?,
+        (None, None, _) => bail!("pass --bbox or --point"),
+    };
+    info!("   Found {} features in {:.2?}.", features.len(), start_time.elapsed());
+
+    match &args.output {
+        Some(path) => {
+            let format = args.format.or_else(|| OutputFormat::from_path(path)).unwrap_or(OutputFormat::Geojsonseq);
+            output::write(path, format, &features, &WriteOptions::default())?;
+            info!("-> Wrote {} to {:?}", format, path);
+        }
+        None => {
+            let mut out = std::io::stdout().lock();
+            for feature in &features {
+                writeln!(out, "{}", geojson::feature(feature))?;
             }
         }
+    }
+    Ok(())
+}
 
-        if !valid_way {
-            bar.inc(1);
-            continue;
+#[cfg(feature = "serve")]
+fn serve(args: &ServeArgs) -> Result<()> {
+    let start_time = Instant::now();
+    // Keeps the index written from the inputs until the server stops
+    let mut written = None;
+    let (path, inputs) = match args.input.as_slice() {
+        [path] if path.extension().is_some_and(|ext| ext == "fidx") => (path.clone(), Vec::new()),
+        patterns => {
+            let inputs = expand_inputs(patterns)?;
+            // Every extraction requested reads the inputs again
+            if inputs.contains(&Input::Stdin) {
+                bail!("stdin (`-`) cannot be served from, only files and URLs");
+            }
+            let mut extractor = Extractor::new(inputs[0].clone())
+                .inputs(inputs.clone())
+                .types(&args.types)
+                .with_members(true)
+                .threads(args.threads)
+                .verbose(true);
+            extractor = match &args.filter {
+                Some(filter) => extractor.filter(filter.clone()),
+                // Not matching an empty `or` selects every tagged object
+                None => extractor.filter(Filter::Or(Vec::new())).invert(true),
+            };
+            if let Some(bbox) = args.bbox {
+                extractor = extractor.bbox(bbox);
+            }
+            let features = extractor.run()?;
+            let file = written.insert(tempfile::NamedTempFile::new()?);
+            osm_construction_extractor::index::write(file.path(), &features)?;
+            (file.path().to_path_buf(), inputs)
         }
+    };
+    let index = FeatureIndex::open(&path)?;
+    info!("-> Serving {} features, ready in {:.2?}", index.len(), start_time.elapsed());
+    let address = format!("{}:{}", args.bind, args.port);
+    osm_construction_extractor::serve::serve(&index, &inputs, &address, args.threads)
+}
 
-        // More efficient tag conversion with pre-allocated capacity
-        let mut tags_map: HashMap<String, String> = HashMap::with_capacity(way.tags.len());
-        way.tags.iter().for_each(|(k, v)| {
-            tags_map.insert(k.to_string(), v.to_string());
-        });
+fn diff(args: &DiffArgs) -> Result<()> {
+    let start_time = Instant::now();
+    info!("-> Filter: {} (on {})", args.filter, join_types(&args.types));
+    let extract = |path: &PathBuf| -> Result<Vec<ExtractedFeature>> {
+        info!("-> Extracting from {:?}", path);
+        let mut extractor = Extractor::new(path)
+            .filter(args.filter.clone())
+            .types(&args.types)
+            .threads(args.threads)
+            .cancel_token(interrupted().clone())
+            .verbose(true);
+        if let Some(bbox) = args.bbox {
+            extractor = extractor.bbox(bbox);
+        }
+        extractor.run()
+    };
+    let diff = diff::compare(extract(&args.old)?, extract(&args.new)?);
 
-        final_ways.push(ConstructionWay {
-            id: way.id,
-            tags: tags_map,
-            geometry: LineString(coords),
-        });
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&args.output))
+        .unwrap_or(OutputFormat::Geojson);
+    for (layer, features) in diff.layers() {
+        let path = diff::layer_path(&args.output, layer, format.extension());
+        output::write(&path, format, features, &WriteOptions::default())?;
+        info!("-> Wrote {:?} ({} features)", path, features.len());
+    }
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
 
-        bar.inc(1);
+#[cfg(feature = "xml")]
+fn update(args: &UpdateArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let mut state = match &args.init {
+        Some(input) => {
+            info!("-> Creating the update state from {:?}", input);
+            info!("-> Filter: {} (on {})", args.filter, join_types(&args.types));
+            let extractor = Extractor::new(input)
+                .filter(args.filter.clone())
+                .types(&args.types)
+                .threads(args.threads)
+                .cancel_token(interrupted().clone())
+                .verbose(true);
+            State::new(args.filter.clone(), &args.types, extractor.objects()?)
+        }
+        None => {
+            let state = State::load(&args.state)?;
+            info!("-> Loaded {} objects from {:?}", state.len(), args.state);
+            info!("-> Filter: {} (on {})", state.filter, join_types(&state.types));
+            state
+        }
+    };
+
+    let mut changes = Vec::new();
+    for path in &args.changes {
+        info!("-> Reading changes from {:?}", path);
+        changes.extend(update::read_changes(path)?);
+    }
+    let changed = state.apply(changes);
+    if !args.changes.is_empty() {
+        print_change_counts(&changed);
+    }
+    state.save(&args.state)?;
+    match state.timestamp {
+        Some(timestamp) => info!("-> Saved the state as of {}", metadata::format_timestamp(timestamp)),
+        None => info!("-> Saved the state"),
     }
-    bar.finish_with_message("Done processing ways.");
 
-    let processing_duration = processing_start_time.elapsed();
-    let total_duration = start_time.elapsed();
+    if let Some(output) = &args.output {
+        let format = args
+            .format
+            .or_else(|| OutputFormat::from_path(output))
+            .unwrap_or(OutputFormat::Geojson);
+        info!("-> Writing {} to {:?}...", format, output);
+        output::write(output, format, &changed, &WriteOptions::default())?;
+        info!("   Wrote {} changed features.", changed.len());
+    }
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
 
-    println!("\n--- BENCHMARK RESULTS ---");
-    println!("Total ways extracted: {}", final_ways.len());
-    println!("Core extraction (PBF read & dependency resolution): {:.2?}", extraction_duration);
-    println!("Data restructuring (geometry building, etc.):       {:.2?}", processing_duration);
-    println!("----------------------------------------------------");
-    println!("Total runtime:                                      {:.2?}", total_duration);
-    println!("\n✅ Success! Data is held in an in-memory array.");
+#[cfg(feature = "xml")]
+fn changes(args: &ChangesArgs) -> Result<()> {
+    let start_time = Instant::now();
+    info!("-> Filter: {} (on {})", args.filter, join_types(&args.types));
+    let objects = match &args.base {
+        Some(base) => {
+            info!("-> Reading the base from {:?}", base);
+            Extractor::new(base)
+                .filter(args.filter.clone())
+                .types(&args.types)
+                .with_metadata(true)
+                .threads(args.threads)
+                .cancel_token(interrupted().clone())
+                .verbose(true)
+                .objects()?
+        }
+        None => Default::default(),
+    };
+    let mut state = State::new(args.filter.clone(), &args.types, objects).with_metadata(true);
 
-    // We can even print one to prove it exists
-    if let Some(first_way) = final_ways.first() {
-        println!("\nExample of first extracted way:");
-        println!("{:#?}", first_way);
+    let mut changes = Vec::new();
+    for path in &args.changes {
+        info!("-> Reading changes from {:?}", path);
+        changes.extend(update::read_changes(path)?);
+    }
+    let mut changed = state.apply_between(changes, args.since, args.until);
+    if !args.changesets.is_empty() {
+        let mut changesets = std::collections::HashMap::new();
+        for path in &args.changesets {
+            info!("-> Reading changesets from {:?}", path);
+            changesets.extend(update::read_changesets(path)?);
+        }
+        update::add_comments(&mut changed, &changesets);
     }
+    print_change_counts(&changed);
+
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&args.output))
+        .unwrap_or(OutputFormat::Geojson);
+    info!("-> Writing {} to {:?}...", format, args.output);
+    output::write(&args.output, format, &changed, &WriteOptions::default())?;
+    info!("   Wrote {} changed features.", changed.len());
+    info!("Total runtime: {:.2?}", start_time.elapsed());
+    info!("✅ Success!");
+    Ok(())
+}
+
+#[cfg(feature = "xml")]
+fn print_change_counts(changed: &[ExtractedFeature]) {
+    for (change, label) in [("create", "Created"), ("modify", "Modified"), ("delete", "Deleted")] {
+        let count = changed.iter().filter(|f| f.tags()[update::CHANGE_TYPE_TAG] == change).count();
+        info!("   {}: {}", label, count);
+    }
+}
+
+#[cfg(feature = "tiles")]
+fn tiles(args: &TilesArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let format = args
+        .format
+        .or_else(|| TileFormat::from_path(&args.output))
+        .unwrap_or(TileFormat::Pmtiles);
+    let default_layer = args.output.file_stem().and_then(|s| s.to_str()).unwrap_or("features");
+    let options = TileOptions {
+        min_zoom: args.min_zoom,
+        max_zoom: args.max_zoom,
+        layer: args.layer.clone().unwrap_or_else(|| default_layer.to_string()),
+        ..TileOptions::default()
+    };
+    let features = args.select.extractor()?.run()?;
+    info!("   Extracted {} features in {:.2?}.", features.len(), start_time.elapsed());
 
+    info!(
+        "-> Writing {} zoom {}-{} to {:?}...",
+        format, options.min_zoom, options.max_zoom, args.output
+    );
+    let write_start_time = Instant::now();
+    let count = tiles::write(&args.output, format, &features, &options)?;
+    info!("   Wrote {} tiles in {:.2?}.", count, write_start_time.elapsed());
+    info!("✅ Success!");
     Ok(())
-}
\ No newline at end of file
+}
+
+// Paths that do not exist are treated as glob patterns
+fn expand_inputs(patterns: &[PathBuf]) -> Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        match Input::from(pattern) {
+            Input::Path(path) if !path.exists() => {
+                let matches = glob::glob(&path.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?;
+                if matches.is_empty() {
+                    bail!("no input file matches {:?}", path);
+                }
+                inputs.extend(matches.into_iter().map(Input::Path));
+            }
+            input => inputs.push(input),
+        }
+    }
+    if inputs.iter().filter(|i| **i == Input::Stdin).count() > 1 {
+        bail!("stdin (`-`) can only be given once as input");
+    }
+    Ok(inputs)
+}
+
+// Input size over read time, to compare buffered and memory-mapped reads
+fn print_throughput(select: &SelectArgs, stats: &ExtractionStats) {
+    if stats.input_bytes == 0 {
+        return;
+    }
+    let mb = stats.input_bytes as f64 / 1e6;
+    info!(
+        "Input read ({}): {:.1} MB at {:.1} MB/s",
+        if select.mmap { "memory-mapped" } else { "buffered" },
+        mb,
+        mb / stats.extraction_duration.as_secs_f64()
+    );
+}
+
+fn print_memory(stats: &ExtractionStats) {
+    let Some(peak) = memory::peak_rss() else {
+        return;
+    };
+    match stats.index_bytes {
+        Some(index) => info!(
+            "Peak memory: {} (node index: {})",
+            memory::format_size(peak),
+            memory::format_size(index)
+        ),
+        None => info!("Peak memory: {}", memory::format_size(peak)),
+    }
+}
+
+// Reads the schema while the arguments are parsed, so a bad file fails
+// before any input is read
+fn parse_schema(path: &str) -> Result<TagSchema, String> {
+    TagSchema::from_file(Path::new(path)).map_err(|e| format!("{:#}", e))
+}
+
+fn join_types(types: &[OsmType]) -> String {
+    types.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        try_parse_from(std::iter::once("osm-construction-extractor").chain(args.iter().copied()).map(OsString::from).collect())
+    }
+
+    #[test]
+    fn logging_options_go_before_or_after_subcommands() {
+        let args = parse(&["-q", "stats", "-i", "a.osm"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Stats(_))));
+        assert_eq!(args.quiet, 1);
+        let args = parse(&["stats", "-i", "a.osm", "-qq"]).unwrap();
+        assert_eq!(args.quiet, 2);
+        let args = parse(&["-v", "bench", "-i", "a.pbf"]).unwrap();
+        assert_eq!(args.verbose, 1);
+        let args = parse(&["--log-format", "json", "stats", "-i", "a.osm"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn extraction_options_conflict_with_subcommands() {
+        let e = parse(&["-i", "a.pbf", "-o", "a.geojson", "stats", "-i", "a.pbf"]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        let e = parse(&["--streaming", "-q", "stats", "-i", "a.pbf"]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+    }
+}
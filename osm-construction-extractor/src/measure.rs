@@ -0,0 +1,79 @@
+//! Geodesic lengths, areas and bounding boxes added to features as
+//! properties.
+
+use crate::feature::ExtractedFeature;
+use clap::ValueEnum;
+use geo::{BoundingRect, GeodesicArea, GeodesicLength, Geometry};
+
+/// Property holding the length of a line in meters
+pub const LENGTH_TAG: &str = "@length";
+/// Property holding the area of a polygon in square meters
+pub const AREA_TAG: &str = "@area";
+/// Property holding the bounding box of a geometry in the output CRS, as a
+/// JSON array `[min_x, min_y, max_x, max_y]`
+pub const BBOX_TAG: &str = "@bbox";
+
+/// A quantity computed from the geometry of each feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Measure {
+    /// Length of lines in meters, as `@length`
+    Length,
+    /// Area of polygons in square meters, as `@area`
+    Area,
+    /// Bounding box in the output CRS, as `@bbox`; GeoJSON writes it as
+    /// the feature's `bbox` member
+    Bbox,
+}
+
+/// Adds the requested measures to a feature with a lon/lat geometry.
+///
+/// Measures that do not apply to a geometry, such as the area of a line,
+/// are left out rather than written as zero; values are rounded to
+/// centimeters and square centimeters. The bounding box is left to
+/// [`add_bbox`], as it depends on the final geometry.
+pub fn add(feature: &mut ExtractedFeature, measures: &[Measure]) {
+    for measure in measures {
+        let (key, value) = match measure {
+            Measure::Length => (LENGTH_TAG, length(feature.geometry())),
+            Measure::Area => (AREA_TAG, area(feature.geometry())),
+            Measure::Bbox => continue,
+        };
+        if let Some(value) = value {
+            feature.tags_mut().insert(key.into(), format!("{:.2}", value).into());
+        }
+    }
+}
+
+/// Adds the bounding box of the feature's geometry as [`BBOX_TAG`], unless
+/// it is empty
+pub fn add_bbox(feature: &mut ExtractedFeature) {
+    if let Some(rect) = feature.geometry().bounding_rect() {
+        let bbox = serde_json::Value::from(vec![rect.min().x, rect.min().y, rect.max().x, rect.max().y]);
+        feature.tags_mut().insert(BBOX_TAG.into(), bbox.to_string().into());
+    }
+}
+
+fn length(geom: &Geometry) -> Option<f64> {
+    match geom {
+        Geometry::Line(line) => Some(line.geodesic_length()),
+        Geometry::LineString(line) => Some(line.geodesic_length()),
+        Geometry::MultiLineString(lines) => Some(lines.geodesic_length()),
+        Geometry::GeometryCollection(collection) => sum(collection.iter().filter_map(length)),
+        _ => None,
+    }
+}
+
+fn area(geom: &Geometry) -> Option<f64> {
+    match geom {
+        Geometry::Polygon(_) | Geometry::MultiPolygon(_) | Geometry::Rect(_) | Geometry::Triangle(_) => {
+            Some(geom.geodesic_area_unsigned())
+        }
+        Geometry::GeometryCollection(collection) => sum(collection.iter().filter_map(area)),
+        _ => None,
+    }
+}
+
+// None if no part had the measure
+fn sum(values: impl Iterator<Item = f64>) -> Option<f64> {
+    values.reduce(|a, b| a + b)
+}
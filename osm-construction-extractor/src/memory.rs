@@ -0,0 +1,77 @@
+//! Memory use of the process, and the limit that ends an extraction with an
+//! error before the OS kills it.
+
+use anyhow::{bail, Result};
+
+/// Parses a size such as `8G`, `512M`, `1.5GB` or a plain byte count; the
+/// units are binary, as for most memory settings, so `1K` is 1024 bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size {:?}, expected e.g. 8G or 512M", s))?;
+    let shift = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit {:?} in size {:?}, expected K, M, G or T", unit, s)),
+    };
+    let bytes = number * (1u64 << shift) as f64;
+    if bytes < 1.0 {
+        return Err(format!("size {:?} is too small", s));
+    }
+    Ok(bytes as u64)
+}
+
+/// Formats a byte count with a binary unit, e.g. `7.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+/// The high water mark of the resident set, only available on Linux
+pub fn peak_rss() -> Option<u64> {
+    status_kb("VmHWM:")
+}
+
+/// The resident memory the process allocated itself. Unlike the total
+/// resident set this leaves out memory-mapped files, such as a node cache,
+/// whose pages the OS can drop when memory runs short.
+pub fn allocated_rss() -> Option<u64> {
+    status_kb("RssAnon:")
+}
+
+fn status_kb(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with(field))?;
+    let kb: u64 = line.trim_start_matches(field).trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Fails once the memory allocated by the process exceeds `limit` bytes.
+///
+/// This is checked at intervals while reading, so the limit can be passed
+/// by what one interval allocates; where the OS does not report memory use
+/// it never fails.
+pub fn check(limit: u64) -> Result<()> {
+    if let Some(used) = allocated_rss().filter(|&used| used > limit) {
+        bail!(
+            "memory use reached {}, above the limit of {}; keep node locations on disk with a node cache in \
+             streaming mode, or extract less at once with a narrower filter or bbox",
+            format_size(used),
+            format_size(limit)
+        );
+    }
+    Ok(())
+}
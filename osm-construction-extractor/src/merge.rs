@@ -0,0 +1,208 @@
+//! Joining of ways that were only split to change a tag, such as a bridge
+//! or a stretch with another `maxspeed`, back into longer lines.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use geo::{Coord, Geometry, LineString};
+use std::collections::HashMap;
+
+/// Tag added to merged lines: a JSON array with the ids of the ways they
+/// were joined from, in order along the line, e.g. `[1001, 1002]`
+pub const WAY_IDS_TAG: &str = "@way_ids";
+
+/// Whether a feature is a way with a line geometry, the only features
+/// [`merge_ways`] joins
+pub fn is_mergeable(feature: &ExtractedFeature) -> bool {
+    feature.osm_type() == OsmType::Way && matches!(feature.geometry(), Geometry::LineString(_))
+}
+
+/// Joins ways with identical tags where one ends at the node the next one
+/// starts at, and no other way with these tags starts or ends there.
+///
+/// Features other than line ways are returned unchanged. Ways are never
+/// reversed, so that oneway streets and waterways keep their direction; as
+/// splitting a way keeps the direction of its parts this finds the usual
+/// splits. A merged line keeps the id of its first way and lists all of
+/// them in [`WAY_IDS_TAG`]; features keep the order of their first way.
+pub fn merge_ways(features: Vec<ExtractedFeature>) -> Vec<ExtractedFeature> {
+    let mut groups: HashMap<Vec<(&str, &str)>, Vec<usize>> = HashMap::new();
+    for (i, feature) in features.iter().enumerate() {
+        if is_mergeable(feature) {
+            let mut tags: Vec<(&str, &str)> = feature.tags().iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            tags.sort_unstable();
+            groups.entry(tags).or_default().push(i);
+        }
+    }
+
+    // The index of the line following each line
+    let mut next: HashMap<usize, usize> = HashMap::new();
+    for lines in groups.values().filter(|lines| lines.len() > 1) {
+        let mut starts: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        let mut ends: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for &i in lines {
+            if let Some((first, last)) = endpoints(&features[i]) {
+                starts.entry(key(first)).or_default().push(i);
+                ends.entry(key(last)).or_default().push(i);
+            }
+        }
+        for (point, ending) in &ends {
+            if let (Some(&[starting]), [ending]) = (starts.get(point).map(Vec::as_slice), ending.as_slice()) {
+                if starting != *ending {
+                    next.insert(*ending, starting);
+                }
+            }
+        }
+    }
+    if next.is_empty() {
+        return features;
+    }
+
+    // Chains start at lines nothing leads to; whatever is left over after
+    // them forms rings, which are cut open at their first line
+    let mut has_previous = vec![false; features.len()];
+    for &i in next.values() {
+        has_previous[i] = true;
+    }
+    let mut chain_of: Vec<Option<usize>> = vec![None; features.len()];
+    let mut chains: Vec<Vec<usize>> = Vec::new();
+    let heads = (0..features.len()).filter(|&i| next.contains_key(&i) && !has_previous[i]);
+    let rings = (0..features.len()).filter(|&i| next.contains_key(&i));
+    for head in heads.chain(rings) {
+        if chain_of[head].is_some() {
+            continue;
+        }
+        let mut chain = vec![head];
+        chain_of[head] = Some(chains.len());
+        let mut current = head;
+        while let Some(&following) = next.get(&current) {
+            if chain_of[following].is_some() {
+                break;
+            }
+            chain_of[following] = Some(chains.len());
+            chain.push(following);
+            current = following;
+        }
+        chains.push(chain);
+    }
+
+    let mut slots: Vec<Option<ExtractedFeature>> = features.into_iter().map(Some).collect();
+    let mut merged = Vec::with_capacity(slots.len());
+    for i in 0..slots.len() {
+        match chain_of[i] {
+            None => merged.extend(slots[i].take()),
+            Some(chain) if chains[chain][0] == i => {
+                let parts: Vec<ExtractedFeature> = chains[chain].iter().filter_map(|&j| slots[j].take()).collect();
+                merged.push(join(parts));
+            }
+            // Written with the first line of its chain
+            Some(_) => {}
+        }
+    }
+    merged
+}
+
+fn endpoints(feature: &ExtractedFeature) -> Option<(Coord, Coord)> {
+    match feature.geometry() {
+        Geometry::LineString(line) => Some((*line.0.first()?, *line.0.last()?)),
+        _ => None,
+    }
+}
+
+// Shared nodes have bit-identical coordinates
+fn key(coord: Coord) -> (u64, u64) {
+    (coord.x.to_bits(), coord.y.to_bits())
+}
+
+fn join(parts: Vec<ExtractedFeature>) -> ExtractedFeature {
+    let ids: Vec<i64> = parts.iter().map(|part| part.id()).collect();
+    let mut parts = parts.into_iter();
+    let mut first = parts.next().expect("chains are never empty");
+    let mut coords = match first.geometry() {
+        Geometry::LineString(line) => line.0.clone(),
+        _ => unreachable!("only lines are merged"),
+    };
+    for part in parts {
+        if let Geometry::LineString(line) = part.geometry() {
+            // The shared node is already there as the end of the line so far
+            coords.extend_from_slice(&line.0[1..]);
+        }
+    }
+    first.set_geometry(Geometry::LineString(LineString(coords)));
+    first.tags_mut().insert(WAY_IDS_TAG.into(), serde_json::Value::from(ids).to_string().into());
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intern::TagMap;
+
+    fn way(id: i64, highway: &str, coords: &[(f64, f64)]) -> ExtractedFeature {
+        let mut tags = TagMap::new();
+        tags.insert("highway".into(), highway.into());
+        ExtractedFeature::new(OsmType::Way, id, tags, Geometry::LineString(coords.to_vec().into()))
+    }
+
+    // The ids and coordinates of the lines
+    fn lines(features: &[ExtractedFeature]) -> Vec<(i64, Vec<(f64, f64)>)> {
+        let coords = |feature: &ExtractedFeature| match feature.geometry() {
+            Geometry::LineString(line) => line.coords().map(|c| (c.x, c.y)).collect(),
+            other => panic!("not a line: {:?}", other),
+        };
+        features.iter().map(|feature| (feature.id(), coords(feature))).collect()
+    }
+
+    fn way_ids(feature: &ExtractedFeature) -> Option<&str> {
+        feature.tags().get(WAY_IDS_TAG).map(|ids| ids.as_str())
+    }
+
+    #[test]
+    fn chains_are_joined_in_order() {
+        let merged = merge_ways(vec![
+            way(2, "primary", &[(1.0, 0.0), (2.0, 0.0)]),
+            way(3, "primary", &[(2.0, 0.0), (3.0, 0.0)]),
+            way(4, "secondary", &[(3.0, 0.0), (4.0, 0.0)]),
+            way(1, "primary", &[(0.0, 0.0), (1.0, 0.0)]),
+        ]);
+        // The merged line is written where its first way was
+        assert_eq!(
+            lines(&merged),
+            [(4, vec![(3.0, 0.0), (4.0, 0.0)]), (1, vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)])]
+        );
+        assert_eq!((way_ids(&merged[0]), way_ids(&merged[1])), (None, Some("[1,2,3]")));
+    }
+
+    #[test]
+    fn rings_are_joined_from_their_first_way() {
+        let merged = merge_ways(vec![
+            way(1, "primary", &[(0.0, 0.0), (1.0, 0.0)]),
+            way(2, "primary", &[(1.0, 0.0), (1.0, 1.0)]),
+            way(3, "primary", &[(1.0, 1.0), (0.0, 0.0)]),
+        ]);
+        assert_eq!(lines(&merged), [(1, vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 0.0)])]);
+        assert_eq!(way_ids(&merged[0]), Some("[1,2,3]"));
+    }
+
+    #[test]
+    fn junctions_are_not_merged_across() {
+        let ways = vec![
+            way(1, "primary", &[(0.0, 0.0), (1.0, 0.0)]),
+            way(2, "primary", &[(1.0, 0.0), (2.0, 0.0)]),
+            way(3, "primary", &[(1.0, 0.0), (1.0, 1.0)]),
+        ];
+        assert_eq!(lines(&merge_ways(ways.clone())), lines(&ways));
+    }
+
+    #[test]
+    fn ways_are_never_reversed() {
+        // Drawn towards each other, and away from each other
+        let ways = vec![
+            way(1, "primary", &[(0.0, 0.0), (1.0, 0.0)]),
+            way(2, "primary", &[(2.0, 0.0), (1.0, 0.0)]),
+            way(3, "primary", &[(5.0, 0.0), (4.0, 0.0)]),
+            way(4, "primary", &[(5.0, 0.0), (6.0, 0.0)]),
+        ];
+        let merged = merge_ways(ways.clone());
+        assert_eq!(lines(&merged), lines(&ways));
+        assert!(merged.iter().all(|feature| way_ids(feature).is_none()));
+    }
+}
@@ -0,0 +1,212 @@
+//! Object metadata: version, timestamp, changeset and author.
+//!
+//! osmpbfreader drops the metadata of the objects it decodes, so it is read
+//! from the primitive blocks separately and carried along as tags with an
+//! `@` prefix, where filters and all output formats see it like any tag.
+
+use anyhow::Result;
+use osmpbfreader::osmformat::{Info, PrimitiveBlock};
+use osmpbfreader::{OsmObj, Tags};
+
+pub const VERSION_TAG: &str = "@version";
+/// In ISO 8601 form, e.g. `2024-03-01T12:00:00Z`
+pub const TIMESTAMP_TAG: &str = "@timestamp";
+pub const CHANGESET_TAG: &str = "@changeset";
+pub const UID_TAG: &str = "@uid";
+pub const USER_TAG: &str = "@user";
+
+/// All tags added by [`Metadata::add_to`]
+pub const TAGS: [&str; 5] = [VERSION_TAG, TIMESTAMP_TAG, CHANGESET_TAG, UID_TAG, USER_TAG];
+
+/// The metadata of one object; files written without it leave every field
+/// empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub version: Option<i32>,
+    /// Seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    pub changeset: Option<i64>,
+    pub uid: Option<i32>,
+    pub user: Option<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Adds the known fields to `tags`
+    pub fn add_to(&self, tags: &mut Tags) {
+        if let Some(version) = self.version {
+            tags.insert(VERSION_TAG.into(), version.to_string().into());
+        }
+        if let Some(timestamp) = self.timestamp {
+            tags.insert(TIMESTAMP_TAG.into(), format_timestamp(timestamp).into());
+        }
+        if let Some(changeset) = self.changeset {
+            tags.insert(CHANGESET_TAG.into(), changeset.to_string().into());
+        }
+        if let Some(uid) = self.uid {
+            tags.insert(UID_TAG.into(), uid.to_string().into());
+        }
+        if let Some(user) = &self.user {
+            tags.insert(USER_TAG.into(), user.as_str().into());
+        }
+    }
+
+    /// Removes the tags added by [`Metadata::add_to`] from `tags`, returning
+    /// the metadata they held; values that do not parse are dropped
+    pub fn take_from(tags: &mut Tags) -> Metadata {
+        let mut take = |key: &str| tags.remove(key).map(|value| value.to_string());
+        Metadata {
+            version: take(VERSION_TAG).and_then(|v| v.parse().ok()),
+            timestamp: take(TIMESTAMP_TAG).and_then(|t| parse_timestamp(&t).ok()),
+            changeset: take(CHANGESET_TAG).and_then(|c| c.parse().ok()),
+            uid: take(UID_TAG).and_then(|u| u.parse().ok()),
+            user: take(USER_TAG),
+        }
+    }
+
+    fn from_info(info: &Info, block: &PrimitiveBlock) -> Metadata {
+        Metadata {
+            version: info.version,
+            timestamp: info.timestamp.map(|t| t * block.date_granularity() as i64 / 1000),
+            changeset: info.changeset,
+            uid: info.uid,
+            user: info.user_sid.and_then(|sid| block_string(block, sid as usize)),
+        }
+    }
+}
+
+/// Removes the tags added by [`Metadata::add_to`]
+pub fn strip(tags: &mut Tags) {
+    for key in TAGS {
+        tags.remove(key);
+    }
+}
+
+/// Adds the metadata of a block to the objects decoded from it, which must
+/// be all of them in block order
+pub fn attach(block: &PrimitiveBlock, objects: &mut [OsmObj]) {
+    for (obj, metadata) in objects.iter_mut().zip(of_block(block)) {
+        let tags = match obj {
+            OsmObj::Node(node) => &mut node.tags,
+            OsmObj::Way(way) => &mut way.tags,
+            OsmObj::Relation(relation) => &mut relation.tags,
+        };
+        metadata.add_to(tags);
+    }
+}
+
+// The metadata of all objects of a block, in the order osmpbfreader decodes
+// them: per group its plain nodes, dense nodes, ways and then relations
+fn of_block(block: &PrimitiveBlock) -> Vec<Metadata> {
+    let mut all = Vec::new();
+    for group in &block.primitivegroup {
+        for node in &group.nodes {
+            all.push(Metadata::from_info(&node.info, block));
+        }
+        let dense = &group.dense;
+        let info = &dense.denseinfo;
+        let (mut timestamp, mut changeset, mut uid, mut user_sid) = (0i64, 0i64, 0i32, 0i32);
+        for i in 0..dense.id.len() {
+            if info.version.is_empty() {
+                all.push(Metadata::default());
+                continue;
+            }
+            timestamp += info.timestamp.get(i).copied().unwrap_or(0);
+            changeset += info.changeset.get(i).copied().unwrap_or(0);
+            uid += info.uid.get(i).copied().unwrap_or(0);
+            user_sid += info.user_sid.get(i).copied().unwrap_or(0);
+            all.push(Metadata {
+                version: info.version.get(i).copied(),
+                timestamp: Some(timestamp * block.date_granularity() as i64 / 1000),
+                changeset: Some(changeset),
+                uid: Some(uid),
+                user: block_string(block, user_sid as usize),
+            });
+        }
+        for way in &group.ways {
+            all.push(Metadata::from_info(&way.info, block));
+        }
+        for relation in &group.relations {
+            all.push(Metadata::from_info(&relation.info, block));
+        }
+    }
+    all
+}
+
+// Index 0 is the empty string, used for anonymous edits
+fn block_string(block: &PrimitiveBlock, index: usize) -> Option<String> {
+    match block.stringtable.s.get(index) {
+        Some(s) if index > 0 => Some(String::from_utf8_lossy(s).into_owned()),
+        _ => None,
+    }
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DDTHH:MM:SSZ`
+pub fn format_timestamp(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Parses a UTC timestamp as written in OSM files, `YYYY-MM-DDTHH:MM:SSZ`,
+/// or just a date, `YYYY-MM-DD`, into seconds since the Unix epoch
+pub fn parse_timestamp(s: &str) -> Result<i64> {
+    let invalid = || anyhow::anyhow!("invalid timestamp {:?}, expected e.g. 2024-03-01T12:00:00Z", s);
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z').ok_or_else(invalid)?)),
+        None => (s, None),
+    };
+    let number = |part: Option<&str>| part.and_then(|p| p.parse::<i64>().ok()).ok_or_else(invalid);
+    let mut date_parts = date.splitn(3, '-');
+    let year = number(date_parts.next())?;
+    let month = number(date_parts.next())?;
+    let day = number(date_parts.next())?;
+    let mut secs = 0;
+    if let Some(time) = time {
+        let mut time_parts = time.splitn(3, ':');
+        let (h, m, sec) = (number(time_parts.next())?, number(time_parts.next())?, number(time_parts.next())?);
+        if h > 23 || m > 59 || sec > 60 {
+            return Err(invalid());
+        }
+        secs = h * 3600 + m * 60 + sec;
+    }
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    Ok(days_from_civil(year, month, day) * 86400 + secs)
+}
+
+// Date conversions of the proleptic Gregorian calendar, after
+// http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
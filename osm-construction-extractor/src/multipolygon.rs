@@ -0,0 +1,294 @@
+use anyhow::{bail, Result};
+use geo::algorithm::coordinate_position::{coord_pos_relative_to_ring, CoordPos};
+use geo::orient::Direction;
+use geo::{Area, LineString, MultiPolygon, Orient, Polygon};
+use crate::store::ObjectStore;
+use osmpbfreader::{NodeId, OsmId, Relation};
+
+/// Whether a relation is assembled as an area rather than a member collection
+pub fn is_area_relation(relation: &Relation) -> bool {
+    relation
+        .tags
+        .get("type")
+        .is_some_and(|t| t == "multipolygon" || t == "boundary")
+}
+
+// A chain of member ways joined at shared end nodes
+struct Chain {
+    nodes: Vec<NodeId>,
+}
+
+impl Chain {
+    fn first(&self) -> NodeId {
+        self.nodes[0]
+    }
+
+    fn last(&self) -> NodeId {
+        self.nodes[self.nodes.len() - 1]
+    }
+
+    fn is_closed(&self) -> bool {
+        self.nodes.len() >= 4 && self.first() == self.last()
+    }
+}
+
+/// Assembles the member ways of a multipolygon relation into a `MultiPolygon`.
+///
+/// Member ways are stitched into closed rings at shared end nodes, regardless
+/// of how the rings were split up by mappers. Whether a ring is an outer or an
+/// inner ring is decided by nesting depth rather than the (often wrong) member
+/// roles: rings inside an even number of other rings are outers, the others
+/// become holes of their immediate parent. Outer rings are oriented
+/// counter-clockwise and inner rings clockwise, as required by RFC 7946.
+pub fn assemble<S: ObjectStore + ?Sized>(relation: &Relation, store: &S) -> Result<MultiPolygon> {
+    let mut chains: Vec<Chain> = Vec::new();
+    for member in &relation.refs {
+        if let OsmId::Way(way_id) = member.member {
+            match store.way_nodes(way_id) {
+                Some(nodes) if nodes.len() >= 2 => chains.push(Chain { nodes: nodes.to_vec() }),
+                _ => {}
+            }
+        }
+    }
+    if chains.is_empty() {
+        bail!("no member ways available");
+    }
+
+    let rings = build_rings(chains)?;
+    let rings = rings
+        .into_iter()
+        .map(|ring| resolve_ring(&ring, store))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(nest_rings(rings))
+}
+
+// Joins chains end-to-end until each one is closed
+fn build_rings(mut open: Vec<Chain>) -> Result<Vec<Chain>> {
+    let mut rings = Vec::new();
+
+    while let Some(mut current) = open.pop() {
+        while !current.is_closed() {
+            let end = current.last();
+            let Some(pos) = open.iter().position(|c| c.first() == end || c.last() == end) else {
+                bail!("ring starting at node {} is not closed", current.first().0);
+            };
+            let mut next = open.swap_remove(pos);
+            if next.first() != end {
+                next.nodes.reverse();
+            }
+            current.nodes.extend_from_slice(&next.nodes[1..]);
+        }
+        rings.push(current);
+    }
+
+    Ok(rings)
+}
+
+fn resolve_ring<S: ObjectStore + ?Sized>(ring: &Chain, store: &S) -> Result<LineString> {
+    let mut coords = Vec::with_capacity(ring.nodes.len());
+    for &node_id in &ring.nodes {
+        match store.node_location(node_id) {
+            Some(coord) => coords.push(coord),
+            None => bail!("node {} of a ring is missing", node_id.0),
+        }
+    }
+    Ok(LineString(coords))
+}
+
+// True if `inner` lies inside `outer`. Rings in OSM may share nodes, so
+// vertices on the boundary are skipped until one decides the question.
+fn ring_contains(outer: &LineString, inner: &LineString) -> bool {
+    for c in inner.coords() {
+        match coord_pos_relative_to_ring(*c, outer) {
+            CoordPos::Inside => return true,
+            CoordPos::Outside => return false,
+            CoordPos::OnBoundary => continue,
+        }
+    }
+    false
+}
+
+fn nest_rings(rings: Vec<LineString>) -> MultiPolygon {
+    // Largest first, so every ring's parent is already placed when it is visited
+    let mut rings: Vec<(f64, LineString)> = rings
+        .into_iter()
+        .map(|r| (Polygon::new(r.clone(), vec![]).unsigned_area(), r))
+        .collect();
+    rings.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut parent: Vec<Option<usize>> = Vec::with_capacity(rings.len());
+    let mut depth: Vec<usize> = Vec::with_capacity(rings.len());
+    for i in 0..rings.len() {
+        // The smallest enclosing ring is the last one found in area order
+        let p = (0..i).rev().find(|&j| ring_contains(&rings[j].1, &rings[i].1));
+        depth.push(p.map_or(0, |j| depth[j] + 1));
+        parent.push(p);
+    }
+
+    let mut polygons: Vec<Option<(LineString, Vec<LineString>)>> = vec![None; rings.len()];
+    let mut holes: Vec<(usize, LineString)> = Vec::new();
+    for (i, (_, ring)) in rings.into_iter().enumerate() {
+        if depth[i].is_multiple_of(2) {
+            polygons[i] = Some((ring, Vec::new()));
+        } else if let Some(p) = parent[i] {
+            holes.push((p, ring));
+        }
+    }
+    for (p, ring) in holes {
+        if let Some((_, interiors)) = polygons[p].as_mut() {
+            interiors.push(ring);
+        }
+    }
+
+    MultiPolygon(
+        polygons
+            .into_iter()
+            .flatten()
+            .map(|(exterior, interiors)| Polygon::new(exterior, interiors).orient(Direction::Default))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Winding;
+    use osmpbfreader::{Node, OsmObj, Ref, RelationId, Tags, Way, WayId};
+    use std::collections::BTreeMap;
+
+    type Objects = BTreeMap<OsmId, OsmObj>;
+
+    fn node(objects: &mut Objects, id: i64, x: i32, y: i32) {
+        let node = Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: y * 10_000_000, decimicro_lon: x * 10_000_000 };
+        objects.insert(node.id.into(), OsmObj::Node(node));
+    }
+
+    fn way(objects: &mut Objects, id: i64, nodes: &[i64]) {
+        let way = Way { id: WayId(id), tags: Tags::new(), nodes: nodes.iter().map(|&n| NodeId(n)).collect() };
+        objects.insert(way.id.into(), OsmObj::Way(way));
+    }
+
+    // Adds the corners of a square as nodes `first..first + 4`, counter-
+    // clockwise from the lower left, and returns them as a closed ring
+    fn square(objects: &mut Objects, first: i64, x: i32, y: i32, size: i32) -> Vec<i64> {
+        for (i, (x, y)) in [(x, y), (x + size, y), (x + size, y + size), (x, y + size)].into_iter().enumerate() {
+            node(objects, first + i as i64, x, y);
+        }
+        vec![first, first + 1, first + 2, first + 3, first]
+    }
+
+    // Every member is tagged outer, as roles are not relied on
+    fn relation(ways: &[i64]) -> Relation {
+        let mut tags = Tags::new();
+        tags.insert("type".into(), "multipolygon".into());
+        let refs = ways.iter().map(|&w| Ref { member: WayId(w).into(), role: "outer".into() }).collect();
+        Relation { id: RelationId(1), tags, refs }
+    }
+
+    fn error(relation: &Relation, objects: &Objects) -> String {
+        match assemble(relation, objects) {
+            Ok(multipolygon) => panic!("assembled {:?}", multipolygon),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn rings_split_across_ways() {
+        let mut objects = Objects::new();
+        let ring = square(&mut objects, 1, 0, 0, 2);
+        // The second way runs against the first
+        way(&mut objects, 10, &ring[..3]);
+        way(&mut objects, 11, &[ring[0], ring[3], ring[2]]);
+        let multipolygon = assemble(&relation(&[10, 11]), &objects).unwrap();
+        assert_eq!(multipolygon.0.len(), 1);
+        assert_eq!(multipolygon.0[0].exterior().0.len(), 5);
+        assert!(multipolygon.0[0].interiors().is_empty());
+        assert_eq!(multipolygon.unsigned_area(), 4.0);
+    }
+
+    #[test]
+    fn rings_nest_by_depth() {
+        let mut objects = Objects::new();
+        let outer = square(&mut objects, 1, 0, 0, 10);
+        let hole = square(&mut objects, 5, 2, 2, 6);
+        let island = square(&mut objects, 9, 4, 4, 2);
+        way(&mut objects, 10, &outer);
+        way(&mut objects, 11, &hole);
+        way(&mut objects, 12, &island);
+        let multipolygon = assemble(&relation(&[12, 10, 11]), &objects).unwrap();
+        assert_eq!(multipolygon.0.len(), 2);
+        let (big, small) = (&multipolygon.0[0], &multipolygon.0[1]);
+        assert_eq!((big.unsigned_area(), small.unsigned_area()), (100.0 - 36.0, 4.0));
+        assert_eq!(big.interiors().len(), 1);
+        assert!(small.interiors().is_empty());
+        assert!(big.exterior().is_ccw() && small.exterior().is_ccw());
+        assert!(big.interiors()[0].is_cw());
+    }
+
+    #[test]
+    fn rings_touching_at_a_node() {
+        let mut objects = Objects::new();
+        let outer = square(&mut objects, 1, 0, 0, 4);
+        // A hole sharing the lower left corner of the outer ring
+        node(&mut objects, 5, 2, 0);
+        node(&mut objects, 6, 2, 2);
+        node(&mut objects, 7, 0, 2);
+        way(&mut objects, 10, &outer);
+        way(&mut objects, 11, &[1, 7, 6, 5, 1]);
+        let multipolygon = assemble(&relation(&[10, 11]), &objects).unwrap();
+        assert_eq!(multipolygon.0.len(), 1);
+        assert_eq!(multipolygon.0[0].interiors().len(), 1);
+        assert_eq!(multipolygon.unsigned_area(), 12.0);
+    }
+
+    #[test]
+    fn rings_stitched_from_reversed_ways() {
+        let mut objects = Objects::new();
+        let ring = square(&mut objects, 1, 0, 0, 2);
+        // Three ways, the middle one drawn the other way round
+        way(&mut objects, 10, &ring[..2]);
+        way(&mut objects, 11, &[ring[3], ring[2], ring[1]]);
+        way(&mut objects, 12, &ring[3..]);
+        let multipolygon = assemble(&relation(&[12, 10, 11]), &objects).unwrap();
+        assert_eq!(multipolygon.0.len(), 1);
+        assert_eq!(multipolygon.0[0].exterior().0.len(), 5);
+        assert_eq!(multipolygon.unsigned_area(), 4.0);
+    }
+
+    #[test]
+    fn outers_with_holes_side_by_side() {
+        let mut objects = Objects::new();
+        let left = square(&mut objects, 1, 0, 0, 4);
+        let left_hole = square(&mut objects, 5, 1, 1, 2);
+        // The ones on the right are drawn clockwise, the wrong way round
+        // for an outer ring
+        let mut right = square(&mut objects, 9, 10, 0, 4);
+        let mut right_hole = square(&mut objects, 13, 11, 1, 2);
+        right.reverse();
+        right_hole.reverse();
+        way(&mut objects, 10, &left);
+        way(&mut objects, 11, &left_hole);
+        way(&mut objects, 12, &right);
+        way(&mut objects, 13, &right_hole);
+        let multipolygon = assemble(&relation(&[11, 13, 10, 12]), &objects).unwrap();
+        assert_eq!(multipolygon.0.len(), 2);
+        for polygon in &multipolygon.0 {
+            assert_eq!(polygon.interiors().len(), 1);
+            assert_eq!(polygon.unsigned_area(), 12.0);
+            assert!(polygon.exterior().is_ccw());
+            assert!(polygon.interiors()[0].is_cw());
+        }
+    }
+
+    #[test]
+    fn incomplete_rings() {
+        let mut objects = Objects::new();
+        let ring = square(&mut objects, 1, 0, 0, 2);
+        way(&mut objects, 10, &ring[..4]);
+        assert_eq!(error(&relation(&[10]), &objects), "ring starting at node 1 is not closed");
+        way(&mut objects, 11, &[1, 99, 4, 1]);
+        assert_eq!(error(&relation(&[11]), &objects), "node 99 of a ring is missing");
+        assert_eq!(error(&relation(&[12]), &objects), "no member ways available");
+    }
+}
@@ -0,0 +1,110 @@
+//! Numeric values in SI units parsed from free-form measurement tags, such
+//! as `maxspeed=50 mph`, `width=3,5 m` or `maxheight=12'`.
+//!
+//! The parsed value of a tag is added as a property named after it with an
+//! `@si:` prefix, e.g. `@si:maxspeed`, and the tag itself is kept as it
+//! was, so an unexpected value can still be looked up. Lengths are in
+//! meters, speeds in meters per second and weights in kilograms; values
+//! that are not a measurement, such as `maxspeed=none` or
+//! `maxheight=default`, add nothing. With the `opening-hours` feature the
+//! `opening_hours` tag is normalized too, see [`hours::add_normalized`].
+
+use crate::building;
+#[cfg(feature = "opening-hours")]
+use crate::hours;
+use crate::feature::ExtractedFeature;
+
+/// Prefix of the properties holding the normalized values
+pub const NORMALIZED_TAG_PREFIX: &str = "@si:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quantity {
+    Length,
+    Speed,
+    Weight,
+}
+
+/// The tags that are normalized, with what they measure
+const KEYS: [(&str, Quantity); 17] = [
+    ("width", Quantity::Length),
+    ("est_width", Quantity::Length),
+    ("height", Quantity::Length),
+    ("min_height", Quantity::Length),
+    ("length", Quantity::Length),
+    ("maxheight", Quantity::Length),
+    ("maxheight:physical", Quantity::Length),
+    ("maxwidth", Quantity::Length),
+    ("maxwidth:physical", Quantity::Length),
+    ("maxlength", Quantity::Length),
+    ("maxspeed", Quantity::Speed),
+    ("maxspeed:forward", Quantity::Speed),
+    ("maxspeed:backward", Quantity::Speed),
+    ("maxspeed:advisory", Quantity::Speed),
+    ("minspeed", Quantity::Speed),
+    ("maxweight", Quantity::Weight),
+    ("maxaxleload", Quantity::Weight),
+];
+
+/// Adds the normalized value of every measurement tag of the feature that
+/// can be parsed; of a `;`-separated list only the first entry is used.
+/// Values are rounded to two decimals.
+pub fn add(feature: &mut ExtractedFeature) {
+    for (key, quantity) in KEYS {
+        let Some(value) = feature.tags().get(key) else {
+            continue;
+        };
+        let value = value.split(';').next().unwrap_or("").trim();
+        let parsed = match quantity {
+            Quantity::Length => parse_length(value),
+            Quantity::Speed => parse_speed(value),
+            Quantity::Weight => parse_weight(value),
+        };
+        if let Some(parsed) = parsed {
+            let normalized = format!("{}{}", NORMALIZED_TAG_PREFIX, key);
+            feature.tags_mut().insert(normalized.into(), format!("{:.2}", parsed).into());
+        }
+    }
+    #[cfg(feature = "opening-hours")]
+    hours::add_normalized(feature);
+}
+
+/// Parses a length into meters: meters, with or without `m`, `km`, `mi`,
+/// or feet and inches as `ft`, `12'` or `10'6"`; a decimal comma is
+/// accepted too
+pub fn parse_length(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    let (number, factor) = if let Some(km) = value.strip_suffix("km") {
+        (km, 1000.0)
+    } else if let Some(miles) = value.strip_suffix("mi") {
+        (miles, 1609.344)
+    } else {
+        return building::parse_height(&value);
+    };
+    let length: f64 = number.trim().parse().ok()?;
+    (length.is_finite() && length >= 0.0).then_some(length * factor)
+}
+
+/// Parses a speed into meters per second: km/h, with or without `km/h`,
+/// `mph` or `knots`
+pub fn parse_speed(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    let value = value.strip_suffix("km/h").or_else(|| value.strip_suffix("kmh")).unwrap_or(&value);
+    crate::graph::parse_maxspeed(value).map(|kmh| kmh / 3.6)
+}
+
+/// Parses a weight into kilograms: metric tonnes, with or without `t`,
+/// `kg`, pounds as `lbs` or US short tons as `st`
+pub fn parse_weight(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    let (number, factor) = if let Some(kg) = value.strip_suffix("kg") {
+        (kg, 1.0)
+    } else if let Some(pounds) = value.strip_suffix("lbs") {
+        (pounds, 0.453_592_37)
+    } else if let Some(short_tons) = value.strip_suffix("st") {
+        (short_tons, 907.184_74)
+    } else {
+        (value.strip_suffix('t').unwrap_or(&value), 1000.0)
+    };
+    let weight: f64 = number.trim().parse().ok()?;
+    (weight.is_finite() && weight > 0.0).then_some(weight * factor)
+}
@@ -0,0 +1,617 @@
+//! Reading of o5m files, as written by osmconvert and osmfilter.
+//!
+//! o5m is a sequence of datasets, each a type byte and (for most types) a
+//! length. Ids, coordinates and references are delta coded against the
+//! previous object, and strings can refer back to one of the last 15000
+//! string pairs; a reset dataset clears both.
+
+use crate::metadata::Metadata;
+use anyhow::{bail, Context, Result};
+use geo::Rect;
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+const NODE: u8 = 0x10;
+const WAY: u8 = 0x11;
+const RELATION: u8 = 0x12;
+const BBOX: u8 = 0xdb;
+const HEADER: u8 = 0xe0;
+const END: u8 = 0xfe;
+const RESET: u8 = 0xff;
+
+// Size of the table of recent string pairs, and the longest pair stored in
+// it (both strings and their terminating zeros)
+const TABLE_SIZE: usize = 15000;
+const MAX_TABLE_ENTRY: usize = 252;
+
+/// Iterator over the objects of an o5m file and their metadata, in file
+/// order.
+///
+/// Deleted objects, which only appear in o5c change files, are skipped.
+pub struct Reader<R: Read> {
+    input: R,
+    buf: Vec<u8>,
+    state: State,
+    bbox: Option<Rect>,
+    finished: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(input: R) -> Reader<R> {
+        Reader {
+            input,
+            buf: Vec::new(),
+            state: State::default(),
+            bbox: None,
+            finished: false,
+        }
+    }
+
+    /// The extent the file claims to cover, known once its bounding box
+    /// dataset has been read, which comes before the first object
+    pub fn bbox(&self) -> Option<Rect> {
+        self.bbox
+    }
+
+    // Reads datasets until one holds an object
+    fn next_object(&mut self) -> Result<Option<(OsmObj, Metadata)>> {
+        loop {
+            let mut kind = [0];
+            if self.input.read(&mut kind)? == 0 {
+                return Ok(None);
+            }
+            match kind[0] {
+                RESET => {
+                    self.state = State::default();
+                    continue;
+                }
+                END => return Ok(None),
+                // Single-byte datasets without a length
+                0xf0..=0xfd => continue,
+                _ => {}
+            }
+            let len = read_varint(&mut self.input)?;
+            self.buf.resize(len as usize, 0);
+            self.input.read_exact(&mut self.buf).context("o5m file ends inside a dataset")?;
+            let mut data = Data { bytes: &self.buf, pos: 0 };
+            let obj = match kind[0] {
+                NODE => self.state.node(&mut data)?,
+                WAY => self.state.way(&mut data)?,
+                RELATION => self.state.relation(&mut data)?,
+                HEADER => {
+                    if self.buf != b"o5m2" && self.buf != b"o5c2" {
+                        bail!("unsupported o5m header {:?}", String::from_utf8_lossy(&self.buf));
+                    }
+                    None
+                }
+                BBOX => {
+                    // In 100 nanodegrees, as the coordinates of nodes
+                    let mut degrees = || data.signed().map(|value| value as f64 * 1e-7);
+                    let (left, bottom, right, top) = (degrees()?, degrees()?, degrees()?, degrees()?);
+                    self.bbox = Some(Rect::new((left, bottom), (right, top)));
+                    None
+                }
+                // File timestamp, sync and jump datasets
+                _ => None,
+            };
+            if obj.is_some() {
+                return Ok(obj);
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<(OsmObj, Metadata)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let next = self.next_object().transpose();
+        if !matches!(next, Some(Ok(_))) {
+            self.finished = true;
+        }
+        next
+    }
+}
+
+// Delta coding bases and string table, all cleared by a reset dataset
+#[derive(Default)]
+struct State {
+    id: i64,
+    timestamp: i64,
+    changeset: i64,
+    lon: i64,
+    lat: i64,
+    way_node: i64,
+    members: [i64; 3],
+    strings: VecDeque<Vec<u8>>,
+}
+
+impl State {
+    fn node(&mut self, data: &mut Data) -> Result<Option<(OsmObj, Metadata)>> {
+        self.id += data.signed()?;
+        let id = NodeId(self.id);
+        let metadata = self.info(data)?;
+        if data.at_end() {
+            return Ok(None);
+        }
+        self.lon += data.signed()?;
+        self.lat += data.signed()?;
+        let node = Node {
+            id,
+            decimicro_lat: self.lat as i32,
+            decimicro_lon: self.lon as i32,
+            tags: self.tags(data)?,
+        };
+        Ok(Some((OsmObj::Node(node), metadata)))
+    }
+
+    fn way(&mut self, data: &mut Data) -> Result<Option<(OsmObj, Metadata)>> {
+        self.id += data.signed()?;
+        let id = WayId(self.id);
+        let metadata = self.info(data)?;
+        if data.at_end() {
+            return Ok(None);
+        }
+        let refs_end = data.section_end()?;
+        let mut nodes = Vec::new();
+        while data.pos < refs_end {
+            self.way_node += data.signed()?;
+            nodes.push(NodeId(self.way_node));
+        }
+        let way = Way {
+            id,
+            nodes,
+            tags: self.tags(data)?,
+        };
+        Ok(Some((OsmObj::Way(way), metadata)))
+    }
+
+    fn relation(&mut self, data: &mut Data) -> Result<Option<(OsmObj, Metadata)>> {
+        self.id += data.signed()?;
+        let id = RelationId(self.id);
+        let metadata = self.info(data)?;
+        if data.at_end() {
+            return Ok(None);
+        }
+        let refs_end = data.section_end()?;
+        let mut refs = Vec::new();
+        while data.pos < refs_end {
+            let delta = data.signed()?;
+            // The member type is the first character of the role string
+            let type_role = self.string(data, 1)?;
+            let (kind, role) = match type_role.split_first() {
+                Some((kind @ b'0'..=b'2', role)) => ((kind - b'0') as usize, role),
+                _ => bail!("invalid member type in o5m relation {}", id.0),
+            };
+            self.members[kind] += delta;
+            let member_id = self.members[kind];
+            let member = match kind {
+                0 => OsmId::Node(NodeId(member_id)),
+                1 => OsmId::Way(WayId(member_id)),
+                _ => OsmId::Relation(RelationId(member_id)),
+            };
+            let role = String::from_utf8_lossy(role.split(|&b| b == 0).next().unwrap_or_default());
+            refs.push(Ref { member, role: role.as_ref().into() });
+        }
+        let relation = Relation {
+            id,
+            refs,
+            tags: self.tags(data)?,
+        };
+        Ok(Some((OsmObj::Relation(relation), metadata)))
+    }
+
+    // Version, timestamp, changeset and author
+    fn info(&mut self, data: &mut Data) -> Result<Metadata> {
+        let mut metadata = Metadata::default();
+        match data.unsigned()? {
+            0 => return Ok(metadata),
+            version => metadata.version = Some(version as i32),
+        }
+        self.timestamp += data.signed()?;
+        if self.timestamp == 0 {
+            return Ok(metadata);
+        }
+        metadata.timestamp = Some(self.timestamp);
+        self.changeset += data.signed()?;
+        metadata.changeset = Some(self.changeset);
+        if data.at_end() {
+            return Ok(metadata);
+        }
+        // The author is a string pair of the uid as a varint and the user
+        // name; an anonymous author is stored as just the uid
+        let entry = if data.peek()? != 0 {
+            self.reference(data)?
+        } else {
+            data.pos += 1;
+            let start = data.pos;
+            let uid = data.unsigned()?;
+            if data.byte()? != 0 {
+                bail!("missing user name in o5m file");
+            }
+            if uid != 0 {
+                data.skip_string()?;
+            }
+            let entry = data.bytes[start..data.pos].to_vec();
+            self.remember(entry.clone());
+            entry
+        };
+        let mut author = Data { bytes: &entry, pos: 0 };
+        let uid = author.unsigned()?;
+        if uid != 0 {
+            author.pos += 1;
+            let user = entry[author.pos..].split(|&b| b == 0).next().unwrap_or_default();
+            metadata.uid = Some(uid as i32);
+            metadata.user = Some(String::from_utf8_lossy(user).into_owned());
+        }
+        Ok(metadata)
+    }
+
+    fn tags(&mut self, data: &mut Data) -> Result<Tags> {
+        let mut tags = Tags::new();
+        while !data.at_end() {
+            let pair = self.string(data, 2)?;
+            let mut parts = pair.split(|&b| b == 0);
+            let key = String::from_utf8_lossy(parts.next().unwrap_or_default());
+            let value = String::from_utf8_lossy(parts.next().unwrap_or_default());
+            tags.insert(key.as_ref().into(), value.as_ref().into());
+        }
+        Ok(tags)
+    }
+
+    // Reads `count` zero-terminated strings, given inline or as a reference,
+    // returning them with their terminators
+    fn string(&mut self, data: &mut Data, count: usize) -> Result<Vec<u8>> {
+        if data.peek()? != 0 {
+            return self.reference(data);
+        }
+        data.pos += 1;
+        let start = data.pos;
+        for _ in 0..count {
+            data.skip_string()?;
+        }
+        let entry = data.bytes[start..data.pos].to_vec();
+        self.remember(entry.clone());
+        Ok(entry)
+    }
+
+    fn reference(&mut self, data: &mut Data) -> Result<Vec<u8>> {
+        let index = data.unsigned()? as usize;
+        match index.checked_sub(1).and_then(|i| self.strings.get(i)) {
+            Some(entry) => Ok(entry.clone()),
+            None => bail!("invalid string reference {} in o5m file", index),
+        }
+    }
+
+    fn remember(&mut self, entry: Vec<u8>) {
+        if entry.len() <= MAX_TABLE_ENTRY {
+            if self.strings.len() == TABLE_SIZE {
+                self.strings.pop_back();
+            }
+            self.strings.push_front(entry);
+        }
+    }
+}
+
+// The contents of one dataset
+struct Data<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Data<'_> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn peek(&self) -> Result<u8> {
+        match self.bytes.get(self.pos) {
+            Some(&b) => Ok(b),
+            None => bail!("o5m dataset ends unexpectedly"),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn unsigned(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("overlong number in o5m file")
+    }
+
+    // Zigzag coded: the lowest bit is the sign
+    fn signed(&mut self) -> Result<i64> {
+        let value = self.unsigned()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    // Reads the length of a reference section, returning where it ends
+    fn section_end(&mut self) -> Result<usize> {
+        let len = self.unsigned()? as usize;
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            bail!("o5m reference section exceeds its dataset");
+        }
+        Ok(end)
+    }
+
+    fn skip_string(&mut self) -> Result<()> {
+        while self.byte()? != 0 {}
+        Ok(())
+    }
+}
+
+fn read_varint<R: Read>(input: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0];
+        if let Err(e) = input.read_exact(&mut b) {
+            return Err(match e.kind() {
+                io::ErrorKind::UnexpectedEof => anyhow::anyhow!("o5m file ends inside a dataset"),
+                _ => e.into(),
+            });
+        }
+        value |= ((b[0] & 0x7f) as u64) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("overlong number in o5m file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsigned(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    fn signed(value: i64) -> Vec<u8> {
+        unsigned(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    // An inline string pair, such as a tag
+    fn pair(first: &str, second: &str) -> Vec<u8> {
+        [&[0][..], first.as_bytes(), &[0], second.as_bytes(), &[0]].concat()
+    }
+
+    fn dataset(kind: u8, data: &[u8]) -> Vec<u8> {
+        [&[kind][..], &unsigned(data.len() as u64), data].concat()
+    }
+
+    fn header() -> Vec<u8> {
+        dataset(HEADER, b"o5m2")
+    }
+
+    // A node without metadata
+    fn node(id: i64, lon: i64, lat: i64, tags: &[u8]) -> Vec<u8> {
+        dataset(NODE, &[signed(id), vec![0], signed(lon), signed(lat), tags.to_vec()].concat())
+    }
+
+    fn read(bytes: &[u8]) -> Result<Vec<(OsmObj, Metadata)>> {
+        Reader::new(bytes).collect()
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match read(bytes) {
+            Ok(objs) => panic!("{} objects read from invalid o5m", objs.len()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn nodes(objs: &[(OsmObj, Metadata)]) -> Vec<(i64, i32, i32)> {
+        let node = |obj: &OsmObj| obj.node().map(|n| (n.id.0, n.decimicro_lon, n.decimicro_lat));
+        objs.iter().filter_map(|(obj, _)| node(obj)).collect()
+    }
+
+    #[test]
+    fn numbers() {
+        let mut data = Data { bytes: &[0x05, 0xc3, 0x01, 0x00, 0x01, 0x02, 0x03], pos: 0 };
+        assert_eq!(data.unsigned().unwrap(), 5);
+        assert_eq!(data.unsigned().unwrap(), 0xc3);
+        let values: Vec<i64> = (0..4).map(|_| data.signed().unwrap()).collect();
+        assert_eq!(values, [0, -1, 1, -2]);
+        assert!(data.at_end());
+
+        let mut data = Data { bytes: &[0xff; 10], pos: 0 };
+        assert_eq!(data.unsigned().unwrap_err().to_string(), "overlong number in o5m file");
+        for value in [0, 1, -1, 63, -64, 1 << 40, i64::MIN / 2] {
+            let bytes = signed(value);
+            assert_eq!(Data { bytes: &bytes, pos: 0 }.signed().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn node_deltas() {
+        let bytes = [header(), node(5, 100, -200, &[]), node(3, -150, 50, &[]), node(-10, 0, 0, &[])].concat();
+        assert_eq!(nodes(&read(&bytes).unwrap()), [(5, 100, -200), (8, -50, -150), (-2, -50, -150)]);
+    }
+
+    #[test]
+    fn reset_clears_deltas() {
+        let bytes = [header(), node(5, 100, 100, &[]), vec![RESET], header(), node(5, 100, 100, &[])].concat();
+        assert_eq!(nodes(&read(&bytes).unwrap()), [(5, 100, 100), (5, 100, 100)]);
+    }
+
+    #[test]
+    fn string_references() {
+        let cafe = pair("amenity", "cafe");
+        let name = pair("name", "Zur Post");
+        let bytes = [
+            header(),
+            node(1, 0, 0, &[cafe, name].concat()),
+            // The most recent string is 1, the one before it 2
+            node(1, 0, 0, &[unsigned(2), unsigned(1)].concat()),
+            node(1, 0, 0, &unsigned(1)),
+        ]
+        .concat();
+        let objs = read(&bytes).unwrap();
+        let tags: Vec<&Tags> = objs.iter().map(|(obj, _)| obj.tags()).collect();
+        for tags in &tags[..2] {
+            assert_eq!(tags.len(), 2);
+            assert!(tags.contains("amenity", "cafe"));
+            assert!(tags.contains("name", "Zur Post"));
+        }
+        assert_eq!(tags[2].len(), 1);
+        assert!(tags[2].contains("name", "Zur Post"));
+
+        let bytes = [header(), node(1, 0, 0, &[pair("a", "b"), unsigned(2)].concat())].concat();
+        assert_eq!(error(&bytes), "invalid string reference 2 in o5m file");
+        let bytes = [header(), node(1, 0, 0, &unsigned(1))].concat();
+        assert_eq!(error(&bytes), "invalid string reference 1 in o5m file");
+    }
+
+    #[test]
+    fn metadata() {
+        let info = |version, timestamp, changeset, author: &[u8]| {
+            [unsigned(version), signed(timestamp), signed(changeset), author.to_vec()].concat()
+        };
+        let author = [&[0][..], &unsigned(42), &[0], b"mapper", &[0]].concat();
+        let anonymous = [&[0][..], &unsigned(0), &[0]].concat();
+        let with_info = |id: i64, info: Vec<u8>| dataset(NODE, &[signed(id), info, signed(0), signed(0)].concat());
+        let bytes = [
+            header(),
+            with_info(1, info(3, 1_600_000_000, 100, &author)),
+            // The author again, as a reference
+            with_info(1, info(1, 60, 1, &unsigned(1))),
+            with_info(1, info(2, 60, 1, &anonymous)),
+            with_info(1, vec![0]),
+        ]
+        .concat();
+        let metadata: Vec<Metadata> = read(&bytes).unwrap().into_iter().map(|(_, metadata)| metadata).collect();
+        let expected = |version, timestamp, changeset, author: Option<(i32, &str)>| Metadata {
+            version: Some(version),
+            timestamp: Some(timestamp),
+            changeset: Some(changeset),
+            uid: author.map(|(uid, _)| uid),
+            user: author.map(|(_, user)| user.to_string()),
+        };
+        assert_eq!(metadata[0], expected(3, 1_600_000_000, 100, Some((42, "mapper"))));
+        assert_eq!(metadata[1], expected(1, 1_600_000_060, 101, Some((42, "mapper"))));
+        assert_eq!(metadata[2], expected(2, 1_600_000_120, 102, None));
+        assert!(metadata[3].is_empty());
+    }
+
+    #[test]
+    fn deleted_objects_are_skipped() {
+        // A version and timestamp but nothing after the info
+        let deleted = dataset(NODE, &[signed(4), unsigned(2), signed(1), signed(1)].concat());
+        let bytes = [header(), node(1, 10, 10, &[]), deleted, node(1, 10, 10, &[])].concat();
+        assert_eq!(nodes(&read(&bytes).unwrap()), [(1, 10, 10), (6, 20, 20)]);
+    }
+
+    #[test]
+    fn ways() {
+        let way = |id: i64, refs: &[i64], tags: &[u8]| {
+            let refs: Vec<u8> = refs.iter().flat_map(|&r| signed(r)).collect();
+            dataset(WAY, &[signed(id), vec![0], unsigned(refs.len() as u64), refs, tags.to_vec()].concat())
+        };
+        let bytes = [
+            header(),
+            way(10, &[100, 2, -1], &pair("building", "yes")),
+            // Node references carry on from the previous way
+            way(1, &[-1, 300], &[]),
+        ]
+        .concat();
+        let objs = read(&bytes).unwrap();
+        let ways: Vec<&Way> = objs.iter().filter_map(|(obj, _)| obj.way()).collect();
+        assert_eq!(ways[0].id, WayId(10));
+        assert_eq!(ways[0].nodes, [NodeId(100), NodeId(102), NodeId(101)]);
+        assert!(ways[0].tags.contains("building", "yes"));
+        assert_eq!(ways[1].id, WayId(11));
+        assert_eq!(ways[1].nodes, [NodeId(100), NodeId(400)]);
+        assert!(ways[1].tags.is_empty());
+    }
+
+    #[test]
+    fn relations() {
+        let member = |delta: i64, type_role: &str| {
+            [signed(delta), [&[0][..], type_role.as_bytes(), &[0]].concat()].concat()
+        };
+        let relation = |id: i64, members: &[u8]| {
+            let tags = pair("type", "multipolygon");
+            dataset(RELATION, &[signed(id), vec![0], unsigned(members.len() as u64), members.to_vec(), tags].concat())
+        };
+        let members = [member(10, "1outer"), member(20, "1inner"), member(10, "0"), member(5, "2subarea")].concat();
+        let bytes = [
+            header(),
+            relation(7, &members),
+            // Each member type keeps its own delta, and the type and role
+            // strings go into the table
+            relation(1, &[signed(-5), unsigned(4)].concat()),
+        ]
+        .concat();
+        let objs = read(&bytes).unwrap();
+        let relations: Vec<&Relation> = objs.iter().filter_map(|(obj, _)| obj.relation()).collect();
+        let refs = |relation: &Relation| -> Vec<(OsmId, String)> {
+            relation.refs.iter().map(|r| (r.member, r.role.to_string())).collect()
+        };
+        assert_eq!(relations[0].id, RelationId(7));
+        assert_eq!(
+            refs(relations[0]),
+            [
+                (OsmId::Way(WayId(10)), "outer".to_string()),
+                (OsmId::Way(WayId(30)), "inner".to_string()),
+                (OsmId::Node(NodeId(10)), String::new()),
+                (OsmId::Relation(RelationId(5)), "subarea".to_string()),
+            ]
+        );
+        assert!(relations[0].tags.contains("type", "multipolygon"));
+        assert_eq!(relations[1].id, RelationId(8));
+        assert_eq!(refs(relations[1]), [(OsmId::Way(WayId(25)), "inner".to_string())]);
+
+        let bytes = [header(), relation(1, &member(1, "3outer"))].concat();
+        assert_eq!(error(&bytes), "invalid member type in o5m relation 1");
+    }
+
+    #[test]
+    fn datasets_without_objects() {
+        let bounding_box = dataset(BBOX, &[signed(-1), signed(-1), signed(1), signed(1)].concat());
+        let bytes = [header(), vec![0xf0], bounding_box, node(1, 0, 0, &[]), vec![END], node(1, 0, 0, &[])].concat();
+        assert_eq!(nodes(&read(&bytes).unwrap()), [(1, 0, 0)]);
+        assert!(read(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bounding_box() {
+        let corners = [signed(-10_000_000), signed(-5_000_000), signed(20_000_000), signed(15_000_000)].concat();
+        let bytes = [header(), dataset(BBOX, &corners), node(1, 0, 0, &[])].concat();
+        let mut reader = Reader::new(&bytes[..]);
+        assert_eq!(reader.bbox(), None);
+        assert!(reader.next().is_some());
+        assert_eq!(reader.bbox(), Some(Rect::new((-1.0, -0.5), (2.0, 1.5))));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert_eq!(error(&dataset(HEADER, b"o5m1")), "unsupported o5m header \"o5m1\"");
+        let bytes = [header(), node(1, 0, 0, &pair("a", "b"))].concat();
+        assert_eq!(error(&bytes[..bytes.len() - 2]), "o5m file ends inside a dataset");
+        assert_eq!(error(&[NODE, 0x80]), "o5m file ends inside a dataset");
+        let truncated = dataset(NODE, &[signed(1), vec![0], signed(0), signed(0), b"\0a\0b".to_vec()].concat());
+        assert_eq!(error(&[header(), truncated].concat()), "o5m dataset ends unexpectedly");
+        let refs = dataset(WAY, &[signed(1), vec![0], unsigned(5), signed(1)].concat());
+        assert_eq!(error(&[header(), refs].concat()), "o5m reference section exceeds its dataset");
+    }
+}
@@ -0,0 +1,214 @@
+//! Arrow record batches of features, with a WKB geometry column, and a
+//! writer streaming them as Arrow IPC to a file or stdout.
+
+use super::wkb;
+use crate::crs::Crs;
+use crate::feature::ExtractedFeature;
+use crate::schema::{self, Column, Storage};
+use anyhow::Result;
+use arrow_array::builder::{
+    BinaryBuilder, BooleanBuilder, Float64Builder, Int64Builder, MapBuilder, StringBuilder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::StreamWriter as IpcStreamWriter;
+use arrow_schema::{Schema, SchemaRef};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// Rows per record batch (and thereby the granularity of memory use while writing)
+pub(super) const BATCH_SIZE: usize = 65_536;
+
+// A builder for one typed tag column
+enum ColumnBuilder {
+    Text(StringBuilder),
+    Int(Int64Builder),
+    Float(Float64Builder),
+    Bool(BooleanBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(storage: Storage) -> ColumnBuilder {
+        match storage {
+            Storage::Text => ColumnBuilder::Text(StringBuilder::new()),
+            Storage::Int => ColumnBuilder::Int(Int64Builder::new()),
+            Storage::Float => ColumnBuilder::Float(Float64Builder::new()),
+            Storage::Bool => ColumnBuilder::Bool(BooleanBuilder::new()),
+        }
+    }
+
+    // Values are of the column's type, parsed by it
+    fn append(&mut self, value: Option<schema::Value>) {
+        match (self, value) {
+            (ColumnBuilder::Text(b), Some(schema::Value::Text(text))) => b.append_value(text),
+            (ColumnBuilder::Int(b), Some(schema::Value::Int(int))) => b.append_value(int),
+            (ColumnBuilder::Float(b), Some(schema::Value::Float(float))) => b.append_value(float),
+            (ColumnBuilder::Bool(b), Some(schema::Value::Bool(v))) => b.append_value(v),
+            (ColumnBuilder::Text(b), _) => b.append_null(),
+            (ColumnBuilder::Int(b), _) => b.append_null(),
+            (ColumnBuilder::Float(b), _) => b.append_null(),
+            (ColumnBuilder::Bool(b), _) => b.append_null(),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Text(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float(b) => Arc::new(b.finish()),
+            ColumnBuilder::Bool(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+/// The `id`, `osm_type` and `osm_id` columns, the tags as a `tags` map
+/// column or one column per entry of `tag_columns`, and the WKB `geometry`
+pub(super) fn build_batch(features: &[ExtractedFeature], tag_columns: &[Column]) -> Result<RecordBatch> {
+    let mut ids = StringBuilder::new();
+    let mut osm_types = StringBuilder::new();
+    let mut osm_ids = Int64Builder::new();
+    let mut tags = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+    let mut tag_values: Vec<ColumnBuilder> = tag_columns.iter().map(|c| ColumnBuilder::new(c.storage())).collect();
+    let mut geometries = BinaryBuilder::new();
+
+    for feature in features {
+        ids.append_value(feature.unique_id());
+        osm_types.append_value(feature.osm_type().to_string());
+        osm_ids.append_value(feature.id());
+
+        if tag_columns.is_empty() {
+            let mut sorted_tags: Vec<_> = feature.tags().iter().collect();
+            sorted_tags.sort();
+            for (k, v) in sorted_tags {
+                tags.keys().append_value(k);
+                tags.values().append_value(v);
+            }
+            tags.append(true)?;
+        } else {
+            for (column, values) in tag_columns.iter().zip(&mut tag_values) {
+                values.append(column.value(feature.tags()));
+            }
+        }
+
+        geometries.append_value(wkb::to_wkb(feature.geometry()));
+    }
+
+    // Tag columns are null for features without a value; declaring that
+    // makes an empty batch have the schema of every other one
+    let mut columns: Vec<(&str, ArrayRef, bool)> = vec![
+        ("id", Arc::new(ids.finish()), false),
+        ("osm_type", Arc::new(osm_types.finish()), false),
+        ("osm_id", Arc::new(osm_ids.finish()), false),
+    ];
+    if tag_columns.is_empty() {
+        columns.push(("tags", Arc::new(tags.finish()), false));
+    } else {
+        for (column, mut values) in tag_columns.iter().zip(tag_values) {
+            columns.push((column.name.as_str(), values.finish(), true));
+        }
+    }
+    columns.push(("geometry", Arc::new(geometries.finish()), false));
+    Ok(RecordBatch::try_from_iter_with_nullable(columns)?)
+}
+
+// The batch schema with the geometry column marked as a GeoArrow WKB
+// column, which GeoPandas and GeoPolars read as geometries
+fn geoarrow_schema(tag_columns: &[Column], crs: Option<&Crs>) -> Result<SchemaRef> {
+    let schema = build_batch(&[], tag_columns)?.schema();
+    let crs = match crs {
+        None => Some("OGC:CRS84".to_string()),
+        Some(crs) => crs.epsg.map(|code| format!("EPSG:{}", code)),
+    };
+    let extension = match crs {
+        Some(crs) => json!({ "crs": crs, "crs_type": "authority_code" }),
+        None => json!({}),
+    };
+    let fields = schema.fields().iter().map(|field| {
+        let field = field.as_ref().clone();
+        if field.name() != "geometry" {
+            return field;
+        }
+        field.with_metadata(HashMap::from([
+            ("ARROW:extension:name".to_string(), "geoarrow.wkb".to_string()),
+            ("ARROW:extension:metadata".to_string(), extension.to_string()),
+        ]))
+    });
+    Ok(Arc::new(Schema::new(fields.collect::<Vec<_>>())))
+}
+
+/// Writes an Arrow IPC stream batch by batch while features are added, so
+/// it can be read as it is written, e.g. piped into `pyarrow.ipc.open_stream`
+/// or `polars.read_ipc_stream`
+pub struct StreamWriter {
+    writer: IpcStreamWriter<Box<dyn Write>>,
+    schema: SchemaRef,
+    columns: Vec<Column>,
+    pending: Vec<ExtractedFeature>,
+    count: usize,
+}
+
+impl StreamWriter {
+    /// Creates the stream at `path`, or on stdout for `-`, with the columns
+    /// of [`write`]
+    pub fn create(path: &Path, columns: &[Column], crs: Option<&Crs>) -> Result<StreamWriter> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(BufWriter::new(io::stdout().lock()))
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        let schema = geoarrow_schema(columns, crs)?;
+        Ok(StreamWriter {
+            writer: IpcStreamWriter::try_new(out, &schema)?,
+            schema,
+            columns: columns.to_vec(),
+            pending: Vec::new(),
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, feature: ExtractedFeature) -> Result<()> {
+        self.pending.push(feature);
+        if self.pending.len() == BATCH_SIZE {
+            let features = std::mem::take(&mut self.pending);
+            self.write_batch(&features)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, features: &[ExtractedFeature]) -> Result<()> {
+        let batch = build_batch(features, &self.columns)?;
+        self.writer.write(&RecordBatch::try_new(self.schema.clone(), batch.columns().to_vec())?)?;
+        self.count += features.len();
+        Ok(())
+    }
+
+    /// Writes the last batch and ends the stream, returning the number of
+    /// features written
+    pub fn finish(mut self) -> Result<usize> {
+        if !self.pending.is_empty() {
+            let features = std::mem::take(&mut self.pending);
+            self.write_batch(&features)?;
+        }
+        self.writer.finish()?;
+        Ok(self.count)
+    }
+}
+
+/// Writes all features as an Arrow IPC stream to `path`, or to stdout for
+/// `-`.
+///
+/// The columns are those of GeoParquet: `id`, `osm_type` and `osm_id`, the
+/// tags as a `tags` map column or one typed column per entry of `columns`,
+/// and the geometry as WKB in `geometry`, marked as a `geoarrow.wkb`
+/// extension column in the CRS of the features.
+pub fn write(path: &Path, features: &[ExtractedFeature], columns: &[Column], crs: Option<&Crs>) -> Result<()> {
+    let mut writer = StreamWriter::create(path, columns, crs)?;
+    for chunk in features.chunks(BATCH_SIZE) {
+        writer.write_batch(chunk)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
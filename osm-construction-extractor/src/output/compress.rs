@@ -0,0 +1,143 @@
+//! Compression of the text outputs (GeoJSON, GeoJSONSeq, CSV and TSV),
+//! applied while they are written, so the uncompressed file never has to
+//! fit on disk.
+
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A compression method and its level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, levels 0 to 9
+    Gzip(u32),
+    /// Zstandard, levels 1 to 22; it compresses about as well as gzip at
+    /// several times the speed
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Tells the compression from a file extension, `.gz` or `.zst`, at
+    /// its default level
+    pub fn from_path(path: &Path) -> Option<Compression> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "gz" | "gzip" => Some(Compression::Gzip(6)),
+            "zst" | "zstd" => Some(Compression::Zstd(3)),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip(_) => "gz",
+            Compression::Zstd(_) => "zst",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// Parses `gzip` or `zstd`, optionally with a level, e.g. `zstd:19`
+    fn from_str(s: &str) -> Result<Self> {
+        let (method, level) = match s.split_once(':') {
+            Some((method, level)) => {
+                let level: i32 = level.parse().with_context(|| format!("invalid compression level {:?}", level))?;
+                (method, Some(level))
+            }
+            None => (s, None),
+        };
+        match (method.to_ascii_lowercase().as_str(), level) {
+            ("gzip" | "gz", None) => Ok(Compression::Gzip(6)),
+            ("gzip" | "gz", Some(level @ 0..=9)) => Ok(Compression::Gzip(level as u32)),
+            ("zstd" | "zst", None) => Ok(Compression::Zstd(3)),
+            ("zstd" | "zst", Some(level @ 1..=22)) => Ok(Compression::Zstd(level)),
+            ("gzip" | "gz", Some(level)) => bail!("gzip level {} is out of range, expected 0 to 9", level),
+            ("zstd" | "zst", Some(level)) => bail!("zstd level {} is out of range, expected 1 to 22", level),
+            _ => bail!("unknown compression {:?}, expected gzip or zstd", method),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Gzip(level) => write!(f, "gzip:{}", level),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+/// A file being written, or stdout for `-`
+pub type Output = BufWriter<Box<dyn Write>>;
+
+/// Creates the file at `path`, or writes to stdout for `-`
+pub fn create(path: &Path) -> io::Result<Output> {
+    let out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(path)?)
+    };
+    Ok(BufWriter::new(out))
+}
+
+/// A file being written, compressed or not; [`finish`](CompressedFile::finish)
+/// writes what the compressor still holds and must be called to get a
+/// complete file
+pub enum CompressedFile {
+    Plain(Output),
+    Gzip(GzEncoder<Output>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, Output>),
+}
+
+impl CompressedFile {
+    /// Creates the file at `path`, or writes to stdout for `-`
+    pub fn create(path: &Path, compression: Option<Compression>) -> Result<CompressedFile> {
+        let out = create(path)?;
+        Ok(match compression {
+            None => CompressedFile::Plain(out),
+            Some(Compression::Gzip(level)) => CompressedFile::Gzip(GzEncoder::new(out, flate2::Compression::new(level))),
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd(level)) => CompressedFile::Zstd(zstd::Encoder::new(out, level)?),
+            #[cfg(not(feature = "zstd"))]
+            Some(Compression::Zstd(_)) => bail!("zstd compression requires building with the `zstd` feature"),
+        })
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let mut out = match self {
+            CompressedFile::Plain(out) => out,
+            CompressedFile::Gzip(gzip) => gzip.finish()?,
+            #[cfg(feature = "zstd")]
+            CompressedFile::Zstd(zstd) => zstd.finish()?,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for CompressedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedFile::Plain(out) => out.write(buf),
+            CompressedFile::Gzip(gzip) => gzip.write(buf),
+            #[cfg(feature = "zstd")]
+            CompressedFile::Zstd(zstd) => zstd.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedFile::Plain(out) => out.flush(),
+            CompressedFile::Gzip(gzip) => gzip.flush(),
+            #[cfg(feature = "zstd")]
+            CompressedFile::Zstd(zstd) => zstd.flush(),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! CSV and TSV writers with WKT geometries.
+
+use super::wkt;
+use crate::feature::ExtractedFeature;
+use crate::schema::Column;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Writes one row per feature with the columns `id`, `osm_type`, `osm_id`,
+/// the tags and a WKT `geometry`.
+///
+/// Without `columns` all tags go into a single JSON-encoded `tags` column;
+/// otherwise each column gets its own field, left empty for features
+/// without a value for it. Fields are quoted as needed, so with `\t` as delimiter
+/// the output is the TSV dialect pandas and spreadsheets read.
+pub fn write(path: &Path, features: &[ExtractedFeature], delimiter: u8, columns: &[Column]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_to(&mut out, features, delimiter, columns)
+}
+
+/// Like [`write`], into any writer, e.g. a compressed file
+pub fn write_to(out: &mut impl Write, features: &[ExtractedFeature], delimiter: u8, columns: &[Column]) -> Result<()> {
+    let delimiter = delimiter as char;
+    let mut line = String::new();
+
+    let mut header: Vec<&str> = vec!["id", "osm_type", "osm_id"];
+    if columns.is_empty() {
+        header.push("tags");
+    } else {
+        header.extend(columns.iter().map(|c| c.name.as_str()));
+    }
+    header.push("geometry");
+    for (i, name) in header.iter().enumerate() {
+        if i > 0 {
+            line.push(delimiter);
+        }
+        push_field(&mut line, name, delimiter);
+    }
+    line.push('\n');
+    out.write_all(line.as_bytes())?;
+
+    for feature in features {
+        line.clear();
+        push_field(&mut line, &feature.unique_id(), delimiter);
+        line.push(delimiter);
+        push_field(&mut line, &feature.osm_type().to_string(), delimiter);
+        line.push(delimiter);
+        push_field(&mut line, &feature.id().to_string(), delimiter);
+        if columns.is_empty() {
+            let tags: BTreeMap<_, _> = feature.tags().iter().collect();
+            line.push(delimiter);
+            push_field(&mut line, &serde_json::to_string(&tags)?, delimiter);
+        } else {
+            for column in columns {
+                line.push(delimiter);
+                if let Some(value) = column.value(feature.tags()) {
+                    push_field(&mut line, &value.to_string(), delimiter);
+                }
+            }
+        }
+        line.push(delimiter);
+        push_field(&mut line, &wkt::to_wkt(feature.geometry()), delimiter);
+        line.push('\n');
+        out.write_all(line.as_bytes())?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+// Quotes a field (RFC 4180 style) if it contains the delimiter, a quote or
+// a line break
+pub(crate) fn push_field(line: &mut String, value: &str, delimiter: char) {
+    if value.contains([delimiter, '"', '\n', '\r']) {
+        line.push('"');
+        line.push_str(&value.replace('"', "\"\""));
+        line.push('"');
+    } else {
+        line.push_str(value);
+    }
+}
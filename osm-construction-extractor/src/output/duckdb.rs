@@ -0,0 +1,115 @@
+//! DuckDB writer, loading the features into a table with a GEOMETRY column
+//! of the spatial extension.
+
+use super::wkb;
+use crate::crs::Crs;
+use crate::feature::ExtractedFeature;
+use crate::schema::{Column, Storage, Value};
+use anyhow::{Context, Result};
+use duckdb::types::Value as DuckValue;
+use duckdb::{params_from_iter, Connection};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Writes all features into the table `table` of the DuckDB database at
+/// `path`, creating the database if needed and replacing the table if it
+/// exists; other tables are left alone.
+///
+/// The feature table has the `id`, `osm_type` and `osm_id` columns, the
+/// tags as a JSON `tags` column or one column per entry of `columns`, and
+/// the geometry, in `geometry`, whose comment names its CRS: `crs`, or
+/// WGS84 if there is none. The spatial extension is installed and loaded
+/// for it if needed, which downloads it once when it is missing.
+pub fn write(
+    path: &Path,
+    features: &[ExtractedFeature],
+    table: &str,
+    columns: &[Column],
+    crs: Option<&Crs>,
+) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch("INSTALL spatial; LOAD spatial;")
+        .context("cannot load the DuckDB spatial extension for the GEOMETRY column")?;
+    let tx = conn.transaction()?;
+
+    // Rows are appended to a staging table, which the spatial extension
+    // then turns into the feature table in one statement
+    let mut definitions = vec![
+        "id VARCHAR NOT NULL".to_string(),
+        "osm_type VARCHAR NOT NULL".to_string(),
+        "osm_id BIGINT NOT NULL".to_string(),
+    ];
+    if columns.is_empty() {
+        definitions.push("tags VARCHAR".to_string());
+    } else {
+        definitions.extend(columns.iter().map(|c| format!("{} {}", quote_ident(&c.name), sql_type(c.storage()))));
+    }
+    definitions.push("wkb BLOB".to_string());
+    tx.execute_batch(&format!(
+        "DROP TABLE IF EXISTS staging_features; CREATE TEMP TABLE staging_features ({});",
+        definitions.join(", ")
+    ))?;
+    {
+        let mut appender = tx.appender("staging_features")?;
+        for feature in features {
+            let mut values = vec![
+                DuckValue::Text(feature.unique_id()),
+                DuckValue::Text(feature.osm_type().to_string()),
+                DuckValue::BigInt(feature.id()),
+            ];
+            if columns.is_empty() {
+                let tags: BTreeMap<_, _> = feature.tags().iter().collect();
+                values.push(DuckValue::Text(serde_json::to_string(&tags)?));
+            } else {
+                for column in columns {
+                    values.push(sql_value(column.value(feature.tags())));
+                }
+            }
+            values.push(DuckValue::Blob(wkb::to_wkb(feature.geometry())));
+            appender.append_row(params_from_iter(values))?;
+        }
+        appender.flush()?;
+    }
+
+    let value_columns = if columns.is_empty() {
+        "CAST(tags AS JSON) AS tags".to_string()
+    } else {
+        columns.iter().map(|c| quote_ident(&c.name)).collect::<Vec<_>>().join(", ")
+    };
+    let table = quote_ident(table);
+    // The GEOMETRY type holds no CRS, so readers find it in the comment
+    let crs = crs.map_or_else(|| "EPSG:4326".to_string(), |crs| crs.to_string());
+    let comment = format!("'{}'", crs.replace('\'', "''"));
+    tx.execute_batch(&format!(
+        "DROP TABLE IF EXISTS {table};
+        CREATE TABLE {table} AS
+            SELECT id, osm_type, osm_id, {value_columns}, ST_GeomFromWKB(wkb) AS geometry FROM staging_features;
+        COMMENT ON COLUMN {table}.geometry IS {comment};
+        DROP TABLE staging_features;"
+    ))?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(storage: Storage) -> &'static str {
+    match storage {
+        Storage::Text => "VARCHAR",
+        Storage::Int => "BIGINT",
+        Storage::Float => "DOUBLE",
+        Storage::Bool => "BOOLEAN",
+    }
+}
+
+fn sql_value(value: Option<Value>) -> DuckValue {
+    match value {
+        None => DuckValue::Null,
+        Some(Value::Text(text)) => DuckValue::Text(text),
+        Some(Value::Int(int)) => DuckValue::BigInt(int),
+        Some(Value::Float(float)) => DuckValue::Double(float),
+        Some(Value::Bool(b)) => DuckValue::Boolean(b),
+    }
+}
@@ -0,0 +1,94 @@
+//! FlatGeobuf writer with a packed Hilbert R-tree index.
+
+use super::wkb;
+use crate::crs::Crs;
+use crate::feature::ExtractedFeature;
+use crate::intern::Tag;
+use anyhow::Result;
+use flatgeobuf::{ColumnType, FgbCrs, FgbWriter, FgbWriterOptions, GeometryType};
+use geozero::{ColumnValue, PropertyProcessor};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+fn geometry_type(name: &str) -> GeometryType {
+    match name {
+        "Point" => GeometryType::Point,
+        "LineString" => GeometryType::LineString,
+        "Polygon" => GeometryType::Polygon,
+        "MultiPoint" => GeometryType::MultiPoint,
+        "MultiLineString" => GeometryType::MultiLineString,
+        "MultiPolygon" => GeometryType::MultiPolygon,
+        "GeometryCollection" => GeometryType::GeometryCollection,
+        _ => GeometryType::Unknown,
+    }
+}
+
+/// Writes all features to a FlatGeobuf file.
+///
+/// FlatGeobuf has a fixed schema, so every tag key occurring in the
+/// extraction becomes a nullable string column next to `id`, `osm_type` and
+/// `osm_id`. Features are Hilbert-sorted and indexed, which lets web maps
+/// fetch only the features in view via HTTP range requests.
+pub fn write(path: &Path, features: &[ExtractedFeature], crs: Option<&Crs>) -> Result<()> {
+    let types: BTreeSet<&str> = features.iter().map(|f| wkb::type_name(f.geometry())).collect();
+    let geometry_type = match types.len() {
+        1 => types.first().map_or(GeometryType::Unknown, |t| geometry_type(t)),
+        _ => GeometryType::Unknown,
+    };
+
+    let keys: BTreeSet<&str> = features.iter().flat_map(|f| f.tags().keys().map(Tag::as_str)).collect();
+    // Property indices 0..=2 are the fixed columns
+    let key_index: BTreeMap<&str, usize> = keys.iter().enumerate().map(|(i, k)| (*k, i + 3)).collect();
+
+    let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("features");
+    let mut fgb = FgbWriter::create_with_options(
+        name,
+        geometry_type,
+        FgbWriterOptions {
+            write_index: true,
+            // Mixed files keep one geometry type per feature
+            detect_type: false,
+            promote_to_multi: false,
+            crs: match crs {
+                None => FgbCrs { code: 4326, ..Default::default() },
+                Some(crs) => FgbCrs {
+                    code: crs.epsg.map_or(0, |code| code as i32),
+                    description: Some(&crs.definition),
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        },
+    )?;
+
+    fgb.add_column("id", ColumnType::String, |_, col| col.nullable = false);
+    fgb.add_column("osm_type", ColumnType::String, |_, col| col.nullable = false);
+    fgb.add_column("osm_id", ColumnType::Long, |_, col| col.nullable = false);
+    for key in &keys {
+        fgb.add_column(key, ColumnType::String, |_, col| col.nullable = true);
+    }
+
+    for feature in features {
+        let mut result = Ok(false);
+        fgb.add_feature_geom(feature.geometry().clone(), |feat| {
+            result = feat
+                .property(0, "id", &ColumnValue::String(&feature.unique_id()))
+                .and_then(|_| feat.property(1, "osm_type", &ColumnValue::String(&feature.osm_type().to_string())))
+                .and_then(|_| feat.property(2, "osm_id", &ColumnValue::Long(feature.id())));
+            let mut tags: Vec<_> = feature.tags().iter().collect();
+            // Properties must be written in column order
+            tags.sort();
+            for (k, v) in tags {
+                if result.is_ok() {
+                    result = feat.property(key_index[k.as_str()], k, &ColumnValue::String(v));
+                }
+            }
+        })?;
+        result?;
+    }
+
+    fgb.write(BufWriter::new(File::create(path)?))?;
+    Ok(())
+}
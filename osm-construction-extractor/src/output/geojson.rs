@@ -0,0 +1,213 @@
+//! GeoJSON FeatureCollection and newline-delimited GeoJSONSeq writers.
+
+use crate::building::{HEIGHT_TAG, LEVELS_TAG, MIN_HEIGHT_TAG};
+use crate::crs::Crs;
+use crate::feature::ExtractedFeature;
+use crate::measure::{AREA_TAG, BBOX_TAG, LENGTH_TAG};
+use crate::normalize::NORMALIZED_TAG_PREFIX;
+use crate::output::compress::{self, CompressedFile, Compression};
+use anyhow::Result;
+use geo::{BoundingRect, Coord, Geometry, LineString, Polygon, Rect};
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::path::Path;
+
+fn position(c: &Coord) -> Value {
+    json!([c.x, c.y])
+}
+
+fn positions(line: &LineString) -> Value {
+    Value::Array(line.coords().map(position).collect())
+}
+
+fn rings(polygon: &Polygon) -> Value {
+    let mut rings = vec![positions(polygon.exterior())];
+    rings.extend(polygon.interiors().iter().map(positions));
+    Value::Array(rings)
+}
+
+/// Converts a `geo` geometry into a GeoJSON geometry object
+pub fn geometry(geom: &Geometry) -> Value {
+    match geom {
+        Geometry::Point(p) => json!({ "type": "Point", "coordinates": position(&p.0) }),
+        Geometry::Line(l) => json!({
+            "type": "LineString",
+            "coordinates": [position(&l.start), position(&l.end)],
+        }),
+        Geometry::LineString(ls) => json!({ "type": "LineString", "coordinates": positions(ls) }),
+        Geometry::Polygon(p) => json!({ "type": "Polygon", "coordinates": rings(p) }),
+        Geometry::MultiPoint(mp) => json!({
+            "type": "MultiPoint",
+            "coordinates": mp.iter().map(|p| position(&p.0)).collect::<Vec<_>>(),
+        }),
+        Geometry::MultiLineString(mls) => json!({
+            "type": "MultiLineString",
+            "coordinates": mls.iter().map(positions).collect::<Vec<_>>(),
+        }),
+        Geometry::MultiPolygon(mp) => json!({
+            "type": "MultiPolygon",
+            "coordinates": mp.iter().map(rings).collect::<Vec<_>>(),
+        }),
+        Geometry::GeometryCollection(gc) => json!({
+            "type": "GeometryCollection",
+            "geometries": gc.iter().map(geometry).collect::<Vec<_>>(),
+        }),
+        Geometry::Rect(r) => geometry_from_polygon(r.to_polygon()),
+        Geometry::Triangle(t) => geometry_from_polygon(t.to_polygon()),
+    }
+}
+
+fn geometry_from_polygon(polygon: Polygon) -> Value {
+    geometry(&Geometry::Polygon(polygon))
+}
+
+// Computed properties written as JSON numbers rather than strings, as are
+// the normalized values
+const NUMERIC_TAGS: [&str; 5] = [LENGTH_TAG, AREA_TAG, HEIGHT_TAG, MIN_HEIGHT_TAG, LEVELS_TAG];
+
+fn bbox(rect: &Rect) -> Value {
+    json!([rect.min().x, rect.min().y, rect.max().x, rect.max().y])
+}
+
+/// Builds a single GeoJSON Feature with the OSM tags as properties, and the
+/// computed bounding box as its `bbox` member
+pub fn feature(feature: &ExtractedFeature) -> Value {
+    let properties: Map<String, Value> = feature
+        .tags()
+        .iter()
+        .filter(|(k, _)| k.as_str() != BBOX_TAG)
+        .map(|(k, v)| (k.to_string(), property(k, v)))
+        .collect();
+
+    let mut value = json!({
+        "type": "Feature",
+        "id": feature.unique_id(),
+        "geometry": geometry(feature.geometry()),
+        "properties": properties,
+    });
+    if feature.tags().contains_key(BBOX_TAG) {
+        if let Some(rect) = feature.geometry().bounding_rect() {
+            value["bbox"] = bbox(&rect);
+        }
+    }
+    value
+}
+
+fn property(key: &str, value: &str) -> Value {
+    if NUMERIC_TAGS.contains(&key) || key.starts_with(NORMALIZED_TAG_PREFIX) {
+        if let Ok(integer) = value.parse::<i64>() {
+            return Value::from(integer);
+        }
+        if let Some(number) = value.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+            return Value::Number(number);
+        }
+    }
+    Value::String(value.to_string())
+}
+
+/// Writes all features as a GeoJSON FeatureCollection, on stdout for `-`.
+///
+/// Features are serialized one by one so the whole collection never has to
+/// exist as a single `serde_json::Value` in memory.
+pub fn write_feature_collection(path: &Path, features: &[ExtractedFeature]) -> Result<()> {
+    write_feature_collection_in(path, features, None)
+}
+
+/// Like [`write_feature_collection`], for features reprojected into `crs`.
+///
+/// The collection's `bbox` member holds the extent of all features, so
+/// viewers can zoom to it before reading them.
+///
+/// RFC 7946 only allows WGS84, so this adds the pre-standard `crs` member
+/// that GDAL and QGIS still read.
+pub fn write_feature_collection_in(path: &Path, features: &[ExtractedFeature], crs: Option<&Crs>) -> Result<()> {
+    let mut out = compress::create(path)?;
+    write_feature_collection_to(&mut out, features, crs)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Like [`write_feature_collection_in`], into any writer, e.g. a `Vec<u8>`
+/// where there are no files to write
+pub fn write_feature_collection_to(out: &mut impl Write, features: &[ExtractedFeature], crs: Option<&Crs>) -> Result<()> {
+    out.write_all(b"{\"type\":\"FeatureCollection\",")?;
+    if let Some(crs) = crs {
+        let name = match crs.epsg {
+            Some(code) => format!("urn:ogc:def:crs:EPSG::{}", code),
+            None => crs.definition.clone(),
+        };
+        let member = json!({ "type": "name", "properties": { "name": name } });
+        write!(out, "\"crs\":{},", member)?;
+    }
+    out.write_all(b"\"features\":[\n")?;
+    let mut extent: Option<Rect> = None;
+    for (i, f) in features.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut *out, &feature(f))?;
+        if let Some(rect) = f.geometry().bounding_rect() {
+            extent = Some(match extent {
+                Some(e) => Rect::new(
+                    (e.min().x.min(rect.min().x), e.min().y.min(rect.min().y)),
+                    (e.max().x.max(rect.max().x), e.max().y.max(rect.max().y)),
+                ),
+                None => rect,
+            });
+        }
+    }
+    // Members may come in any order, and only here the extent is known
+    match extent {
+        Some(extent) => write!(out, "\n],\"bbox\":{}}}\n", bbox(&extent))?,
+        None => out.write_all(b"\n]}\n")?,
+    }
+    Ok(())
+}
+
+/// Writes newline-delimited GeoJSON (GeoJSONSeq): one Feature per line,
+/// written as it arrives, so memory use does not grow with the output.
+pub struct SeqWriter {
+    out: CompressedFile,
+    count: usize,
+}
+
+impl SeqWriter {
+    /// Creates the file at `path`, or writes to stdout for `-`
+    pub fn create(path: &Path) -> Result<SeqWriter> {
+        SeqWriter::create_compressed(path, None)
+    }
+
+    /// Like [`create`](SeqWriter::create), compressing the lines as they
+    /// are written
+    pub fn create_compressed(path: &Path, compression: Option<Compression>) -> Result<SeqWriter> {
+        Ok(SeqWriter { out: CompressedFile::create(path, compression)?, count: 0 })
+    }
+
+    pub fn write(&mut self, f: &ExtractedFeature) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &feature(f))?;
+        self.out.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flushes the file, returning the number of features written
+    pub fn finish(self) -> Result<usize> {
+        self.out.finish()?;
+        Ok(self.count)
+    }
+}
+
+/// Writes all features as GeoJSONSeq, see [`SeqWriter`]
+pub fn write_seq(path: &Path, features: &[ExtractedFeature]) -> Result<()> {
+    write_seq_compressed(path, features, None)
+}
+
+/// Like [`write_seq`], compressing the output
+pub fn write_seq_compressed(path: &Path, features: &[ExtractedFeature], compression: Option<Compression>) -> Result<()> {
+    let mut writer = SeqWriter::create_compressed(path, compression)?;
+    for f in features {
+        writer.write(f)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! GeoParquet 1.1 writer with a WKB geometry column and a map column of tags
+//! or one typed column per selected tag.
+
+use super::arrow::{build_batch, BATCH_SIZE};
+use super::wkb;
+use crate::crs::Crs;
+use crate::feature::ExtractedFeature;
+use crate::schema::Column;
+use anyhow::Result;
+use geo::{BoundingRect, Rect};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::Path;
+
+// The `geo` file metadata describing the geometry column
+fn geo_metadata(geometry_types: &BTreeSet<&str>, bbox: Option<Rect>, crs: Option<&Crs>) -> String {
+    let mut column = json!({
+        "encoding": "WKB",
+        "geometry_types": geometry_types,
+    });
+    if let Some(bbox) = bbox {
+        column["bbox"] = json!([bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y]);
+    }
+    if let Some(crs) = crs {
+        // A PROJJSON object identified by its EPSG code; without one the CRS
+        // is recorded as unknown
+        column["crs"] = match crs.epsg {
+            Some(code) => json!({ "id": { "authority": "EPSG", "code": code } }),
+            None => Value::Null,
+        };
+    }
+    json!({
+        "version": "1.1.0",
+        "primary_column": "geometry",
+        "columns": { "geometry": column },
+    })
+    .to_string()
+}
+
+/// Writes all features to a GeoParquet file.
+///
+/// Coordinates are stored as WGS84 longitude/latitude, which is the
+/// GeoParquet default CRS (OGC:CRS84), so no explicit `crs` is written
+/// unless the features were reprojected into `crs`. Without `columns` all
+/// tags go into a `tags` map column; otherwise each column is written with
+/// its type, null for features without a value for it.
+pub fn write(path: &Path, features: &[ExtractedFeature], columns: &[Column], crs: Option<&Crs>) -> Result<()> {
+    let schema = build_batch(&[], columns)?.schema();
+    let props = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, Some(props))?;
+
+    let mut geometry_types = BTreeSet::new();
+    let mut bbox: Option<Rect> = None;
+    for chunk in features.chunks(BATCH_SIZE) {
+        writer.write(&build_batch(chunk, columns)?)?;
+        for feature in chunk {
+            geometry_types.insert(wkb::type_name(feature.geometry()));
+            if let Some(rect) = feature.geometry().bounding_rect() {
+                bbox = Some(match bbox {
+                    Some(b) => merge_rects(b, rect),
+                    None => rect,
+                });
+            }
+        }
+    }
+
+    writer.append_key_value_metadata(KeyValue::new("geo".to_string(), geo_metadata(&geometry_types, bbox, crs)));
+    writer.close()?;
+    Ok(())
+}
+
+fn merge_rects(a: Rect, b: Rect) -> Rect {
+    Rect::new(
+        (a.min().x.min(b.min().x), a.min().y.min(b.min().y)),
+        (a.max().x.max(b.max().x), a.max().y.max(b.max().y)),
+    )
+}
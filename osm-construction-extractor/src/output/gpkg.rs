@@ -0,0 +1,284 @@
+//! GeoPackage writer with an R-tree spatial index.
+
+use super::wkb;
+use crate::crs::{Crs, WEB_MERCATOR_WKT, WGS84_WKT};
+use crate::feature::ExtractedFeature;
+use crate::schema::{Column, Storage, Value};
+use anyhow::Result;
+use geo::{BoundingRect, Rect};
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+// 'GPKG' in ASCII and version 1.3.0
+const APPLICATION_ID: i32 = 0x4750_4B47;
+const USER_VERSION: i32 = 10300;
+
+const SCHEMA: &str = "
+    CREATE TABLE gpkg_spatial_ref_sys (
+        srs_name TEXT NOT NULL,
+        srs_id INTEGER PRIMARY KEY,
+        organization TEXT NOT NULL,
+        organization_coordsys_id INTEGER NOT NULL,
+        definition TEXT NOT NULL,
+        description TEXT
+    );
+    CREATE TABLE gpkg_contents (
+        table_name TEXT NOT NULL PRIMARY KEY,
+        data_type TEXT NOT NULL,
+        identifier TEXT UNIQUE,
+        description TEXT DEFAULT '',
+        last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+        min_x DOUBLE, min_y DOUBLE, max_x DOUBLE, max_y DOUBLE,
+        srs_id INTEGER,
+        CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+    );
+    CREATE TABLE gpkg_geometry_columns (
+        table_name TEXT NOT NULL,
+        column_name TEXT NOT NULL,
+        geometry_type_name TEXT NOT NULL,
+        srs_id INTEGER NOT NULL,
+        z TINYINT NOT NULL,
+        m TINYINT NOT NULL,
+        CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+        CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+        CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+    );
+    CREATE TABLE gpkg_extensions (
+        table_name TEXT,
+        column_name TEXT,
+        extension_name TEXT NOT NULL,
+        definition TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        CONSTRAINT ge_tce UNIQUE (table_name, column_name, extension_name)
+    );
+    INSERT INTO gpkg_spatial_ref_sys VALUES
+        ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', 'undefined cartesian coordinate reference system'),
+        ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', 'undefined geographic coordinate reference system');
+";
+
+/// Writes all features into a new GeoPackage with a single feature table
+/// named `layer`.
+///
+/// Tags are stored in a JSON `tags` column, or one column per entry of
+/// `columns`, typed as INTEGER, REAL, BOOLEAN or TEXT. The geometry column gets the standard `gpkg_rtree_index`
+/// extension, including the triggers keeping it current when the table is
+/// edited later, so GIS tools can query the layer by extent right away.
+pub fn write(
+    path: &Path,
+    features: &[ExtractedFeature],
+    layer: &str,
+    columns: &[Column],
+    crs: Option<&Crs>,
+) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let mut conn = Connection::open(path)?;
+    conn.pragma_update(None, "application_id", APPLICATION_ID)?;
+    conn.pragma_update(None, "user_version", USER_VERSION)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+
+    let srs_id = register_srs(&tx, crs)?;
+    let table = quote_ident(layer);
+    let mut definitions = vec![
+        "fid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL".to_string(),
+        "geom GEOMETRY".to_string(),
+        "id TEXT NOT NULL".to_string(),
+        "osm_type TEXT NOT NULL".to_string(),
+        "osm_id INTEGER NOT NULL".to_string(),
+    ];
+    if columns.is_empty() {
+        definitions.push("tags TEXT".to_string());
+    } else {
+        definitions.extend(columns.iter().map(|c| format!("{} {}", quote_ident(&c.name), sql_type(c.storage()))));
+    }
+    tx.execute_batch(&format!("CREATE TABLE {} ({});", table, definitions.join(", ")))?;
+
+    let value_columns = if columns.is_empty() {
+        "tags".to_string()
+    } else {
+        columns.iter().map(|c| quote_ident(&c.name)).collect::<Vec<_>>().join(", ")
+    };
+    let placeholders = vec!["?"; 4 + columns.len().max(1)].join(", ");
+    let mut geometry_types = BTreeSet::new();
+    let mut extent: Option<Rect> = None;
+    let rtree_table = quote_ident(&format!("rtree_{}_geom", layer));
+    tx.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE {} USING rtree(id, minx, maxx, miny, maxy);",
+        rtree_table
+    ))?;
+    {
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO {} (geom, id, osm_type, osm_id, {}) VALUES ({})",
+            table, value_columns, placeholders
+        ))?;
+        let mut rtree = tx.prepare(&format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?)", rtree_table))?;
+
+        for feature in features {
+            let bounds = feature.geometry().bounding_rect();
+            let mut values: Vec<rusqlite::types::Value> = vec![
+                geometry_blob(feature, srs_id, bounds).into(),
+                feature.unique_id().into(),
+                feature.osm_type().to_string().into(),
+                feature.id().into(),
+            ];
+            if columns.is_empty() {
+                let tags: BTreeMap<_, _> = feature.tags().iter().collect();
+                values.push(serde_json::to_string(&tags)?.into());
+            } else {
+                for column in columns {
+                    values.push(sql_value(column.value(feature.tags())));
+                }
+            }
+            insert.execute(rusqlite::params_from_iter(values))?;
+
+            geometry_types.insert(wkb::type_name(feature.geometry()));
+            if let Some(b) = bounds {
+                rtree.execute(params![tx.last_insert_rowid(), b.min().x, b.max().x, b.min().y, b.max().y])?;
+                extent = Some(match extent {
+                    Some(e) => Rect::new(
+                        (e.min().x.min(b.min().x), e.min().y.min(b.min().y)),
+                        (e.max().x.max(b.max().x), e.max().y.max(b.max().y)),
+                    ),
+                    None => b,
+                });
+            }
+        }
+    }
+
+    // The triggers call ST_* functions provided by GIS tools, so they may
+    // only exist once the index has been filled by hand
+    create_rtree_triggers(&tx, layer)?;
+
+    let geometry_type = match geometry_types.len() {
+        1 => geometry_types.first().map_or("GEOMETRY".to_string(), |t| t.to_uppercase()),
+        _ => "GEOMETRY".to_string(),
+    };
+    tx.execute(
+        "INSERT INTO gpkg_contents (table_name, data_type, identifier, description, min_x, min_y, max_x, max_y, srs_id)
+         VALUES (?1, 'features', ?1, 'OSM features extracted by osm-construction-extractor', ?2, ?3, ?4, ?5, ?6)",
+        params![
+            layer,
+            extent.map(|e| e.min().x),
+            extent.map(|e| e.min().y),
+            extent.map(|e| e.max().x),
+            extent.map(|e| e.max().y),
+            srs_id
+        ],
+    )?;
+    tx.execute(
+        "INSERT INTO gpkg_geometry_columns VALUES (?1, 'geom', ?2, ?3, 0, 0)",
+        params![layer, geometry_type, srs_id],
+    )?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// The id of the features' CRS in gpkg_spatial_ref_sys, adding it if it is
+// not one of the rows every GeoPackage has
+fn register_srs(conn: &Connection, crs: Option<&Crs>) -> Result<i32> {
+    conn.execute(
+        "INSERT INTO gpkg_spatial_ref_sys VALUES ('WGS 84 geodetic', 4326, 'EPSG', 4326, ?1, NULL)",
+        params![WGS84_WKT],
+    )?;
+    let (srs_id, name, definition) = match crs.map(|c| (c.epsg, c)) {
+        None | Some((Some(4326), _)) => return Ok(4326),
+        Some((None, _)) => return Ok(-1),
+        Some((Some(3857), _)) => (3857, "WGS 84 / Pseudo-Mercator", WEB_MERCATOR_WKT.to_string()),
+        Some((Some(code), crs)) => (code as i32, "Reprojected", format!("undefined ({})", crs.definition)),
+    };
+    conn.execute(
+        "INSERT INTO gpkg_spatial_ref_sys VALUES (?1, ?2, 'EPSG', ?2, ?3, NULL)",
+        params![name, srs_id, definition],
+    )?;
+    Ok(srs_id)
+}
+
+// The triggers of the standard R-tree extension for the `geom` column of
+// `layer`, keeping the index in sync with later edits
+fn create_rtree_triggers(conn: &Connection, layer: &str) -> Result<()> {
+    let t = quote_ident(layer);
+    let r = quote_ident(&format!("rtree_{}_geom", layer));
+    let trigger = |suffix: &str| quote_ident(&format!("rtree_{}_geom_{}", layer, suffix));
+    let bounds = "NEW.fid, ST_MinX(NEW.geom), ST_MaxX(NEW.geom), ST_MinY(NEW.geom), ST_MaxY(NEW.geom)";
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER {insert} AFTER INSERT ON {t}
+            WHEN (NEW.geom NOT NULL AND NOT ST_IsEmpty(NEW.geom))
+            BEGIN INSERT OR REPLACE INTO {r} VALUES ({bounds}); END;
+        CREATE TRIGGER {update1} AFTER UPDATE OF geom ON {t}
+            WHEN OLD.fid = NEW.fid AND (NEW.geom NOTNULL AND NOT ST_IsEmpty(NEW.geom))
+            BEGIN INSERT OR REPLACE INTO {r} VALUES ({bounds}); END;
+        CREATE TRIGGER {update2} AFTER UPDATE OF geom ON {t}
+            WHEN OLD.fid = NEW.fid AND (NEW.geom IS NULL OR ST_IsEmpty(NEW.geom))
+            BEGIN DELETE FROM {r} WHERE id = OLD.fid; END;
+        CREATE TRIGGER {update3} AFTER UPDATE ON {t}
+            WHEN OLD.fid != NEW.fid AND (NEW.geom NOTNULL AND NOT ST_IsEmpty(NEW.geom))
+            BEGIN DELETE FROM {r} WHERE id = OLD.fid; INSERT OR REPLACE INTO {r} VALUES ({bounds}); END;
+        CREATE TRIGGER {update4} AFTER UPDATE ON {t}
+            WHEN OLD.fid != NEW.fid AND (NEW.geom IS NULL OR ST_IsEmpty(NEW.geom))
+            BEGIN DELETE FROM {r} WHERE id IN (OLD.fid, NEW.fid); END;
+        CREATE TRIGGER {delete} AFTER DELETE ON {t}
+            WHEN OLD.geom NOT NULL
+            BEGIN DELETE FROM {r} WHERE id = OLD.fid; END;",
+        insert = trigger("insert"),
+        update1 = trigger("update1"),
+        update2 = trigger("update2"),
+        update3 = trigger("update3"),
+        update4 = trigger("update4"),
+        delete = trigger("delete"),
+    ))?;
+    conn.execute(
+        "INSERT INTO gpkg_extensions VALUES (?1, 'geom', 'gpkg_rtree_index', 'http://www.geopackage.org/spec120/#extension_rtree', 'write-only')",
+        params![layer],
+    )?;
+    Ok(())
+}
+
+// A GeoPackage geometry blob: header with srs id and envelope, then WKB
+fn geometry_blob(feature: &ExtractedFeature, srs_id: i32, bounds: Option<Rect>) -> Vec<u8> {
+    let mut blob = b"GP\x00".to_vec();
+    match bounds {
+        Some(b) => {
+            // Little endian, [minx, maxx, miny, maxy] envelope
+            blob.push(0b0000_0011);
+            blob.extend_from_slice(&srs_id.to_le_bytes());
+            for v in [b.min().x, b.max().x, b.min().y, b.max().y] {
+                blob.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        None => {
+            // Little endian, no envelope, empty geometry
+            blob.push(0b0001_0001);
+            blob.extend_from_slice(&srs_id.to_le_bytes());
+        }
+    }
+    wkb::write_geometry(&mut blob, feature.geometry());
+    blob
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(storage: Storage) -> &'static str {
+    match storage {
+        Storage::Text => "TEXT",
+        Storage::Int => "INTEGER",
+        Storage::Float => "REAL",
+        Storage::Bool => "BOOLEAN",
+    }
+}
+
+fn sql_value(value: Option<Value>) -> rusqlite::types::Value {
+    match value {
+        None => rusqlite::types::Value::Null,
+        Some(Value::Text(text)) => text.into(),
+        Some(Value::Int(int)) => int.into(),
+        Some(Value::Float(float)) => float.into(),
+        Some(Value::Bool(b)) => b.into(),
+    }
+}
@@ -0,0 +1,46 @@
+//! Lists of object ids, such as `w123`, written by `--ids-only`: one per
+//! line, or as a JSON array for files ending in `.json`.
+
+use anyhow::Result;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes ids as they are found, to a file or to stdout for `-`
+pub struct IdWriter {
+    out: Box<dyn Write>,
+    json: bool,
+    count: usize,
+}
+
+impl IdWriter {
+    pub fn create(path: &Path) -> Result<IdWriter> {
+        let out: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(BufWriter::new(io::stdout().lock()))
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        let json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        Ok(IdWriter { out, json, count: 0 })
+    }
+
+    pub fn write(&mut self, id: &str) -> Result<()> {
+        if self.json {
+            self.out.write_all(if self.count == 0 { b"[" } else { b"," })?;
+            serde_json::to_writer(&mut self.out, id)?;
+        } else {
+            writeln!(self.out, "{}", id)?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flushes the output, returning the number of ids written
+    pub fn finish(mut self) -> Result<usize> {
+        if self.json {
+            self.out.write_all(if self.count == 0 { b"[]\n" } else { b"]\n" })?;
+        }
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
@@ -0,0 +1,251 @@
+//! Writers for the supported output formats.
+
+use crate::crs::Crs;
+use crate::output::compress::{CompressedFile, Compression};
+use crate::feature::ExtractedFeature;
+use crate::schema::{Column, TagSchema};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod compress;
+pub mod csv;
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
+#[cfg(feature = "flatgeobuf")]
+pub mod flatgeobuf;
+pub mod geojson;
+#[cfg(feature = "gpkg")]
+pub mod gpkg;
+pub mod ids;
+#[cfg(feature = "geoparquet")]
+pub mod geoparquet;
+pub mod pbf;
+#[cfg(feature = "postgis")]
+pub mod postgis;
+pub mod shapefile;
+#[cfg(feature = "spatialite")]
+pub mod spatialite;
+pub mod wkb;
+pub mod wkt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A single GeoJSON FeatureCollection
+    Geojson,
+    /// Newline-delimited GeoJSON, one Feature per line, written while the
+    /// input is still being read
+    Geojsonseq,
+    /// GeoParquet with a WKB geometry column and a map column of tags
+    Geoparquet,
+    /// An Arrow IPC stream with the columns of GeoParquet, written while the
+    /// input is still being read; `-o -` writes it to stdout
+    Arrow,
+    /// FlatGeobuf with a packed Hilbert R-tree index
+    Fgb,
+    /// GeoPackage with an R-tree spatial index
+    Gpkg,
+    /// Comma-separated values with a WKT geometry column
+    Csv,
+    /// Tab-separated values with a WKT geometry column
+    Tsv,
+    /// ESRI Shapefile, one per geometry type if the features mix them
+    Shp,
+    /// OSM PBF with the matched objects and everything they reference, as
+    /// they were read: without geometry options such as --simplify applied
+    Pbf,
+    /// SpatiaLite, an SQLite database with a spatial index that GIS tools
+    /// open without a database server
+    Spatialite,
+    /// A DuckDB database table with a GEOMETRY column of the spatial
+    /// extension
+    Duckdb,
+    /// A local feature store with an R-tree index, for the `query`
+    /// subcommand
+    Index,
+}
+
+impl OutputFormat {
+    /// Guesses the format from a file extension, e.g. `out.parquet`; for a
+    /// compressed file, such as `out.geojson.gz`, from the one before
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        let path = match Compression::from_path(path) {
+            Some(_) => Path::new(path.file_stem()?),
+            None => path,
+        };
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "geojson" | "json" => Some(OutputFormat::Geojson),
+            "geojsonl" | "geojsonseq" | "ndjson" | "jsonl" => Some(OutputFormat::Geojsonseq),
+            "parquet" | "geoparquet" => Some(OutputFormat::Geoparquet),
+            "arrows" | "arrow" => Some(OutputFormat::Arrow),
+            "fgb" => Some(OutputFormat::Fgb),
+            "gpkg" => Some(OutputFormat::Gpkg),
+            "csv" => Some(OutputFormat::Csv),
+            "tsv" => Some(OutputFormat::Tsv),
+            "shp" => Some(OutputFormat::Shp),
+            "pbf" => Some(OutputFormat::Pbf),
+            "sqlite" | "spatialite" => Some(OutputFormat::Spatialite),
+            "duckdb" | "ddb" => Some(OutputFormat::Duckdb),
+            "fidx" => Some(OutputFormat::Index),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Geojson => "geojson",
+            OutputFormat::Geojsonseq => "geojsonseq",
+            OutputFormat::Geoparquet => "parquet",
+            OutputFormat::Arrow => "arrows",
+            OutputFormat::Fgb => "fgb",
+            OutputFormat::Gpkg => "gpkg",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Shp => "shp",
+            OutputFormat::Pbf => "osm.pbf",
+            OutputFormat::Spatialite => "sqlite",
+            OutputFormat::Duckdb => "duckdb",
+            OutputFormat::Index => "fidx",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    /// Parses a format name as `--format` takes it, e.g. `gpkg`
+    fn from_str(s: &str) -> Result<Self> {
+        match <OutputFormat as ValueEnum>::from_str(s, true) {
+            Ok(format) => Ok(format),
+            Err(_) => bail!("unknown output format {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Geojson => "GeoJSON",
+            OutputFormat::Geojsonseq => "GeoJSONSeq",
+            OutputFormat::Geoparquet => "GeoParquet",
+            OutputFormat::Arrow => "Arrow IPC",
+            OutputFormat::Fgb => "FlatGeobuf",
+            OutputFormat::Gpkg => "GeoPackage",
+            OutputFormat::Csv => "CSV",
+            OutputFormat::Tsv => "TSV",
+            OutputFormat::Shp => "Shapefile",
+            OutputFormat::Pbf => "OSM PBF",
+            OutputFormat::Spatialite => "SpatiaLite",
+            OutputFormat::Duckdb => "DuckDB",
+            OutputFormat::Index => "feature index",
+        })
+    }
+}
+
+/// Format-specific settings for [`write`]
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Tag keys written as separate CSV/TSV/GeoPackage/SpatiaLite/
+    /// GeoParquet/Arrow/DuckDB columns instead of a single `tags` column, and the
+    /// only tags written to shapefiles
+    pub columns: Vec<String>,
+    /// Typed columns mapped from the tags, replacing `columns` in the
+    /// GeoParquet, Arrow, GeoPackage, SpatiaLite, DuckDB and CSV/TSV outputs
+    pub schema: Option<TagSchema>,
+    /// GeoPackage layer or SpatiaLite or DuckDB table name; defaults to the
+    /// file name without extension
+    pub layer: Option<String>,
+    /// The CRS the features were reprojected into, recorded in the output
+    /// metadata where the format supports it; `None` means WGS84
+    pub crs: Option<Crs>,
+    /// How GeoJSON, GeoJSONSeq, CSV and TSV are compressed; other formats
+    /// cannot be
+    pub compression: Option<Compression>,
+}
+
+impl WriteOptions {
+    /// The columns written instead of a single `tags` column: those of the
+    /// schema, else a text column per key in `columns`, else none
+    pub fn tag_columns(&self) -> Vec<Column> {
+        match &self.schema {
+            Some(schema) => schema.columns.clone(),
+            None => self.columns.iter().map(|key| Column::text(key)).collect(),
+        }
+    }
+
+    /// The GeoPackage layer or the SpatiaLite or DuckDB table written to
+    /// `path`
+    pub fn layer(&self, path: &Path) -> String {
+        let default_layer = path.file_stem().and_then(|s| s.to_str()).unwrap_or("features");
+        self.layer.as_deref().unwrap_or(default_layer).to_string()
+    }
+}
+
+/// Writes all features to `path` in the given format; PBF files are
+/// written from the OSM objects instead, with [`pbf::write`]
+pub fn write(path: &Path, format: OutputFormat, features: &[ExtractedFeature], options: &WriteOptions) -> Result<()> {
+    let text = matches!(format, OutputFormat::Geojson | OutputFormat::Geojsonseq | OutputFormat::Csv | OutputFormat::Tsv);
+    if let (Some(compression), false) = (options.compression, text) {
+        bail!("{} output cannot be compressed with {}; only GeoJSON, GeoJSONSeq, CSV and TSV can", format, compression);
+    }
+    let write_text = |write: &dyn Fn(&mut CompressedFile) -> Result<()>| {
+        let mut out = CompressedFile::create(path, options.compression)?;
+        write(&mut out)?;
+        out.finish()
+    };
+    match format {
+        OutputFormat::Geojson => {
+            write_text(&|out| geojson::write_feature_collection_to(out, features, options.crs.as_ref()))
+        }
+        OutputFormat::Geojsonseq => geojson::write_seq_compressed(path, features, options.compression),
+        #[cfg(feature = "geoparquet")]
+        OutputFormat::Geoparquet => geoparquet::write(path, features, &options.tag_columns(), options.crs.as_ref()),
+        #[cfg(not(feature = "geoparquet"))]
+        OutputFormat::Geoparquet => {
+            anyhow::bail!("GeoParquet output requires building with the `geoparquet` feature")
+        }
+        #[cfg(feature = "arrow")]
+        OutputFormat::Arrow => arrow::write(path, features, &options.tag_columns(), options.crs.as_ref()),
+        #[cfg(not(feature = "arrow"))]
+        OutputFormat::Arrow => anyhow::bail!("Arrow IPC output requires building with the `arrow` feature"),
+        #[cfg(feature = "flatgeobuf")]
+        OutputFormat::Fgb => flatgeobuf::write(path, features, options.crs.as_ref()),
+        #[cfg(not(feature = "flatgeobuf"))]
+        OutputFormat::Fgb => anyhow::bail!("FlatGeobuf output requires building with the `flatgeobuf` feature"),
+        #[cfg(feature = "gpkg")]
+        OutputFormat::Gpkg => {
+            gpkg::write(path, features, &options.layer(path), &options.tag_columns(), options.crs.as_ref())
+        }
+        #[cfg(not(feature = "gpkg"))]
+        OutputFormat::Gpkg => anyhow::bail!("GeoPackage output requires building with the `gpkg` feature"),
+        #[cfg(feature = "spatialite")]
+        OutputFormat::Spatialite => {
+            spatialite::write(path, features, &options.layer(path), &options.tag_columns(), options.crs.as_ref())
+        }
+        #[cfg(not(feature = "spatialite"))]
+        OutputFormat::Spatialite => anyhow::bail!("SpatiaLite output requires building with the `spatialite` feature"),
+        #[cfg(feature = "duckdb")]
+        OutputFormat::Duckdb => {
+            duckdb::write(path, features, &options.layer(path), &options.tag_columns(), options.crs.as_ref())
+        }
+        #[cfg(not(feature = "duckdb"))]
+        OutputFormat::Duckdb => anyhow::bail!("DuckDB output requires building with the `duckdb` feature"),
+        OutputFormat::Csv => write_text(&|out| csv::write_to(out, features, b',', &options.tag_columns())),
+        OutputFormat::Tsv => write_text(&|out| csv::write_to(out, features, b'\t', &options.tag_columns())),
+        OutputFormat::Shp => shapefile::write(path, features, &options.columns, options.crs.as_ref()),
+        OutputFormat::Pbf => anyhow::bail!("PBF output holds OSM objects, not assembled features"),
+        OutputFormat::Index => {
+            if options.crs.is_some() {
+                bail!("a feature index is queried in WGS84 and cannot hold reprojected features");
+            }
+            crate::index::write(path, features)
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! OSM PBF writer for the raw objects of an extraction, so that its result
+//! can be read by osm2pgsql, OSRM or another run of this tool.
+
+use crate::metadata::Metadata;
+use crate::pbf::writer::Writer;
+use anyhow::Result;
+use osmpbfreader::OsmObj;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Writes `objects` into a PBF, returning the number written. They must
+/// come sorted by type then id, as the values of the map
+/// [`Extractor::objects`](crate::Extractor::objects) returns do.
+///
+/// Metadata read along with the objects, carried in their
+/// [`metadata::TAGS`](crate::metadata::TAGS), is written as object metadata
+/// again instead of as tags.
+pub fn write(path: &Path, objects: impl IntoIterator<Item = OsmObj>) -> Result<usize> {
+    let mut writer = Writer::new(BufWriter::new(File::create(path)?))?;
+    let mut count = 0;
+    for mut obj in objects {
+        let tags = match &mut obj {
+            OsmObj::Node(node) => &mut node.tags,
+            OsmObj::Way(way) => &mut way.tags,
+            OsmObj::Relation(relation) => &mut relation.tags,
+        };
+        let metadata = Metadata::take_from(tags);
+        writer.write_with_metadata(obj, metadata)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
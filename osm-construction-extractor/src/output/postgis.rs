@@ -0,0 +1,224 @@
+//! PostGIS sink creating a table and bulk-loading features via `COPY`.
+
+use super::wkb;
+use crate::feature::ExtractedFeature;
+use crate::intern::Tag;
+use crate::schema::{Column, Storage};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use postgres::{Client, NoTls};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::io::Write;
+use tracing::warn;
+
+// NAMEDATALEN - 1; longer identifiers are silently truncated by PostgreSQL
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+const FIXED_COLUMNS: [&str; 4] = ["id", "osm_type", "osm_id", "geometry"];
+
+/// How OSM tags are stored in the target table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum TagStorage {
+    /// A single `jsonb` column
+    #[default]
+    Jsonb,
+    /// A single `hstore` column (requires the hstore extension)
+    Hstore,
+    /// One `text` column per tag key found in the extraction
+    Columns,
+}
+
+/// Where and how features are loaded into PostGIS
+#[derive(Debug, Clone)]
+pub struct PostgisOptions {
+    pub schema: String,
+    pub table: String,
+    pub tags: TagStorage,
+    /// Typed columns mapped from the tags; if given, they are stored
+    /// instead of `tags`
+    pub columns: Vec<Column>,
+    /// Drop an existing table of the same name instead of failing
+    pub overwrite: bool,
+    /// Spatial reference id of the geometries, `0` if unknown
+    pub srid: u32,
+}
+
+impl Default for PostgisOptions {
+    fn default() -> Self {
+        PostgisOptions {
+            schema: "public".to_string(),
+            table: "osm_features".to_string(),
+            tags: TagStorage::default(),
+            columns: Vec::new(),
+            overwrite: false,
+            srid: 4326,
+        }
+    }
+}
+
+/// Loads all features into a new table at `url`, e.g.
+/// `postgresql://user@localhost/gis`.
+///
+/// The table is created, filled via `COPY` and given a GiST index on its
+/// `geometry(Geometry, <srid>)` column within a single transaction, so a
+/// failed import leaves no half-written table behind. The PostGIS extension
+/// (and hstore, if used) must already be installed in the database.
+pub fn write(url: &str, options: &PostgisOptions, features: &[ExtractedFeature]) -> Result<()> {
+    let mut client = Client::connect(url, NoTls).context("could not connect to PostgreSQL")?;
+    let mut tx = client.transaction()?;
+
+    let table = format!("{}.{}", quote_ident(&options.schema), quote_ident(&options.table));
+    let tag_columns = match options.tags {
+        TagStorage::Columns if options.columns.is_empty() => tag_columns(features),
+        _ => BTreeMap::new(),
+    };
+
+    let mut columns = vec![
+        "id text PRIMARY KEY".to_string(),
+        "osm_type text NOT NULL".to_string(),
+        "osm_id bigint NOT NULL".to_string(),
+    ];
+    match options.tags {
+        _ if !options.columns.is_empty() => columns.extend(
+            options.columns.iter().map(|c| format!("{} {}", quote_ident(&truncate(&c.name)), sql_type(c.storage()))),
+        ),
+        TagStorage::Jsonb => columns.push("tags jsonb".to_string()),
+        TagStorage::Hstore => columns.push("tags hstore".to_string()),
+        TagStorage::Columns => columns.extend(tag_columns.values().map(|c| format!("{} text", quote_ident(c)))),
+    }
+    columns.push(format!("geometry geometry(Geometry, {}) NOT NULL", options.srid));
+
+    if options.overwrite {
+        tx.batch_execute(&format!("DROP TABLE IF EXISTS {}", table))?;
+    }
+    tx.batch_execute(&format!("CREATE TABLE {} ({})", table, columns.join(", ")))
+        .with_context(|| format!("could not create table {}", table))?;
+
+    let mut names: Vec<String> = vec!["id".to_string(), "osm_type".to_string(), "osm_id".to_string()];
+    match options.tags {
+        _ if !options.columns.is_empty() => {
+            names.extend(options.columns.iter().map(|c| quote_ident(&truncate(&c.name))))
+        }
+        TagStorage::Columns => names.extend(tag_columns.values().map(|c| quote_ident(c))),
+        _ => names.push("tags".to_string()),
+    }
+    names.push("geometry".to_string());
+    let mut copy = tx.copy_in(&format!("COPY {} ({}) FROM STDIN", table, names.join(", ")))?;
+
+    let mut line = String::new();
+    for feature in features {
+        line.clear();
+        push_field(&mut line, &feature.unique_id());
+        line.push('\t');
+        push_field(&mut line, &feature.osm_type().to_string());
+        write!(line, "\t{}", feature.id())?;
+        match options.tags {
+            _ if !options.columns.is_empty() => {
+                for column in &options.columns {
+                    line.push('\t');
+                    match column.value(feature.tags()) {
+                        Some(value) => push_field(&mut line, &value.to_string()),
+                        None => line.push_str("\\N"),
+                    }
+                }
+            }
+            TagStorage::Jsonb => {
+                line.push('\t');
+                push_field(&mut line, &serde_json::to_string(feature.tags())?);
+            }
+            TagStorage::Hstore => {
+                line.push('\t');
+                push_field(&mut line, &hstore(feature));
+            }
+            TagStorage::Columns => {
+                for key in tag_columns.keys() {
+                    line.push('\t');
+                    match feature.tags().get(*key) {
+                        Some(value) => push_field(&mut line, value),
+                        None => line.push_str("\\N"),
+                    }
+                }
+            }
+        }
+        // PostGIS accepts hex WKB with an EWKT-style SRID prefix
+        write!(line, "\tSRID={};", options.srid)?;
+        for byte in wkb::to_wkb(feature.geometry()) {
+            write!(line, "{:02X}", byte)?;
+        }
+        line.push('\n');
+        copy.write_all(line.as_bytes())?;
+    }
+    copy.finish().context("COPY into PostGIS failed")?;
+
+    let index = quote_ident(&truncate(&format!("{}_geometry_idx", options.table)));
+    tx.batch_execute(&format!("CREATE INDEX {} ON {} USING GIST (geometry)", index, table))?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// Maps every tag key to a unique column name, skipping keys that clash with
+// the fixed columns or with another key once truncated
+fn tag_columns(features: &[ExtractedFeature]) -> BTreeMap<&str, String> {
+    let keys: BTreeSet<&str> = features.iter().flat_map(|f| f.tags().keys().map(Tag::as_str)).collect();
+    let mut used: BTreeSet<String> = FIXED_COLUMNS.iter().map(|c| c.to_string()).collect();
+    let mut columns = BTreeMap::new();
+    for key in keys {
+        let column = truncate(key);
+        if used.insert(column.clone()) {
+            columns.insert(key, column);
+        } else {
+            warn!("Tag key {:?} clashes with column {:?} and is not stored.", key, column);
+        }
+    }
+    columns
+}
+
+fn sql_type(storage: Storage) -> &'static str {
+    match storage {
+        Storage::Text => "text",
+        Storage::Int => "bigint",
+        Storage::Float => "double precision",
+        Storage::Bool => "boolean",
+    }
+}
+
+fn truncate(name: &str) -> String {
+    let mut end = name.len().min(MAX_IDENTIFIER_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Escapes a value for COPY's text format
+fn push_field(line: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => line.push_str("\\\\"),
+            '\t' => line.push_str("\\t"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            _ => line.push(c),
+        }
+    }
+}
+
+// The hstore text representation, e.g. `"highway"=>"construction"`
+fn hstore(feature: &ExtractedFeature) -> String {
+    let mut tags: Vec<_> = feature.tags().iter().collect();
+    tags.sort();
+    tags.iter()
+        .map(|(k, v)| format!("\"{}\"=>\"{}\"", hstore_escape(k), hstore_escape(v)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn hstore_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
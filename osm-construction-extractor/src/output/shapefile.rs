@@ -0,0 +1,391 @@
+//! ESRI Shapefile writer: `.shp` geometries with their `.shx` index, `.dbf`
+//! attributes, `.prj` CRS and `.cpg` encoding.
+
+use crate::crs::{Crs, WEB_MERCATOR_WKT, WGS84_WKT};
+use crate::feature::ExtractedFeature;
+use crate::intern::Tag;
+use crate::metadata;
+use anyhow::{bail, Result};
+use geo::orient::{Direction, Orient};
+use geo::{Coord, Geometry, LineString, Polygon};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+const NULL: i32 = 0;
+const POINT: i32 = 1;
+const POLYLINE: i32 = 3;
+const POLYGON: i32 = 5;
+const MULTIPOINT: i32 = 8;
+
+// Lengths in the headers count 16-bit words in a signed 32-bit integer,
+// which the specification limits to 2 GB files
+const HEADER_SIZE: u64 = 100;
+const MAX_FILE_SIZE: u64 = i32::MAX as u64;
+
+// dBase III limits
+const MAX_FIELDS: usize = 255;
+const MAX_FIELD_NAME: usize = 10;
+const MAX_TEXT: usize = 254;
+
+/// Writes the features as a shapefile at `path` (the `.shp`, with the other
+/// parts next to it).
+///
+/// A shapefile holds a single geometry type, so when the features mix
+/// points, lines and polygons each kind goes into its own shapefile named
+/// after `path` with a `_points`, `_lines` or `_polygons` suffix, e.g.
+/// `out_lines.shp`; the parts of a geometry collection end up in several of
+/// them. Attributes are `id`, `osm_type` and `osm_id` plus one text field
+/// per tag key in `columns`, or per key found in the features if it is
+/// empty. Field names are cut to the 10 characters dBase allows and values
+/// to 254 bytes, with a warning for each.
+pub fn write(path: &Path, features: &[ExtractedFeature], columns: &[String], crs: Option<&Crs>) -> Result<()> {
+    let mut layers: [Vec<(&ExtractedFeature, Shape)>; 3] = Default::default();
+    for feature in features {
+        let mut parts = Parts::default();
+        parts.add(feature.geometry());
+        if !parts.points.is_empty() {
+            layers[0].push((feature, Shape::Points(parts.points)));
+        }
+        if !parts.lines.is_empty() {
+            layers[1].push((feature, Shape::Lines(parts.lines)));
+        }
+        if !parts.polygons.is_empty() {
+            layers[2].push((feature, Shape::Polygons(parts.polygons)));
+        }
+    }
+
+    let non_empty: Vec<(usize, &Vec<(&ExtractedFeature, Shape)>)> =
+        layers.iter().enumerate().filter(|(_, layer)| !layer.is_empty()).collect();
+    match non_empty.as_slice() {
+        [] => write_layer(path, NULL, &[], columns, crs),
+        [(kind, layer)] => write_layer(path, shape_type(*kind, layer), layer, columns, crs),
+        _ => {
+            for (kind, layer) in non_empty {
+                let suffix = ["points", "lines", "polygons"][kind];
+                write_layer(&suffixed(path, suffix), shape_type(kind, layer), layer, columns, crs)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Points are written as multipoints if any feature has more than one
+fn shape_type(kind: usize, layer: &[(&ExtractedFeature, Shape)]) -> i32 {
+    match kind {
+        0 if layer.iter().any(|(_, shape)| matches!(shape, Shape::Points(points) if points.len() > 1)) => MULTIPOINT,
+        0 => POINT,
+        1 => POLYLINE,
+        _ => POLYGON,
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("features");
+    path.with_file_name(format!("{}_{}.shp", stem, suffix))
+}
+
+// The parts of a geometry that fit one shape type each
+#[derive(Default)]
+struct Parts {
+    points: Vec<Coord>,
+    lines: Vec<LineString>,
+    polygons: Vec<Polygon>,
+}
+
+impl Parts {
+    fn add(&mut self, geom: &Geometry) {
+        match geom {
+            Geometry::Point(p) => self.points.push(p.0),
+            Geometry::MultiPoint(mp) => self.points.extend(mp.iter().map(|p| p.0)),
+            Geometry::Line(l) => self.lines.push(LineString::new(vec![l.start, l.end])),
+            Geometry::LineString(ls) => self.lines.push(ls.clone()),
+            Geometry::MultiLineString(mls) => self.lines.extend(mls.iter().cloned()),
+            Geometry::Polygon(p) => self.polygons.push(p.clone()),
+            Geometry::MultiPolygon(mp) => self.polygons.extend(mp.iter().cloned()),
+            Geometry::Rect(r) => self.polygons.push(r.to_polygon()),
+            Geometry::Triangle(t) => self.polygons.push(t.to_polygon()),
+            Geometry::GeometryCollection(gc) => gc.iter().for_each(|g| self.add(g)),
+        }
+    }
+}
+
+enum Shape {
+    Points(Vec<Coord>),
+    Lines(Vec<LineString>),
+    Polygons(Vec<Polygon>),
+}
+
+impl Shape {
+    // The point lists the shape is stored as; polygon rings are turned
+    // clockwise for outer rings and counterclockwise for holes
+    fn parts(&self) -> Vec<Vec<Coord>> {
+        match self {
+            Shape::Points(points) => vec![points.clone()],
+            Shape::Lines(lines) => lines.iter().map(|l| l.0.clone()).collect(),
+            Shape::Polygons(polygons) => polygons
+                .iter()
+                .flat_map(|p| {
+                    let p = p.orient(Direction::Reversed);
+                    let (exterior, interiors) = p.into_inner();
+                    std::iter::once(exterior).chain(interiors).map(|ring| ring.0)
+                })
+                .collect(),
+        }
+    }
+}
+
+fn write_layer(
+    path: &Path,
+    shape_type: i32,
+    layer: &[(&ExtractedFeature, Shape)],
+    columns: &[String],
+    crs: Option<&Crs>,
+) -> Result<()> {
+    let mut shp = BufWriter::new(File::create(path.with_extension("shp"))?);
+    let mut shx = BufWriter::new(File::create(path.with_extension("shx"))?);
+    shp.write_all(&[0; HEADER_SIZE as usize])?;
+    shx.write_all(&[0; HEADER_SIZE as usize])?;
+
+    let mut bounds: Option<[f64; 4]> = None;
+    let mut offset = HEADER_SIZE;
+    let mut content = Vec::new();
+    for (i, (_, shape)) in layer.iter().enumerate() {
+        content.clear();
+        content.extend_from_slice(&shape_type.to_le_bytes());
+        let parts = shape.parts();
+        let box_ = bbox(parts.iter().flatten());
+        bounds = Some(match bounds {
+            Some(b) => [b[0].min(box_[0]), b[1].min(box_[1]), b[2].max(box_[2]), b[3].max(box_[3])],
+            None => box_,
+        });
+        if shape_type == POINT {
+            push_coord(&mut content, &parts[0][0]);
+        } else {
+            box_.iter().for_each(|v| content.extend_from_slice(&v.to_le_bytes()));
+            if shape_type != MULTIPOINT {
+                content.extend_from_slice(&(parts.len() as i32).to_le_bytes());
+            }
+            let point_count: usize = parts.iter().map(Vec::len).sum();
+            content.extend_from_slice(&(point_count as i32).to_le_bytes());
+            if shape_type != MULTIPOINT {
+                let mut start = 0;
+                for part in &parts {
+                    content.extend_from_slice(&(start as i32).to_le_bytes());
+                    start += part.len();
+                }
+            }
+            parts.iter().flatten().for_each(|c| push_coord(&mut content, c));
+        }
+
+        shx.write_all(&((offset / 2) as i32).to_be_bytes())?;
+        shx.write_all(&((content.len() / 2) as i32).to_be_bytes())?;
+        shp.write_all(&(i as i32 + 1).to_be_bytes())?;
+        shp.write_all(&((content.len() / 2) as i32).to_be_bytes())?;
+        shp.write_all(&content)?;
+        offset += 8 + content.len() as u64;
+        if offset > MAX_FILE_SIZE {
+            bail!("{} exceeds the 2 GB limit of shapefiles; pick a smaller area or another format", path.display());
+        }
+    }
+
+    let bounds = bounds.unwrap_or_default();
+    shp.seek(SeekFrom::Start(0))?;
+    write_header(&mut shp, offset, shape_type, &bounds)?;
+    shp.flush()?;
+    shx.seek(SeekFrom::Start(0))?;
+    write_header(&mut shx, HEADER_SIZE + 8 * layer.len() as u64, shape_type, &bounds)?;
+    shx.flush()?;
+
+    let features: Vec<&ExtractedFeature> = layer.iter().map(|(feature, _)| *feature).collect();
+    write_dbf(&path.with_extension("dbf"), &features, columns)?;
+    fs::write(path.with_extension("cpg"), "UTF-8")?;
+    match crs.map(|crs| (crs.epsg, crs)) {
+        None | Some((Some(4326), _)) => fs::write(path.with_extension("prj"), WGS84_WKT)?,
+        Some((Some(3857), _)) => fs::write(path.with_extension("prj"), WEB_MERCATOR_WKT)?,
+        Some((_, crs)) => warn!("No .prj file written for {}, whose WKT definition is not known.", crs),
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(out: &mut W, file_size: u64, shape_type: i32, bounds: &[f64; 4]) -> Result<()> {
+    out.write_all(&9994i32.to_be_bytes())?;
+    out.write_all(&[0; 20])?;
+    out.write_all(&((file_size / 2) as i32).to_be_bytes())?;
+    out.write_all(&1000i32.to_le_bytes())?;
+    out.write_all(&shape_type.to_le_bytes())?;
+    for v in bounds {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    // Z and M ranges
+    out.write_all(&[0; 32])?;
+    Ok(())
+}
+
+fn bbox<'a>(coords: impl Iterator<Item = &'a Coord>) -> [f64; 4] {
+    coords.fold([f64::MAX, f64::MAX, f64::MIN, f64::MIN], |b, c| {
+        [b[0].min(c.x), b[1].min(c.y), b[2].max(c.x), b[3].max(c.y)]
+    })
+}
+
+fn push_coord(out: &mut Vec<u8>, c: &Coord) {
+    out.extend_from_slice(&c.x.to_le_bytes());
+    out.extend_from_slice(&c.y.to_le_bytes());
+}
+
+// Where the value of a DBF field comes from
+enum Source {
+    Id,
+    OsmType,
+    OsmId,
+    Tag(String),
+}
+
+struct Field {
+    name: String,
+    source: Source,
+    numeric: bool,
+    width: usize,
+    truncated: usize,
+}
+
+impl Field {
+    fn value<'a>(&self, feature: &'a ExtractedFeature) -> std::borrow::Cow<'a, str> {
+        match &self.source {
+            Source::Id => feature.unique_id().into(),
+            Source::OsmType => feature.osm_type().to_string().into(),
+            Source::OsmId => feature.id().to_string().into(),
+            Source::Tag(key) => feature.tags().get(key.as_str()).map_or("", Tag::as_str).into(),
+        }
+    }
+}
+
+fn write_dbf(path: &Path, features: &[&ExtractedFeature], columns: &[String]) -> Result<()> {
+    let mut fields = vec![
+        Field { name: "id".to_string(), source: Source::Id, numeric: false, width: 1, truncated: 0 },
+        Field { name: "osm_type".to_string(), source: Source::OsmType, numeric: false, width: 8, truncated: 0 },
+        Field { name: "osm_id".to_string(), source: Source::OsmId, numeric: true, width: 20, truncated: 0 },
+    ];
+    let mut taken: HashSet<String> = fields.iter().map(|f| f.name.clone()).collect();
+    for key in tag_keys(features, columns, MAX_FIELDS - fields.len()) {
+        let name = field_name(&key, &mut taken);
+        if name != key {
+            warn!("Tag key {:?} is written as DBF field {:?}.", key, name);
+        }
+        fields.push(Field { name, source: Source::Tag(key), numeric: false, width: 1, truncated: 0 });
+    }
+    for field in &mut fields {
+        if !field.numeric {
+            let longest = features.iter().map(|f| field.value(f).len()).max().unwrap_or(0);
+            field.width = longest.clamp(field.width, MAX_TEXT);
+        }
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    let record_size = 1 + fields.iter().map(|f| f.width).sum::<usize>();
+    let days = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64 / 86400;
+    let (year, month, day) = metadata::civil_from_days(days);
+    out.write_all(&[3, (year - 1900) as u8, month as u8, day as u8])?;
+    out.write_all(&(features.len() as u32).to_le_bytes())?;
+    out.write_all(&((32 + 32 * fields.len() + 1) as u16).to_le_bytes())?;
+    out.write_all(&(record_size as u16).to_le_bytes())?;
+    out.write_all(&[0; 20])?;
+    for field in &fields {
+        let mut descriptor = [0u8; 32];
+        descriptor[..field.name.len()].copy_from_slice(field.name.as_bytes());
+        descriptor[11] = if field.numeric { b'N' } else { b'C' };
+        descriptor[16] = field.width as u8;
+        out.write_all(&descriptor)?;
+    }
+    out.write_all(&[0x0d])?;
+
+    let mut record = Vec::with_capacity(record_size);
+    for feature in features {
+        record.clear();
+        record.push(b' ');
+        for field in &mut fields {
+            let value = field.value(feature);
+            let mut bytes = value.as_bytes();
+            if bytes.len() > field.width {
+                // Cut at a character boundary
+                let mut end = field.width;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                bytes = &bytes[..end];
+                field.truncated += 1;
+            }
+            let padding = std::iter::repeat_n(b' ', field.width - bytes.len());
+            if field.numeric {
+                record.extend(padding);
+                record.extend_from_slice(bytes);
+            } else {
+                record.extend_from_slice(bytes);
+                record.extend(padding);
+            }
+        }
+        out.write_all(&record)?;
+    }
+    out.write_all(&[0x1a])?;
+    out.flush()?;
+
+    for field in fields.iter().filter(|f| f.truncated > 0) {
+        warn!(
+            "{} values of DBF field {:?} were cut to {} bytes.",
+            field.truncated, field.name, MAX_TEXT
+        );
+    }
+    Ok(())
+}
+
+// The tag keys given, or else the keys of all features, keeping the most
+// common ones if there are more than `limit`
+fn tag_keys(features: &[&ExtractedFeature], columns: &[String], limit: usize) -> Vec<String> {
+    let mut keys: Vec<(String, usize)> = if columns.is_empty() {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for feature in features {
+            for key in feature.tags().keys() {
+                *counts.entry(key).or_default() += 1;
+            }
+        }
+        counts.into_iter().map(|(key, count)| (key.to_string(), count)).collect()
+    } else {
+        columns.iter().map(|key| (key.clone(), 0)).collect()
+    };
+    if keys.len() > limit {
+        warn!(
+            "{} tag keys exceed the {} DBF fields a shapefile can have; only the {} most common are written.",
+            keys.len(),
+            MAX_FIELDS,
+            limit
+        );
+        keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        keys.truncate(limit);
+    }
+    if columns.is_empty() {
+        keys.sort();
+    }
+    keys.into_iter().map(|(key, _)| key).collect()
+}
+
+// A unique name of at most 10 ASCII characters: other characters become
+// `_` and clashes get a numbered suffix, the way GDAL names them
+fn field_name(key: &str, taken: &mut HashSet<String>) -> String {
+    let clean: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .take(MAX_FIELD_NAME)
+        .collect();
+    let clean = if clean.is_empty() { "field".to_string() } else { clean };
+    let mut name = clean.clone();
+    let mut n = 1;
+    while !taken.insert(name.to_ascii_lowercase()) {
+        let suffix = format!("_{}", n);
+        name = format!("{}{}", &clean[..clean.len().min(MAX_FIELD_NAME - suffix.len())], suffix);
+        n += 1;
+    }
+    name
+}
@@ -0,0 +1,303 @@
+//! SpatiaLite writer: a plain SQLite file with SpatiaLite geometry blobs,
+//! metadata and spatial index, which QGIS and GDAL open as a spatial
+//! database without any server or GDAL install on the writing side.
+
+use crate::crs::{Crs, WEB_MERCATOR_WKT, WGS84_WKT};
+use crate::feature::ExtractedFeature;
+use crate::schema::{Column, Storage, Value};
+use anyhow::Result;
+use geo::{BoundingRect, Coord, Geometry, LineString, Polygon, Rect};
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+// The tables of the current (SpatiaLite 4 and later) metadata layout
+const SCHEMA: &str = "
+    CREATE TABLE spatial_ref_sys (
+        srid INTEGER NOT NULL PRIMARY KEY,
+        auth_name TEXT NOT NULL,
+        auth_srid INTEGER NOT NULL,
+        ref_sys_name TEXT NOT NULL DEFAULT 'Unknown',
+        proj4text TEXT NOT NULL,
+        srtext TEXT NOT NULL DEFAULT 'Undefined'
+    );
+    CREATE TABLE geometry_columns (
+        f_table_name TEXT NOT NULL,
+        f_geometry_column TEXT NOT NULL,
+        geometry_type INTEGER NOT NULL,
+        coord_dimension INTEGER NOT NULL,
+        srid INTEGER NOT NULL,
+        spatial_index_enabled INTEGER NOT NULL,
+        CONSTRAINT pk_geom_cols PRIMARY KEY (f_table_name, f_geometry_column),
+        CONSTRAINT fk_gc_srs FOREIGN KEY (srid) REFERENCES spatial_ref_sys (srid)
+    );
+    INSERT INTO spatial_ref_sys VALUES
+        (-1, 'NONE', -1, 'Undefined - Cartesian', '', 'Undefined'),
+        (0, 'NONE', 0, 'Undefined - Geographic Long/Lat', '', 'Undefined');
+";
+
+// Markers of the SpatiaLite blob format
+const START: u8 = 0x00;
+const LITTLE_ENDIAN: u8 = 0x01;
+const MBR_END: u8 = 0x7C;
+const ENTITY: u8 = 0x69;
+const END: u8 = 0xFE;
+
+const POINT: i32 = 1;
+const LINE_STRING: i32 = 2;
+const POLYGON: i32 = 3;
+const MULTI_POINT: i32 = 4;
+const MULTI_LINE_STRING: i32 = 5;
+const MULTI_POLYGON: i32 = 6;
+const GEOMETRY_COLLECTION: i32 = 7;
+
+/// Writes all features into a new SpatiaLite database with a single
+/// feature table named `layer`.
+///
+/// Tags are stored in a JSON `tags` column, or one column per entry of
+/// `columns`, as in GeoPackages. The `geom` column is registered in
+/// `geometry_columns` with an R*Tree spatial index, `idx_<layer>_geom`,
+/// and the triggers SpatiaLite keeps it current with. Features with an
+/// empty geometry get a NULL one, which SpatiaLite cannot represent
+/// otherwise.
+pub fn write(
+    path: &Path,
+    features: &[ExtractedFeature],
+    layer: &str,
+    columns: &[Column],
+    crs: Option<&Crs>,
+) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let mut conn = Connection::open(path)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+
+    let srid = register_srs(&tx, crs)?;
+    let table = quote_ident(layer);
+    let mut definitions = vec![
+        "fid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL".to_string(),
+        "id TEXT NOT NULL".to_string(),
+        "osm_type TEXT NOT NULL".to_string(),
+        "osm_id INTEGER NOT NULL".to_string(),
+    ];
+    if columns.is_empty() {
+        definitions.push("tags TEXT".to_string());
+    } else {
+        definitions.extend(columns.iter().map(|c| format!("{} {}", quote_ident(&c.name), sql_type(c.storage()))));
+    }
+    definitions.push("geom BLOB".to_string());
+    tx.execute_batch(&format!("CREATE TABLE {} ({});", table, definitions.join(", ")))?;
+
+    let value_columns = if columns.is_empty() {
+        "tags".to_string()
+    } else {
+        columns.iter().map(|c| quote_ident(&c.name)).collect::<Vec<_>>().join(", ")
+    };
+    let placeholders = vec!["?"; 4 + columns.len().max(1)].join(", ");
+    let mut geometry_types = BTreeSet::new();
+    let index = quote_ident(&format!("idx_{}_geom", layer));
+    tx.execute_batch(&format!("CREATE VIRTUAL TABLE {} USING rtree(pkid, xmin, xmax, ymin, ymax);", index))?;
+    {
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO {} (id, osm_type, osm_id, {}, geom) VALUES ({})",
+            table, value_columns, placeholders
+        ))?;
+        let mut rtree = tx.prepare(&format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?)", index))?;
+
+        for feature in features {
+            let bounds = feature.geometry().bounding_rect();
+            let mut values: Vec<rusqlite::types::Value> = vec![
+                feature.unique_id().into(),
+                feature.osm_type().to_string().into(),
+                feature.id().into(),
+            ];
+            if columns.is_empty() {
+                let tags: BTreeMap<_, _> = feature.tags().iter().collect();
+                values.push(serde_json::to_string(&tags)?.into());
+            } else {
+                for column in columns {
+                    values.push(sql_value(column.value(feature.tags())));
+                }
+            }
+            values.push(match bounds {
+                Some(b) => geometry_blob(feature.geometry(), srid, b).into(),
+                None => rusqlite::types::Value::Null,
+            });
+            insert.execute(rusqlite::params_from_iter(values))?;
+
+            if let Some(b) = bounds {
+                geometry_types.insert(class_type(feature.geometry()));
+                rtree.execute(params![tx.last_insert_rowid(), b.min().x, b.max().x, b.min().y, b.max().y])?;
+            }
+        }
+    }
+
+    // The triggers call functions of the SpatiaLite extension, so they may
+    // only exist once the index has been filled by hand
+    create_index_triggers(&tx, layer)?;
+
+    // 0 is SpatiaLite's code for any geometry
+    let geometry_type = match geometry_types.len() {
+        1 => geometry_types.first().copied().unwrap_or(0),
+        _ => 0,
+    };
+    tx.execute(
+        "INSERT INTO geometry_columns VALUES (?1, 'geom', ?2, 2, ?3, 1)",
+        params![layer.to_lowercase(), geometry_type, srid],
+    )?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// The srid of the features' CRS in spatial_ref_sys, adding it if it is not
+// one of the undefined ones every database has
+fn register_srs(conn: &Connection, crs: Option<&Crs>) -> Result<i32> {
+    conn.execute(
+        "INSERT INTO spatial_ref_sys VALUES (4326, 'epsg', 4326, 'WGS 84', '+proj=longlat +datum=WGS84 +no_defs', ?1)",
+        params![WGS84_WKT],
+    )?;
+    let (srid, name, proj4, definition) = match crs.map(|c| (c.epsg, c)) {
+        None | Some((Some(4326), _)) => return Ok(4326),
+        Some((None, _)) => return Ok(-1),
+        Some((Some(3857), _)) => (
+            3857,
+            "WGS 84 / Pseudo-Mercator",
+            "+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m +nadgrids=@null +wktext +no_defs"
+                .to_string(),
+            WEB_MERCATOR_WKT.to_string(),
+        ),
+        Some((Some(code), crs)) => (code as i32, "Reprojected", crs.definition.clone(), "Undefined".to_string()),
+    };
+    conn.execute(
+        "INSERT INTO spatial_ref_sys VALUES (?1, 'epsg', ?1, ?2, ?3, ?4)",
+        params![srid, name, proj4, definition],
+    )?;
+    Ok(srid)
+}
+
+// The triggers SpatiaLite creates for a spatial index on the `geom` column
+// of `layer`, keeping it in sync with later edits
+fn create_index_triggers(conn: &Connection, layer: &str) -> Result<()> {
+    let t = quote_ident(layer);
+    let index = format!("idx_{}_geom", layer);
+    let i = quote_ident(&index);
+    let literal = format!("'{}'", index.replace('\'', "''"));
+    let trigger = |prefix: &str| quote_ident(&format!("{}_{}_geom", prefix, layer));
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER {insert} AFTER INSERT ON {t} FOR EACH ROW BEGIN
+            DELETE FROM {i} WHERE pkid = NEW.ROWID;
+            SELECT RTreeAlign({literal}, NEW.ROWID, NEW.geom);
+        END;
+        CREATE TRIGGER {update} AFTER UPDATE OF geom ON {t} FOR EACH ROW BEGIN
+            DELETE FROM {i} WHERE pkid = NEW.ROWID;
+            SELECT RTreeAlign({literal}, NEW.ROWID, NEW.geom);
+        END;
+        CREATE TRIGGER {delete} AFTER DELETE ON {t} FOR EACH ROW BEGIN
+            DELETE FROM {i} WHERE pkid = OLD.ROWID;
+        END;",
+        insert = trigger("gii"),
+        update = trigger("giu"),
+        delete = trigger("gid"),
+    ))?;
+    Ok(())
+}
+
+// A SpatiaLite geometry blob: srid and bounding box, then the geometry in
+// a WKB-like encoding with entity markers instead of byte order marks
+fn geometry_blob(geom: &Geometry, srid: i32, bounds: Rect) -> Vec<u8> {
+    let mut blob = vec![START, LITTLE_ENDIAN];
+    blob.extend_from_slice(&srid.to_le_bytes());
+    for v in [bounds.min().x, bounds.min().y, bounds.max().x, bounds.max().y] {
+        blob.extend_from_slice(&v.to_le_bytes());
+    }
+    blob.push(MBR_END);
+    blob.extend_from_slice(&class_type(geom).to_le_bytes());
+    write_body(&mut blob, geom);
+    blob.push(END);
+    blob
+}
+
+fn class_type(geom: &Geometry) -> i32 {
+    match geom {
+        Geometry::Point(_) => POINT,
+        Geometry::Line(_) | Geometry::LineString(_) => LINE_STRING,
+        Geometry::Polygon(_) | Geometry::Rect(_) | Geometry::Triangle(_) => POLYGON,
+        Geometry::MultiPoint(_) => MULTI_POINT,
+        Geometry::MultiLineString(_) => MULTI_LINE_STRING,
+        Geometry::MultiPolygon(_) => MULTI_POLYGON,
+        Geometry::GeometryCollection(_) => GEOMETRY_COLLECTION,
+    }
+}
+
+fn write_body(out: &mut Vec<u8>, geom: &Geometry) {
+    match geom {
+        Geometry::Point(p) => coord(out, &p.0),
+        Geometry::Line(l) => line_string(out, &LineString(vec![l.start, l.end])),
+        Geometry::LineString(ls) => line_string(out, ls),
+        Geometry::Polygon(p) => polygon(out, p),
+        Geometry::Rect(r) => polygon(out, &r.to_polygon()),
+        Geometry::Triangle(t) => polygon(out, &t.to_polygon()),
+        Geometry::MultiPoint(mp) => entities(out, mp.iter().map(|p| Geometry::Point(*p)).collect()),
+        Geometry::MultiLineString(mls) => entities(out, mls.iter().cloned().map(Geometry::LineString).collect()),
+        Geometry::MultiPolygon(mp) => entities(out, mp.iter().cloned().map(Geometry::Polygon).collect()),
+        Geometry::GeometryCollection(gc) => entities(out, gc.0.clone()),
+    }
+}
+
+// The parts of a multi geometry or collection, each after an entity marker
+// and its class type
+fn entities(out: &mut Vec<u8>, parts: Vec<Geometry>) {
+    out.extend_from_slice(&(parts.len() as i32).to_le_bytes());
+    for part in parts {
+        out.push(ENTITY);
+        out.extend_from_slice(&class_type(&part).to_le_bytes());
+        write_body(out, &part);
+    }
+}
+
+fn coord(out: &mut Vec<u8>, c: &Coord) {
+    out.extend_from_slice(&c.x.to_le_bytes());
+    out.extend_from_slice(&c.y.to_le_bytes());
+}
+
+fn line_string(out: &mut Vec<u8>, ls: &LineString) {
+    out.extend_from_slice(&(ls.0.len() as i32).to_le_bytes());
+    for c in ls.coords() {
+        coord(out, c);
+    }
+}
+
+fn polygon(out: &mut Vec<u8>, p: &Polygon) {
+    out.extend_from_slice(&(1 + p.interiors().len() as i32).to_le_bytes());
+    line_string(out, p.exterior());
+    for ring in p.interiors() {
+        line_string(out, ring);
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(storage: Storage) -> &'static str {
+    match storage {
+        Storage::Text => "TEXT",
+        Storage::Int => "INTEGER",
+        Storage::Float => "REAL",
+        Storage::Bool => "BOOLEAN",
+    }
+}
+
+fn sql_value(value: Option<Value>) -> rusqlite::types::Value {
+    match value {
+        None => rusqlite::types::Value::Null,
+        Some(Value::Text(text)) => text.into(),
+        Some(Value::Int(int)) => int.into(),
+        Some(Value::Float(float)) => float.into(),
+        Some(Value::Bool(b)) => b.into(),
+    }
+}
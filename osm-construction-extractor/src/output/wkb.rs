@@ -0,0 +1,210 @@
+//! Minimal little-endian WKB encoder for `geo` geometries, and the decoder
+//! reading them back.
+
+use anyhow::{bail, Context, Result};
+use geo::{Coord, Geometry, GeometryCollection, LineString, MultiLineString, MultiPoint, MultiPolygon, Point, Polygon};
+
+const POINT: u32 = 1;
+const LINE_STRING: u32 = 2;
+const POLYGON: u32 = 3;
+const MULTI_POINT: u32 = 4;
+const MULTI_LINE_STRING: u32 = 5;
+const MULTI_POLYGON: u32 = 6;
+const GEOMETRY_COLLECTION: u32 = 7;
+
+/// Encodes a geometry as ISO WKB (2D, little-endian)
+pub fn to_wkb(geom: &Geometry) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_geometry(&mut out, geom);
+    out
+}
+
+/// Appends the WKB encoding of `geom` to `out`
+pub fn write_geometry(out: &mut Vec<u8>, geom: &Geometry) {
+    match geom {
+        Geometry::Point(p) => {
+            header(out, POINT);
+            coord(out, &p.0);
+        }
+        Geometry::Line(l) => {
+            header(out, LINE_STRING);
+            count(out, 2);
+            coord(out, &l.start);
+            coord(out, &l.end);
+        }
+        Geometry::LineString(ls) => {
+            header(out, LINE_STRING);
+            line_string(out, ls);
+        }
+        Geometry::Polygon(p) => {
+            header(out, POLYGON);
+            polygon(out, p);
+        }
+        Geometry::MultiPoint(mp) => {
+            header(out, MULTI_POINT);
+            count(out, mp.0.len());
+            for p in mp {
+                header(out, POINT);
+                coord(out, &p.0);
+            }
+        }
+        Geometry::MultiLineString(mls) => {
+            header(out, MULTI_LINE_STRING);
+            count(out, mls.0.len());
+            for ls in mls {
+                header(out, LINE_STRING);
+                line_string(out, ls);
+            }
+        }
+        Geometry::MultiPolygon(mp) => {
+            header(out, MULTI_POLYGON);
+            count(out, mp.0.len());
+            for p in mp {
+                header(out, POLYGON);
+                polygon(out, p);
+            }
+        }
+        Geometry::GeometryCollection(gc) => {
+            header(out, GEOMETRY_COLLECTION);
+            count(out, gc.0.len());
+            for g in gc {
+                write_geometry(out, g);
+            }
+        }
+        Geometry::Rect(r) => write_geometry(out, &Geometry::Polygon(r.to_polygon())),
+        Geometry::Triangle(t) => write_geometry(out, &Geometry::Polygon(t.to_polygon())),
+    }
+}
+
+/// Decodes a 2D WKB geometry, of either byte order, such as [`to_wkb`]
+/// writes
+pub fn from_wkb(bytes: &[u8]) -> Result<Geometry> {
+    let mut reader = Reader { bytes, little_endian: true };
+    let geom = reader.geometry()?;
+    if !reader.bytes.is_empty() {
+        bail!("{} bytes left after the WKB geometry", reader.bytes.len());
+    }
+    Ok(geom)
+}
+
+/// The WKB/GeoParquet type name of a geometry, e.g. `"MultiPolygon"`
+pub fn type_name(geom: &Geometry) -> &'static str {
+    match geom {
+        Geometry::Point(_) => "Point",
+        Geometry::Line(_) | Geometry::LineString(_) => "LineString",
+        Geometry::Polygon(_) | Geometry::Rect(_) | Geometry::Triangle(_) => "Polygon",
+        Geometry::MultiPoint(_) => "MultiPoint",
+        Geometry::MultiLineString(_) => "MultiLineString",
+        Geometry::MultiPolygon(_) => "MultiPolygon",
+        Geometry::GeometryCollection(_) => "GeometryCollection",
+    }
+}
+
+fn header(out: &mut Vec<u8>, geometry_type: u32) {
+    out.push(1);
+    out.extend_from_slice(&geometry_type.to_le_bytes());
+}
+
+fn count(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn coord(out: &mut Vec<u8>, c: &Coord) {
+    out.extend_from_slice(&c.x.to_le_bytes());
+    out.extend_from_slice(&c.y.to_le_bytes());
+}
+
+fn line_string(out: &mut Vec<u8>, ls: &LineString) {
+    count(out, ls.0.len());
+    for c in ls.coords() {
+        coord(out, c);
+    }
+}
+
+fn polygon(out: &mut Vec<u8>, p: &Polygon) {
+    count(out, 1 + p.interiors().len());
+    line_string(out, p.exterior());
+    for ring in p.interiors() {
+        line_string(out, ring);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let (head, rest) = self.bytes.split_first_chunk::<N>().context("truncated WKB geometry")?;
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take::<4>()?;
+        Ok(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.take::<8>()?;
+        Ok(if self.little_endian { f64::from_le_bytes(bytes) } else { f64::from_be_bytes(bytes) })
+    }
+
+    // Reads the byte order and type of a geometry, switching to its order
+    fn header(&mut self) -> Result<u32> {
+        self.little_endian = match self.take::<1>()? {
+            [0] => false,
+            [1] => true,
+            [order] => bail!("invalid WKB byte order {}", order),
+        };
+        self.u32()
+    }
+
+    fn geometry(&mut self) -> Result<Geometry> {
+        Ok(match self.header()? {
+            POINT => Geometry::Point(Point(self.coord()?)),
+            LINE_STRING => Geometry::LineString(self.line_string()?),
+            POLYGON => Geometry::Polygon(self.polygon()?),
+            MULTI_POINT => Geometry::MultiPoint(MultiPoint(self.parts(POINT, |r| Ok(Point(r.coord()?)))?)),
+            MULTI_LINE_STRING => Geometry::MultiLineString(MultiLineString(self.parts(LINE_STRING, Reader::line_string)?)),
+            MULTI_POLYGON => Geometry::MultiPolygon(MultiPolygon(self.parts(POLYGON, Reader::polygon)?)),
+            GEOMETRY_COLLECTION => {
+                let n = self.u32()?;
+                let parts = (0..n).map(|_| self.geometry()).collect::<Result<_>>()?;
+                Geometry::GeometryCollection(GeometryCollection(parts))
+            }
+            other => bail!("unsupported WKB geometry type {}", other),
+        })
+    }
+
+    // The parts of a multi geometry, each with its own header
+    fn parts<T>(&mut self, part_type: u32, read: impl Fn(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let n = self.u32()?;
+        (0..n)
+            .map(|_| match self.header()? {
+                t if t == part_type => read(self),
+                other => bail!("WKB geometry type {} where {} was expected", other, part_type),
+            })
+            .collect()
+    }
+
+    fn coord(&mut self) -> Result<Coord> {
+        Ok(Coord { x: self.f64()?, y: self.f64()? })
+    }
+
+    fn line_string(&mut self) -> Result<LineString> {
+        let n = self.u32()?;
+        (0..n).map(|_| self.coord()).collect::<Result<Vec<_>>>().map(LineString)
+    }
+
+    fn polygon(&mut self) -> Result<Polygon> {
+        let n = self.u32()?;
+        let mut rings = (0..n).map(|_| self.line_string()).collect::<Result<Vec<_>>>()?;
+        if rings.is_empty() {
+            return Ok(Polygon::new(LineString(Vec::new()), Vec::new()));
+        }
+        let exterior = rings.remove(0);
+        Ok(Polygon::new(exterior, rings))
+    }
+}
@@ -0,0 +1,88 @@
+//! Minimal WKT encoder for `geo` geometries.
+
+use geo::{Coord, Geometry, LineString, Polygon};
+use std::fmt::Write;
+
+/// Encodes a geometry as 2D WKT, e.g. `LINESTRING (7.4 43.7, 7.5 43.7)`
+pub fn to_wkt(geom: &Geometry) -> String {
+    let mut out = String::new();
+    write_geometry(&mut out, geom);
+    out
+}
+
+/// Appends the WKT encoding of `geom` to `out`
+pub fn write_geometry(out: &mut String, geom: &Geometry) {
+    match geom {
+        Geometry::Point(p) => {
+            out.push_str("POINT (");
+            coord(out, &p.0);
+            out.push(')');
+        }
+        Geometry::Line(l) => {
+            out.push_str("LINESTRING (");
+            coord(out, &l.start);
+            out.push_str(", ");
+            coord(out, &l.end);
+            out.push(')');
+        }
+        Geometry::LineString(ls) => {
+            out.push_str("LINESTRING ");
+            line_string(out, ls);
+        }
+        Geometry::Polygon(p) => {
+            out.push_str("POLYGON ");
+            polygon(out, p);
+        }
+        Geometry::MultiPoint(mp) => {
+            out.push_str("MULTIPOINT ");
+            list(out, &mp.0, |out, p| {
+                out.push('(');
+                coord(out, &p.0);
+                out.push(')');
+            });
+        }
+        Geometry::MultiLineString(mls) => {
+            out.push_str("MULTILINESTRING ");
+            list(out, &mls.0, line_string);
+        }
+        Geometry::MultiPolygon(mp) => {
+            out.push_str("MULTIPOLYGON ");
+            list(out, &mp.0, polygon);
+        }
+        Geometry::GeometryCollection(gc) => {
+            out.push_str("GEOMETRYCOLLECTION ");
+            list(out, &gc.0, write_geometry);
+        }
+        Geometry::Rect(r) => write_geometry(out, &Geometry::Polygon(r.to_polygon())),
+        Geometry::Triangle(t) => write_geometry(out, &Geometry::Polygon(t.to_polygon())),
+    }
+}
+
+// A parenthesized, comma-separated list; empty lists are written as `EMPTY`
+fn list<T>(out: &mut String, items: impl IntoIterator<Item = T>, mut item: impl FnMut(&mut String, T)) {
+    let mut items = items.into_iter().peekable();
+    if items.peek().is_none() {
+        out.push_str("EMPTY");
+        return;
+    }
+    out.push('(');
+    for (i, value) in items.enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        item(out, value);
+    }
+    out.push(')');
+}
+
+fn coord(out: &mut String, c: &Coord) {
+    let _ = write!(out, "{} {}", c.x, c.y);
+}
+
+fn line_string(out: &mut String, ls: &LineString) {
+    list(out, &ls.0, coord);
+}
+
+fn polygon(out: &mut String, p: &Polygon) {
+    list(out, std::iter::once(p.exterior()).chain(p.interiors()), line_string);
+}
@@ -0,0 +1,449 @@
+//! A subset of Overpass QL, answered from a [`FeatureIndex`], so that tools
+//! written against the Overpass API can query a local extract.
+//!
+//! Supported are the settings `[out:json]`, `[timeout:…]`, `[maxsize:…]`
+//! and `[bbox:south,west,north,east]`; queries of `node`, `way`, `rel`
+//! (`relation`) and `nwr` with tag filters such as `[highway][!access]` or
+//! `["name"~"park",i]` (see [`Filter`]), boxes `(south,west,north,east)`,
+//! `(around:meters,lat,lon)` and ids `(123)` or `(id:1,2,3)`; unions
+//! `( … ; … ; );`; and `out` with `ids`, `skel`, `body`, `tags`, `meta`,
+//! `geom`, `center` or `count`, optionally with a limit, e.g. `out geom 10;`.
+//!
+//! The index holds assembled features rather than OSM objects, so ways
+//! always come with their geometry, as with `out geom`, but without the ids
+//! of their nodes, and relations with their members only if they were
+//! extracted with them. Named sets and recursion such as `>;` are not
+//! supported.
+
+use crate::feature::{ExtractedFeature, OsmType, MEMBERS_TAG};
+use crate::filter::Filter;
+use crate::index::FeatureIndex;
+use anyhow::{bail, Context, Result};
+use geo::{BoundingRect, Coord, Geometry, Intersects, Point, Rect};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A parsed Overpass QL script
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    bbox: Option<Rect>,
+    statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Query(Query),
+    Union(Vec<Statement>),
+    Out(Out),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    types: Vec<OsmType>,
+    tags: Vec<Filter>,
+    bbox: Option<Rect>,
+    around: Option<(f64, Point)>,
+    ids: Option<Vec<i64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Out {
+    mode: OutMode,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutMode {
+    Ids,
+    Skel,
+    Body,
+    Tags,
+    Center,
+    Count,
+}
+
+impl Script {
+    /// Runs the script, returning the elements it outputs as an Overpass
+    /// JSON document
+    pub fn run(&self, index: &FeatureIndex) -> Result<Value> {
+        let mut set: Vec<ExtractedFeature> = Vec::new();
+        let mut elements = Vec::new();
+        for statement in &self.statements {
+            self.execute(statement, index, &mut set, &mut elements)?;
+        }
+        Ok(json!({
+            "version": 0.6,
+            "generator": concat!("osm-construction-extractor ", env!("CARGO_PKG_VERSION")),
+            "osm3s": {
+                "copyright": "The data included in this document is from www.openstreetmap.org. \
+                              The data is made available under ODbL."
+            },
+            "elements": elements,
+        }))
+    }
+
+    fn execute(
+        &self,
+        statement: &Statement,
+        index: &FeatureIndex,
+        set: &mut Vec<ExtractedFeature>,
+        elements: &mut Vec<Value>,
+    ) -> Result<()> {
+        match statement {
+            Statement::Query(query) => *set = query.run(index, self.bbox)?,
+            Statement::Union(statements) => {
+                let mut union = Vec::new();
+                let mut seen = HashSet::new();
+                for statement in statements {
+                    self.execute(statement, index, set, elements)?;
+                    union.extend(set.drain(..).filter(|f| seen.insert((f.osm_type(), f.id()))));
+                }
+                *set = union;
+            }
+            Statement::Out(out) => out.write(set, elements),
+        }
+        Ok(())
+    }
+}
+
+impl Query {
+    fn run(&self, index: &FeatureIndex, global_bbox: Option<Rect>) -> Result<Vec<ExtractedFeature>> {
+        let bbox = self.bbox.or(global_bbox);
+        let candidates = match (self.around, bbox) {
+            (Some((meters, point)), _) => index.within(point, meters)?.into_iter().map(|(_, f)| f).collect(),
+            (None, Some(bbox)) => index.bbox(&bbox)?,
+            (None, None) => index.all()?,
+        };
+        let found = candidates
+            .into_iter()
+            .filter(|f| self.types.contains(&f.osm_type()))
+            .filter(|f| self.ids.as_ref().is_none_or(|ids| ids.contains(&f.id())))
+            .filter(|f| self.tags.iter().all(|filter| filter.matches(f.tags())))
+            .filter(|f| self.around.is_none() || bbox.is_none_or(|bbox| f.geometry().intersects(&bbox)))
+            .collect();
+        Ok(found)
+    }
+}
+
+impl Out {
+    fn write(&self, set: &[ExtractedFeature], elements: &mut Vec<Value>) {
+        if self.mode == OutMode::Count {
+            let count = |osm_type| set.iter().filter(|f| f.osm_type() == osm_type).count().to_string();
+            elements.push(json!({
+                "type": "count",
+                "id": 0,
+                "tags": {
+                    "nodes": count(OsmType::Node),
+                    "ways": count(OsmType::Way),
+                    "relations": count(OsmType::Relation),
+                    "total": set.len().to_string(),
+                },
+            }));
+            return;
+        }
+        // Like Overpass: nodes, then ways, then relations, each by id
+        let mut sorted: Vec<&ExtractedFeature> = set.iter().collect();
+        sorted.sort_by_key(|f| (type_order(f.osm_type()), f.id()));
+        let limit = self.limit.unwrap_or(usize::MAX);
+        elements.extend(sorted.into_iter().take(limit).map(|f| element(f, self.mode)));
+    }
+}
+
+fn type_order(osm_type: OsmType) -> u8 {
+    match osm_type {
+        OsmType::Node => 0,
+        OsmType::Way => 1,
+        OsmType::Relation => 2,
+    }
+}
+
+// An element of the Overpass JSON format
+fn element(feature: &ExtractedFeature, mode: OutMode) -> Value {
+    let mut element = Map::new();
+    element.insert("type".into(), feature.osm_type().to_string().into());
+    element.insert("id".into(), feature.id().into());
+    if mode == OutMode::Ids {
+        return element.into();
+    }
+    let geometry = feature.geometry();
+    let bounds = geometry.bounding_rect();
+    match (feature.osm_type(), geometry) {
+        (OsmType::Node, Geometry::Point(point)) if mode != OutMode::Tags => {
+            element.insert("lat".into(), point.y().into());
+            element.insert("lon".into(), point.x().into());
+        }
+        (OsmType::Node, _) => {}
+        (_, _) if mode == OutMode::Center => {
+            if let Some(bounds) = bounds {
+                element.insert("center".into(), lat_lon(bounds.center()));
+            }
+        }
+        (_, _) if mode == OutMode::Tags => {}
+        (osm_type, geometry) => {
+            if let Some(bounds) = bounds {
+                element.insert(
+                    "bounds".into(),
+                    json!({
+                        "minlat": bounds.min().y,
+                        "minlon": bounds.min().x,
+                        "maxlat": bounds.max().y,
+                        "maxlon": bounds.max().x,
+                    }),
+                );
+            }
+            let line = match geometry {
+                Geometry::LineString(line) => Some(line),
+                Geometry::Polygon(polygon) => Some(polygon.exterior()),
+                _ => None,
+            };
+            if let (OsmType::Way, Some(line)) = (osm_type, line) {
+                element.insert("geometry".into(), line.0.iter().map(|&c| lat_lon(c)).collect());
+            }
+            let members = feature.tags().get(MEMBERS_TAG).and_then(|m| serde_json::from_str::<Value>(m).ok());
+            if let (OsmType::Relation, Some(members)) = (osm_type, members) {
+                element.insert("members".into(), members);
+            }
+        }
+    }
+    if mode != OutMode::Skel {
+        let tags: Map<String, Value> = feature
+            .tags()
+            .iter()
+            .filter(|(key, _)| !key.starts_with('@'))
+            .map(|(key, value)| (key.to_string(), value.as_str().into()))
+            .collect();
+        if !tags.is_empty() {
+            element.insert("tags".into(), tags.into());
+        }
+    }
+    element.into()
+}
+
+fn lat_lon(coord: Coord) -> Value {
+    json!({ "lat": coord.y, "lon": coord.x })
+}
+
+/// Parses an Overpass QL script
+pub fn parse(ql: &str) -> Result<Script> {
+    let mut parser = QlParser { chars: ql.chars().peekable() };
+    let mut script = Script { bbox: None, statements: Vec::new() };
+    parser.skip_blank();
+    if parser.chars.peek() == Some(&'[') {
+        while parser.eat('[') {
+            let setting = parser.until(']')?;
+            script.bbox = parse_setting(&setting)?.or(script.bbox);
+            parser.skip_blank();
+        }
+        parser.expect(';')?;
+    }
+    loop {
+        parser.skip_blank();
+        if parser.chars.peek().is_none() {
+            return Ok(script);
+        }
+        script.statements.push(parser.statement()?);
+    }
+}
+
+// Applies a setting, returning the bbox it sets
+fn parse_setting(setting: &str) -> Result<Option<Rect>> {
+    let (name, value) = setting.split_once(':').unwrap_or((setting, ""));
+    match (name.trim(), value.trim()) {
+        ("out", "json") => Ok(None),
+        ("out", format) => bail!("only [out:json] is supported, not {}", format),
+        ("timeout" | "maxsize", _) => Ok(None),
+        ("bbox", bbox) => parse_bbox(bbox).map(Some),
+        (name, _) => bail!("the setting [{}] is not supported", name),
+    }
+}
+
+// `south,west,north,east`, the order of Overpass
+fn parse_bbox(s: &str) -> Result<Rect> {
+    let numbers = parse_numbers(s)?;
+    let [south, west, north, east] = numbers[..] else {
+        bail!("expected south,west,north,east but found ({})", s);
+    };
+    Ok(Rect::new((west, south), (east, north)))
+}
+
+fn parse_numbers(s: &str) -> Result<Vec<f64>> {
+    s.split(',')
+        .map(|n| n.trim().parse::<f64>().with_context(|| format!("invalid number {:?}", n.trim())))
+        .collect()
+}
+
+// A box, circle or ids in parentheses after a query
+fn parse_spatial(query: &mut Query, spatial: &str) -> Result<()> {
+    if let Some(around) = spatial.strip_prefix("around:") {
+        let numbers = parse_numbers(around)?;
+        let [meters, lat, lon] = numbers[..] else {
+            bail!("expected (around:meters,lat,lon) but found ({})", spatial);
+        };
+        query.around = Some((meters, Point::new(lon, lat)));
+    } else if let Some(ids) = spatial.strip_prefix("id:") {
+        let ids: Result<Vec<i64>> =
+            ids.split(',').map(|id| id.trim().parse().with_context(|| format!("invalid id {:?}", id))).collect();
+        query.ids = Some(ids?);
+    } else if let Ok(id) = spatial.parse::<i64>() {
+        query.ids = Some(vec![id]);
+    } else {
+        query.bbox = Some(parse_bbox(spatial)?);
+    }
+    Ok(())
+}
+
+struct QlParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl QlParser<'_> {
+    // Skips whitespace and comments
+    fn skip_blank(&mut self) {
+        loop {
+            while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let mut ahead = self.chars.clone();
+            match (ahead.next(), ahead.next()) {
+                (Some('/'), Some('/')) => while self.chars.next_if(|&c| c != '\n').is_some() {},
+                (Some('/'), Some('*')) => {
+                    self.chars.nth(1);
+                    let mut last = ' ';
+                    for c in self.chars.by_ref() {
+                        if last == '*' && c == '/' {
+                            break;
+                        }
+                        last = c;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_blank();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.eat(c) {
+            match self.chars.peek() {
+                Some(found) => bail!("expected '{}' but found '{}'", c, found),
+                None => bail!("expected '{}' at the end of the query", c),
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> String {
+        self.skip_blank();
+        let mut word = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            word.push(c);
+        }
+        word
+    }
+
+    // The text up to the closing character, outside of quotes
+    fn until(&mut self, close: char) -> Result<String> {
+        let mut text = String::new();
+        let mut quote = None;
+        loop {
+            let c = self.chars.next().with_context(|| format!("missing '{}'", close))?;
+            match quote {
+                None if c == close => return Ok(text),
+                None if c == '"' || c == '\'' => quote = Some(c),
+                Some(q) if c == q => quote = None,
+                Some(_) if c == '\\' => {
+                    text.push(c);
+                    text.extend(self.chars.next());
+                    continue;
+                }
+                _ => {}
+            }
+            text.push(c);
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        if self.eat('(') {
+            let mut statements = Vec::new();
+            while !self.eat(')') {
+                if self.chars.peek().is_none() {
+                    bail!("missing ')' after a union");
+                }
+                statements.push(self.statement()?);
+            }
+            self.expect(';')?;
+            return Ok(Statement::Union(statements));
+        }
+        let word = self.word();
+        let types = match word.as_str() {
+            "out" => return self.out(),
+            "node" => vec![OsmType::Node],
+            "way" => vec![OsmType::Way],
+            "rel" | "relation" => vec![OsmType::Relation],
+            "nwr" => vec![OsmType::Node, OsmType::Way, OsmType::Relation],
+            "nw" => vec![OsmType::Node, OsmType::Way],
+            "wr" => vec![OsmType::Way, OsmType::Relation],
+            "nr" => vec![OsmType::Node, OsmType::Relation],
+            "area" => bail!("area queries are not supported"),
+            "" => match self.chars.peek() {
+                Some(c @ ('>' | '<' | '.')) => bail!("'{}' statements (recursion and named sets) are not supported", c),
+                Some(c) => bail!("unexpected '{}'", c),
+                None => bail!("unexpected end of the query"),
+            },
+            other => bail!("the statement '{}' is not supported", other),
+        };
+        let mut query = Query { types, tags: Vec::new(), bbox: None, around: None, ids: None };
+        loop {
+            if self.eat('[') {
+                let clause = self.until(']')?;
+                query.tags.push(format!("[{}]", clause).parse()?);
+            } else if self.eat('(') {
+                let spatial = self.until(')')?;
+                parse_spatial(&mut query, spatial.trim())?;
+            } else if self.eat(';') {
+                return Ok(Statement::Query(query));
+            } else {
+                match self.chars.peek() {
+                    Some('-') => bail!("named sets are not supported"),
+                    Some(c) => bail!("unexpected '{}' in a query", c),
+                    None => bail!("missing ';' after a query"),
+                }
+            }
+        }
+    }
+
+    fn out(&mut self) -> Result<Statement> {
+        let mut out = Out { mode: OutMode::Body, limit: None };
+        loop {
+            if self.eat(';') {
+                return Ok(Statement::Out(out));
+            }
+            let word = self.word();
+            out.mode = match word.as_str() {
+                "ids" => OutMode::Ids,
+                "skel" => OutMode::Skel,
+                "body" | "meta" | "geom" => OutMode::Body,
+                "tags" => OutMode::Tags,
+                "center" => OutMode::Center,
+                "count" => OutMode::Count,
+                // Sort orders: elements always come by id
+                "qt" | "asc" => out.mode,
+                "" => match self.chars.peek() {
+                    Some(c) => bail!("unexpected '{}' in an out statement", c),
+                    None => bail!("missing ';' after out"),
+                },
+                limit => match limit.parse() {
+                    Ok(limit) => {
+                        out.limit = Some(limit);
+                        out.mode
+                    }
+                    Err(_) => bail!("the out option '{}' is not supported", limit),
+                },
+            };
+        }
+    }
+}
@@ -0,0 +1,509 @@
+//! Parallel decoding of PBF primitive blocks.
+//!
+//! Blobs are read sequentially from the file, then decompressed and decoded
+//! in batches on a rayon thread pool. Objects are yielded in file order, so
+//! consumers relying on the type-then-id sort of a PBF keep working.
+//! A [`BlockIndex`] lets passes skip decoding the blocks they have no use
+//! for. [`writer`] goes the other way, encoding objects into a PBF.
+
+use crate::cancel::CancelToken;
+use crate::checkpoint::Checkpoint;
+use crate::metadata;
+use crate::spatial;
+use crate::store::{DependencyStore, NodeIndex, NodeIndexKind};
+use anyhow::Result;
+use geo::Rect;
+use osmpbfreader::fileformat::Blob;
+use osmpbfreader::{blocks, primitive_block_from_blob, NodeId, OsmId, OsmObj, OsmPbfReader, RelationId, WayId};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{Read, Seek};
+use std::sync::Arc;
+
+pub mod writer;
+
+/// Builds the thread pool used for block decoding; `0` uses all cores
+#[cfg(not(target_family = "wasm"))]
+pub fn thread_pool(threads: usize) -> Result<Arc<ThreadPool>> {
+    Ok(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(threads).build()?))
+}
+
+/// Returns the pool decoding blocks on the calling thread, as browsers
+/// cannot start threads. Such a pool is never freed, so there is only the
+/// one, whatever `threads` asks for.
+#[cfg(target_family = "wasm")]
+pub fn thread_pool(_threads: usize) -> Result<Arc<ThreadPool>> {
+    thread_local! {
+        static POOL: std::cell::OnceCell<Arc<ThreadPool>> = const { std::cell::OnceCell::new() };
+    }
+    POOL.with(|pool| match pool.get() {
+        Some(pool) => Ok(pool.clone()),
+        None => {
+            let built = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).use_current_thread().build()?);
+            Ok(pool.get_or_init(|| built).clone())
+        }
+    })
+}
+
+// Decodes a blob, adding the metadata of the objects as tags if asked to
+type Decoder = fn(osmpbfreader::Result<Blob>, bool) -> Result<Vec<OsmObj>>;
+
+fn decode_all(blob: osmpbfreader::Result<Blob>, with_metadata: bool) -> Result<Vec<OsmObj>> {
+    let block = primitive_block_from_blob(&blob?)?;
+    let mut objects: Vec<OsmObj> = blocks::iter(&block).collect();
+    if with_metadata {
+        metadata::attach(&block, &mut objects);
+    }
+    Ok(objects)
+}
+
+// Only used where metadata is not needed, as the metadata of a block is
+// listed for all of its objects together
+fn decode_nodes(blob: osmpbfreader::Result<Blob>, _with_metadata: bool) -> Result<Vec<OsmObj>> {
+    let block = primitive_block_from_blob(&blob?)?;
+    Ok(blocks::nodes(&block).map(OsmObj::Node).collect())
+}
+
+fn decode_ways(blob: osmpbfreader::Result<Blob>, _with_metadata: bool) -> Result<Vec<OsmObj>> {
+    let block = primitive_block_from_blob(&blob?)?;
+    Ok(blocks::ways(&block).map(OsmObj::Way).collect())
+}
+
+fn decode_ways_and_relations(blob: osmpbfreader::Result<Blob>, _with_metadata: bool) -> Result<Vec<OsmObj>> {
+    let block = primitive_block_from_blob(&blob?)?;
+    let ways = blocks::ways(&block).map(OsmObj::Way);
+    Ok(ways.chain(blocks::relations(&block).map(OsmObj::Relation)).collect())
+}
+
+/// Iterator over all objects of a PBF, decoded in parallel
+pub struct ParallelObjects<'a, R: Read> {
+    reader: &'a mut OsmPbfReader<R>,
+    pool: &'a ThreadPool,
+    decode: Decoder,
+    with_metadata: bool,
+    // Which blocks to decode, by their position in the file, how many of
+    // the first are skipped and the position of the next one
+    blocks: Option<Vec<bool>>,
+    skip: usize,
+    block: usize,
+    pending: VecDeque<OsmObj>,
+    finished: bool,
+    cancel: CancelToken,
+}
+
+impl<'a, R: Read> ParallelObjects<'a, R> {
+    /// Yields nodes, ways and relations
+    pub fn new(reader: &'a mut OsmPbfReader<R>, pool: &'a ThreadPool) -> Self {
+        Self::with_decoder(reader, pool, decode_all)
+    }
+
+    /// Yields only nodes, skipping the decoding of ways and relations
+    pub fn nodes(reader: &'a mut OsmPbfReader<R>, pool: &'a ThreadPool) -> Self {
+        Self::with_decoder(reader, pool, decode_nodes)
+    }
+
+    /// Yields only ways, skipping the decoding of nodes and relations
+    pub fn ways(reader: &'a mut OsmPbfReader<R>, pool: &'a ThreadPool) -> Self {
+        Self::with_decoder(reader, pool, decode_ways)
+    }
+
+    /// Yields ways and relations, skipping the decoding of nodes
+    pub fn without_nodes(reader: &'a mut OsmPbfReader<R>, pool: &'a ThreadPool) -> Self {
+        Self::with_decoder(reader, pool, decode_ways_and_relations)
+    }
+
+    /// Adds version, timestamp, changeset and author as `@` tags, see
+    /// [`metadata`]; only supported by [`ParallelObjects::new`]
+    pub fn with_metadata(mut self, with_metadata: bool) -> Self {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    /// Ends the input at the next batch of blocks once `cancel` is
+    /// cancelled
+    pub fn cancel_token(mut self, cancel: &CancelToken) -> Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    /// Only decodes the blocks marked in `blocks`, e.g. by a
+    /// [`BlockIndex`], by their position in the file; the others are read
+    /// but yield nothing
+    pub fn only_blocks(mut self, blocks: Vec<bool>) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// Reads the first `blocks` blocks without decoding them, to resume a
+    /// pass where it was left
+    pub fn skip_blocks(mut self, blocks: usize) -> Self {
+        self.skip = blocks;
+        self
+    }
+
+    /// The blocks read so far, once all of their objects were yielded and
+    /// none of a later block is pending
+    pub fn blocks_done(&self) -> Option<usize> {
+        self.pending.is_empty().then_some(self.block)
+    }
+
+    fn with_decoder(reader: &'a mut OsmPbfReader<R>, pool: &'a ThreadPool, decode: Decoder) -> Self {
+        ParallelObjects {
+            reader,
+            pool,
+            decode,
+            with_metadata: false,
+            blocks: None,
+            skip: 0,
+            block: 0,
+            pending: VecDeque::new(),
+            finished: false,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    // Decodes the next batch of blobs; a few blobs per thread keep every
+    // thread busy without holding much of the file in memory. A cancelled
+    // run ends the input here, see `cancel`
+    fn fill(&mut self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            self.finished = true;
+            return Ok(());
+        }
+        let batch_size = self.pool.current_num_threads() * 4;
+        let blobs: Vec<_> = self.reader.blobs().take(batch_size).collect();
+        if blobs.len() < batch_size {
+            self.finished = true;
+        }
+        let (decode, with_metadata) = (self.decode, self.with_metadata);
+        let (first, skip, keep) = (self.block, self.skip, self.blocks.as_deref());
+        self.block += blobs.len();
+        let decoded: Vec<Result<Vec<OsmObj>>> = self.pool.install(|| {
+            blobs
+                .into_par_iter()
+                .enumerate()
+                .map(|(i, blob)| {
+                    let skipped = first + i < skip || keep.is_some_and(|keep| !keep.get(first + i).copied().unwrap_or(true));
+                    match blob {
+                        Ok(_) if skipped => Ok(Vec::new()),
+                        blob => decode(blob, with_metadata),
+                    }
+                })
+                .collect()
+        });
+        for objects in decoded {
+            self.pending.extend(objects?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for ParallelObjects<'_, R> {
+    type Item = Result<OsmObj>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.finished {
+            if let Err(e) = self.fill() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// What each data block of a PBF holds, in file order: the range of ids
+/// of each type and whether any of its nodes lies inside a box. Built by
+/// a pass over the file before the others, it lets later passes skip
+/// decoding the blocks of nodes outside it, and dependency passes all
+/// blocks that cannot hold a missing object. Files sorted spatially, as
+/// extracts renumbered along a curve are, gain most, but as the ranges are
+/// exact any file can be indexed.
+#[derive(Debug, Clone, Default)]
+pub struct BlockIndex {
+    blocks: Vec<BlockSummary>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct BlockSummary {
+    // Smallest and largest id of each type the block holds
+    nodes: Option<(i64, i64)>,
+    ways: Option<(i64, i64)>,
+    relations: Option<(i64, i64)>,
+    nodes_inside: bool,
+}
+
+impl BlockIndex {
+    /// Indexes the blocks, telling those with nodes inside `bbox`, up to
+    /// the batch of blocks where `cancel` is cancelled
+    pub fn build<R: Read>(
+        reader: &mut OsmPbfReader<R>,
+        pool: &ThreadPool,
+        cancel: &CancelToken,
+        bbox: &Rect,
+    ) -> Result<BlockIndex> {
+        let mut index = BlockIndex::default();
+        let batch_size = pool.current_num_threads() * 4;
+        loop {
+            let blobs: Vec<_> = reader.blobs().take(batch_size).collect();
+            let finished = blobs.len() < batch_size || cancel.is_cancelled();
+            let summaries: Vec<Result<BlockSummary>> =
+                pool.install(|| blobs.into_par_iter().map(|blob| summarize(blob, bbox)).collect());
+            for summary in summaries {
+                index.blocks.push(summary?);
+            }
+            if finished {
+                return Ok(index);
+            }
+        }
+    }
+
+    /// Data blocks in the file
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Blocks holding nodes, and those of them with nodes inside the box
+    pub fn node_blocks(&self) -> (usize, usize) {
+        let with_nodes = self.blocks.iter().filter(|b| b.nodes.is_some());
+        (with_nodes.clone().count(), with_nodes.filter(|b| b.nodes_inside).count())
+    }
+
+    /// The blocks that can hold a candidate, where nodes outside the box
+    /// never are one: all but those holding only nodes, all outside
+    pub fn candidates(&self) -> Vec<bool> {
+        self.blocks
+            .iter()
+            .map(|b| b.nodes_inside || b.ways.is_some() || b.relations.is_some())
+            .collect()
+    }
+
+    /// The blocks whose ranges hold one of `ids`
+    pub fn holding(&self, ids: &BTreeSet<OsmId>) -> Vec<bool> {
+        let any = |range: Option<(i64, i64)>, id: fn(i64) -> OsmId| {
+            range.is_some_and(|(first, last)| ids.range(id(first)..=id(last)).next().is_some())
+        };
+        self.blocks
+            .iter()
+            .map(|b| {
+                any(b.nodes, |id| OsmId::Node(NodeId(id)))
+                    || any(b.ways, |id| OsmId::Way(WayId(id)))
+                    || any(b.relations, |id| OsmId::Relation(RelationId(id)))
+            })
+            .collect()
+    }
+}
+
+fn summarize(blob: osmpbfreader::Result<Blob>, bbox: &Rect) -> Result<BlockSummary> {
+    let block = primitive_block_from_blob(&blob?)?;
+    let extend = |range: &mut Option<(i64, i64)>, id: i64| {
+        *range = Some(range.map_or((id, id), |(first, last)| (first.min(id), last.max(id))));
+    };
+    let mut summary = BlockSummary::default();
+    for node in blocks::nodes(&block) {
+        extend(&mut summary.nodes, node.id.0);
+        summary.nodes_inside |= spatial::bbox_contains(bbox, node.lon(), node.lat());
+    }
+    for group in &block.primitivegroup {
+        for way in &group.ways {
+            extend(&mut summary.ways, way.id());
+        }
+        for relation in &group.relations {
+            extend(&mut summary.relations, relation.id());
+        }
+    }
+    Ok(summary)
+}
+
+/// Collects all objects matching `pred` plus everything they (recursively)
+/// reference, re-reading the file until no dependency is missing.
+///
+/// This mirrors `OsmPbfReader::get_objs_and_deps`, but decodes blocks on the
+/// given thread pool. With `with_metadata`, matching objects get their
+/// metadata as tags; dependencies only contribute geometry and never do.
+///
+/// Relations that are members of matched relations are only looked up
+/// `relation_depth` levels down, as deep as building the features resolves
+/// them (see [`crate::feature::BuildOptions::relation_depth`]).
+///
+/// With a `checkpoint`, what was collected is saved after every pass and
+/// every [`Checkpoint::interval`] blocks within one, and what it already
+/// covers is skipped. With an `index`, the first
+/// pass only decodes the [`BlockIndex::candidates`], for a `pred` only
+/// matching nodes inside its box, and the later ones the blocks holding a
+/// missing dependency. Once `cancel` is cancelled, the pass under way ends
+/// at its next batch of blocks and no other follows.
+#[allow(clippy::too_many_arguments)]
+pub fn get_objs_and_deps<R: Read + Seek>(
+    reader: &mut OsmPbfReader<R>,
+    pool: &ThreadPool,
+    cancel: &CancelToken,
+    with_metadata: bool,
+    relation_depth: usize,
+    checkpoint: Option<&mut Checkpoint>,
+    index: Option<&BlockIndex>,
+    pred: impl FnMut(&OsmObj) -> Result<bool>,
+) -> Result<BTreeMap<OsmId, OsmObj>> {
+    collect_deps(reader, pool, cancel, with_metadata, relation_depth, None, checkpoint, index, pred)
+}
+
+/// Like [`get_objs_and_deps`], but keeps only the locations of the nodes
+/// that are dependencies, in a [`NodeIndex`], for assembling geometries
+/// with about half the memory; matched nodes are kept in full.
+#[allow(clippy::too_many_arguments)]
+pub fn get_objs_and_slim_deps<R: Read + Seek>(
+    reader: &mut OsmPbfReader<R>,
+    pool: &ThreadPool,
+    cancel: &CancelToken,
+    with_metadata: bool,
+    relation_depth: usize,
+    checkpoint: Option<&mut Checkpoint>,
+    index: Option<&BlockIndex>,
+    pred: impl FnMut(&OsmObj) -> Result<bool>,
+) -> Result<DependencyStore> {
+    let mut nodes = NodeIndex::new(NodeIndexKind::Sorted);
+    let slim = Some(&mut nodes);
+    let objects = collect_deps(reader, pool, cancel, with_metadata, relation_depth, slim, checkpoint, index, pred)?;
+    nodes.prepare();
+    Ok(DependencyStore { objects, nodes })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect_deps<R: Read + Seek>(
+    reader: &mut OsmPbfReader<R>,
+    pool: &ThreadPool,
+    cancel: &CancelToken,
+    with_metadata: bool,
+    relation_depth: usize,
+    mut slim_nodes: Option<&mut NodeIndex>,
+    mut checkpoint: Option<&mut Checkpoint>,
+    index: Option<&BlockIndex>,
+    mut pred: impl FnMut(&OsmObj) -> Result<bool>,
+) -> Result<BTreeMap<OsmId, OsmObj>> {
+    let mut objects = BTreeMap::new();
+    let mut deps = BTreeSet::new();
+    // How many levels below a matched relation each relation collected or
+    // missing was found
+    let mut depths: BTreeMap<RelationId, usize> = BTreeMap::new();
+    let mut first_pass = true;
+    let mut finished = false;
+    // Blocks of the pass under way the loaded progress already covers
+    let mut resume_at = 0;
+    if let Some(progress) = checkpoint.as_deref().map(|checkpoint| checkpoint.load(pool)).transpose()?.flatten() {
+        (objects, deps, depths, finished) = (progress.objects, progress.missing, progress.depths, progress.finished);
+        if let Some(slim) = slim_nodes.as_deref_mut() {
+            *slim = progress.nodes;
+        }
+        first_pass = progress.passes == 0;
+        resume_at = progress.block;
+    }
+
+    loop {
+        // A pass resumed within keeps what it found before
+        let start = std::mem::take(&mut resume_at);
+        if start == 0 {
+            if finished {
+                break;
+            }
+            finished = true;
+        }
+        reader.rewind()?;
+        // Later passes only look for dependencies
+        let mut objects_read = ParallelObjects::new(reader, pool)
+            .with_metadata(with_metadata && first_pass)
+            .cancel_token(cancel)
+            .skip_blocks(start);
+        match index {
+            Some(index) if first_pass => objects_read = objects_read.only_blocks(index.candidates()),
+            Some(index) => objects_read = objects_read.only_blocks(index.holding(&deps)),
+            None => {}
+        }
+        let mut saved_at = start;
+        loop {
+            // Between two blocks, everything before them was collected
+            if let (Some(checkpoint), Some(block)) = (checkpoint.as_deref_mut(), objects_read.blocks_done()) {
+                if block >= saved_at + checkpoint.interval() {
+                    if let Some(slim) = slim_nodes.as_deref_mut() {
+                        slim.prepare();
+                    }
+                    checkpoint.save_within(&objects, &deps, &depths, slim_nodes.as_deref(), block, finished)?;
+                    saved_at = block;
+                }
+            }
+            let Some(obj) = objects_read.next() else {
+                break;
+            };
+            let mut obj = obj?;
+            let matched = first_pass && pred(&obj)?;
+            if !matched && !deps.contains(&obj.id()) {
+                continue;
+            }
+            if let (Some(slim), OsmObj::Node(node), false) = (slim_nodes.as_deref_mut(), &obj, matched) {
+                slim.insert(node)?;
+                deps.remove(&obj.id());
+                continue;
+            }
+            if with_metadata && !matched {
+                strip_metadata(&mut obj);
+            }
+            let refs: Vec<OsmId> = match &obj {
+                OsmObj::Node(_) => Vec::new(),
+                OsmObj::Way(way) => way.nodes.iter().map(|&n| n.into()).collect(),
+                OsmObj::Relation(relation) => {
+                    let depth = if matched { 0 } else { depths.get(&relation.id).copied().unwrap_or(0) };
+                    depths.insert(relation.id, depth);
+                    let members = relation.refs.iter().map(|r| r.member);
+                    let members = members.filter(|member| depth < relation_depth || !matches!(member, OsmId::Relation(_)));
+                    for member in members.clone().filter_map(|member| member.relation()) {
+                        let found = depths.entry(member).or_insert(depth + 1);
+                        *found = (*found).min(depth + 1);
+                    }
+                    members.collect()
+                }
+            };
+            // Nodes come first in a file, so this only sorts the ones added
+            // in this pass, once
+            if let (Some(slim), false) = (slim_nodes.as_deref_mut(), refs.is_empty()) {
+                slim.prepare();
+            }
+            for id in refs {
+                let slim = match (id, slim_nodes.as_deref()) {
+                    (OsmId::Node(node), Some(slim)) => slim.get(node).is_some(),
+                    _ => false,
+                };
+                if !slim && !objects.contains_key(&id) && deps.insert(id) {
+                    finished = false;
+                }
+            }
+            deps.remove(&obj.id());
+            objects.insert(obj.id(), obj);
+        }
+        first_pass = false;
+        // What a cancelled pass found since its last save is kept, but not
+        // saved
+        if cancel.is_cancelled() {
+            break;
+        }
+        if let Some(checkpoint) = checkpoint.as_deref_mut() {
+            if let Some(slim) = slim_nodes.as_deref_mut() {
+                slim.prepare();
+            }
+            checkpoint.save(&objects, &deps, &depths, slim_nodes.as_deref(), finished)?;
+        }
+    }
+
+    Ok(objects)
+}
+
+fn strip_metadata(obj: &mut OsmObj) {
+    match obj {
+        OsmObj::Node(node) => metadata::strip(&mut node.tags),
+        OsmObj::Way(way) => metadata::strip(&mut way.tags),
+        OsmObj::Relation(relation) => metadata::strip(&mut relation.tags),
+    }
+}
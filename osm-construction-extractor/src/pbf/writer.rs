@@ -0,0 +1,258 @@
+//! Encoding of OSM objects into a PBF file.
+//!
+//! Blocks hold up to [`BLOCK_SIZE`] objects of a single type, with nodes
+//! written as dense nodes and every block zlib-compressed, much like osmium
+//! writes them. Object metadata is written when it is known.
+
+use anyhow::{bail, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use osmpbfreader::fileformat::{Blob, BlobHeader};
+use osmpbfreader::osmformat::{
+    self, relation::MemberType, DenseInfo, DenseNodes, HeaderBBox, HeaderBlock, Info, PrimitiveBlock, PrimitiveGroup,
+};
+use osmpbfreader::{OsmId, OsmObj, Tags};
+use crate::info::Header;
+use crate::metadata::Metadata;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Objects per primitive block
+pub const BLOCK_SIZE: usize = 8000;
+
+/// Writes objects into a PBF, which must be given in the type-then-id
+/// order every PBF is expected to have: all nodes, then all ways, then all
+/// relations, each sorted by id.
+pub struct Writer<W: Write> {
+    out: W,
+    block: Vec<(OsmObj, Metadata)>,
+    last: Option<OsmId>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts the file by writing its header block
+    pub fn new(out: W) -> Result<Writer<W>> {
+        let writing_program = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string();
+        Writer::with_header(out, &Header { writing_program: Some(writing_program), ..Header::default() })
+    }
+
+    /// Starts the file with a header block giving the extent, writing
+    /// program, source and replication state of `header`, e.g. those of a
+    /// file converted from another format; the features required and
+    /// offered are those of what is written
+    pub fn with_header(mut out: W, header: &Header) -> Result<Writer<W>> {
+        let mut block = HeaderBlock::new();
+        block.required_features = vec!["OsmSchema-V0.6".to_string(), "DenseNodes".to_string()];
+        block.optional_features = vec!["Sort.Type_then_ID".to_string()];
+        if let Some(bbox) = &header.bbox {
+            // In nanodegrees
+            let nano = |degrees: f64| (degrees * 1e9).round() as i64;
+            let (min, max) = (bbox.min(), bbox.max());
+            let mut header_bbox = HeaderBBox::new();
+            header_bbox.set_left(nano(min.x));
+            header_bbox.set_bottom(nano(min.y));
+            header_bbox.set_right(nano(max.x));
+            header_bbox.set_top(nano(max.y));
+            block.bbox = Some(header_bbox).into();
+        }
+        block.writingprogram = header.writing_program.clone();
+        block.source = header.source.clone();
+        block.osmosis_replication_timestamp = header.replication_timestamp;
+        block.osmosis_replication_sequence_number = header.replication_sequence;
+        block.osmosis_replication_base_url = header.replication_url.clone();
+        write_blob(&mut out, "OSMHeader", &block.write_to_bytes()?)?;
+        Ok(Writer {
+            out,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last: None,
+        })
+    }
+
+    pub fn write(&mut self, obj: OsmObj) -> Result<()> {
+        self.write_with_metadata(obj, Metadata::default())
+    }
+
+    pub fn write_with_metadata(&mut self, obj: OsmObj, metadata: Metadata) -> Result<()> {
+        let id = obj.id();
+        if let Some(last) = self.last {
+            if id <= last {
+                bail!("objects must be written sorted by type and id, but {:?} follows {:?}", id, last);
+            }
+            if self.block.len() == BLOCK_SIZE || std::mem::discriminant(&id) != std::mem::discriminant(&last) {
+                self.flush_block()?;
+            }
+        }
+        self.last = Some(id);
+        self.block.push((obj, metadata));
+        Ok(())
+    }
+
+    /// Writes the last block, returning the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.flush_block()?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let mut strings = StringTable::default();
+        let mut group = PrimitiveGroup::new();
+        let mut dense = DenseNodes::new();
+        let (mut id, mut lat, mut lon) = (0, 0, 0);
+        // Dense nodes carry metadata for all nodes of a block or for none
+        let mut dense_info = DenseInfo::new();
+        let with_dense_info = self.block.iter().any(|(obj, metadata)| obj.is_node() && !metadata.is_empty());
+        let mut last_info = (0, 0, 0, 0);
+        for (obj, metadata) in self.block.drain(..) {
+            match obj {
+                OsmObj::Node(node) => {
+                    dense.id.push(node.id.0 - id);
+                    dense.lat.push(node.decimicro_lat as i64 - lat);
+                    dense.lon.push(node.decimicro_lon as i64 - lon);
+                    (id, lat, lon) = (node.id.0, node.decimicro_lat as i64, node.decimicro_lon as i64);
+                    for (k, v) in node.tags.iter() {
+                        dense.keys_vals.push(strings.index(k) as i32);
+                        dense.keys_vals.push(strings.index(v) as i32);
+                    }
+                    dense.keys_vals.push(0);
+                    if with_dense_info {
+                        let info = (
+                            metadata.timestamp.unwrap_or(0),
+                            metadata.changeset.unwrap_or(0),
+                            metadata.uid.unwrap_or(0),
+                            strings.user(&metadata) as i32,
+                        );
+                        dense_info.version.push(metadata.version.unwrap_or(0));
+                        dense_info.timestamp.push(info.0 - last_info.0);
+                        dense_info.changeset.push(info.1 - last_info.1);
+                        dense_info.uid.push(info.2 - last_info.2);
+                        dense_info.user_sid.push(info.3 - last_info.3);
+                        last_info = info;
+                    }
+                }
+                OsmObj::Way(way) => {
+                    let mut pbf_way = osmformat::Way::new();
+                    pbf_way.id = Some(way.id.0);
+                    pbf_way.info = strings.info(&metadata).into();
+                    (pbf_way.keys, pbf_way.vals) = strings.tags(&way.tags);
+                    let mut prev = 0;
+                    for node in &way.nodes {
+                        pbf_way.refs.push(node.0 - prev);
+                        prev = node.0;
+                    }
+                    group.ways.push(pbf_way);
+                }
+                OsmObj::Relation(relation) => {
+                    let mut pbf_relation = osmformat::Relation::new();
+                    pbf_relation.id = Some(relation.id.0);
+                    pbf_relation.info = strings.info(&metadata).into();
+                    (pbf_relation.keys, pbf_relation.vals) = strings.tags(&relation.tags);
+                    let mut prev = 0;
+                    for member in &relation.refs {
+                        let (member_type, member_id) = match member.member {
+                            OsmId::Node(id) => (MemberType::NODE, id.0),
+                            OsmId::Way(id) => (MemberType::WAY, id.0),
+                            OsmId::Relation(id) => (MemberType::RELATION, id.0),
+                        };
+                        pbf_relation.roles_sid.push(strings.index(&member.role) as i32);
+                        pbf_relation.memids.push(member_id - prev);
+                        pbf_relation.types.push(member_type.into());
+                        prev = member_id;
+                    }
+                    group.relations.push(pbf_relation);
+                }
+            }
+        }
+        if !dense.id.is_empty() {
+            if with_dense_info {
+                dense.denseinfo = Some(dense_info).into();
+            }
+            group.dense = Some(dense).into();
+        }
+
+        let mut block = PrimitiveBlock::new();
+        block.stringtable = Some(strings.into_table()).into();
+        block.primitivegroup.push(group);
+        write_blob(&mut self.out, "OSMData", &block.write_to_bytes()?)
+    }
+}
+
+// The strings of a block, each stored once; index 0 is reserved for the
+// empty string that terminates the tags of a dense node
+struct StringTable {
+    strings: Vec<Vec<u8>>,
+    indices: HashMap<String, u32>,
+}
+
+impl Default for StringTable {
+    fn default() -> StringTable {
+        StringTable {
+            strings: vec![Vec::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, s: &str) -> u32 {
+        if let Some(&index) = self.indices.get(s) {
+            return index;
+        }
+        let index = self.strings.len() as u32;
+        self.strings.push(s.as_bytes().to_vec());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+
+    fn tags(&mut self, tags: &Tags) -> (Vec<u32>, Vec<u32>) {
+        tags.iter().map(|(k, v)| (self.index(k), self.index(v))).unzip()
+    }
+
+    // The user name as a string index, 0 if it is unknown
+    fn user(&mut self, metadata: &Metadata) -> u32 {
+        metadata.user.as_deref().map_or(0, |user| self.index(user))
+    }
+
+    fn info(&mut self, metadata: &Metadata) -> Option<Info> {
+        if metadata.is_empty() {
+            return None;
+        }
+        let mut info = Info::new();
+        info.version = metadata.version;
+        info.timestamp = metadata.timestamp;
+        info.changeset = metadata.changeset;
+        info.uid = metadata.uid;
+        info.user_sid = metadata.user.is_some().then(|| self.user(metadata));
+        Some(info)
+    }
+
+    fn into_table(self) -> osmformat::StringTable {
+        let mut table = osmformat::StringTable::new();
+        table.s = self.strings;
+        table
+    }
+}
+
+// Writes the data as a zlib-compressed blob, preceded by its header
+fn write_blob<W: Write>(out: &mut W, kind: &str, data: &[u8]) -> Result<()> {
+    let mut blob = Blob::new();
+    blob.set_raw_size(data.len() as i32);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    blob.set_zlib_data(encoder.finish()?);
+    let blob = blob.write_to_bytes()?;
+
+    let mut header = BlobHeader::new();
+    header.set_type(kind.to_string());
+    header.set_datasize(blob.len() as i32);
+    let header = header.write_to_bytes()?;
+
+    out.write_all(&(header.len() as u32).to_be_bytes())?;
+    out.write_all(&header)?;
+    out.write_all(&blob)?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Built-in extraction themes: a curated filter plus the post-processing
+//! that makes the features ready for a particular use.
+
+use crate::building;
+use crate::centroid::{self, OutputGeometry};
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::filter::Filter;
+use crate::profile::GeometryKind;
+use clap::ValueEnum;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// The road network for routing and cartography: the classified roads,
+    /// their link roads, residential and service streets, and tracks
+    Roads,
+    /// Building footprints, including multipolygons, with `@height`,
+    /// `@min_height` and `@levels` for 3D rendering
+    Buildings,
+    /// Rivers, streams, canals, drains and ditches, as lines
+    Waterways,
+    /// Points of interest: amenities, shops, tourism, leisure, craft,
+    /// offices, healthcare and historic sites, with buildings and areas
+    /// reduced to a point on their surface
+    Pois,
+    /// Land use areas such as residential, farmland or forest
+    Landuse,
+    /// Power transmission and distribution lines, overhead or as cables
+    PowerLines,
+    /// Tracks of mainline, light and narrow gauge rail, subways, trams,
+    /// monorails and funiculars in use
+    Railways,
+    /// Construction sites of roads, railways, buildings and land use, with
+    /// their dates parsed and an `@status` of planned, active or overdue
+    Construction,
+}
+
+impl Preset {
+    /// The filter selecting the objects of the theme
+    pub fn filter(&self) -> Filter {
+        let expr = match self {
+            Preset::Roads => {
+                "highway=motorway,motorway_link,trunk,trunk_link,primary,primary_link,secondary,secondary_link,\
+                 tertiary,tertiary_link,unclassified,residential,living_street,service,road,track,busway"
+            }
+            Preset::Buildings => "building=* and not building=no",
+            Preset::Waterways => "waterway=river,stream,canal,drain,ditch",
+            Preset::Pois => {
+                "(amenity=* or shop=* or tourism=* or leisure=* or craft=* or office=* or healthcare=* or \
+                 historic=*) and not amenity=parking_space,bench,waste_basket"
+            }
+            Preset::Landuse => "landuse=*",
+            Preset::PowerLines => "power=line,minor_line,cable",
+            Preset::Railways => "railway=rail,light_rail,narrow_gauge,subway,tram,monorail,funicular",
+            Preset::Construction => {
+                "highway=construction or railway=construction or building=construction or \
+                 landuse=construction or construction=*"
+            }
+        };
+        expr.parse().expect("preset filters are valid")
+    }
+
+    pub fn types(&self) -> &'static [OsmType] {
+        match self {
+            Preset::Roads | Preset::Waterways | Preset::PowerLines | Preset::Railways => &[OsmType::Way],
+            Preset::Buildings | Preset::Landuse | Preset::Construction => &[OsmType::Way, OsmType::Relation],
+            Preset::Pois => &[OsmType::Node, OsmType::Way, OsmType::Relation],
+        }
+    }
+
+    /// The geometries kept, e.g. no unclosed or unassembled buildings, nor
+    /// roads mapped as areas
+    pub fn geometry(&self) -> Option<GeometryKind> {
+        match self {
+            Preset::Roads | Preset::Waterways | Preset::PowerLines | Preset::Railways => Some(GeometryKind::Line),
+            Preset::Buildings | Preset::Landuse => Some(GeometryKind::Polygon),
+            Preset::Pois | Preset::Construction => None,
+        }
+    }
+
+    /// Post-processes a matched feature; `false` if it is to be dropped
+    pub fn finish(&self, feature: &mut ExtractedFeature) -> bool {
+        if self.geometry().is_some_and(|kind| !kind.matches(feature.geometry())) {
+            return false;
+        }
+        match self {
+            Preset::Buildings => building::add_heights(feature),
+            Preset::Pois => {
+                if let Some(point) = centroid::reduce(feature.geometry(), OutputGeometry::PointOnSurface) {
+                    feature.set_geometry(point);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Preset::Roads => "roads",
+            Preset::Buildings => "buildings",
+            Preset::Waterways => "waterways",
+            Preset::Pois => "pois",
+            Preset::Landuse => "landuse",
+            Preset::PowerLines => "power-lines",
+            Preset::Railways => "railways",
+            Preset::Construction => "construction",
+        })
+    }
+}
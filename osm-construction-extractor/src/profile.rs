@@ -0,0 +1,180 @@
+//! Extraction profiles: several named layers, each with its own filter and
+//! output file, produced from a single pass over the input.
+//!
+//! A profile is a YAML or TOML file with a list of layers:
+//!
+//! ```yaml
+//! layers:
+//!   - name: roads
+//!     filter: highway=* and not highway=proposed
+//!     geometry: line
+//!     output: roads.fgb
+//!   - name: buildings
+//!     filter: building=*
+//!     types: [way, relation]
+//!     geometry: polygon
+//!     output: buildings.gpkg
+//!   - name: pois
+//!     filter: amenity=* or shop=*
+//!     types: [node]
+//!     output: pois.csv
+//!     columns: [name, amenity, shop]
+//! ```
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::filter::Filter;
+use crate::output::OutputFormat;
+use anyhow::{bail, Context, Result};
+use geo::Geometry;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which geometries a layer accepts; single and multi geometries of a kind
+/// both match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeometryKind {
+    Point,
+    Line,
+    Polygon,
+}
+
+impl GeometryKind {
+    pub fn matches(&self, geom: &Geometry) -> bool {
+        match self {
+            GeometryKind::Point => matches!(geom, Geometry::Point(_) | Geometry::MultiPoint(_)),
+            GeometryKind::Line => {
+                matches!(geom, Geometry::Line(_) | Geometry::LineString(_) | Geometry::MultiLineString(_))
+            }
+            GeometryKind::Polygon => matches!(
+                geom,
+                Geometry::Polygon(_) | Geometry::MultiPolygon(_) | Geometry::Rect(_) | Geometry::Triangle(_)
+            ),
+        }
+    }
+}
+
+/// One thematic output of a [`Profile`]
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    pub filter: Filter,
+    pub types: Vec<OsmType>,
+    /// Only keep features of this geometry kind; `None` keeps all
+    pub geometry: Option<GeometryKind>,
+    pub output: PathBuf,
+    pub format: OutputFormat,
+    /// Tag keys written as separate columns, where the format supports it
+    pub columns: Vec<String>,
+}
+
+impl Layer {
+    pub fn matches(&self, feature: &ExtractedFeature) -> bool {
+        self.types.contains(&feature.osm_type())
+            && self.filter.matches(feature.tags())
+            && self.geometry.is_none_or(|kind| kind.matches(feature.geometry()))
+    }
+}
+
+/// A set of layers extracted together
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    layers: Vec<RawLayer>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLayer {
+    name: String,
+    filter: String,
+    #[serde(default = "default_types")]
+    types: Vec<OsmType>,
+    geometry: Option<GeometryKind>,
+    output: PathBuf,
+    format: Option<OutputFormat>,
+    #[serde(default)]
+    columns: Vec<String>,
+}
+
+fn default_types() -> Vec<OsmType> {
+    vec![OsmType::Way]
+}
+
+impl Profile {
+    /// Reads a profile from a `.yaml`/`.yml` or `.toml` file
+    pub fn from_file(path: &Path) -> Result<Profile> {
+        let text = fs::read_to_string(path).with_context(|| format!("cannot read profile {:?}", path))?;
+        let is_toml = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"));
+        let raw: RawProfile = if is_toml {
+            toml::from_str(&text).with_context(|| format!("invalid profile {:?}", path))?
+        } else {
+            serde_yaml_ng::from_str(&text).with_context(|| format!("invalid profile {:?}", path))?
+        };
+        if raw.layers.is_empty() {
+            bail!("profile {:?} does not define any layers", path);
+        }
+
+        let mut names = HashSet::new();
+        let mut outputs = HashSet::new();
+        let mut layers = Vec::new();
+        for layer in raw.layers {
+            if !names.insert(layer.name.clone()) {
+                bail!("layer {:?} is defined more than once", layer.name);
+            }
+            if !outputs.insert(layer.output.clone()) {
+                bail!("layer {:?} writes to {:?}, which another layer already uses", layer.name, layer.output);
+            }
+            if layer.types.is_empty() {
+                bail!("layer {:?} does not select any OSM types", layer.name);
+            }
+            let filter = layer
+                .filter
+                .parse()
+                .with_context(|| format!("invalid filter in layer {:?}", layer.name))?;
+            let format = match layer.format.or_else(|| OutputFormat::from_path(&layer.output)) {
+                Some(format) => format,
+                None => bail!("cannot tell the output format of layer {:?}; set `format`", layer.name),
+            };
+            if format == OutputFormat::Pbf {
+                bail!("layer {:?} cannot be written as PBF, which holds OSM objects rather than features", layer.name);
+            }
+            layers.push(Layer {
+                name: layer.name,
+                filter,
+                types: layer.types,
+                geometry: layer.geometry,
+                output: layer.output,
+                format,
+                columns: layer.columns,
+            });
+        }
+        Ok(Profile { layers })
+    }
+
+    /// A filter matching everything any layer wants, for the shared pass
+    pub fn filter(&self) -> Filter {
+        match self.layers.as_slice() {
+            [layer] => layer.filter.clone(),
+            layers => Filter::Or(layers.iter().map(|l| l.filter.clone()).collect()),
+        }
+    }
+
+    /// All OSM types selected by any layer
+    pub fn types(&self) -> Vec<OsmType> {
+        let mut types = Vec::new();
+        for t in self.layers.iter().flat_map(|l| &l.types) {
+            if !types.contains(t) {
+                types.push(*t);
+            }
+        }
+        types
+    }
+}
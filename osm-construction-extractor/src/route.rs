@@ -0,0 +1,191 @@
+//! Assembly of route relations (bus, bicycle, hiking, ...) into lines.
+
+use crate::store::ObjectStore;
+use anyhow::{bail, Result};
+use geo::{LineString, MultiLineString};
+use osmpbfreader::{NodeId, OsmId, Relation};
+
+/// Tag added to assembled routes: a JSON array with the member role of each
+/// line of the `MultiLineString`, e.g. `["", "forward", "backward"]`
+pub const ROLES_TAG: &str = "@roles";
+
+/// Whether a relation is assembled as a route
+pub fn is_route_relation(relation: &Relation) -> bool {
+    relation.tags.get("type").is_some_and(|t| t == "route")
+}
+
+/// The path of a route, as lines with one member role each
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub lines: MultiLineString,
+    /// The role of the member ways of each line, in the same order
+    pub roles: Vec<String>,
+}
+
+// Consecutive member ways with the same role, joined at shared end nodes
+struct Run {
+    nodes: Vec<NodeId>,
+    role: String,
+    ways: usize,
+}
+
+impl Run {
+    fn first(&self) -> NodeId {
+        self.nodes[0]
+    }
+
+    fn last(&self) -> NodeId {
+        self.nodes[self.nodes.len() - 1]
+    }
+
+    // Appends the way if it touches the end of the run, flipping the way or
+    // (while it holds a single way) the run as needed
+    fn join(&mut self, nodes: &[NodeId]) -> bool {
+        if self.ways == 1 && (nodes[0] == self.first() || nodes[nodes.len() - 1] == self.first()) {
+            self.nodes.reverse();
+        }
+        if nodes[0] == self.last() {
+            self.nodes.extend_from_slice(&nodes[1..]);
+        } else if nodes[nodes.len() - 1] == self.last() {
+            self.nodes.extend(nodes.iter().rev().skip(1));
+        } else {
+            return false;
+        }
+        self.ways += 1;
+        true
+    }
+}
+
+// Stops and platforms are members of a route, but not part of its path
+fn is_path_role(role: &str) -> bool {
+    !(role.starts_with("stop") || role.starts_with("platform"))
+}
+
+/// Assembles the member ways of a route relation in member order.
+///
+/// Consecutive ways sharing an end node are stitched into one line, turned
+/// around where mappers drew them against the direction of travel; a gap in
+/// the member list (e.g. ways missing from a regional extract) or a change
+/// of role such as `forward` or `backward` starts a new line. Stop and
+/// platform members are left out.
+pub fn assemble<S: ObjectStore + ?Sized>(relation: &Relation, store: &S) -> Result<Route> {
+    let mut runs: Vec<Run> = Vec::new();
+    for member in &relation.refs {
+        let OsmId::Way(way_id) = member.member else { continue };
+        if !is_path_role(&member.role) {
+            continue;
+        }
+        let nodes = match store.way_nodes(way_id) {
+            Some(nodes) if nodes.len() >= 2 => nodes,
+            _ => continue,
+        };
+        if let Some(run) = runs.last_mut() {
+            if run.role == member.role.as_str() && run.join(nodes) {
+                continue;
+            }
+        }
+        let mut run = Run { nodes: nodes.to_vec(), role: member.role.to_string(), ways: 1 };
+        // Keep the direction of travel across a change of role
+        if runs.last().is_some_and(|prev| run.last() == prev.last()) {
+            run.nodes.reverse();
+        }
+        runs.push(run);
+    }
+    if runs.is_empty() {
+        bail!("no member ways available");
+    }
+
+    let mut lines = Vec::with_capacity(runs.len());
+    let mut roles = Vec::with_capacity(runs.len());
+    for run in runs {
+        let mut coords = Vec::with_capacity(run.nodes.len());
+        for &node_id in &run.nodes {
+            match store.node_location(node_id) {
+                Some(coord) => coords.push(coord),
+                None => bail!("node {} of a member way is missing", node_id.0),
+            }
+        }
+        lines.push(LineString(coords));
+        roles.push(run.role);
+    }
+    Ok(Route { lines: MultiLineString(lines), roles })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{Node, OsmObj, Ref, RelationId, Tags, Way, WayId};
+    use std::collections::BTreeMap;
+
+    type Objects = BTreeMap<OsmId, OsmObj>;
+
+    // Every node lies on the x axis, at its id
+    fn objects(ways: &[(i64, &[i64])]) -> Objects {
+        let mut objects = Objects::new();
+        for &(id, nodes) in ways {
+            for &n in nodes {
+                let node = Node { id: NodeId(n), tags: Tags::new(), decimicro_lat: 0, decimicro_lon: n as i32 * 10_000_000 };
+                objects.insert(node.id.into(), OsmObj::Node(node));
+            }
+            let way = Way { id: WayId(id), tags: Tags::new(), nodes: nodes.iter().map(|&n| NodeId(n)).collect() };
+            objects.insert(way.id.into(), OsmObj::Way(way));
+        }
+        objects
+    }
+
+    fn relation(members: &[(i64, &str)]) -> Relation {
+        let mut tags = Tags::new();
+        tags.insert("type".into(), "route".into());
+        let refs = members.iter().map(|&(w, role)| Ref { member: WayId(w).into(), role: role.into() }).collect();
+        Relation { id: RelationId(1), tags, refs }
+    }
+
+    // The node ids along each line, with its role
+    fn lines(route: &Route) -> Vec<(Vec<i64>, &str)> {
+        let ids = |line: &LineString| line.coords().map(|c| c.x as i64).collect();
+        route.lines.iter().map(ids).zip(route.roles.iter().map(String::as_str)).collect()
+    }
+
+    #[test]
+    fn members_drawn_against_the_direction_of_travel_are_turned() {
+        // The first way, the middle one and the last one each drawn the
+        // wrong way round
+        let objects = objects(&[(1, &[2, 1]), (2, &[2, 3, 4]), (3, &[6, 5, 4]), (4, &[7, 6])]);
+        let route = assemble(&relation(&[(1, ""), (2, ""), (3, ""), (4, "")]), &objects).unwrap();
+        assert_eq!(lines(&route), [(vec![1, 2, 3, 4, 5, 6, 7], "")]);
+    }
+
+    #[test]
+    fn roles_and_gaps_start_new_lines() {
+        let objects = objects(&[
+            (1, &[1, 2]),
+            // Drawn against the direction of travel, and with another role
+            // than the way before it
+            (2, &[3, 2]),
+            (3, &[3, 4]),
+            (4, &[4, 5]),
+            (5, &[5, 6]),
+            // Not touching the way before it
+            (6, &[8, 9]),
+            (7, &[20, 21]),
+        ]);
+        let members = [(1, ""), (2, "forward"), (3, "forward"), (4, "backward"), (7, "platform"), (5, ""), (6, "")];
+        let route = assemble(&relation(&members), &objects).unwrap();
+        assert_eq!(
+            lines(&route),
+            [
+                (vec![1, 2], ""),
+                (vec![2, 3, 4], "forward"),
+                (vec![4, 5], "backward"),
+                (vec![5, 6], ""),
+                (vec![8, 9], ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_without_member_ways_fail() {
+        let objects = objects(&[(1, &[1, 2])]);
+        assert!(assemble(&relation(&[(1, "stop"), (2, "")]), &objects).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Typed output columns mapped from tags, with unit parsing and fallbacks.
+//!
+//! A schema is a YAML or TOML file listing the columns, either in the short
+//! form `key:type` or spelled out:
+//!
+//! ```yaml
+//! columns:
+//!   - name                  # text, the type can be left out
+//!   - lanes:int
+//!   - width:float_m         # "3.5", "3.5 m" or "12'" all give meters
+//!   - name: speed
+//!     key: maxspeed
+//!     type: speed_kmh       # "50", "30 mph", "10 knots" in km/h
+//!     fallback: [maxspeed:forward, maxspeed:backward]
+//!     default: 50
+//!   - oneway:bool
+//! ```
+//!
+//! The first of `key` and its `fallback` keys holding a value of the column
+//! type is used: a tag that cannot be parsed, such as `lanes=several`, counts
+//! as missing. Without any, the `default` is written, else null.
+
+use crate::intern::TagMap;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Columns every structured writer adds itself
+const RESERVED: [&str; 6] = ["fid", "geom", "geometry", "id", "osm_id", "osm_type"];
+
+/// The tag value types a column can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    Text,
+    /// A whole number, e.g. `lanes=2`
+    Int,
+    /// A number, with a decimal point or comma
+    Float,
+    /// `yes`/`true`/`1` or `no`/`false`/`0`
+    Bool,
+    /// A length converted to meters: plain numbers are meters already, `ft`
+    /// and `'`/`"` are converted
+    FloatM,
+    /// A speed converted to km/h: plain numbers are km/h already, `mph` and
+    /// `knots` are converted
+    SpeedKmh,
+}
+
+/// How values of a type are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    Text,
+    Int,
+    Float,
+    Bool,
+}
+
+impl ValueType {
+    pub fn storage(&self) -> Storage {
+        match self {
+            ValueType::Text => Storage::Text,
+            ValueType::Int => Storage::Int,
+            ValueType::Float | ValueType::FloatM | ValueType::SpeedKmh => Storage::Float,
+            ValueType::Bool => Storage::Bool,
+        }
+    }
+
+    /// Parses a tag value; of a `;`-separated list only the first entry is
+    /// used
+    pub fn parse(&self, value: &str) -> Option<Value> {
+        let first = || value.split(';').next().unwrap_or("").trim();
+        match self {
+            ValueType::Text => Some(Value::Text(value.to_string())),
+            ValueType::Int => match first().parse::<i64>() {
+                Ok(int) => Some(Value::Int(int)),
+                Err(_) => {
+                    let float = parse_float(first())?;
+                    (float.fract() == 0.0).then_some(Value::Int(float as i64))
+                }
+            },
+            ValueType::Float => parse_float(first()).map(Value::Float),
+            ValueType::Bool => match first() {
+                "yes" | "true" | "1" => Some(Value::Bool(true)),
+                "no" | "false" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            ValueType::FloatM => crate::building::parse_height(first()).map(Value::Float),
+            ValueType::SpeedKmh => crate::graph::parse_maxspeed(first()).map(Value::Float),
+        }
+    }
+}
+
+fn parse_float(value: &str) -> Option<f64> {
+    let float: f64 = value.replace(',', ".").parse().ok()?;
+    float.is_finite().then_some(float)
+}
+
+/// A typed column value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Float(float) => write!(f, "{}", float),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// One output column
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// The tag keys tried in order
+    pub keys: Vec<String>,
+    pub value_type: ValueType,
+    pub default: Option<Value>,
+}
+
+impl Column {
+    /// A text column holding the tag of the same name, as `--columns` makes
+    pub fn text(key: &str) -> Column {
+        Column {
+            name: key.to_string(),
+            keys: vec![key.to_string()],
+            value_type: ValueType::Text,
+            default: None,
+        }
+    }
+
+    pub fn storage(&self) -> Storage {
+        self.value_type.storage()
+    }
+
+    /// The value of the column for a feature's tags
+    pub fn value(&self, tags: &TagMap) -> Option<Value> {
+        self.keys
+            .iter()
+            .filter_map(|key| tags.get(key.as_str()))
+            .find_map(|value| self.value_type.parse(value))
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// The typed columns written instead of the tags
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSchema {
+    pub columns: Vec<Column>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSchema {
+    columns: Vec<RawColumn>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawColumn {
+    Short(String),
+    Full {
+        name: String,
+        key: Option<String>,
+        #[serde(rename = "type", default = "default_type")]
+        value_type: ValueType,
+        #[serde(default)]
+        fallback: Vec<String>,
+        default: Option<serde_json::Value>,
+    },
+}
+
+fn default_type() -> ValueType {
+    ValueType::Text
+}
+
+impl TagSchema {
+    /// Reads a schema from a `.yaml`/`.yml` or `.toml` file
+    pub fn from_file(path: &Path) -> Result<TagSchema> {
+        let text = fs::read_to_string(path).with_context(|| format!("cannot read schema {:?}", path))?;
+        let is_toml = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"));
+        let raw: RawSchema = if is_toml {
+            toml::from_str(&text).with_context(|| format!("invalid schema {:?}", path))?
+        } else {
+            serde_yaml_ng::from_str(&text).with_context(|| format!("invalid schema {:?}", path))?
+        };
+        if raw.columns.is_empty() {
+            bail!("schema {:?} does not define any columns", path);
+        }
+
+        let mut names = HashSet::new();
+        let mut columns = Vec::new();
+        for raw in raw.columns {
+            let column = match raw {
+                RawColumn::Short(spec) => short_column(&spec),
+                RawColumn::Full { name, key, value_type, fallback, default } => {
+                    let mut keys = vec![key.unwrap_or_else(|| name.clone())];
+                    keys.extend(fallback);
+                    let default = match default {
+                        None => None,
+                        Some(value) => {
+                            let text = match value {
+                                serde_json::Value::String(s) => s,
+                                other => other.to_string(),
+                            };
+                            match value_type.parse(&text) {
+                                Some(value) => Some(value),
+                                None => bail!("default {:?} of column {:?} is not a valid {:?}", text, name, value_type),
+                            }
+                        }
+                    };
+                    Column { name, keys, value_type, default }
+                }
+            };
+            if RESERVED.contains(&column.name.as_str()) {
+                bail!("column {:?} clashes with a column every output has; pick another `name`", column.name);
+            }
+            if !names.insert(column.name.clone()) {
+                bail!("column {:?} is defined more than once", column.name);
+            }
+            columns.push(column);
+        }
+        Ok(TagSchema { columns })
+    }
+}
+
+// `key:type`, or just `key` for text; keys may contain colons themselves,
+// as in `building:levels:int`
+fn short_column(spec: &str) -> Column {
+    if let Some((key, type_name)) = spec.rsplit_once(':') {
+        let value_type = serde_json::from_value(serde_json::Value::String(type_name.to_string()));
+        if let Ok(value_type) = value_type {
+            return Column {
+                name: key.to_string(),
+                keys: vec![key.to_string()],
+                value_type,
+                default: None,
+            };
+        }
+    }
+    Column::text(spec)
+}
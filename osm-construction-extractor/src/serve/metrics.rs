@@ -0,0 +1,153 @@
+//! Counters and latency histograms of the server, exposed at `/metrics` in
+//! the Prometheus text format.
+
+use crate::extractor::ExtractionStats;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// The parts of answering a request whose latency is measured
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    /// Reading the input and resolving dependencies
+    Read,
+    /// Assembling geometries
+    Assemble,
+    /// Writing an output file before sending it
+    Write,
+    /// Answering Overpass QL from the index
+    Query,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [Phase::Read, Phase::Assemble, Phase::Write, Phase::Query];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::Assemble => "assemble",
+            Phase::Write => "write",
+            Phase::Query => "query",
+        }
+    }
+}
+
+/// What the worker threads of the server have done so far
+#[derive(Debug, Default)]
+pub struct Metrics(Mutex<Counts>);
+
+#[derive(Debug, Default)]
+struct Counts {
+    extractions: Outcomes,
+    queries: Outcomes,
+    input_bytes: u64,
+    extracted_features: u64,
+    queried_elements: u64,
+    phases: [Histogram; Phase::ALL.len()],
+}
+
+#[derive(Debug, Default)]
+struct Outcomes {
+    ok: u64,
+    error: u64,
+}
+
+impl Outcomes {
+    fn add(&mut self, ok: bool) {
+        if ok {
+            self.ok += 1;
+        } else {
+            self.error += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Not cumulative; summed up when written
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+impl Metrics {
+    /// Counts an extraction, with its statistics if it ran to the end
+    pub fn extraction(&self, stats: Option<&ExtractionStats>, features: usize) {
+        let mut counts = self.0.lock().expect("metrics lock");
+        counts.extractions.add(stats.is_some());
+        counts.extracted_features += features as u64;
+        if let Some(stats) = stats {
+            counts.input_bytes += stats.input_bytes;
+            counts.phases[Phase::Read as usize].observe(stats.extraction_duration);
+            counts.phases[Phase::Assemble as usize].observe(stats.processing_duration);
+        }
+    }
+
+    /// Counts an Overpass QL query that took `duration`
+    pub fn query(&self, ok: bool, elements: usize, duration: Duration) {
+        let mut counts = self.0.lock().expect("metrics lock");
+        counts.queries.add(ok);
+        counts.queried_elements += elements as u64;
+        counts.phases[Phase::Query as usize].observe(duration);
+    }
+
+    /// Records the latency of a phase not covered by the above
+    pub fn observe(&self, phase: Phase, duration: Duration) {
+        self.0.lock().expect("metrics lock").phases[phase as usize].observe(duration);
+    }
+}
+
+/// Writes the metrics in the Prometheus text exposition format
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = self.0.lock().expect("metrics lock");
+        let outcomes = |f: &mut fmt::Formatter<'_>, name: &str, help: &str, outcomes: &Outcomes| {
+            header(f, name, "counter", help)?;
+            writeln!(f, "{}{{status=\"ok\"}} {}", name, outcomes.ok)?;
+            writeln!(f, "{}{{status=\"error\"}} {}", name, outcomes.error)
+        };
+        outcomes(f, "osm_extractor_extractions_total", "Extractions run for /extract, by outcome", &counts.extractions)?;
+        outcomes(f, "osm_extractor_queries_total", "Overpass QL queries answered, by outcome", &counts.queries)?;
+
+        let name = "osm_extractor_input_bytes_total";
+        header(f, name, "counter", "Bytes of input read by finished extractions")?;
+        writeln!(f, "{} {}", name, counts.input_bytes)?;
+        let name = "osm_extractor_features_emitted_total";
+        header(f, name, "counter", "Features sent by extractions and elements by Overpass QL queries")?;
+        writeln!(f, "{}{{endpoint=\"extract\"}} {}", name, counts.extracted_features)?;
+        writeln!(f, "{}{{endpoint=\"interpreter\"}} {}", name, counts.queried_elements)?;
+
+        let name = "osm_extractor_phase_duration_seconds";
+        header(f, name, "histogram", "Time spent per phase of answering requests")?;
+        for (phase, histogram) in Phase::ALL.iter().zip(&counts.phases) {
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                writeln!(f, "{}_bucket{{phase=\"{}\",le=\"{}\"}} {}", name, phase.name(), le, cumulative)?;
+            }
+            writeln!(f, "{}_bucket{{phase=\"{}\",le=\"+Inf\"}} {}", name, phase.name(), histogram.count)?;
+            writeln!(f, "{}_sum{{phase=\"{}\"}} {}", name, phase.name(), histogram.sum)?;
+            writeln!(f, "{}_count{{phase=\"{}\"}} {}", name, phase.name(), histogram.count)?;
+        }
+        Ok(())
+    }
+}
+
+fn header(f: &mut fmt::Formatter<'_>, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP {} {}", name, help)?;
+    writeln!(f, "# TYPE {} {}", name, kind)
+}
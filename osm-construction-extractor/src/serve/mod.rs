@@ -0,0 +1,304 @@
+//! An HTTP server answering Overpass QL (see [`overpass`]) from a feature
+//! index, at `/api/interpreter` like the Overpass API, so that tools
+//! written against it can run offline against a local extract.
+//!
+//! Queries are taken from the `data` parameter of a GET request, e.g.
+//! `/api/interpreter?data=[out:json];way[highway];out;`, or from the
+//! body of a POST request, form-encoded or as is.
+//!
+//! When started from PBF files, it also runs extractions from them for
+//! `POST /extract` with a JSON body such as
+//! `{"filter": "highway=construction", "bbox": "13.0,52.3,13.8,52.7"}`,
+//! see [`ExtractRequest`]. GeoJSONSeq and GeoJSON are sent while the file
+//! is read; other formats are written to a temporary file first.
+//!
+//! `/metrics` reports what has been answered in the Prometheus text
+//! format, see [`Metrics`].
+
+use crate::feature::OsmType;
+use crate::filter::Filter;
+use crate::index::FeatureIndex;
+use crate::input::Input;
+use crate::output::{self, geojson, OutputFormat, WriteOptions};
+use crate::{overpass, spatial, ExtractionStats, Extractor};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::thread;
+use std::time::Instant;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tracing::{debug, info, warn};
+
+mod metrics;
+
+pub use metrics::{Metrics, Phase};
+
+/// The body of `POST /extract`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractRequest {
+    /// Tag filter expression, as for `--filter`
+    pub filter: String,
+    /// OSM object types the filter is applied to; ways if omitted
+    #[serde(default = "default_types")]
+    pub types: Vec<OsmType>,
+    /// Only extract inside this box: `min_lon,min_lat,max_lon,max_lat`
+    pub bbox: Option<String>,
+    /// Output format; GeoJSONSeq if omitted
+    pub format: Option<OutputFormat>,
+}
+
+fn default_types() -> Vec<OsmType> {
+    vec![OsmType::Way]
+}
+
+/// Serves queries on `address`, e.g. `127.0.0.1:8080`, from `threads`
+/// threads (0 = one per CPU core) until the process is stopped;
+/// extractions read `inputs`, and are refused if there are none
+pub fn serve(index: &FeatureIndex, inputs: &[Input], address: &str, threads: usize) -> Result<()> {
+    let server = Server::http(address).map_err(|e| anyhow!("cannot listen on {}: {}", address, e))?;
+    let workers = match threads {
+        0 => thread::available_parallelism().map_or(1, usize::from),
+        n => n,
+    };
+    info!("-> Answering Overpass QL at http://{}/api/interpreter", address);
+    if !inputs.is_empty() {
+        info!("-> Extracting from {} file(s) at http://{}/extract", inputs.len(), address);
+    }
+    let service = Service { index, inputs, threads, metrics: Metrics::default() };
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Ok(request) = server.recv() {
+                    service.handle(request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// What the worker threads share
+struct Service<'a> {
+    index: &'a FeatureIndex,
+    inputs: &'a [Input],
+    threads: usize,
+    metrics: Metrics,
+}
+
+impl Service<'_> {
+    fn handle(&self, mut request: Request) {
+        debug!("{} {}", request.method(), request.url());
+        let response = match (request.method(), request.url().split_once('?')) {
+            (Method::Get, Some(("/api/interpreter", query))) => match form_value(query, "data") {
+                Some(ql) => self.interpret(&ql),
+                None => error(400, "missing the data parameter with the query"),
+            },
+            (Method::Post, _) if path(request.url()) == "/api/interpreter" => {
+                let mut body = String::new();
+                match request.as_reader().read_to_string(&mut body) {
+                    // Forms send the query as the data field
+                    Ok(_) => self.interpret(&form_value(&body, "data").unwrap_or(body)),
+                    Err(e) => error(400, &format!("cannot read the request: {}", e)),
+                }
+            }
+            (Method::Get | Method::Post, _) if path(request.url()) == "/api/interpreter" => {
+                error(400, "missing the data parameter with the query")
+            }
+            (Method::Post, _) if path(request.url()) == "/extract" => {
+                if self.inputs.is_empty() {
+                    error(400, "extractions need the PBF files, but the server was started from an index")
+                } else {
+                    // Answered as it is extracted, possibly while still running
+                    return self.extract(request);
+                }
+            }
+            (_, _) if path(request.url()) == "/extract" => error(405, "extractions are requested with POST"),
+            (Method::Get, _) if path(request.url()) == "/metrics" => with_content_type(
+                Response::from_string(self.metrics.to_string()),
+                "text/plain; version=0.0.4; charset=utf-8",
+            ),
+            _ => error(404, "not found; queries go to /api/interpreter and extractions to /extract"),
+        };
+        respond(request, response);
+    }
+
+    fn interpret(&self, ql: &str) -> Response<io::Cursor<Vec<u8>>> {
+        let start_time = Instant::now();
+        let result = overpass::parse(ql).and_then(|script| script.run(self.index));
+        let elements = result.as_ref().map_or(0, |document| document["elements"].as_array().map_or(0, Vec::len));
+        self.metrics.query(result.is_ok(), elements, start_time.elapsed());
+        match result {
+            Ok(document) => with_content_type(Response::from_data(document.to_string()), "application/json"),
+            Err(e) => error(400, &format!("{:#}", e)),
+        }
+    }
+
+    fn extract(&self, mut request: Request) {
+        let mut body = String::new();
+        let parsed = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => extractor(self.inputs, self.threads, &body),
+            Err(e) => Err(anyhow!("cannot read the request: {}", e)),
+        };
+        let (extractor, format) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => return respond(request, error(400, &format!("{:#}", e))),
+        };
+        info!("-> Extracting {} for {}", format, request.remote_addr().map_or("?".into(), |a| a.to_string()));
+
+        if let OutputFormat::Geojson | OutputFormat::Geojsonseq = format {
+            let (reader, writer) = match io::pipe() {
+                Ok(pipe) => pipe,
+                Err(e) => return respond(request, error(500, &format!("cannot stream the features: {}", e))),
+            };
+            let response = Response::new(StatusCode(200), Vec::new(), reader, None, None);
+            thread::scope(|scope| {
+                // Stops with an error once the client is gone and the pipe closed
+                scope.spawn(move || {
+                    let mut out = BufWriter::new(writer);
+                    let mut count = 0;
+                    let result = stream(&extractor, format, &mut out, &mut count).and_then(|stats| {
+                        out.flush()?;
+                        Ok(stats)
+                    });
+                    match result {
+                        Ok(stats) => {
+                            info!("   Sent {} features", count);
+                            self.metrics.extraction(Some(&stats), count);
+                        }
+                        Err(e) => {
+                            warn!("Extraction stopped: {:#}", e);
+                            self.metrics.extraction(None, count);
+                        }
+                    }
+                });
+                respond(request, with_content_type(response, content_type(format)));
+            });
+            return;
+        }
+
+        let written = tempfile::tempdir().map_err(anyhow::Error::from).and_then(|dir| {
+            let path = dir.path().join(format!("extract.{}", format.extension()));
+            let (features, stats) = extractor.run_with_stats()?;
+            let start_time = Instant::now();
+            output::write(&path, format, &features, &WriteOptions::default())?;
+            let file = File::open(&path).context("cannot read the written features")?;
+            self.metrics.observe(Phase::Write, start_time.elapsed());
+            self.metrics.extraction(Some(&stats), features.len());
+            Ok((file, dir))
+        });
+        match written {
+            // The directory is removed once the file has been sent
+            Ok((file, _dir)) => respond(request, with_content_type(Response::from_file(file), content_type(format))),
+            Err(e) => {
+                self.metrics.extraction(None, 0);
+                respond(request, error(500, &format!("{:#}", e)))
+            }
+        }
+    }
+}
+
+fn respond<R: Read>(request: Request, response: Response<R>) {
+    if let Err(e) = request.respond(response) {
+        warn!("Could not send a response: {}", e);
+    }
+}
+
+fn path(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(path, _)| path)
+}
+
+// The extraction of a request to /extract, and its output format
+fn extractor(inputs: &[Input], threads: usize, body: &str) -> Result<(Extractor, OutputFormat)> {
+    let request: ExtractRequest = serde_json::from_str(body).context("invalid extraction request")?;
+    let filter: Filter = request.filter.parse().context("invalid filter")?;
+    let format = match request.format.unwrap_or(OutputFormat::Geojsonseq) {
+        OutputFormat::Shp => bail!("Shapefile output consists of several files; choose another format"),
+        OutputFormat::Pbf => bail!("PBF output cannot be served, only assembled features"),
+        format => format,
+    };
+    let mut extractor =
+        Extractor::new(inputs[0].clone()).inputs(inputs.to_vec()).filter(filter).types(&request.types).threads(threads);
+    if let Some(bbox) = &request.bbox {
+        extractor = extractor.bbox(spatial::parse_bbox(bbox)?);
+    }
+    Ok((extractor, format))
+}
+
+// Writes the features as they are extracted, counting them in `count`
+fn stream(
+    extractor: &Extractor,
+    format: OutputFormat,
+    out: &mut impl Write,
+    count: &mut usize,
+) -> Result<ExtractionStats> {
+    let collection = format == OutputFormat::Geojson;
+    if collection {
+        out.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+    }
+    let stats = extractor.for_each(|feature| {
+        if collection && *count > 0 {
+            out.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut *out, &geojson::feature(&feature))?;
+        if !collection {
+            out.write_all(b"\n")?;
+        }
+        *count += 1;
+        Ok(())
+    })?;
+    if collection {
+        out.write_all(b"\n]}\n")?;
+    }
+    Ok(stats)
+}
+
+fn content_type(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Geojson => "application/geo+json",
+        OutputFormat::Geojsonseq => "application/geo+json-seq",
+        OutputFormat::Csv => "text/csv",
+        OutputFormat::Tsv => "text/tab-separated-values",
+        OutputFormat::Arrow => "application/vnd.apache.arrow.stream",
+        _ => "application/octet-stream",
+    }
+}
+
+fn error(status: u16, message: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let response = Response::from_string(format!("Error: {}\n", message)).with_status_code(status);
+    with_content_type(response, "text/plain; charset=utf-8")
+}
+
+// Also allows pages of other origins to query, as overpass turbo does
+fn with_content_type<R: Read>(response: Response<R>, content_type: &str) -> Response<R> {
+    let header = |name: &str, value: &str| Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header");
+    response.with_header(header("Content-Type", content_type)).with_header(header("Access-Control-Allow-Origin", "*"))
+}
+
+// The decoded value of a field of `a=1&b=2`
+fn form_value(form: &str, name: &str) -> Option<String> {
+    form.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (decode(key) == name).then(|| decode(value))
+    })
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => decoded.push(b' '),
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
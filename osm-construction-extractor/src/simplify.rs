@@ -0,0 +1,60 @@
+//! Douglas-Peucker simplification with a tolerance in meters, and rounding
+//! of coordinates to fewer decimals.
+
+use geo::{
+    BoundingRect, Coord, Geometry, GeometryCollection, LineString, MapCoordsInPlace, MultiLineString, MultiPolygon,
+    Polygon, SimplifyIdx,
+};
+
+// Length of one degree of latitude
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Simplifies the lines and polygon rings of a lon/lat geometry so that no
+/// removed vertex is further than `tolerance` meters from the result.
+///
+/// Longitudes are scaled by the cosine of the geometry's mean latitude
+/// first, so the tolerance is about equally strict in every direction. Kept
+/// vertices are copied unchanged, and rings that would collapse below four
+/// points are left as they are.
+pub fn simplify(geom: &Geometry, tolerance: f64) -> Geometry {
+    let Some(rect) = geom.bounding_rect() else {
+        return geom.clone();
+    };
+    let scale = rect.center().y.to_radians().cos();
+    simplify_scaled(geom, scale, tolerance / METERS_PER_DEGREE)
+}
+
+fn simplify_scaled(geom: &Geometry, scale: f64, epsilon: f64) -> Geometry {
+    let ring = |r: &LineString| simplify_line(r, scale, epsilon, true);
+    let polygon = |p: &Polygon| Polygon::new(ring(p.exterior()), p.interiors().iter().map(ring).collect());
+    match geom {
+        Geometry::LineString(ls) => Geometry::LineString(simplify_line(ls, scale, epsilon, false)),
+        Geometry::MultiLineString(mls) => Geometry::MultiLineString(MultiLineString(
+            mls.iter().map(|ls| simplify_line(ls, scale, epsilon, false)).collect(),
+        )),
+        Geometry::Polygon(p) => Geometry::Polygon(polygon(p)),
+        Geometry::MultiPolygon(mp) => Geometry::MultiPolygon(MultiPolygon(mp.iter().map(polygon).collect())),
+        Geometry::GeometryCollection(gc) => Geometry::GeometryCollection(GeometryCollection(
+            gc.iter().map(|g| simplify_scaled(g, scale, epsilon)).collect(),
+        )),
+        other => other.clone(),
+    }
+}
+
+fn simplify_line(line: &LineString, scale: f64, epsilon: f64, is_ring: bool) -> LineString {
+    let scaled: LineString = line.coords().map(|c| Coord { x: c.x * scale, y: c.y }).collect();
+    let kept = scaled.simplify_idx(&epsilon);
+    if is_ring && kept.len() < 4 {
+        return line.clone();
+    }
+    LineString(kept.into_iter().map(|i| line.0[i]).collect())
+}
+
+/// Rounds every coordinate to `decimals` decimals, which shortens text
+/// outputs such as GeoJSON, CSV and WKT; 6 decimals of a degree are about
+/// 10 cm, 7 are the precision of OSM itself. Vertices rounded onto each
+/// other are kept, so lines and rings keep their number of points.
+pub fn round(geom: &mut Geometry, decimals: u8) {
+    let scale = 10f64.powi(decimals.into());
+    geom.map_coords_in_place(|c| Coord { x: (c.x * scale).round() / scale, y: (c.y * scale).round() / scale });
+}
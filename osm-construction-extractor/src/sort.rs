@@ -0,0 +1,162 @@
+//! Ordering of extracted features before they are written.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use clap::ValueEnum;
+use geo::{BoundingRect, Rect};
+
+/// The order features are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SortOrder {
+    /// The order they are extracted in, which depends on the input and mode
+    #[default]
+    None,
+    /// Nodes, then ways, then relations, each by id, so that the outputs of
+    /// different runs can be diffed
+    Id,
+    /// Along a Hilbert curve through the centers of their bounding boxes,
+    /// keeping features that are close in space close in the file
+    Hilbert,
+}
+
+/// Sorts the features in place; both orders are stable
+pub fn sort(features: &mut [ExtractedFeature], order: SortOrder) {
+    match order {
+        SortOrder::None => {}
+        SortOrder::Id => features.sort_by_key(|f| (type_rank(f.osm_type()), f.id())),
+        SortOrder::Hilbert => {
+            let rects = features.iter().filter_map(|f| f.geometry().bounding_rect());
+            let Some(extent) = rects.reduce(|a, b| {
+                Rect::new(
+                    (a.min().x.min(b.min().x), a.min().y.min(b.min().y)),
+                    (a.max().x.max(b.max().x), a.max().y.max(b.max().y)),
+                )
+            }) else {
+                return;
+            };
+            // Features without a geometry go last
+            features.sort_by_cached_key(|f| {
+                f.geometry().bounding_rect().map_or(u64::MAX, |r| hilbert_key(&r, &extent) as u64)
+            });
+        }
+    }
+}
+
+fn type_rank(osm_type: OsmType) -> u8 {
+    match osm_type {
+        OsmType::Node => 0,
+        OsmType::Way => 1,
+        OsmType::Relation => 2,
+    }
+}
+
+// The position of the box center on a Hilbert curve filling the extent on
+// a 65536 × 65536 grid, as FlatGeobuf computes it
+fn hilbert_key(rect: &Rect, extent: &Rect) -> u32 {
+    const MAX: f64 = 65535.0;
+    let scale = |v: f64, min: f64, size: f64| if size > 0.0 { (MAX * (v - min) / size).floor() as u32 } else { 0 };
+    let center = rect.center();
+    let x = scale(center.x, extent.min().x, extent.width());
+    let y = scale(center.y, extent.min().y, extent.height());
+    hilbert(x, y)
+}
+
+// Index of (x, y) on a 16-bit Hilbert curve, after "Fast Hilbert curve
+// generation, sorting, and range queries" by rawrunprotected
+fn hilbert(x: u32, y: u32) -> u32 {
+    let mut a = x ^ y;
+    let mut b = 0xFFFF ^ a;
+    let mut c = 0xFFFF ^ (x | y);
+    let mut d = x & (y ^ 0xFFFF);
+
+    let mut aa = a | (b >> 1);
+    let mut bb = (a >> 1) ^ a;
+    let mut cc = ((c >> 1) ^ (b & (d >> 1))) ^ c;
+    let mut dd = ((a & (c >> 1)) ^ (d >> 1)) ^ d;
+
+    (a, b, c, d) = (aa, bb, cc, dd);
+    aa = (a & (a >> 2)) ^ (b & (b >> 2));
+    bb = (a & (b >> 2)) ^ (b & ((a ^ b) >> 2));
+    cc ^= (a & (c >> 2)) ^ (b & (d >> 2));
+    dd ^= (b & (c >> 2)) ^ ((a ^ b) & (d >> 2));
+
+    (a, b, c, d) = (aa, bb, cc, dd);
+    aa = (a & (a >> 4)) ^ (b & (b >> 4));
+    bb = (a & (b >> 4)) ^ (b & ((a ^ b) >> 4));
+    cc ^= (a & (c >> 4)) ^ (b & (d >> 4));
+    dd ^= (b & (c >> 4)) ^ ((a ^ b) & (d >> 4));
+
+    (a, b, c, d) = (aa, bb, cc, dd);
+    cc ^= (a & (c >> 8)) ^ (b & (d >> 8));
+    dd ^= (b & (c >> 8)) ^ ((a ^ b) & (d >> 8));
+
+    a = cc ^ (cc >> 1);
+    b = dd ^ (dd >> 1);
+
+    let i0 = interleave(x ^ y);
+    let i1 = interleave(b | (0xFFFF ^ ((x ^ y) | a)));
+    (i1 << 1) | i0
+}
+
+// Spreads the 16 low bits to the even bit positions
+fn interleave(mut v: u32) -> u32 {
+    v = (v | (v << 8)) & 0x00FF00FF;
+    v = (v | (v << 4)) & 0x0F0F0F0F;
+    v = (v | (v << 2)) & 0x33333333;
+    (v | (v << 1)) & 0x55555555
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intern::TagMap;
+    use geo::{Geometry, Point};
+
+    fn feature(osm_type: OsmType, id: i64, x: f64, y: f64) -> ExtractedFeature {
+        ExtractedFeature::new(osm_type, id, TagMap::new(), Geometry::Point(Point::new(x, y)))
+    }
+
+    #[test]
+    fn hilbert_curve_starts_by_filling_the_corner() {
+        // The curve of order 2 through the 4 × 4 cells at the origin, a
+        // column of cells per x
+        let expected = [
+            [0, 3, 4, 5],
+            [1, 2, 7, 6],
+            [14, 13, 8, 9],
+            [15, 12, 11, 10],
+        ];
+        for (x, column) in expected.iter().enumerate() {
+            for (y, &index) in column.iter().enumerate() {
+                assert_eq!(hilbert(x as u32, y as u32), index, "cell ({}, {})", x, y);
+            }
+        }
+        assert_eq!(hilbert(0xFFFF, 0), u32::MAX);
+    }
+
+    #[test]
+    fn hilbert_order_keeps_neighbours_together() {
+        let mut features = Vec::new();
+        for i in 0..64 {
+            features.push(feature(OsmType::Node, i, (i % 8) as f64, (i / 8) as f64));
+        }
+        sort(&mut features, SortOrder::Hilbert);
+        for pair in features.windows(2) {
+            let (a, b) = (pair[0].geometry().bounding_rect().unwrap(), pair[1].geometry().bounding_rect().unwrap());
+            assert_eq!((a.min().x - b.min().x).abs() + (a.min().y - b.min().y).abs(), 1.0);
+        }
+    }
+
+    #[test]
+    fn id_order_is_by_type_then_id() {
+        let mut features = vec![
+            feature(OsmType::Relation, 1, 0.0, 0.0),
+            feature(OsmType::Way, 7, 0.0, 0.0),
+            feature(OsmType::Node, 9, 0.0, 0.0),
+            feature(OsmType::Way, 2, 0.0, 0.0),
+            feature(OsmType::Node, 3, 0.0, 0.0),
+        ];
+        sort(&mut features, SortOrder::Id);
+        let ids: Vec<String> = features.iter().map(ExtractedFeature::unique_id).collect();
+        assert_eq!(ids, ["n3", "n9", "w2", "w7", "r1"]);
+    }
+}
@@ -0,0 +1,342 @@
+//! Spatial filters restricting an extraction to an area of interest.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use geo::{
+    BooleanOps, BoundingRect, Contains, Coord, Geometry, GeometryCollection, Intersects, LineString,
+    MultiLineString, MultiPoint, MultiPolygon, Point, Polygon, Rect,
+};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Parses a `min_lon,min_lat,max_lon,max_lat` bounding box
+pub fn parse_bbox(s: &str) -> Result<Rect> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().with_context(|| format!("invalid number '{}' in bbox", v.trim())))
+        .collect::<Result<Vec<f64>>>()?;
+
+    let [min_lon, min_lat, max_lon, max_lat] = values[..] else {
+        bail!("bbox must have exactly four values: min_lon,min_lat,max_lon,max_lat");
+    };
+    if min_lon > max_lon || min_lat > max_lat {
+        bail!("bbox minimum must not exceed maximum");
+    }
+    if !(-180.0..=180.0).contains(&min_lon)
+        || !(-180.0..=180.0).contains(&max_lon)
+        || !(-90.0..=90.0).contains(&min_lat)
+        || !(-90.0..=90.0).contains(&max_lat)
+    {
+        bail!("bbox is outside the WGS84 coordinate range");
+    }
+
+    Ok(Rect::new((min_lon, min_lat), (max_lon, max_lat)))
+}
+
+/// Parses a `lon,lat` point
+pub fn parse_point(s: &str) -> Result<Point> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().with_context(|| format!("invalid number '{}' in point", v.trim())))
+        .collect::<Result<Vec<f64>>>()?;
+    let [lon, lat] = values[..] else {
+        bail!("point must have exactly two values: lon,lat");
+    };
+    if !(-180.0..=180.0).contains(&lon) || !(-90.0..=90.0).contains(&lat) {
+        bail!("point is outside the WGS84 coordinate range");
+    }
+    Ok(Point::new(lon, lat))
+}
+
+/// True if the location lies inside or on the edge of `bbox`
+pub fn bbox_contains(bbox: &Rect, lon: f64, lat: f64) -> bool {
+    lon >= bbox.min().x && lon <= bbox.max().x && lat >= bbox.min().y && lat <= bbox.max().y
+}
+
+/// The overlapping part of two boxes, if they overlap at all
+pub fn rect_overlap(a: &Rect, b: &Rect) -> Option<Rect> {
+    let min = Coord { x: a.min().x.max(b.min().x), y: a.min().y.max(b.min().y) };
+    let max = Coord { x: a.max().x.min(b.max().x), y: a.max().y.min(b.max().y) };
+    (min.x <= max.x && min.y <= max.y).then(|| Rect::new(min, max))
+}
+
+/// How features are treated relative to a boundary polygon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ClipMode {
+    /// Keep every feature intersecting the boundary, with its full geometry
+    #[default]
+    Intersect,
+    /// Cut geometries at the boundary, keeping only the parts inside
+    Clip,
+}
+
+/// A boundary polygon restricting the extraction
+#[derive(Debug, Clone)]
+pub struct Boundary {
+    pub polygons: MultiPolygon,
+    pub mode: ClipMode,
+}
+
+impl Boundary {
+    /// Reads a boundary from a GeoJSON file or an Osmosis `.poly` file
+    pub fn from_file(path: &Path, mode: ClipMode) -> Result<Boundary> {
+        let text = fs::read_to_string(path).with_context(|| format!("cannot read boundary {:?}", path))?;
+        let is_poly = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("poly"))
+            || !text.trim_start().starts_with('{');
+        let polygons = if is_poly { parse_poly(&text)? } else { parse_geojson_polygons(&text)? };
+        if polygons.0.is_empty() {
+            bail!("boundary {:?} does not contain any polygons", path);
+        }
+        Ok(Boundary { polygons, mode })
+    }
+
+    pub fn bounding_rect(&self) -> Option<Rect> {
+        self.polygons.bounding_rect()
+    }
+
+    /// Applies the boundary to an assembled geometry.
+    ///
+    /// Returns `None` if nothing of the geometry is left, otherwise the full
+    /// geometry (`ClipMode::Intersect`) or the parts inside (`ClipMode::Clip`).
+    pub fn apply(&self, geom: &Geometry) -> Option<Geometry> {
+        if !geom.intersects(&self.polygons) {
+            return None;
+        }
+        match self.mode {
+            ClipMode::Intersect => Some(geom.clone()),
+            ClipMode::Clip => clip_geometry(geom, &self.polygons),
+        }
+    }
+}
+
+fn clip_geometry(geom: &Geometry, boundary: &MultiPolygon) -> Option<Geometry> {
+    match geom {
+        Geometry::Point(p) => boundary.contains(p).then_some(Geometry::Point(*p)),
+        Geometry::MultiPoint(mp) => {
+            let inside: Vec<_> = mp.iter().filter(|p| boundary.contains(*p)).copied().collect();
+            (!inside.is_empty()).then_some(Geometry::MultiPoint(MultiPoint(inside)))
+        }
+        Geometry::Line(l) => clip_lines(boundary, MultiLineString(vec![LineString(vec![l.start, l.end])])),
+        Geometry::LineString(ls) => clip_lines(boundary, MultiLineString(vec![ls.clone()])),
+        Geometry::MultiLineString(mls) => clip_lines(boundary, mls.clone()),
+        Geometry::Polygon(p) => clip_polygons(boundary, MultiPolygon(vec![p.clone()])),
+        Geometry::MultiPolygon(mp) => clip_polygons(boundary, mp.clone()),
+        Geometry::Rect(r) => clip_polygons(boundary, MultiPolygon(vec![r.to_polygon()])),
+        Geometry::Triangle(t) => clip_polygons(boundary, MultiPolygon(vec![t.to_polygon()])),
+        Geometry::GeometryCollection(gc) => {
+            let parts: Vec<Geometry> = gc.iter().filter_map(|g| clip_geometry(g, boundary)).collect();
+            (!parts.is_empty()).then_some(Geometry::GeometryCollection(GeometryCollection(parts)))
+        }
+    }
+}
+
+// Clipped single-part results are unwrapped so a way stays a LineString when
+// it is only cut once
+fn clip_lines(boundary: &MultiPolygon, lines: MultiLineString) -> Option<Geometry> {
+    let mut clipped = boundary.clip(&lines, false);
+    clipped.0.retain(|ls| ls.0.len() >= 2);
+    match clipped.0.len() {
+        0 => None,
+        1 => clipped.0.pop().map(Geometry::LineString),
+        _ => Some(Geometry::MultiLineString(clipped)),
+    }
+}
+
+fn clip_polygons(boundary: &MultiPolygon, polygons: MultiPolygon) -> Option<Geometry> {
+    let mut clipped = polygons.intersection(boundary);
+    match clipped.0.len() {
+        0 => None,
+        1 => clipped.0.pop().map(Geometry::Polygon),
+        _ => Some(Geometry::MultiPolygon(clipped)),
+    }
+}
+
+/// Parses the Osmosis polygon filter file format.
+///
+/// Each section lists `lon lat` pairs and is terminated by `END`; sections
+/// whose name starts with `!` are holes in the preceding polygon.
+pub fn parse_poly(text: &str) -> Result<MultiPolygon> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    // The first line is the file's name
+    lines.next().context("empty .poly file")?;
+
+    let mut polygons: Vec<Polygon> = Vec::new();
+    loop {
+        let Some(section) = lines.next() else {
+            bail!(".poly file is missing its final END");
+        };
+        if section.eq_ignore_ascii_case("END") {
+            break;
+        }
+        let is_hole = section.starts_with('!');
+
+        let mut coords = Vec::new();
+        loop {
+            let line = lines.next().with_context(|| format!("section '{}' is missing its END", section))?;
+            if line.eq_ignore_ascii_case("END") {
+                break;
+            }
+            let mut parts = line.split_whitespace().map(|v| v.parse::<f64>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(x)), Some(Ok(y))) => coords.push(Coord { x, y }),
+                _ => bail!("invalid coordinate line '{}' in .poly file", line),
+            }
+        }
+        let ring = LineString(coords);
+
+        if is_hole {
+            let Some(polygon) = polygons.last_mut() else {
+                bail!("hole section '{}' before any polygon in .poly file", section);
+            };
+            polygon.interiors_push(ring);
+        } else {
+            polygons.push(Polygon::new(ring, vec![]));
+        }
+    }
+
+    Ok(MultiPolygon(polygons))
+}
+
+/// Collects all Polygon and MultiPolygon geometries of a GeoJSON document
+pub fn parse_geojson_polygons(text: &str) -> Result<MultiPolygon> {
+    let doc: Value = serde_json::from_str(text).context("invalid GeoJSON boundary")?;
+    let mut polygons = Vec::new();
+    collect_polygons(&doc, &mut polygons)?;
+    Ok(MultiPolygon(polygons))
+}
+
+fn collect_polygons(value: &Value, polygons: &mut Vec<Polygon>) -> Result<()> {
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_polygons(&value["geometry"], polygons)?,
+        Some("GeometryCollection") => {
+            for geometry in value["geometries"].as_array().into_iter().flatten() {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        Some("Polygon") => polygons.push(json_polygon(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in value["coordinates"].as_array().into_iter().flatten() {
+                polygons.push(json_polygon(polygon)?);
+            }
+        }
+        // Points and lines cannot bound an area and are ignored
+        _ => {}
+    }
+    Ok(())
+}
+
+fn json_polygon(rings: &Value) -> Result<Polygon> {
+    let mut rings = rings
+        .as_array()
+        .context("polygon coordinates must be an array of rings")?
+        .iter()
+        .map(json_ring)
+        .collect::<Result<Vec<_>>>()?;
+    if rings.is_empty() {
+        bail!("polygon without rings in GeoJSON boundary");
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+fn json_ring(ring: &Value) -> Result<LineString> {
+    ring.as_array()
+        .context("ring must be an array of positions")?
+        .iter()
+        .map(|p| match (p[0].as_f64(), p[1].as_f64()) {
+            (Some(x), Some(y)) => Ok(Coord { x, y }),
+            _ => bail!("invalid position {} in GeoJSON boundary", p),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(LineString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Point;
+
+    fn coords(ring: &LineString) -> Vec<(f64, f64)> {
+        ring.coords().map(|c| (c.x, c.y)).collect()
+    }
+
+    #[test]
+    fn bbox() {
+        let bbox = parse_bbox("13.3, 52.4,13.5 ,52.6").unwrap();
+        assert_eq!((bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y), (13.3, 52.4, 13.5, 52.6));
+        let world = parse_bbox("-180,-90,180,90").unwrap();
+        assert_eq!((world.min().x, world.max().y), (-180.0, 90.0));
+        // A box may be a line or a point
+        assert!(parse_bbox("1,2,1,2").is_ok());
+    }
+
+    #[test]
+    fn bbox_errors() {
+        let error = |s| parse_bbox(s).unwrap_err().to_string();
+        let four = "bbox must have exactly four values: min_lon,min_lat,max_lon,max_lat";
+        assert_eq!(error("1,2,3"), four);
+        assert_eq!(error("1,2,3,4,5"), four);
+        assert_eq!(error("1,2,x,4"), "invalid number 'x' in bbox");
+        assert_eq!(error(""), "invalid number '' in bbox");
+        assert_eq!(error("3,2,1,4"), "bbox minimum must not exceed maximum");
+        assert_eq!(error("1,4,3,2"), "bbox minimum must not exceed maximum");
+        assert_eq!(error("-181,0,0,1"), "bbox is outside the WGS84 coordinate range");
+        assert_eq!(error("0,0,1,90.5"), "bbox is outside the WGS84 coordinate range");
+    }
+
+    #[test]
+    fn poly() {
+        let text = "berlin
+1
+   1.0E+01   5.0E+01
+   11 50
+   11 51
+
+   10 51
+END
+!hole
+   10.2 50.2
+   10.4 50.2
+   10.4 50.4
+end
+2
+   20 50
+   21 50
+   21 51
+END
+END
+";
+        let polygons = parse_poly(text).unwrap();
+        assert_eq!(polygons.0.len(), 2);
+        let [first, second] = &polygons.0[..] else { unreachable!() };
+        // Rings are closed
+        assert_eq!(coords(first.exterior()), [(10.0, 50.0), (11.0, 50.0), (11.0, 51.0), (10.0, 51.0), (10.0, 50.0)]);
+        assert_eq!(first.interiors().len(), 1);
+        assert_eq!(coords(&first.interiors()[0]), [(10.2, 50.2), (10.4, 50.2), (10.4, 50.4), (10.2, 50.2)]);
+        assert_eq!(coords(second.exterior()), [(20.0, 50.0), (21.0, 50.0), (21.0, 51.0), (20.0, 50.0)]);
+        assert!(second.interiors().is_empty());
+        assert!(first.contains(&Point::new(10.8, 50.8)));
+        assert!(!first.contains(&Point::new(10.3, 50.25)));
+    }
+
+    #[test]
+    fn poly_errors() {
+        let error = |text| parse_poly(text).unwrap_err().to_string();
+        assert_eq!(error(""), "empty .poly file");
+        assert_eq!(error("name\n"), ".poly file is missing its final END");
+        assert_eq!(error("name\n1\n10 50\n11 50\n11 51\nEND\n"), ".poly file is missing its final END");
+        assert_eq!(error("name\n1\n10 50\n11 50\n"), "section '1' is missing its END");
+        assert_eq!(error("name\n1\n10 50\n11\nEND\nEND\n"), "invalid coordinate line '11' in .poly file");
+        assert_eq!(error("name\n1\n10 north\nEND\nEND\n"), "invalid coordinate line '10 north' in .poly file");
+        assert_eq!(
+            error("name\n!hole\n10 50\n11 50\n11 51\nEND\nEND\n"),
+            "hole section '!hole' before any polygon in .poly file"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Splitting an output into one file per part of the area it covers, e.g.
+//! per web map tile (`tiles:z8`) or per cell of a grid in degrees
+//! (`grid:0.5`), for distributing large extracts and processing the parts
+//! in parallel, per value of a tag (`tag:highway`), or per region of a
+//! polygon layer, e.g. per country.
+//!
+//! Each feature goes to the part its centroid lies in, or with
+//! [`SplitMode::Clip`] to every part it crosses, cut at the part's edges.
+
+use crate::feature::ExtractedFeature;
+use crate::join::JoinLayer;
+use crate::output::compress::Compression;
+use crate::spatial::{Boundary, ClipMode};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use geo::{BoundingRect, Centroid, Geometry, MultiPolygon, Rect};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The highest zoom level tiles can be split by
+pub const MAX_ZOOM: u8 = 20;
+
+/// How the output is split into parts
+#[derive(Debug, Clone)]
+pub enum SplitBy {
+    /// Web map tiles of a zoom level, named `z_x_y`
+    Tiles(u8),
+    /// Cells of this many degrees, counted from 180°W and 90°S and named
+    /// `x_y`, as the `--grid` of the coastline
+    Grid(f64),
+    /// Values of this tag, named after the value with the characters not
+    /// allowed in file names replaced, or `no_KEY` for features without it
+    Tag(String),
+    /// Polygons of a layer read with the property `key` only, named after
+    /// its value as for tags; features go to the polygon they lie in, as
+    /// judged by [`JoinLayer::apply`]
+    Polygons { layer: JoinLayer, key: String },
+}
+
+/// Which parts a feature crossing their edges is written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SplitMode {
+    /// Only the part its centroid lies in, with its full geometry
+    #[default]
+    Centroid,
+    /// Every part it crosses, cut at the edges of the part
+    Clip,
+}
+
+impl FromStr for SplitBy {
+    type Err = anyhow::Error;
+
+    /// Parses `tiles:z8` (or `tiles:8`), `grid:0.5` or just a cell size
+    /// in degrees, `0.5`, or `tag:KEY`
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s.split_once(':').unwrap_or(("grid", s));
+        match kind {
+            "tiles" => {
+                let zoom: u8 = value
+                    .strip_prefix('z')
+                    .unwrap_or(value)
+                    .parse()
+                    .with_context(|| format!("invalid zoom level {:?}, expected e.g. tiles:z8", value))?;
+                if zoom > MAX_ZOOM {
+                    bail!("zoom level {} is above the highest of {}", zoom, MAX_ZOOM);
+                }
+                Ok(SplitBy::Tiles(zoom))
+            }
+            "grid" => {
+                let size: f64 =
+                    value.parse().with_context(|| format!("invalid cell size {:?}, expected e.g. grid:0.5", value))?;
+                if !(size > 0.0 && size <= 360.0) {
+                    bail!("the cell size must be above 0 and at most 360 degrees");
+                }
+                Ok(SplitBy::Grid(size))
+            }
+            "tag" if !value.is_empty() => Ok(SplitBy::Tag(value.to_string())),
+            "tag" => bail!("missing the key to split by, e.g. tag:highway"),
+            _ => bail!("unknown split {:?}, expected tiles:zN, grid:DEGREES or tag:KEY", s),
+        }
+    }
+}
+
+impl fmt::Display for SplitBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitBy::Tiles(zoom) => write!(f, "tiles of zoom {}", zoom),
+            SplitBy::Grid(size) => write!(f, "a grid of {}°", size),
+            SplitBy::Tag(key) => write!(f, "the values of {}", key),
+            SplitBy::Polygons { key, .. } => write!(f, "the {} of the polygons", key),
+        }
+    }
+}
+
+// The splits by area, into cells
+#[derive(Debug, Clone, Copy)]
+enum Cells {
+    Tiles(u8),
+    Degrees(f64),
+}
+
+impl Cells {
+    // The cell column and row of a coordinate
+    fn cell(&self, lon: f64, lat: f64) -> (i64, i64) {
+        match *self {
+            Cells::Tiles(zoom) => {
+                let n = (1u64 << zoom) as f64;
+                // Web Mercator ends at about 85.05°
+                let lat = lat.clamp(-85.051_128, 85.051_128).to_radians();
+                let x = ((lon + 180.0) / 360.0 * n).floor();
+                let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * n).floor();
+                (x.clamp(0.0, n - 1.0) as i64, y.clamp(0.0, n - 1.0) as i64)
+            }
+            Cells::Degrees(size) => {
+                // 180°E and 90°N are the far edges of the last column and row
+                let (columns, rows) = ((360.0 / size).ceil(), (180.0 / size).ceil());
+                let x = ((lon + 180.0) / size).floor().min(columns - 1.0);
+                let y = ((lat + 90.0) / size).floor().min(rows - 1.0);
+                (x as i64, y as i64)
+            }
+        }
+    }
+
+    // The area of a cell
+    fn bounds(&self, (x, y): (i64, i64)) -> Rect {
+        match *self {
+            Cells::Tiles(zoom) => {
+                let n = (1u64 << zoom) as f64;
+                let lon = |x: f64| x / n * 360.0 - 180.0;
+                let lat = |y: f64| (PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees();
+                Rect::new((lon(x as f64), lat((y + 1) as f64)), (lon((x + 1) as f64), lat(y as f64)))
+            }
+            Cells::Degrees(size) => Rect::new(
+                (-180.0 + x as f64 * size, -90.0 + y as f64 * size),
+                (-180.0 + (x + 1) as f64 * size, -90.0 + (y + 1) as f64 * size),
+            ),
+        }
+    }
+
+    fn name(&self, (x, y): (i64, i64)) -> String {
+        match self {
+            Cells::Tiles(zoom) => format!("{}_{}_{}", zoom, x, y),
+            Cells::Degrees(_) => format!("{}_{}", x, y),
+        }
+    }
+}
+
+/// Splits the features into parts, by the name of the part; `mode` only
+/// applies to the splits by area
+pub fn split(features: Vec<ExtractedFeature>, by: &SplitBy, mode: SplitMode) -> BTreeMap<String, Vec<ExtractedFeature>> {
+    match by {
+        SplitBy::Tiles(zoom) => split_cells(features, Cells::Tiles(*zoom), mode),
+        SplitBy::Grid(size) => split_cells(features, Cells::Degrees(*size), mode),
+        SplitBy::Tag(key) => {
+            split_values(features, key, |feature| feature.tags().get(key.as_str()).map(|value| file_name(value)))
+        }
+        SplitBy::Polygons { layer, key } => split_values(features, key, |feature| {
+            let attributes = layer.attributes_at(feature.geometry())?;
+            attributes.first().map(|(_, value)| file_name(value))
+        }),
+    }
+}
+
+// Splits by a value of each feature, given as the name of its part, into
+// `no_KEY` where there is none
+fn split_values(
+    features: Vec<ExtractedFeature>,
+    key: &str,
+    value: impl Fn(&ExtractedFeature) -> Option<String>,
+) -> BTreeMap<String, Vec<ExtractedFeature>> {
+    let mut parts: BTreeMap<String, Vec<ExtractedFeature>> = BTreeMap::new();
+    for feature in features {
+        let name = value(&feature).unwrap_or_else(|| format!("no_{}", file_name(key)));
+        parts.entry(name).or_default().push(feature);
+    }
+    parts
+}
+
+fn split_cells(features: Vec<ExtractedFeature>, by: Cells, mode: SplitMode) -> BTreeMap<String, Vec<ExtractedFeature>> {
+    let mut parts: BTreeMap<String, Vec<ExtractedFeature>> = BTreeMap::new();
+    for feature in features {
+        let Some(bbox) = feature.geometry().bounding_rect() else {
+            continue;
+        };
+        let single = (bbox.width() == 0.0 && bbox.height() == 0.0) || mode == SplitMode::Centroid;
+        if single {
+            let center = feature.geometry().centroid().map_or(bbox.min(), |c| c.0);
+            parts.entry(by.name(by.cell(center.x, center.y))).or_default().push(feature);
+            continue;
+        }
+        // Rows of tiles count from the north, of the grid from the south
+        let (min, max) = (by.cell(bbox.min().x, bbox.min().y), by.cell(bbox.max().x, bbox.max().y));
+        for x in min.0..=max.0 {
+            for y in min.1.min(max.1)..=min.1.max(max.1) {
+                let cell = Boundary { polygons: MultiPolygon(vec![by.bounds((x, y)).to_polygon()]), mode: ClipMode::Clip };
+                if let Some(geometry) = cell.apply(feature.geometry()).filter(|g| !is_empty(g)) {
+                    let mut part = feature.clone();
+                    part.set_geometry(geometry);
+                    parts.entry(by.name((x, y))).or_default().push(part);
+                }
+            }
+        }
+    }
+    parts
+}
+
+// Clipping leaves empty collections where a geometry only touches an edge
+fn is_empty(geometry: &Geometry) -> bool {
+    match geometry {
+        Geometry::GeometryCollection(collection) => collection.iter().all(is_empty),
+        _ => geometry.bounding_rect().is_none(),
+    }
+}
+
+// A tag value usable in file names, e.g. `primary_link` as it is and
+// `yes;no` as `yes_no`; values only differing there end up in one part
+fn file_name(value: &str) -> String {
+    let name: String = value
+        .chars()
+        .take(100)
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "_".repeat(name.len().max(1)),
+        _ => name,
+    }
+}
+
+/// The file a part is written to: `roads.gpkg` gives `roads_8_134_85.gpkg`,
+/// and `roads.geojson.gz` gives `roads_8_134_85.geojson.gz`
+pub fn part_path(path: &Path, part: &str) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("split");
+    let dots: Vec<usize> = name.match_indices('.').map(|(i, _)| i).collect();
+    let extensions = if Compression::from_path(path).is_some() { 2 } else { 1 };
+    let at = dots.len().checked_sub(extensions).map_or(name.len(), |i| dots[i]);
+    path.with_file_name(format!("{}_{}{}", &name[..at], part, &name[at..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature::OsmType;
+    use crate::intern::TagMap;
+    use geo::{LineString, Point};
+
+    fn feature(id: i64, geometry: Geometry) -> ExtractedFeature {
+        ExtractedFeature::new(OsmType::Way, id, TagMap::new(), geometry)
+    }
+
+    #[test]
+    fn tiles_are_numbered_from_the_north_west() {
+        let tiles = Cells::Tiles(8);
+        assert_eq!(tiles.cell(9.0, 51.0), (134, 85));
+        let bounds = tiles.bounds((134, 85));
+        assert_eq!((bounds.min().x, bounds.max().x), (8.4375, 9.84375));
+        assert!((bounds.min().y - 50.736).abs() < 0.001 && (bounds.max().y - 51.618).abs() < 0.001);
+        // Beyond the edges of Web Mercator
+        assert_eq!(tiles.cell(-180.0, 89.0), (0, 0));
+        assert_eq!(tiles.cell(180.0, -89.0), (255, 255));
+    }
+
+    #[test]
+    fn grid_cells_are_numbered_from_the_south_west() {
+        let grid = Cells::Degrees(0.5);
+        assert_eq!(grid.cell(-180.0, -90.0), (0, 0));
+        assert_eq!(grid.cell(13.4, 52.5), (386, 285));
+        assert_eq!(grid.bounds((386, 285)), Rect::new((13.0, 52.5), (13.5, 53.0)));
+        // The far edges belong to the last column and row
+        assert_eq!(grid.cell(180.0, 90.0), (719, 359));
+        assert_eq!(Cells::Degrees(7.0).cell(180.0, 0.0), (51, 12));
+        assert_eq!(grid.name((386, 285)), "386_285");
+    }
+
+    #[test]
+    fn features_crossing_cells_are_clipped_into_each() {
+        let line = |id| feature(id, Geometry::LineString(LineString::from(vec![(0.2, 0.5), (1.8, 0.5)])));
+        let point = feature(2, Geometry::Point(Point::new(0.5, 0.5)));
+
+        let parts = split(vec![line(1), point.clone()], &SplitBy::Grid(1.0), SplitMode::Clip);
+        assert_eq!(parts.keys().collect::<Vec<_>>(), ["180_90", "181_90"]);
+        let clipped = |name: &str| parts[name][0].geometry().bounding_rect().unwrap();
+        assert_eq!((clipped("180_90").min().x, clipped("180_90").max().x), (0.2, 1.0));
+        assert_eq!((clipped("181_90").min().x, clipped("181_90").max().x), (1.0, 1.8));
+        assert_eq!(parts["180_90"].len(), 2);
+
+        // Whole, in the cell of its centroid
+        let parts = split(vec![line(1), point], &SplitBy::Grid(1.0), SplitMode::Centroid);
+        assert_eq!(parts.keys().collect::<Vec<_>>(), ["180_90", "181_90"]);
+        assert_eq!(parts["181_90"][0].geometry(), line(1).geometry());
+    }
+
+    #[test]
+    fn parts_are_named_before_the_extensions() {
+        let part = |path: &str| part_path(Path::new(path), "8_134_85");
+        assert_eq!(part("out/roads.gpkg"), Path::new("out/roads_8_134_85.gpkg"));
+        assert_eq!(part("roads.geojson.gz"), Path::new("roads_8_134_85.geojson.gz"));
+        assert_eq!(part("roads.v2.geojson"), Path::new("roads.v2_8_134_85.geojson"));
+        assert_eq!(part("roads"), Path::new("roads_8_134_85"));
+    }
+
+    #[test]
+    fn tag_values_are_made_file_names() {
+        assert_eq!(file_name("primary_link"), "primary_link");
+        assert_eq!(file_name("yes;no"), "yes_no");
+        // No path separator is left
+        assert_eq!(file_name("../etc"), ".._etc");
+        assert_eq!(file_name(".."), "__");
+        assert_eq!(file_name(""), "_");
+        assert_eq!(file_name(&"x".repeat(150)).len(), 100);
+    }
+
+    #[test]
+    fn features_without_the_tag_go_to_no_key() {
+        let tagged = |id, value: Option<&str>| {
+            let mut feature = feature(id, Geometry::Point(Point::new(0.0, 0.0)));
+            if let Some(value) = value {
+                feature.tags_mut().insert("addr:street".into(), value.into());
+            }
+            feature
+        };
+        let features = vec![tagged(1, Some("Main St")), tagged(2, None), tagged(3, Some("Main/St")), tagged(4, Some(""))];
+        let parts = split(features, &SplitBy::Tag("addr:street".into()), SplitMode::Centroid);
+        let ids = |name: &str| parts[name].iter().map(ExtractedFeature::id).collect::<Vec<_>>();
+        assert_eq!(parts.keys().collect::<Vec<_>>(), ["Main_St", "_", "no_addr_street"]);
+        assert_eq!((ids("Main_St"), ids("_"), ids("no_addr_street")), (vec![1, 3], vec![4], vec![2]));
+    }
+}
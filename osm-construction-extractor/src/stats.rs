@@ -0,0 +1,117 @@
+//! Summary statistics over matched features, for sizing an extraction.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use geo::{Geometry, HaversineLength};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Value counts of one tag key among the matched features
+#[derive(Debug, Clone, Default)]
+pub struct KeyStats {
+    /// Number of features carrying the key
+    pub count: u64,
+    pub values: HashMap<String, u64>,
+}
+
+/// Counts, lengths and size distributions collected by [`Report::add`]
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The tag keys whose values are counted
+    pub keys: Vec<String>,
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+    /// Sum of all way lengths in meters (polygon ways by their outline)
+    pub way_length: f64,
+    pub tags: BTreeMap<String, KeyStats>,
+    /// Number of ways by node count, bucketed by powers of two: the key
+    /// `n` counts ways with `n..2n` nodes
+    pub way_node_counts: BTreeMap<usize, u64>,
+    /// How many of the most common values to show per key
+    pub top_values: usize,
+}
+
+impl Report {
+    /// Creates an empty report counting the values of `keys`, typically the
+    /// keys referenced by the filter (see [`crate::Filter::keys`])
+    pub fn new(keys: &[&str]) -> Report {
+        Report {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            nodes: 0,
+            ways: 0,
+            relations: 0,
+            way_length: 0.0,
+            tags: BTreeMap::new(),
+            way_node_counts: BTreeMap::new(),
+            top_values: 10,
+        }
+    }
+
+    pub fn add(&mut self, feature: &ExtractedFeature) {
+        match feature.osm_type() {
+            OsmType::Node => self.nodes += 1,
+            OsmType::Way => {
+                self.ways += 1;
+                let line = match feature.geometry() {
+                    Geometry::LineString(line) => Some(line),
+                    Geometry::Polygon(polygon) => Some(polygon.exterior()),
+                    _ => None,
+                };
+                if let Some(line) = line {
+                    self.way_length += line.haversine_length();
+                    let bucket = 1 << line.0.len().max(1).ilog2();
+                    *self.way_node_counts.entry(bucket).or_default() += 1;
+                }
+            }
+            OsmType::Relation => self.relations += 1,
+        }
+
+        for key in &self.keys {
+            if let Some(value) = feature.tags().get(key.as_str()) {
+                let stats = self.tags.entry(key.clone()).or_default();
+                stats.count += 1;
+                *stats.values.entry(value.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.nodes + self.ways + self.relations
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Matched features: {}", self.total())?;
+        writeln!(f, "  nodes: {}", self.nodes)?;
+        writeln!(f, "  ways: {}", self.ways)?;
+        writeln!(f, "  relations: {}", self.relations)?;
+        writeln!(f, "Total way length: {:.1} km", self.way_length / 1000.0)?;
+
+        for key in &self.keys {
+            let Some(stats) = self.tags.get(key) else {
+                writeln!(f, "\n{}: not present", key)?;
+                continue;
+            };
+            writeln!(f, "\n{}: {} features, {} distinct values", key, stats.count, stats.values.len())?;
+            let mut values: Vec<(&String, &u64)> = stats.values.iter().collect();
+            values.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (value, count) in values.iter().take(self.top_values) {
+                writeln!(f, "  {:>9}  {}", count, value)?;
+            }
+            if values.len() > self.top_values {
+                writeln!(f, "  {:>9}  ({} more values)", "...", values.len() - self.top_values)?;
+            }
+        }
+
+        if !self.way_node_counts.is_empty() {
+            writeln!(f, "\nWay node counts:")?;
+            let max = self.way_node_counts.values().copied().max().unwrap_or(1);
+            for (&bucket, &count) in &self.way_node_counts {
+                let bar = "#".repeat(((count * 40).div_ceil(max)) as usize);
+                writeln!(f, "  {:>6}-{:<6} {:>9}  {}", bucket, bucket * 2 - 1, count, bar)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! Lookups of node locations and way node lists used for geometry assembly.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use geo::Coord;
+use memmap2::MmapMut;
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Relation, RelationId, WayId};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+
+/// Access to the objects a matched feature depends on
+pub trait ObjectStore {
+    /// The location of a node as lon/lat
+    fn node_location(&self, id: NodeId) -> Option<Coord>;
+
+    /// The node list of a way (needed to assemble relations)
+    fn way_nodes(&self, id: WayId) -> Option<&[NodeId]>;
+
+    /// A relation that is a member of another one, for resolving nested
+    /// relations; stores that do not keep them find none
+    fn relation(&self, _id: RelationId) -> Option<&Relation> {
+        None
+    }
+}
+
+/// The dependency map produced by `OsmPbfReader::get_objs_and_deps`
+impl ObjectStore for BTreeMap<OsmId, OsmObj> {
+    fn node_location(&self, id: NodeId) -> Option<Coord> {
+        match self.get(&id.into()) {
+            Some(OsmObj::Node(node)) => Some(Coord { x: node.lon(), y: node.lat() }),
+            _ => None,
+        }
+    }
+
+    fn way_nodes(&self, id: WayId) -> Option<&[NodeId]> {
+        match self.get(&id.into()) {
+            Some(OsmObj::Way(way)) => Some(&way.nodes),
+            _ => None,
+        }
+    }
+
+    fn relation(&self, id: RelationId) -> Option<&Relation> {
+        match self.get(&id.into()) {
+            Some(OsmObj::Relation(relation)) => Some(relation),
+            _ => None,
+        }
+    }
+}
+
+/// The store of dependency mode (see [`crate::pbf::get_objs_and_slim_deps`]):
+/// the matched objects with the ways and relations they reference, and
+/// only the locations of the nodes they reference
+#[derive(Debug)]
+pub struct DependencyStore {
+    pub objects: BTreeMap<OsmId, OsmObj>,
+    pub nodes: NodeIndex,
+}
+
+impl DependencyStore {
+    /// The number of objects and node locations held
+    pub fn len(&self) -> usize {
+        self.objects.len() + self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ObjectStore for DependencyStore {
+    fn node_location(&self, id: NodeId) -> Option<Coord> {
+        self.nodes.get(id).or_else(|| self.objects.node_location(id))
+    }
+
+    fn way_nodes(&self, id: WayId) -> Option<&[NodeId]> {
+        self.objects.way_nodes(id)
+    }
+
+    fn relation(&self, id: RelationId) -> Option<&Relation> {
+        self.objects.relation(id)
+    }
+}
+
+/// How node locations are kept in streaming mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum NodeIndexKind {
+    /// Parallel sorted arrays searched by binary search (16 bytes per node);
+    /// ideal for PBFs sorted by id, as all extracts and the planet are
+    #[default]
+    Sorted,
+    /// A hash map; faster lookups, roughly twice the memory
+    Hash,
+}
+
+// Nodes per byte of PBF input, as in the planet and large extracts
+const NODES_PER_INPUT_BYTE: f64 = 0.125;
+
+impl NodeIndexKind {
+    /// Roughly the memory the index takes for a PBF of `input_bytes`
+    pub fn estimated_size(&self, input_bytes: u64) -> u64 {
+        (input_bytes as f64 * NODES_PER_INPUT_BYTE) as u64 * self.bytes_per_node()
+    }
+
+    /// Roughly the memory the index takes per node
+    pub fn bytes_per_node(&self) -> u64 {
+        match self {
+            NodeIndexKind::Sorted => 16,
+            NodeIndexKind::Hash => 32,
+        }
+    }
+}
+
+/// A compact node id → location index storing fixed-point coordinates at
+/// the PBF's native 1e-7 degree precision
+#[derive(Debug)]
+pub enum NodeIndex {
+    Sorted {
+        ids: Vec<i64>,
+        locations: Vec<[i32; 2]>,
+        sorted: bool,
+    },
+    Hash(HashMap<i64, [i32; 2]>),
+    /// A location file on disk, see [`FlatNodes`]
+    Flat(FlatNodes),
+}
+
+impl NodeIndex {
+    pub fn new(kind: NodeIndexKind) -> NodeIndex {
+        match kind {
+            NodeIndexKind::Sorted => NodeIndex::Sorted {
+                ids: Vec::new(),
+                locations: Vec::new(),
+                sorted: true,
+            },
+            NodeIndexKind::Hash => NodeIndex::Hash(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&mut self, node: &Node) -> Result<()> {
+        let location = [node.decimicro_lon, node.decimicro_lat];
+        match self {
+            NodeIndex::Sorted { ids, locations, sorted } => {
+                if ids.last().is_some_and(|&last| last >= node.id.0) {
+                    *sorted = false;
+                }
+                ids.push(node.id.0);
+                locations.push(location);
+            }
+            NodeIndex::Hash(map) => {
+                map.insert(node.id.0, location);
+            }
+            NodeIndex::Flat(flat) => flat.insert(node.id.0, location)?,
+        }
+        Ok(())
+    }
+
+    /// Makes the index ready for lookups; only does work if nodes arrived
+    /// out of id order since the last call
+    pub fn prepare(&mut self) {
+        if let NodeIndex::Sorted { ids, locations, sorted } = self {
+            if !*sorted {
+                let mut entries: Vec<(i64, [i32; 2])> = ids.drain(..).zip(locations.drain(..)).collect();
+                entries.sort_by_key(|&(id, _)| id);
+                entries.dedup_by_key(|&mut (id, _)| id);
+                (*ids, *locations) = entries.into_iter().unzip();
+                *sorted = true;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            NodeIndex::Sorted { ids, .. } => ids.len(),
+            NodeIndex::Hash(map) => map.len(),
+            NodeIndex::Flat(flat) => flat.len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The memory held by the index, not counting the pages of a node
+    /// cache file
+    pub fn heap_size(&self) -> u64 {
+        let bytes = match self {
+            NodeIndex::Sorted { ids, locations, .. } => ids.capacity() * 8 + locations.capacity() * 8,
+            // An entry plus a control byte per bucket
+            NodeIndex::Hash(map) => map.capacity() * 17,
+            NodeIndex::Flat(_) => 0,
+        };
+        bytes as u64
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<Coord> {
+        let [lon, lat] = match self {
+            NodeIndex::Sorted { ids, locations, .. } => locations[ids.binary_search(&id.0).ok()?],
+            NodeIndex::Hash(map) => *map.get(&id.0)?,
+            NodeIndex::Flat(flat) => flat.get(id.0)?,
+        };
+        Some(Coord { x: lon as f64 * 1e-7, y: lat as f64 * 1e-7 })
+    }
+}
+
+// Layout of a node cache file: a header, then 8 bytes per node id from 0
+// up, each coordinate XORed with i32::MIN so that unset slots are the zero
+// bytes of a sparse file
+const FLAT_MAGIC: &[u8; 8] = b"OSMNODES";
+const FLAT_VERSION: u32 = 1;
+const FLAT_HEADER: usize = 64;
+// The file grows in steps of at least this many bytes
+const FLAT_GROWTH: u64 = 1 << 26;
+
+/// A dense file of node locations indexed by id, like the flatnodes file of
+/// osm2pgsql.
+///
+/// It takes 8 bytes per id up to the highest node id (over 100 GB for the
+/// planet, less on file systems with sparse files), but only the pages in
+/// use are held in memory, by the OS page cache. Once filled from a local
+/// file the cache records its size and modification time, and reopening it
+/// for the same unchanged file reuses the locations instead of refilling it.
+/// Negative ids, as in unsaved JOSM data, cannot be stored.
+#[derive(Debug)]
+pub struct FlatNodes {
+    file: File,
+    map: MmapMut,
+    len: u64,
+    complete: bool,
+}
+
+impl FlatNodes {
+    /// Opens the cache at `path`, creating it if needed. `source` identifies
+    /// the input it is filled from (see [`crate::Input::fingerprint`]); the
+    /// cache is cleared unless it was completed for the same source.
+    pub fn open(path: &Path, source: Option<[u64; 2]>) -> Result<FlatNodes> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut header = [0u8; FLAT_HEADER];
+        let existing = file.metadata()?.len() >= FLAT_HEADER as u64;
+        if existing {
+            (&file).read_exact(&mut header)?;
+            if &header[..8] != FLAT_MAGIC {
+                bail!("{} is not a node cache file", path.display());
+            }
+        }
+        let field = |range: std::ops::Range<usize>| u64::from_le_bytes(header[range].try_into().unwrap());
+        let complete = existing
+            && u32::from_le_bytes(header[8..12].try_into().unwrap()) == FLAT_VERSION
+            && header[12] == 1
+            && source.is_some_and(|source| source == [field(24..32), field(32..40)]);
+        if !complete {
+            file.set_len(0)?;
+            file.set_len(FLAT_HEADER as u64 + FLAT_GROWTH)?;
+        }
+        // SAFETY: the file is private to this process while it is mapped;
+        // another process writing it at the same time is not supported
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let mut flat = FlatNodes {
+            file,
+            map,
+            len: if complete { field(16..24) } else { 0 },
+            complete,
+        };
+        if !complete {
+            flat.write_header(None)?;
+        }
+        Ok(flat)
+    }
+
+    /// Whether the cache already holds all locations of its source, so
+    /// that inserting them again can be skipped
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Marks the cache as holding every node of `source`, if it is known
+    pub fn finish(&mut self, source: Option<[u64; 2]>) -> Result<()> {
+        if !self.complete {
+            self.write_header(source)?;
+            self.complete = source.is_some();
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, id: i64, [lon, lat]: [i32; 2]) -> Result<()> {
+        if self.complete {
+            return Ok(());
+        }
+        if id < 0 {
+            bail!("the node cache cannot hold the negative node id {}", id);
+        }
+        let offset = FLAT_HEADER + id as usize * 8;
+        if offset + 8 > self.map.len() {
+            let len = ((offset + 8) as u64).next_power_of_two().max(self.map.len() as u64 + FLAT_GROWTH);
+            self.file.set_len(len)?;
+            // SAFETY: as in `open`
+            self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        }
+        let slot = &mut self.map[offset..offset + 8];
+        if slot.iter().all(|&b| b == 0) {
+            self.len += 1;
+        }
+        slot[..4].copy_from_slice(&(lon ^ i32::MIN).to_le_bytes());
+        slot[4..].copy_from_slice(&(lat ^ i32::MIN).to_le_bytes());
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<[i32; 2]> {
+        let offset = FLAT_HEADER + usize::try_from(id).ok()? * 8;
+        let slot = self.map.get(offset..offset + 8)?;
+        if slot.iter().all(|&b| b == 0) {
+            return None;
+        }
+        let lon = i32::from_le_bytes(slot[..4].try_into().unwrap()) ^ i32::MIN;
+        let lat = i32::from_le_bytes(slot[4..].try_into().unwrap()) ^ i32::MIN;
+        Some([lon, lat])
+    }
+
+    // Records the node count, and the source once the cache is complete
+    fn write_header(&mut self, source: Option<[u64; 2]>) -> Result<()> {
+        let header = &mut self.map[..FLAT_HEADER];
+        header.fill(0);
+        header[..8].copy_from_slice(FLAT_MAGIC);
+        header[8..12].copy_from_slice(&FLAT_VERSION.to_le_bytes());
+        header[16..24].copy_from_slice(&self.len.to_le_bytes());
+        if let Some([size, modified]) = source {
+            header[12] = 1;
+            header[24..32].copy_from_slice(&size.to_le_bytes());
+            header[32..40].copy_from_slice(&modified.to_le_bytes());
+        }
+        self.map.flush()?;
+        Ok(())
+    }
+}
+
+/// The store used in streaming mode: every node location plus the node
+/// lists of ways referenced by matched relations
+#[derive(Debug)]
+pub struct StreamingStore {
+    pub nodes: NodeIndex,
+    pub ways: HashMap<WayId, Vec<NodeId>>,
+}
+
+impl StreamingStore {
+    /// Roughly the memory held by the node index and the way node lists
+    pub fn heap_size(&self) -> u64 {
+        let ways: usize = self.ways.values().map(|nodes| 32 + nodes.capacity() * 8).sum();
+        self.nodes.heap_size() + ways as u64
+    }
+}
+
+impl ObjectStore for StreamingStore {
+    fn node_location(&self, id: NodeId) -> Option<Coord> {
+        self.nodes.get(id)
+    }
+
+    fn way_nodes(&self, id: WayId) -> Option<&[NodeId]> {
+        self.ways.get(&id).map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Option<[u64; 2]> = Some([1000, 1_700_000_000]);
+
+    #[test]
+    fn flat_nodes_are_reused_for_the_same_source_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.cache");
+        let mut flat = FlatNodes::open(&path, SOURCE).unwrap();
+        assert!(!flat.is_complete());
+        flat.insert(1, [10, 20]).unwrap();
+        flat.insert(5, [-30, -40]).unwrap();
+        flat.finish(SOURCE).unwrap();
+        drop(flat);
+
+        let flat = FlatNodes::open(&path, SOURCE).unwrap();
+        assert!(flat.is_complete());
+        assert_eq!((flat.len, flat.get(1), flat.get(5), flat.get(2)), (2, Some([10, 20]), Some([-30, -40]), None));
+        drop(flat);
+
+        let flat = FlatNodes::open(&path, Some([1000, 1_700_000_001])).unwrap();
+        assert!(!flat.is_complete());
+        assert_eq!((flat.len, flat.get(1)), (0, None));
+    }
+
+    #[test]
+    fn flat_nodes_grow_past_their_first_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut flat = FlatNodes::open(&dir.path().join("nodes.cache"), None).unwrap();
+        let far = (FLAT_GROWTH / 8) as i64 + 100;
+        assert_eq!(flat.get(far), None);
+        flat.insert(1, [1, 2]).unwrap();
+        flat.insert(far, [3, 4]).unwrap();
+        assert!(flat.map.len() as u64 > FLAT_HEADER as u64 + FLAT_GROWTH);
+        assert_eq!((flat.len, flat.get(1), flat.get(far)), (2, Some([1, 2]), Some([3, 4])));
+        // Without a known source the cache is never complete
+        flat.finish(None).unwrap();
+        assert!(!flat.is_complete());
+    }
+
+    #[test]
+    fn flat_nodes_reject_negative_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut flat = FlatNodes::open(&dir.path().join("nodes.cache"), None).unwrap();
+        assert!(flat.insert(-1, [1, 2]).is_err());
+        assert_eq!(flat.get(-1), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Async API for running extractions inside a tokio runtime.
+//!
+//! Extractions themselves read files with blocking I/O and keep CPU cores
+//! busy, so [`Extractor::stream`] runs them on tokio's blocking thread pool
+//! and hands the features over through a channel. Inputs that arrive as an
+//! [`AsyncRead`], such as an upload or the body of an async HTTP client, are
+//! first [`spool`]ed to a temporary file, as extractions rewind their input.
+//! `http(s)://` inputs can be given directly, as they are downloaded on the
+//! blocking pool as well.
+
+use crate::extractor::Extractor;
+use crate::feature::ExtractedFeature;
+use crate::input::Input;
+use anyhow::{anyhow, Result};
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tempfile::TempPath;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Features assembled ahead of the consumer of a stream
+const FEATURE_BUFFER: usize = 1024;
+
+impl Extractor {
+    /// Runs the extraction on tokio's blocking thread pool, yielding
+    /// features as they are assembled; the async counterpart of
+    /// [`Extractor::features`].
+    ///
+    /// Must be called from within a tokio runtime. An error ends the stream
+    /// as its last item, and dropping the stream early stops the extraction.
+    pub fn stream(&self) -> FeatureStream {
+        let (sender, receiver) = mpsc::channel(FEATURE_BUFFER);
+        let extractor = self.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = extractor.for_each(|feature| {
+                sender.blocking_send(Ok(feature)).map_err(|_| anyhow!("the feature stream was dropped"))
+            });
+            if let Err(e) = result {
+                // Fails only if nobody is listening anymore
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+        FeatureStream {
+            receiver,
+            task: Some(task),
+        }
+    }
+}
+
+/// The features of an extraction, see [`Extractor::stream`]
+pub struct FeatureStream {
+    receiver: mpsc::Receiver<Result<ExtractedFeature>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Stream for FeatureStream {
+    type Item = Result<ExtractedFeature>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(None) => {}
+            other => return other,
+        }
+        // The extraction is done; pass on a panic instead of ending as if it
+        // had succeeded
+        if let Some(task) = &mut self.task {
+            let result = std::task::ready!(Pin::new(task).poll(cx));
+            self.task = None;
+            if let Err(e) = result {
+                if e.is_panic() {
+                    std::panic::resume_unwind(e.into_panic());
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+/// An input copied to a temporary file by [`spool`], which is deleted when
+/// this is dropped
+pub struct SpooledInput {
+    path: TempPath,
+}
+
+impl SpooledInput {
+    /// The temporary file as an input; its format is told from its first
+    /// bytes
+    pub fn input(&self) -> Input {
+        Input::Path(self.path.to_path_buf())
+    }
+}
+
+/// Copies an async reader, e.g. an uploaded file or a download, into a
+/// temporary file that extractions can read
+pub async fn spool<R: AsyncRead + Unpin>(mut reader: R) -> Result<SpooledInput> {
+    let path = tempfile::NamedTempFile::new()?.into_temp_path();
+    let mut file = tokio::fs::File::create(&path).await?;
+    tokio::io::copy(&mut reader, &mut file).await?;
+    file.flush().await?;
+    Ok(SpooledInput { path })
+}
@@ -0,0 +1,170 @@
+//! What a run of the command line tool did, as the exit code and the JSON
+//! file of `--summary-json`, so pipelines can check an extraction without
+//! parsing its log.
+//!
+//! The exit codes are stable: besides success and the usage errors clap
+//! reports with 2, an extraction tells apart failing to read or write a file
+//! and being interrupted, and with `--strict-exit` also finding nothing and
+//! leaving out matches whose geometry could not be built, which regional
+//! extracts routinely do.
+
+use crate::extractor::ExtractionStats;
+use crate::feature::SkipCause;
+use crate::info::TypeCounts;
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// How a run ended, by its exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitStatus {
+    /// 0: features were extracted and all outputs written
+    #[default]
+    Success,
+    /// 1: any other error
+    Error,
+    /// 3: nothing matched the filter, only told with `--strict-exit`
+    NoMatches,
+    /// 4: some matches were left out as their geometry could not be built,
+    /// e.g. ways with nodes outside an extract, only told with
+    /// `--strict-exit`
+    PartialGeometry,
+    /// 5: reading the input or writing an output failed
+    IoError,
+    /// 130: stopped by Ctrl-C, the outputs holding what was extracted
+    /// before
+    Interrupted,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> u8 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::Error => 1,
+            ExitStatus::NoMatches => 3,
+            ExitStatus::PartialGeometry => 4,
+            ExitStatus::IoError => 5,
+            ExitStatus::Interrupted => 130,
+        }
+    }
+
+    /// An I/O error if one caused `error`, and a plain error otherwise
+    pub fn of_error(error: &Error) -> ExitStatus {
+        if error.chain().any(|cause| cause.is::<io::Error>()) {
+            ExitStatus::IoError
+        } else {
+            ExitStatus::Error
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Error => "error",
+            ExitStatus::NoMatches => "no matches",
+            ExitStatus::PartialGeometry => "partial geometry",
+            ExitStatus::IoError => "I/O error",
+            ExitStatus::Interrupted => "interrupted",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A file or table written by the run
+#[derive(Debug, Clone, Serialize)]
+pub struct WrittenOutput {
+    /// The file, or the table for PostGIS
+    pub path: String,
+    /// As `--format` takes it, e.g. `geojson`
+    pub format: String,
+    /// Features written to it, or objects for PBF output
+    pub features: usize,
+}
+
+/// The counts, durations, warnings and outputs of an extraction
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    /// Version of this crate
+    pub version: &'static str,
+    pub status: ExitStatus,
+    pub exit_code: u8,
+    pub inputs: Vec<String>,
+    pub input_bytes: u64,
+    /// Features extracted, or objects written for PBF output
+    pub features: usize,
+    /// Features per type of the object they were built from
+    pub types: TypeCounts,
+    /// Objects held for geometry assembly, see
+    /// [`ExtractionStats::objects_read`]
+    pub objects_read: usize,
+    /// Matches left out, per cause
+    pub skipped: BTreeMap<SkipCause, usize>,
+    /// Reading and dependency resolution
+    pub extraction_ms: f64,
+    /// Geometry assembly
+    pub processing_ms: f64,
+    pub total_ms: f64,
+    pub outputs: Vec<WrittenOutput>,
+    /// The warnings logged during the run
+    pub warnings: Vec<String>,
+    /// The error the run failed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Summary {
+    pub fn new(inputs: &[impl fmt::Display]) -> Summary {
+        Summary {
+            version: env!("CARGO_PKG_VERSION"),
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            ..Summary::default()
+        }
+    }
+
+    /// Takes over the counts and durations of the extraction
+    pub fn record(&mut self, stats: &ExtractionStats) {
+        self.input_bytes = stats.input_bytes;
+        self.objects_read = stats.objects_read;
+        self.skipped = stats.skipped.clone();
+        self.extraction_ms = milliseconds(stats.extraction_duration);
+        self.processing_ms = milliseconds(stats.processing_duration);
+    }
+
+    pub fn add_output(&mut self, path: &Path, format: &str, features: usize) {
+        self.outputs.push(WrittenOutput { path: path.display().to_string(), format: format.to_string(), features });
+    }
+
+    /// How a run that did not fail ended, for `--strict-exit`: with
+    /// [`ExitStatus::PartialGeometry`] if any match was skipped,
+    /// [`ExitStatus::NoMatches`] if nothing was extracted and successfully
+    /// otherwise
+    pub fn status(&self) -> ExitStatus {
+        if self.skipped.values().any(|&count| count > 0) {
+            ExitStatus::PartialGeometry
+        } else if self.features == 0 {
+            ExitStatus::NoMatches
+        } else {
+            ExitStatus::Success
+        }
+    }
+
+    /// Records how the run ended, after `total` time
+    pub fn finish(&mut self, status: ExitStatus, error: Option<&Error>, warnings: Vec<String>, total: Duration) {
+        self.status = status;
+        self.exit_code = status.code();
+        self.error = error.map(|e| format!("{:#}", e));
+        self.warnings = warnings;
+        self.total_ms = milliseconds(total);
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
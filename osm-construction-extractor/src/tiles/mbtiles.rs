@@ -0,0 +1,65 @@
+//! MBTiles 1.3 archive writer.
+
+use super::Metadata;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use std::fs;
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE metadata (name TEXT, value TEXT);
+    CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+    CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+/// Inserts tiles into a new SQLite database within one transaction
+pub struct Writer {
+    conn: Connection,
+}
+
+impl Writer {
+    pub fn create(path: &Path) -> Result<Writer> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute_batch("BEGIN")?;
+        Ok(Writer { conn })
+    }
+
+    /// Adds a gzip-compressed tile
+    pub fn add(&mut self, z: u8, x: u32, y: u32, data: Vec<u8>) -> Result<()> {
+        // MBTiles numbers rows from the south (TMS), unlike XYZ tile URLs
+        let row = (1u32 << z) - 1 - y;
+        self.conn
+            .prepare_cached("INSERT INTO tiles VALUES (?1, ?2, ?3, ?4)")?
+            .execute(params![z, x, row, data])?;
+        Ok(())
+    }
+
+    pub fn finish(self, metadata: &Metadata) -> Result<()> {
+        let bounds = metadata.bounds;
+        let center = bounds.center();
+        let rows = [
+            ("name", metadata.name.clone()),
+            ("format", "pbf".to_string()),
+            ("type", "overlay".to_string()),
+            ("minzoom", metadata.min_zoom.to_string()),
+            ("maxzoom", metadata.max_zoom.to_string()),
+            (
+                "bounds",
+                format!("{},{},{},{}", bounds.min().x, bounds.min().y, bounds.max().x, bounds.max().y),
+            ),
+            ("center", format!("{},{},{}", center.x, center.y, metadata.min_zoom)),
+            ("json", metadata.vector_layers().to_string()),
+        ];
+        let mut insert = self.conn.prepare("INSERT INTO metadata VALUES (?1, ?2)")?;
+        for (name, value) in rows {
+            insert.execute(params![name, value])?;
+        }
+        drop(insert);
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
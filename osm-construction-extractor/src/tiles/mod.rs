@@ -0,0 +1,519 @@
+//! Vector tile archives: cuts features into Mapbox Vector Tiles over a zoom
+//! range and writes them as MBTiles or PMTiles.
+
+use crate::feature::ExtractedFeature;
+use crate::intern::Tag;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use geo::{BoundingRect, Coord, Geometry, LineString, Polygon, Rect, Simplify};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::PI;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+mod mbtiles;
+pub mod mvt;
+mod pmtiles;
+
+use mvt::{GeomType, GeometryEncoder, Layer};
+
+// Deepest supported zoom; beyond it tile coordinates lose precision
+const MAX_ZOOM: u8 = 24;
+
+// Simplification tolerance below the maximum zoom, in tile units
+const SIMPLIFY_TOLERANCE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TileFormat {
+    /// An SQLite database of tiles
+    Mbtiles,
+    /// A single-file archive that web maps can read over HTTP range requests
+    Pmtiles,
+}
+
+impl TileFormat {
+    /// Guesses the format from a file extension, e.g. `out.pmtiles`
+    pub fn from_path(path: &Path) -> Option<TileFormat> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "mbtiles" => Some(TileFormat::Mbtiles),
+            "pmtiles" => Some(TileFormat::Pmtiles),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TileFormat::Mbtiles => "MBTiles",
+            TileFormat::Pmtiles => "PMTiles",
+        })
+    }
+}
+
+/// How features are cut into tiles
+#[derive(Debug, Clone)]
+pub struct TileOptions {
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Name of the single layer holding all features
+    pub layer: String,
+    /// Tile resolution in integer coordinate units per side
+    pub extent: u32,
+    /// How far lines and polygons reach past the tile edge, in tile units,
+    /// so that strokes are not cut off visibly at tile boundaries
+    pub buffer: u32,
+}
+
+impl Default for TileOptions {
+    fn default() -> Self {
+        TileOptions {
+            min_zoom: 0,
+            max_zoom: 14,
+            layer: "features".to_string(),
+            extent: 4096,
+            buffer: 64,
+        }
+    }
+}
+
+/// What the archive metadata describes
+pub struct Metadata {
+    pub name: String,
+    pub layer: String,
+    /// Property names found in the layer
+    pub fields: BTreeSet<String>,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// Extent of all features in lon/lat
+    pub bounds: Rect,
+}
+
+impl Metadata {
+    /// The TileJSON `vector_layers` object readers use to style the layer
+    pub fn vector_layers(&self) -> Value {
+        let fields: serde_json::Map<String, Value> =
+            self.fields.iter().map(|f| (f.clone(), json!("String"))).collect();
+        json!({
+            "vector_layers": [{
+                "id": self.layer,
+                "fields": fields,
+                "minzoom": self.min_zoom,
+                "maxzoom": self.max_zoom,
+            }]
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut json = self.vector_layers();
+        json["name"] = json!(self.name);
+        json
+    }
+}
+
+enum Writer {
+    Mbtiles(mbtiles::Writer),
+    Pmtiles(pmtiles::Writer),
+}
+
+impl Writer {
+    fn add(&mut self, z: u8, x: u32, y: u32, data: Vec<u8>) -> Result<()> {
+        match self {
+            Writer::Mbtiles(w) => w.add(z, x, y, data),
+            Writer::Pmtiles(w) => w.add(z, x, y, data),
+        }
+    }
+
+    fn finish(self, metadata: &Metadata) -> Result<()> {
+        match self {
+            Writer::Mbtiles(w) => w.finish(metadata),
+            Writer::Pmtiles(w) => w.finish(metadata),
+        }
+    }
+}
+
+// A feature geometry in Web Mercator world coordinates, where the whole
+// map spans 0..1 with y pointing south
+enum Shape {
+    Points(Vec<Coord>),
+    Lines(Vec<LineString>),
+    Polygons(Vec<Polygon>),
+}
+
+struct Part {
+    feature: usize,
+    shape: Shape,
+    bounds: Rect,
+}
+
+/// Writes a tile archive of all lon/lat features to `path` and returns the
+/// number of tiles written.
+///
+/// Every feature is stored in one layer with its OSM tags and `id` as
+/// string properties. Lines and polygons are simplified to the resolution
+/// of each zoom level below `max_zoom`, which keeps full detail for
+/// overzooming. Tiles are gzip-compressed.
+pub fn write(path: &Path, format: TileFormat, features: &[ExtractedFeature], options: &TileOptions) -> Result<usize> {
+    if options.min_zoom > options.max_zoom {
+        bail!("the minimum zoom {} is above the maximum zoom {}", options.min_zoom, options.max_zoom);
+    }
+    if options.max_zoom > MAX_ZOOM {
+        bail!("zoom levels above {} are not supported", MAX_ZOOM);
+    }
+
+    let mut parts = Vec::new();
+    for (i, feature) in features.iter().enumerate() {
+        flatten(i, feature.geometry(), &mut parts);
+    }
+    let bounds = features
+        .iter()
+        .filter_map(|f| f.geometry().bounding_rect())
+        .reduce(|a, b| Rect::new(
+            Coord { x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y) },
+            Coord { x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y) },
+        ))
+        .unwrap_or_else(|| Rect::new(Coord { x: -180.0, y: -85.0 }, Coord { x: 180.0, y: 85.0 }));
+
+    let mut writer = match format {
+        TileFormat::Mbtiles => Writer::Mbtiles(mbtiles::Writer::create(path)?),
+        TileFormat::Pmtiles => Writer::Pmtiles(pmtiles::Writer::create(path)?),
+    };
+    let mut count = 0;
+    for z in options.min_zoom..=options.max_zoom {
+        let mut tiles: BTreeMap<(u32, u32), Layer> = BTreeMap::new();
+        for part in &parts {
+            cut(part, &features[part.feature], z, options, &mut tiles);
+        }
+        for ((x, y), layer) in tiles {
+            writer.add(z, x, y, gzip(&layer.into_tile())?)?;
+            count += 1;
+        }
+    }
+
+    let mut fields: BTreeSet<String> = features.iter().flat_map(|f| f.tags().keys().map(Tag::to_string)).collect();
+    fields.insert("id".to_string());
+    let metadata = Metadata {
+        name: path.file_stem().and_then(|s| s.to_str()).unwrap_or("features").to_string(),
+        layer: options.layer.clone(),
+        fields,
+        min_zoom: options.min_zoom,
+        max_zoom: options.max_zoom,
+        bounds,
+    };
+    writer.finish(&metadata)?;
+    Ok(count)
+}
+
+// Splits a geometry into parts of a single tile geometry type, since vector
+// tiles have no geometry collections
+fn flatten(feature: usize, geom: &Geometry, parts: &mut Vec<Part>) {
+    let shape = match geom {
+        Geometry::Point(p) => Shape::Points(vec![world(p.0)]),
+        Geometry::MultiPoint(mp) => Shape::Points(mp.iter().map(|p| world(p.0)).collect()),
+        Geometry::Line(l) => Shape::Lines(vec![LineString::new(vec![world(l.start), world(l.end)])]),
+        Geometry::LineString(ls) => Shape::Lines(vec![world_line(ls)]),
+        Geometry::MultiLineString(mls) => Shape::Lines(mls.iter().map(world_line).collect()),
+        Geometry::Polygon(p) => Shape::Polygons(vec![world_polygon(p)]),
+        Geometry::MultiPolygon(mp) => Shape::Polygons(mp.iter().map(world_polygon).collect()),
+        Geometry::Rect(r) => Shape::Polygons(vec![world_polygon(&r.to_polygon())]),
+        Geometry::Triangle(t) => Shape::Polygons(vec![world_polygon(&t.to_polygon())]),
+        Geometry::GeometryCollection(gc) => {
+            for g in gc {
+                flatten(feature, g, parts);
+            }
+            return;
+        }
+    };
+    let bounds = match &shape {
+        Shape::Points(points) => geo::MultiPoint::from(points.clone()).bounding_rect(),
+        Shape::Lines(lines) => geo::MultiLineString::new(lines.clone()).bounding_rect(),
+        Shape::Polygons(polygons) => geo::MultiPolygon::new(polygons.clone()).bounding_rect(),
+    };
+    if let Some(bounds) = bounds {
+        parts.push(Part { feature, shape, bounds });
+    }
+}
+
+fn world(c: Coord) -> Coord {
+    let lat = c.y.clamp(-crate::crs::MAX_MERCATOR_LAT, crate::crs::MAX_MERCATOR_LAT).to_radians();
+    Coord {
+        x: (c.x + 180.0) / 360.0,
+        y: (1.0 - (PI / 4.0 + lat / 2.0).tan().ln() / PI) / 2.0,
+    }
+}
+
+fn world_line(ls: &LineString) -> LineString {
+    ls.coords().map(|&c| world(c)).collect()
+}
+
+fn world_polygon(p: &Polygon) -> Polygon {
+    Polygon::new(world_line(p.exterior()), p.interiors().iter().map(world_line).collect())
+}
+
+// Adds the part to every tile of zoom `z` it reaches
+fn cut(part: &Part, feature: &ExtractedFeature, z: u8, options: &TileOptions, tiles: &mut BTreeMap<(u32, u32), Layer>) {
+    let extent = options.extent as f64;
+    let scale = extent * (1u64 << z) as f64;
+    let buffer = match part.shape {
+        Shape::Points(_) => 0.0,
+        _ => options.buffer as f64,
+    };
+    let last = (1u32 << z) - 1;
+    let tile_range = |min: f64, max: f64| {
+        let first = ((min * scale - buffer) / extent).floor().max(0.0) as u32;
+        let end = (((max * scale + buffer) / extent).floor().max(0.0) as u32).min(last);
+        first.min(last)..=end
+    };
+    let xs = tile_range(part.bounds.min().x, part.bounds.max().x);
+    let ys = tile_range(part.bounds.min().y, part.bounds.max().y);
+
+    // Geometry in pixel coordinates of the whole zoom level
+    let to_pixels = |c: &Coord| Coord { x: c.x * scale, y: c.y * scale };
+    let simplify = z < options.max_zoom;
+    let shape = match &part.shape {
+        Shape::Points(points) => Shape::Points(points.iter().map(to_pixels).collect()),
+        Shape::Lines(lines) => Shape::Lines(
+            lines
+                .iter()
+                .map(|ls| {
+                    let ls: LineString = ls.coords().map(to_pixels).collect();
+                    if simplify { ls.simplify(&SIMPLIFY_TOLERANCE) } else { ls }
+                })
+                .collect(),
+        ),
+        Shape::Polygons(polygons) => Shape::Polygons(
+            polygons
+                .iter()
+                .map(|p| {
+                    let p = Polygon::new(
+                        p.exterior().coords().map(to_pixels).collect(),
+                        p.interiors().iter().map(|r| r.coords().map(to_pixels).collect()).collect(),
+                    );
+                    if simplify { p.simplify(&SIMPLIFY_TOLERANCE) } else { p }
+                })
+                .collect(),
+        ),
+    };
+
+    for x in xs {
+        for y in ys.clone() {
+            let origin = Coord { x: x as f64 * extent, y: y as f64 * extent };
+            let (lo, hi) = (-buffer, extent + buffer);
+            let mut encoder = GeometryEncoder::new();
+            let geom_type = match &shape {
+                Shape::Points(points) => {
+                    let points: Vec<(i32, i32)> = points
+                        .iter()
+                        .map(|&c| c - origin)
+                        .filter(|c| c.x >= 0.0 && c.x < extent && c.y >= 0.0 && c.y < extent)
+                        .map(quantize)
+                        .collect();
+                    if !points.is_empty() {
+                        encoder.points(&points);
+                    }
+                    GeomType::Point
+                }
+                Shape::Lines(lines) => {
+                    for ls in lines {
+                        let line: Vec<Coord> = ls.coords().map(|&c| c - origin).collect();
+                        for piece in clip_line(&line, lo, hi) {
+                            let piece = dedup(piece.into_iter().map(quantize));
+                            if piece.len() >= 2 {
+                                encoder.line(&piece);
+                            }
+                        }
+                    }
+                    GeomType::LineString
+                }
+                Shape::Polygons(polygons) => {
+                    for p in polygons {
+                        let ring = |ls: &LineString, exterior: bool| {
+                            let ring: Vec<Coord> = ls.coords().map(|&c| c - origin).collect();
+                            tile_ring(&clip_ring(&ring, lo, hi), exterior)
+                        };
+                        let Some(exterior) = ring(p.exterior(), true) else { continue };
+                        encoder.ring(&exterior);
+                        for interior in p.interiors() {
+                            if let Some(interior) = ring(interior, false) {
+                                encoder.ring(&interior);
+                            }
+                        }
+                    }
+                    GeomType::Polygon
+                }
+            };
+            if encoder.is_empty() {
+                continue;
+            }
+
+            let id = feature.unique_id();
+            let properties = std::iter::once(("id", id.as_str()))
+                .chain(feature.tags().iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            tiles
+                .entry((x, y))
+                .or_insert_with(|| Layer::new(&options.layer, options.extent))
+                .add_feature(properties, geom_type, &encoder.into_commands());
+        }
+    }
+}
+
+fn quantize(c: Coord) -> (i32, i32) {
+    (c.x.round() as i32, c.y.round() as i32)
+}
+
+fn dedup(points: impl Iterator<Item = (i32, i32)>) -> Vec<(i32, i32)> {
+    let mut out: Vec<(i32, i32)> = Vec::new();
+    for p in points {
+        if out.last() != Some(&p) {
+            out.push(p);
+        }
+    }
+    out
+}
+
+// Turns a clipped ring into tile coordinates without the closing point,
+// wound the way vector tiles expect: exterior rings with a positive area in
+// the y-down tile space, interior rings with a negative one. Rings that
+// collapse when rounded are dropped.
+fn tile_ring(ring: &[Coord], exterior: bool) -> Option<Vec<(i32, i32)>> {
+    let mut ring = dedup(ring.iter().map(|&c| quantize(c)));
+    while ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    if ring.len() < 3 {
+        return None;
+    }
+    let area: i64 = (0..ring.len())
+        .map(|i| {
+            let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+            a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64
+        })
+        .sum();
+    if area == 0 {
+        return None;
+    }
+    if (area > 0) != exterior {
+        ring.reverse();
+    }
+    Some(ring)
+}
+
+// Cuts a line at the square lo..hi, returning the pieces inside it
+fn clip_line(line: &[Coord], lo: f64, hi: f64) -> Vec<Vec<Coord>> {
+    let mut pieces = Vec::new();
+    let mut current: Vec<Coord> = Vec::new();
+    for segment in line.windows(2) {
+        let (a, b) = (segment[0], segment[1]);
+        match clip_segment(a, b, lo, hi) {
+            Some((start, end)) => {
+                if current.last() != Some(&start) {
+                    if current.len() > 1 {
+                        pieces.push(std::mem::take(&mut current));
+                    }
+                    current.clear();
+                    current.push(start);
+                }
+                current.push(end);
+                if end != b {
+                    pieces.push(std::mem::take(&mut current));
+                }
+            }
+            None => {
+                if current.len() > 1 {
+                    pieces.push(std::mem::take(&mut current));
+                }
+                current.clear();
+            }
+        }
+    }
+    if current.len() > 1 {
+        pieces.push(current);
+    }
+    pieces
+}
+
+// Liang-Barsky clipping of the segment a-b
+fn clip_segment(a: Coord, b: Coord, lo: f64, hi: f64) -> Option<(Coord, Coord)> {
+    let d = b - a;
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [(-d.x, a.x - lo), (d.x, hi - a.x), (-d.y, a.y - lo), (d.y, hi - a.y)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let r = q / p;
+            if p < 0.0 {
+                if r > t1 {
+                    return None;
+                }
+                t0 = t0.max(r);
+            } else {
+                if r < t0 {
+                    return None;
+                }
+                t1 = t1.min(r);
+            }
+        }
+    }
+    let start = if t0 > 0.0 { a + d * t0 } else { a };
+    let end = if t1 < 1.0 { a + d * t1 } else { b };
+    Some((start, end))
+}
+
+// Sutherland-Hodgman clipping of a closed ring at the square lo..hi; the
+// result runs along the square's edges where the ring leaves it
+fn clip_ring(ring: &[Coord], lo: f64, hi: f64) -> Vec<Coord> {
+    if ring.iter().all(|c| c.x >= lo && c.x <= hi && c.y >= lo && c.y <= hi) {
+        return ring.to_vec();
+    }
+    let mut points: Vec<Coord> = ring.to_vec();
+    points.pop();
+    for edge in 0..4 {
+        let inside = |c: &Coord| match edge {
+            0 => c.x >= lo,
+            1 => c.x <= hi,
+            2 => c.y >= lo,
+            _ => c.y <= hi,
+        };
+        let intersect = |a: Coord, b: Coord| match edge {
+            0 | 1 => {
+                let x = if edge == 0 { lo } else { hi };
+                Coord { x, y: a.y + (b.y - a.y) * (x - a.x) / (b.x - a.x) }
+            }
+            _ => {
+                let y = if edge == 2 { lo } else { hi };
+                Coord { x: a.x + (b.x - a.x) * (y - a.y) / (b.y - a.y), y }
+            }
+        };
+        let input = std::mem::take(&mut points);
+        let Some(&last) = input.last() else { break };
+        let mut prev = last;
+        for &c in &input {
+            match (inside(&prev), inside(&c)) {
+                (true, true) => points.push(c),
+                (true, false) => points.push(intersect(prev, c)),
+                (false, true) => {
+                    points.push(intersect(prev, c));
+                    points.push(c);
+                }
+                (false, false) => {}
+            }
+            prev = c;
+        }
+    }
+    if let Some(&first) = points.first() {
+        points.push(first);
+    }
+    points
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
@@ -0,0 +1,328 @@
+//! Minimal Mapbox Vector Tile (v2) encoder.
+
+use std::collections::HashMap;
+
+// Protobuf wire types
+const VARINT: u32 = 0;
+const LEN: u32 = 2;
+
+// Geometry commands
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+/// The geometry types of a vector tile feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeomType {
+    Point = 1,
+    LineString = 2,
+    Polygon = 3,
+}
+
+/// Builds the command stream of a feature geometry from integer tile
+/// coordinates
+#[derive(Debug, Default)]
+pub struct GeometryEncoder {
+    commands: Vec<u32>,
+    cursor: (i32, i32),
+}
+
+impl GeometryEncoder {
+    pub fn new() -> GeometryEncoder {
+        GeometryEncoder::default()
+    }
+
+    /// Adds the points of a (multi)point geometry
+    pub fn points(&mut self, points: &[(i32, i32)]) {
+        self.commands.push(command(MOVE_TO, points.len()));
+        for &p in points {
+            self.delta(p);
+        }
+    }
+
+    /// Adds one linestring of at least two points
+    pub fn line(&mut self, line: &[(i32, i32)]) {
+        self.commands.push(command(MOVE_TO, 1));
+        self.delta(line[0]);
+        self.commands.push(command(LINE_TO, line.len() - 1));
+        for &p in &line[1..] {
+            self.delta(p);
+        }
+    }
+
+    /// Adds one polygon ring of at least three points, without repeating
+    /// the first point at the end
+    pub fn ring(&mut self, ring: &[(i32, i32)]) {
+        self.line(ring);
+        self.commands.push(command(CLOSE_PATH, 1));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn into_commands(self) -> Vec<u32> {
+        self.commands
+    }
+
+    fn delta(&mut self, (x, y): (i32, i32)) {
+        self.commands.push(zigzag(x - self.cursor.0));
+        self.commands.push(zigzag(y - self.cursor.1));
+        self.cursor = (x, y);
+    }
+}
+
+/// One layer of a tile, with its keys and values deduplicated
+#[derive(Debug)]
+pub struct Layer {
+    name: String,
+    extent: u32,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<String>,
+    value_index: HashMap<String, u32>,
+    features: Vec<u8>,
+}
+
+impl Layer {
+    pub fn new(name: &str, extent: u32) -> Layer {
+        Layer {
+            name: name.to_string(),
+            extent,
+            keys: Vec::new(),
+            key_index: HashMap::new(),
+            values: Vec::new(),
+            value_index: HashMap::new(),
+            features: Vec::new(),
+        }
+    }
+
+    /// Adds a feature with string properties and an encoded geometry
+    pub fn add_feature<'a>(
+        &mut self,
+        properties: impl IntoIterator<Item = (&'a str, &'a str)>,
+        geom_type: GeomType,
+        geometry: &[u32],
+    ) {
+        let mut tags = Vec::new();
+        for (key, value) in properties {
+            tags.push(intern(&mut self.keys, &mut self.key_index, key));
+            tags.push(intern(&mut self.values, &mut self.value_index, value));
+        }
+        let mut feature = Vec::new();
+        packed(&mut feature, 2, &tags);
+        field(&mut feature, 3, VARINT);
+        varint(&mut feature, geom_type as u64);
+        packed(&mut feature, 4, geometry);
+        bytes(&mut self.features, 2, &feature);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Encodes a tile holding just this layer
+    pub fn into_tile(self) -> Vec<u8> {
+        let mut layer = Vec::new();
+        field(&mut layer, 15, VARINT);
+        varint(&mut layer, 2);
+        bytes(&mut layer, 1, self.name.as_bytes());
+        layer.extend_from_slice(&self.features);
+        for key in &self.keys {
+            bytes(&mut layer, 3, key.as_bytes());
+        }
+        for value in &self.values {
+            let mut encoded = Vec::new();
+            bytes(&mut encoded, 1, value.as_bytes());
+            bytes(&mut layer, 4, &encoded);
+        }
+        field(&mut layer, 5, VARINT);
+        varint(&mut layer, self.extent as u64);
+
+        let mut tile = Vec::new();
+        bytes(&mut tile, 3, &layer);
+        tile
+    }
+}
+
+fn intern(list: &mut Vec<String>, index: &mut HashMap<String, u32>, s: &str) -> u32 {
+    if let Some(&i) = index.get(s) {
+        return i;
+    }
+    let i = list.len() as u32;
+    list.push(s.to_string());
+    index.insert(s.to_string(), i);
+    i
+}
+
+fn command(id: u32, count: usize) -> u32 {
+    id | ((count as u32) << 3)
+}
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn field(out: &mut Vec<u8>, number: u32, wire_type: u32) {
+    varint(out, ((number << 3) | wire_type) as u64);
+}
+
+fn bytes(out: &mut Vec<u8>, number: u32, data: &[u8]) {
+    field(out, number, LEN);
+    varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn packed(out: &mut Vec<u8>, number: u32, values: &[u32]) {
+    let mut data = Vec::new();
+    for &v in values {
+        varint(&mut data, v as u64);
+    }
+    bytes(out, number, &data);
+}
+
+/// Appends `n` as a protobuf base-128 varint
+pub(crate) fn varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields of a protobuf message, with the bytes of length-delimited
+    // ones and the value of varints
+    fn message(bytes: &[u8]) -> Vec<(u32, Vec<u8>, u64)> {
+        let read = |at: &mut usize| {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = bytes[*at];
+                *at += 1;
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        };
+        let mut fields = Vec::new();
+        let at = &mut 0;
+        while *at < bytes.len() {
+            let key = read(at);
+            let (number, wire_type) = ((key >> 3) as u32, (key & 7) as u32);
+            match wire_type {
+                VARINT => fields.push((number, Vec::new(), read(at))),
+                LEN => {
+                    let len = read(at) as usize;
+                    fields.push((number, bytes[*at..*at + len].to_vec(), 0));
+                    *at += len;
+                }
+                _ => panic!("unexpected wire type {}", wire_type),
+            }
+        }
+        fields
+    }
+
+    fn packed_values(bytes: &[u8]) -> Vec<u32> {
+        let mut values = Vec::new();
+        let mut at = 0;
+        while at < bytes.len() {
+            let mut value = 0u32;
+            let mut shift = 0;
+            loop {
+                let byte = bytes[at];
+                at += 1;
+                value |= u32::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            values.push(value);
+        }
+        values
+    }
+
+    fn commands(encode: impl FnOnce(&mut GeometryEncoder)) -> Vec<u32> {
+        let mut encoder = GeometryEncoder::new();
+        encode(&mut encoder);
+        encoder.into_commands()
+    }
+
+    #[test]
+    fn zigzag_and_varints() {
+        assert_eq!([0, -1, 1, -2, 2].map(zigzag), [0, 1, 2, 3, 4]);
+        assert_eq!(zigzag(i32::MAX), u32::MAX - 1);
+        assert_eq!(zigzag(i32::MIN), u32::MAX);
+        let mut out = Vec::new();
+        for n in [0, 1, 127, 128, 300, u64::MAX] {
+            varint(&mut out, n);
+        }
+        assert_eq!(out[..7], [0, 1, 127, 0x80, 1, 0xac, 2]);
+        assert_eq!(out[7..], [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1]);
+    }
+
+    // The examples of the vector tile specification, 4.3.5
+    #[test]
+    fn geometry_commands() {
+        assert_eq!(commands(|e| e.points(&[(25, 17)])), [9, 50, 34]);
+        assert_eq!(commands(|e| e.points(&[(5, 7), (3, 2)])), [17, 10, 14, 3, 9]);
+        assert_eq!(commands(|e| e.line(&[(2, 2), (2, 10), (10, 10)])), [9, 4, 4, 18, 0, 16, 16, 0]);
+        let lines = commands(|e| {
+            e.line(&[(2, 2), (2, 10), (10, 10)]);
+            e.line(&[(1, 1), (3, 5)]);
+        });
+        assert_eq!(lines, [9, 4, 4, 18, 0, 16, 16, 0, 9, 17, 17, 10, 4, 8]);
+        assert_eq!(commands(|e| e.ring(&[(3, 6), (8, 12), (20, 34)])), [9, 6, 12, 18, 10, 12, 24, 44, 15]);
+        let polygons = commands(|e| {
+            e.ring(&[(0, 0), (10, 0), (10, 10), (0, 10)]);
+            e.ring(&[(11, 11), (20, 11), (20, 20), (11, 20)]);
+            e.ring(&[(13, 13), (13, 17), (17, 17), (17, 13)]);
+        });
+        assert_eq!(
+            polygons,
+            [
+                9, 0, 0, 26, 20, 0, 0, 20, 19, 0, 15, 9, 22, 2, 26, 18, 0, 0, 18, 17, 0, 15, 9, 4, 13, 26, 0, 8, 8,
+                0, 0, 7, 15
+            ]
+        );
+        assert!(GeometryEncoder::new().is_empty());
+    }
+
+    #[test]
+    fn tile_layout() {
+        let mut layer = Layer::new("roads", 4096);
+        assert!(layer.is_empty());
+        let point = commands(|e| e.points(&[(25, 17)]));
+        layer.add_feature([("highway", "primary"), ("name", "Main")], GeomType::Point, &point);
+        layer.add_feature([("highway", "primary")], GeomType::LineString, &[9, 4, 4, 18, 0, 16, 16, 0]);
+        assert!(!layer.is_empty());
+
+        let tile = message(&layer.into_tile());
+        assert_eq!(tile.len(), 1);
+        assert_eq!(tile[0].0, 3);
+        let layer = message(&tile[0].1);
+        let numbered = |number: u32| layer.iter().filter(move |field| field.0 == number);
+        assert_eq!(numbered(15).map(|field| field.2).collect::<Vec<_>>(), [2]);
+        assert_eq!(numbered(1).map(|field| field.1.clone()).collect::<Vec<_>>(), [b"roads".to_vec()]);
+        assert_eq!(numbered(5).map(|field| field.2).collect::<Vec<_>>(), [4096]);
+        // Keys and values are stored once
+        let keys: Vec<Vec<u8>> = numbered(3).map(|field| field.1.clone()).collect();
+        assert_eq!(keys, [b"highway".to_vec(), b"name".to_vec()]);
+        let values: Vec<Vec<(u32, Vec<u8>, u64)>> = numbered(4).map(|field| message(&field.1)).collect();
+        assert_eq!(values, [vec![(1, b"primary".to_vec(), 0)], vec![(1, b"Main".to_vec(), 0)]]);
+
+        let features: Vec<Vec<(u32, Vec<u8>, u64)>> = numbered(2).map(|field| message(&field.1)).collect();
+        assert_eq!(features.len(), 2);
+        assert_eq!(packed_values(&features[0][0].1), [0, 0, 1, 1]);
+        assert_eq!(features[0][1], (3, Vec::new(), GeomType::Point as u64));
+        assert_eq!(packed_values(&features[0][2].1), point);
+        assert_eq!(packed_values(&features[1][0].1), [0, 0]);
+        assert_eq!(features[1][1], (3, Vec::new(), GeomType::LineString as u64));
+        assert_eq!(packed_values(&features[1][2].1), [9, 4, 4, 18, 0, 16, 16, 0]);
+    }
+}
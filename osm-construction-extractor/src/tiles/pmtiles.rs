@@ -0,0 +1,365 @@
+//! PMTiles v3 archive writer.
+
+use super::mvt::varint;
+use super::{gzip, Metadata};
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const HEADER_LEN: usize = 127;
+// Readers fetch the header and root directory with a single 16 KiB request
+const MAX_ROOT_LEN: usize = 16_384 - HEADER_LEN;
+
+const COMPRESSION_GZIP: u8 = 2;
+const TILE_TYPE_MVT: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u32,
+    // 0 marks a pointer to a leaf directory
+    run_length: u32,
+}
+
+/// Collects tiles in memory and lays out the archive on [`Writer::finish`]
+pub struct Writer {
+    file: File,
+    tiles: Vec<(u64, Vec<u8>)>,
+}
+
+impl Writer {
+    pub fn create(path: &Path) -> Result<Writer> {
+        Ok(Writer { file: File::create(path)?, tiles: Vec::new() })
+    }
+
+    /// Adds a gzip-compressed tile
+    pub fn add(&mut self, z: u8, x: u32, y: u32, data: Vec<u8>) -> Result<()> {
+        self.tiles.push((tile_id(z, x, y), data));
+        Ok(())
+    }
+
+    pub fn finish(mut self, metadata: &Metadata) -> Result<()> {
+        self.tiles.sort_by_key(|(id, _)| *id);
+
+        // Identical tiles (e.g. the inside of a large polygon) are stored once
+        let mut data = Vec::new();
+        let mut contents: HashMap<u64, (u64, u32)> = HashMap::new();
+        let mut entries: Vec<Entry> = Vec::new();
+        for (tile_id, tile) in &self.tiles {
+            let mut hasher = DefaultHasher::new();
+            tile.hash(&mut hasher);
+            let (offset, length) = match contents.get(&hasher.finish()) {
+                Some(&(offset, length)) if &data[offset as usize..][..length as usize] == tile.as_slice() => {
+                    (offset, length)
+                }
+                _ => {
+                    let location = (data.len() as u64, tile.len() as u32);
+                    data.extend_from_slice(tile);
+                    contents.insert(hasher.finish(), location);
+                    location
+                }
+            };
+            match entries.last_mut() {
+                Some(last)
+                    if last.tile_id + last.run_length as u64 == *tile_id
+                        && last.offset == offset
+                        && last.length == length =>
+                {
+                    last.run_length += 1
+                }
+                _ => entries.push(Entry { tile_id: *tile_id, offset, length, run_length: 1 }),
+            }
+        }
+
+        let (root, leaves) = directories(&entries)?;
+        let json = gzip(metadata.to_json().to_string().as_bytes())?;
+
+        let root_offset = HEADER_LEN as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + json.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for n in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            json.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data.len() as u64,
+            self.tiles.len() as u64,
+            entries.len() as u64,
+            contents.len() as u64,
+        ] {
+            header.extend_from_slice(&n.to_le_bytes());
+        }
+        header.extend_from_slice(&[
+            1, // clustered: tile data is ordered by tile id
+            COMPRESSION_GZIP,
+            COMPRESSION_GZIP,
+            TILE_TYPE_MVT,
+            metadata.min_zoom,
+            metadata.max_zoom,
+        ]);
+        let bounds = metadata.bounds;
+        for v in [bounds.min().x, bounds.min().y, bounds.max().x, bounds.max().y] {
+            header.extend_from_slice(&e7(v).to_le_bytes());
+        }
+        header.push(metadata.min_zoom);
+        let center = bounds.center();
+        header.extend_from_slice(&e7(center.x).to_le_bytes());
+        header.extend_from_slice(&e7(center.y).to_le_bytes());
+        debug_assert_eq!(header.len(), HEADER_LEN);
+
+        let mut out = BufWriter::new(self.file);
+        for part in [&header, &root, &json, &leaves, &data] {
+            out.write_all(part)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// Splits the entries into leaf directories until the root fits the first
+// request a reader makes
+fn directories(entries: &[Entry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = directory(entries)?;
+    if root.len() <= MAX_ROOT_LEN {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = 4096;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+        let root = directory(&root_entries)?;
+        if root.len() <= MAX_ROOT_LEN {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+fn directory(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    varint(&mut out, entries.len() as u64);
+    let mut last_id = 0;
+    for entry in entries {
+        varint(&mut out, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+    for entry in entries {
+        varint(&mut out, entry.run_length as u64);
+    }
+    for entry in entries {
+        varint(&mut out, entry.length as u64);
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        // 0 means "directly after the previous entry"
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            varint(&mut out, 0);
+        } else {
+            varint(&mut out, entry.offset + 1);
+        }
+    }
+    gzip(&out)
+}
+
+// Position of a tile on the Hilbert curve of its zoom level, after all
+// tiles of the lower zoom levels
+fn tile_id(z: u8, x: u32, y: u32) -> u64 {
+    let base = ((1u64 << (2 * z as u64)) - 1) / 3;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0;
+    let mut s = (1u64 << z) / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    base + d
+}
+
+fn e7(degrees: f64) -> i32 {
+    (degrees * 1e7).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use geo::Rect;
+    use std::io::Read;
+
+    fn read_varint(bytes: &[u8], at: &mut usize) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes[*at];
+            *at += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    // Reads a directory back the way the spec lays it out
+    fn read_directory(gzipped: &[u8]) -> Vec<Entry> {
+        let mut bytes = Vec::new();
+        GzDecoder::new(gzipped).read_to_end(&mut bytes).unwrap();
+        let at = &mut 0;
+        let len = read_varint(&bytes, at) as usize;
+        let mut entries = vec![Entry { tile_id: 0, offset: 0, length: 0, run_length: 0 }; len];
+        let mut last_id = 0;
+        for entry in &mut entries {
+            last_id += read_varint(&bytes, at);
+            entry.tile_id = last_id;
+        }
+        for entry in &mut entries {
+            entry.run_length = read_varint(&bytes, at) as u32;
+        }
+        for entry in &mut entries {
+            entry.length = read_varint(&bytes, at) as u32;
+        }
+        for i in 0..len {
+            entries[i].offset = match read_varint(&bytes, at) {
+                0 => entries[i - 1].offset + entries[i - 1].length as u64,
+                offset => offset - 1,
+            };
+        }
+        assert_eq!(*at, bytes.len());
+        entries
+    }
+
+    fn fields(entries: &[Entry]) -> Vec<(u64, u64, u32, u32)> {
+        entries.iter().map(|e| (e.tile_id, e.offset, e.length, e.run_length)).collect()
+    }
+
+    #[test]
+    fn tile_ids() {
+        // The ids of the specification and its reference implementations
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(1, 0, 0), 1);
+        assert_eq!(tile_id(1, 0, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 1, 0), 4);
+        assert_eq!(tile_id(2, 0, 0), 5);
+        assert_eq!(tile_id(12, 3423, 1763), 19_078_479);
+        // Every zoom level starts after all tiles of the ones before
+        for z in 0..=20u8 {
+            let base = (0..z).map(|z| 1u64 << (2 * z)).sum::<u64>();
+            assert_eq!(tile_id(z, 0, 0), base);
+            let last = (1u32 << z) - 1;
+            assert_eq!(tile_id(z, last, 0), base + (1u64 << (2 * z)) - 1);
+        }
+        // A zoom level is covered once
+        let mut ids: Vec<u64> = (0..8).flat_map(|x| (0..8).map(move |y| tile_id(3, x, y))).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (21..85).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let entries = [
+            Entry { tile_id: 0, offset: 0, length: 100, run_length: 1 },
+            // Right after the previous tile
+            Entry { tile_id: 1, offset: 100, length: 20, run_length: 3 },
+            // Sharing the data of the first tile
+            Entry { tile_id: 7, offset: 0, length: 100, run_length: 1 },
+            Entry { tile_id: 19_078_479, offset: 120, length: 300, run_length: 1 },
+        ];
+        let bytes = directory(&entries).unwrap();
+        assert_eq!(fields(&read_directory(&bytes)), fields(&entries));
+
+        let (root, leaves) = directories(&entries).unwrap();
+        assert_eq!(fields(&read_directory(&root)), fields(&entries));
+        assert!(leaves.is_empty());
+    }
+
+    #[test]
+    fn large_directories_are_split_into_leaves() {
+        // Lengths that hardly compress keep the directory large
+        let mut offset = 0;
+        let mut state = 1u64;
+        let entries: Vec<Entry> = (0..200_000)
+            .map(|i| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                let length = (state >> 40) as u32 % 100_000 + 1;
+                let entry = Entry { tile_id: i * 3, offset, length, run_length: 1 };
+                offset += length as u64;
+                entry
+            })
+            .collect();
+        let (root, leaves) = directories(&entries).unwrap();
+        assert!(root.len() <= MAX_ROOT_LEN);
+        let pointers = read_directory(&root);
+        assert!(pointers.len() > 1);
+        let mut read = Vec::new();
+        for pointer in &pointers {
+            assert_eq!(pointer.run_length, 0);
+            let leaf = read_directory(&leaves[pointer.offset as usize..][..pointer.length as usize]);
+            assert_eq!(leaf[0].tile_id, pointer.tile_id);
+            read.extend(leaf);
+        }
+        assert_eq!(fields(&read), fields(&entries));
+    }
+
+    #[test]
+    fn archive_layout() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = Writer::create(file.path()).unwrap();
+        writer.add(1, 1, 0, b"second".to_vec()).unwrap();
+        writer.add(0, 0, 0, b"first".to_vec()).unwrap();
+        writer.add(1, 0, 0, b"first".to_vec()).unwrap();
+        let metadata = Metadata {
+            name: "test".to_string(),
+            layer: "features".to_string(),
+            fields: Default::default(),
+            min_zoom: 0,
+            max_zoom: 1,
+            bounds: Rect::new((-10.0, -5.0), (10.0, 5.0)),
+        };
+        writer.finish(&metadata).unwrap();
+
+        let bytes = std::fs::read(file.path()).unwrap();
+        assert_eq!(&bytes[..8], b"PMTiles\x03");
+        let number = |i: usize| u64::from_le_bytes(bytes[8 + 8 * i..][..8].try_into().unwrap());
+        let (root_offset, root_len, data_offset) = (number(0), number(1), number(6));
+        assert_eq!(root_offset, HEADER_LEN as u64);
+        // Tiles, entries and distinct contents
+        assert_eq!((number(8), number(9), number(10)), (3, 2, 2));
+        let root = read_directory(&bytes[root_offset as usize..][..root_len as usize]);
+        // The tiles sorted by id, the repeated content stored once as a run
+        assert_eq!(fields(&root), [(0, 0, 5, 2), (4, 5, 6, 1)]);
+        assert_eq!(&bytes[data_offset as usize..], b"firstsecond");
+        assert_eq!(bytes[96..102], [1, COMPRESSION_GZIP, COMPRESSION_GZIP, TILE_TYPE_MVT, 0, 1]);
+        assert_eq!(i32::from_le_bytes(bytes[102..106].try_into().unwrap()), -100_000_000);
+    }
+}
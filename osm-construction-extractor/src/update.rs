@@ -0,0 +1,446 @@
+//! Incremental updates of an extraction from OSM change files (`.osc`,
+//! `.osc.gz`), as published by the minutely, hourly and daily replication.
+//!
+//! A [`State`] holds the objects matching a filter plus everything they
+//! reference, as [`Extractor::objects`](crate::Extractor::objects) returns
+//! them. Applying a change file to it updates these objects and returns
+//! only the features that were created, modified or deleted, with the kind
+//! of change in [`CHANGE_TYPE_TAG`].
+//!
+//! Change files only carry the objects that were edited. A way that starts
+//! to match, say because a `construction` tag was added to it, can thus
+//! only be assembled if its nodes are in the state already or were edited
+//! as well; otherwise it is left out with a warning until an edit brings
+//! them in.
+
+use crate::feature::{ExtractedFeature, OsmType};
+use crate::filter::Filter;
+use crate::metadata;
+use crate::output;
+use crate::xml::{self, Action};
+use anyhow::{Context, Result};
+use osmpbfreader::{OsmId, OsmObj, OsmPbfReader, Tags};
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Property added to the features [`State::apply`] returns: `create`,
+/// `modify` or `delete`
+pub const CHANGE_TYPE_TAG: &str = "@change_type";
+/// Property added by [`add_comments`]: the comment of the changeset a
+/// feature was last edited in
+pub const COMMENT_TAG: &str = "@comment";
+
+const OBJECTS_FILE: &str = "objects.osm.pbf";
+const STATE_FILE: &str = "state.json";
+
+/// How a feature changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    /// It matches now but did not before
+    Create,
+    /// Its tags or geometry changed
+    Modify,
+    /// It no longer matches or was deleted; the feature is the old one
+    Delete,
+}
+
+impl fmt::Display for ChangeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChangeType::Create => "create",
+            ChangeType::Modify => "modify",
+            ChangeType::Delete => "delete",
+        })
+    }
+}
+
+/// The matched objects of an extraction, kept between updates
+pub struct State {
+    pub filter: Filter,
+    pub types: Vec<OsmType>,
+    /// The latest edit applied, as seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    objects: BTreeMap<OsmId, OsmObj>,
+    with_metadata: bool,
+}
+
+// What state.json holds next to the objects
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    filter: String,
+    types: Vec<OsmType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+}
+
+impl State {
+    /// Starts a state from the objects of an extraction with `filter` and
+    /// `types`
+    pub fn new(filter: Filter, types: &[OsmType], objects: BTreeMap<OsmId, OsmObj>) -> State {
+        State {
+            filter,
+            types: types.to_vec(),
+            timestamp: None,
+            objects,
+            with_metadata: false,
+        }
+    }
+
+    /// Keeps the metadata of edited objects as tags, like
+    /// [`Extractor::with_metadata`](crate::Extractor::with_metadata), so
+    /// that the changed features tell who changed them in which changeset;
+    /// deleted features get the metadata of the edit that deleted them or
+    /// made them stop matching. A state saved with [`State::save`] loses it.
+    pub fn with_metadata(mut self, with_metadata: bool) -> State {
+        self.with_metadata = with_metadata;
+        self
+    }
+
+    /// Loads a state saved with [`State::save`]
+    pub fn load(dir: &Path) -> Result<State> {
+        let path = dir.join(STATE_FILE);
+        let text = fs::read_to_string(&path).with_context(|| format!("no update state in {:?}", dir))?;
+        let file: StateFile = serde_json::from_str(&text).with_context(|| format!("invalid state {:?}", path))?;
+        let mut objects = BTreeMap::new();
+        let mut reader = OsmPbfReader::new(File::open(dir.join(OBJECTS_FILE))?);
+        for obj in reader.iter() {
+            let obj = obj?;
+            objects.insert(obj.id(), obj);
+        }
+        Ok(State {
+            filter: file.filter.parse().with_context(|| format!("invalid filter in {:?}", path))?,
+            types: file.types,
+            timestamp: file.timestamp.map(|t| metadata::parse_timestamp(&t)).transpose()?,
+            objects,
+            with_metadata: false,
+        })
+    }
+
+    /// Saves the state into `dir`, creating it if needed; the previous
+    /// state there is only replaced once the new one is complete
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let partial = dir.join(format!("{}.partial", OBJECTS_FILE));
+        output::pbf::write(&partial, self.objects.values().cloned())?;
+        let file = StateFile {
+            filter: self.filter.to_string(),
+            types: self.types.clone(),
+            timestamp: self.timestamp.map(metadata::format_timestamp),
+        };
+        fs::rename(&partial, dir.join(OBJECTS_FILE))?;
+        fs::write(dir.join(STATE_FILE), serde_json::to_string_pretty(&file)?)?;
+        Ok(())
+    }
+
+    /// The number of objects held, matched or referenced
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Applies the objects of a change file, as read by [`read_changes`],
+    /// in order, and returns the features that changed, sorted by type and
+    /// id.
+    ///
+    /// Besides the edited objects themselves this finds the ways whose
+    /// nodes moved and the relations whose members changed.
+    pub fn apply(&mut self, changes: Vec<(Action, OsmObj, metadata::Metadata)>) -> Vec<ExtractedFeature> {
+        let mut affected: BTreeSet<OsmId> = changes.iter().map(|(_, obj, _)| obj.id()).collect();
+        let users = self.users();
+        let mut queue: Vec<OsmId> = affected.iter().copied().collect();
+        while let Some(id) = queue.pop() {
+            for &user in users.get(&id).into_iter().flatten() {
+                if affected.insert(user) {
+                    queue.push(user);
+                }
+            }
+        }
+        let mut before: HashMap<OsmId, ExtractedFeature> = HashMap::new();
+        for id in &affected {
+            if let Some(feature) = self.feature(id) {
+                before.insert(*id, feature);
+            }
+        }
+
+        // Edited objects that are neither kept nor matched, in case a
+        // matched object turns out to need them
+        let mut unused: HashMap<OsmId, OsmObj> = HashMap::new();
+        // The metadata of the last edit of each object, for the features
+        // that no longer match
+        let mut edits: HashMap<OsmId, Tags> = HashMap::new();
+        for (action, mut obj, metadata) in changes {
+            if let Some(timestamp) = metadata.timestamp {
+                self.timestamp = Some(self.timestamp.map_or(timestamp, |t| t.max(timestamp)));
+            }
+            let id = obj.id();
+            if self.with_metadata {
+                metadata.add_to(obj_tags(&mut obj));
+                let mut edit = Tags::new();
+                metadata.add_to(&mut edit);
+                edits.insert(id, edit);
+            }
+            match action {
+                Action::Delete => {
+                    self.objects.remove(&id);
+                    unused.remove(&id);
+                }
+                Action::Create | Action::Modify => {
+                    if self.objects.contains_key(&id) || self.is_match(&obj) {
+                        self.objects.insert(id, obj);
+                    } else {
+                        unused.insert(id, obj);
+                    }
+                }
+            }
+        }
+        self.keep_referenced(&mut unused);
+
+        let mut changed = Vec::new();
+        for id in &affected {
+            let (mut feature, change) = match (before.remove(id), self.feature(id)) {
+                (None, Some(new)) => (new, ChangeType::Create),
+                (Some(old), None) => (old, ChangeType::Delete),
+                (Some(old), Some(new)) if old.tags() != new.tags() || old.geometry() != new.geometry() => {
+                    (new, ChangeType::Modify)
+                }
+                _ => continue,
+            };
+            if let (ChangeType::Delete, Some(edit)) = (change, edits.get(id)) {
+                for (key, value) in edit.iter() {
+                    feature.tags_mut().insert(key.as_str().into(), value.as_str().into());
+                }
+            }
+            feature.tags_mut().insert(CHANGE_TYPE_TAG.into(), change.to_string().into());
+            changed.push(feature);
+        }
+        changed
+    }
+
+    /// Applies the changes made before `since` without reporting them and
+    /// then those made from `since` until before `until`, returning the
+    /// features those changed: what changed between the two dates. Changes
+    /// without a timestamp count as made in between; later ones are left
+    /// out.
+    pub fn apply_between(
+        &mut self,
+        changes: Vec<(Action, OsmObj, metadata::Metadata)>,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Vec<ExtractedFeature> {
+        let (earlier, mut between): (Vec<_>, Vec<_>) = changes
+            .into_iter()
+            .partition(|(_, _, metadata)| since.zip(metadata.timestamp).is_some_and(|(since, t)| t < since));
+        between.retain(|(_, _, metadata)| until.zip(metadata.timestamp).is_none_or(|(until, t)| t < until));
+        self.apply(earlier);
+        self.apply(between)
+    }
+
+    fn is_match(&self, obj: &OsmObj) -> bool {
+        match obj {
+            OsmObj::Way(way) if way.nodes.len() < 2 => false,
+            _ => self.types.contains(&OsmType::of(obj)) && self.filter.matches(obj.tags()),
+        }
+    }
+
+    fn feature(&self, id: &OsmId) -> Option<ExtractedFeature> {
+        let obj = self.objects.get(id).filter(|obj| self.is_match(obj))?;
+        ExtractedFeature::build(obj, &self.objects)
+    }
+
+    // The ways and relations referencing each object
+    fn users(&self) -> HashMap<OsmId, Vec<OsmId>> {
+        let mut users: HashMap<OsmId, Vec<OsmId>> = HashMap::new();
+        for (&id, obj) in &self.objects {
+            for reference in references(obj) {
+                users.entry(reference).or_default().push(id);
+            }
+        }
+        users
+    }
+
+    // Drops whatever no matched object needs anymore and takes over the
+    // unused edited objects that one does need now
+    fn keep_referenced(&mut self, unused: &mut HashMap<OsmId, OsmObj>) {
+        let matched: Vec<OsmId> = self.objects.values().filter(|obj| self.is_match(obj)).map(OsmObj::id).collect();
+        let mut needed = HashSet::new();
+        let mut queue = matched;
+        while let Some(id) = queue.pop() {
+            if !needed.insert(id) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.objects.entry(id) {
+                // Assembling the feature warns about what is still missing
+                let Some(obj) = unused.remove(&id) else {
+                    continue;
+                };
+                entry.insert(obj);
+            }
+            queue.extend(references(&self.objects[&id]));
+        }
+        self.objects.retain(|id, _| needed.contains(id));
+    }
+}
+
+// The objects needed to assemble a feature; member relations are left out,
+// as by an extraction without a relation depth
+fn references(obj: &OsmObj) -> Box<dyn Iterator<Item = OsmId> + '_> {
+    match obj {
+        OsmObj::Node(_) => Box::new(std::iter::empty()),
+        OsmObj::Way(way) => Box::new(way.nodes.iter().map(|&node| OsmId::Node(node))),
+        OsmObj::Relation(relation) => Box::new(
+            relation
+                .refs
+                .iter()
+                .map(|member| member.member)
+                .filter(|member| !matches!(member, OsmId::Relation(_))),
+        ),
+    }
+}
+
+/// Reads an osmChange file, plain or gzip or bzip2 compressed as told by
+/// its extension
+pub fn read_changes(path: &Path) -> Result<Vec<(Action, OsmObj, metadata::Metadata)>> {
+    xml::read_changes(open(path)?).with_context(|| format!("cannot read change file {:?}", path))
+}
+
+/// Reads the tags of the changesets in a changeset file, such as the
+/// `changesets-*.osm.bz2` dumps or the replication files of
+/// planet.openstreetmap.org, by changeset id
+pub fn read_changesets(path: &Path) -> Result<HashMap<i64, Tags>> {
+    xml::read_changesets(open(path)?).with_context(|| format!("cannot read changeset file {:?}", path))
+}
+
+/// Adds the `comment` of the changeset each feature was last edited in, by
+/// its [`metadata::CHANGESET_TAG`], as [`COMMENT_TAG`]
+pub fn add_comments(features: &mut [ExtractedFeature], changesets: &HashMap<i64, Tags>) {
+    for feature in features {
+        let changeset = feature.tags().get(metadata::CHANGESET_TAG).and_then(|c| c.parse::<i64>().ok());
+        let comment = changeset.and_then(|id| changesets.get(&id)?.get("comment"));
+        if let Some(comment) = comment {
+            feature.tags_mut().insert(COMMENT_TAG.into(), comment.to_string().into());
+        }
+    }
+}
+
+// Opens a file, decompressing it as told by its extension
+fn open(path: &Path) -> Result<BufReader<Box<dyn Read>>> {
+    let file = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+    let name = path.to_string_lossy().to_ascii_lowercase();
+    let reader: Box<dyn Read> = if name.ends_with(".gz") {
+        Box::new(flate2::read::MultiGzDecoder::new(file))
+    } else if name.ends_with(".bz2") {
+        Box::new(bzip2::read::MultiBzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(BufReader::new(reader))
+}
+
+fn obj_tags(obj: &mut OsmObj) -> &mut Tags {
+    match obj {
+        OsmObj::Node(node) => &mut node.tags,
+        OsmObj::Way(way) => &mut way.tags,
+        OsmObj::Relation(relation) => &mut relation.tags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+    use geo::Geometry;
+    use osmpbfreader::{Node, NodeId, Way, WayId};
+
+    fn node(id: i64, x: i32) -> OsmObj {
+        OsmObj::Node(Node { id: NodeId(id), tags: Tags::new(), decimicro_lat: 0, decimicro_lon: x * 10_000_000 })
+    }
+
+    fn way(id: i64, highway: Option<&str>, nodes: &[i64]) -> OsmObj {
+        let mut tags = Tags::new();
+        if let Some(highway) = highway {
+            tags.insert("highway".into(), highway.into());
+        }
+        OsmObj::Way(Way { id: WayId(id), tags, nodes: nodes.iter().map(|&n| NodeId(n)).collect() })
+    }
+
+    // Two roads between nodes 1 and 2, which are all the state holds
+    fn state() -> State {
+        let objects = [node(1, 0), node(2, 1), way(10, Some("residential"), &[1, 2]), way(12, Some("service"), &[2, 1])];
+        State::new("highway=*".parse().unwrap(), &[OsmType::Way], objects.into_iter().map(|obj| (obj.id(), obj)).collect())
+    }
+
+    fn change(action: Action, obj: OsmObj) -> (Action, OsmObj, Metadata) {
+        (action, obj, Metadata::default())
+    }
+
+    fn at(timestamp: i64, action: Action, obj: OsmObj) -> (Action, OsmObj, Metadata) {
+        (action, obj, Metadata { timestamp: Some(timestamp), ..Metadata::default() })
+    }
+
+    // The unique ids of the features with their change type and highway
+    fn changes(features: &[ExtractedFeature]) -> Vec<(String, String, String)> {
+        let tag = |feature: &ExtractedFeature, key: &str| feature.tags().get(key).map_or("", |v| v.as_str()).to_string();
+        features.iter().map(|f| (f.unique_id(), tag(f, CHANGE_TYPE_TAG), tag(f, "highway"))).collect()
+    }
+
+    fn expected(changes: &[(&str, &str, &str)]) -> Vec<(String, String, String)> {
+        changes.iter().map(|&(id, change, highway)| (id.into(), change.into(), highway.into())).collect()
+    }
+
+    #[test]
+    fn edits_are_reported_by_change_type() {
+        let mut state = state();
+        let features = state.apply(vec![
+            change(Action::Modify, way(10, Some("primary"), &[1, 2])),
+            change(Action::Delete, way(12, None, &[])),
+            // Neither matched nor needed by the time it is read, but then
+            // the new road needs it
+            change(Action::Create, node(3, 2)),
+            change(Action::Create, way(11, Some("track"), &[2, 3])),
+            change(Action::Create, way(13, None, &[1, 3])),
+        ]);
+        assert_eq!(
+            changes(&features),
+            expected(&[("w10", "modify", "primary"), ("w11", "create", "track"), ("w12", "delete", "service")])
+        );
+        assert_eq!(state.len(), 5);
+        assert!(state.objects.contains_key(&NodeId(3).into()));
+        assert!(!state.objects.contains_key(&WayId(13).into()));
+    }
+
+    #[test]
+    fn moved_nodes_modify_their_ways() {
+        let mut state = state();
+        let features = state.apply(vec![change(Action::Modify, node(2, 5))]);
+        assert_eq!(
+            changes(&features),
+            expected(&[("w10", "modify", "residential"), ("w12", "modify", "service")])
+        );
+        assert_eq!(features[0].geometry(), &Geometry::LineString(vec![(0.0, 0.0), (5.0, 0.0)].into()));
+    }
+
+    #[test]
+    fn changes_before_the_range_are_applied_silently() {
+        let mut state = state();
+        let features = state.apply_between(
+            vec![
+                at(100, Action::Modify, way(10, Some("primary"), &[1, 2])),
+                at(200, Action::Modify, way(10, Some("secondary"), &[1, 2])),
+                at(300, Action::Delete, way(10, None, &[])),
+            ],
+            Some(150),
+            Some(250),
+        );
+        assert_eq!(changes(&features), expected(&[("w10", "modify", "secondary")]));
+        assert_eq!(state.timestamp, Some(200));
+        assert!(state.objects.contains_key(&WayId(10).into()));
+    }
+}
@@ -0,0 +1,299 @@
+//! Checks of polygon validity: unclosed rings, rings with too few points or
+//! running the wrong way, rings crossing or touching themselves or each
+//! other along a stretch, and polygons of a multipolygon overlapping; and
+//! the repair of invalid polygons.
+//!
+//! Segments are tested for crossings with a sweep line, so large boundaries
+//! are checked in `O((n + k) log n)` for `n` segments and `k` crossings.
+
+use crate::feature::ExtractedFeature;
+use clap::ValueEnum;
+use geo::orient::{Direction, Orient};
+use geo::sweep::{Cross, Intersections, LineOrPoint};
+use geo::winding_order::Winding;
+use geo::{
+    BooleanOps, BoundingRect, Contains, Coord, Geometry, InteriorPoint, Intersects, Line, LineIntersection,
+    LineString, MultiPolygon, Polygon,
+};
+use std::fmt;
+
+/// Property telling whether the polygons of a feature are valid (see
+/// [`check`]), `true` or `false`
+pub const VALID_TAG: &str = "@valid";
+/// Property holding what is wrong with an invalid feature
+pub const INVALID_REASON_TAG: &str = "@invalid_reason";
+/// Property holding what was wrong with a feature whose polygons were
+/// repaired
+pub const REPAIRED_TAG: &str = "@repaired";
+
+/// What happens to features with invalid polygons
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InvalidMode {
+    /// Keep them, with `@valid` and `@invalid_reason` set on every polygon
+    /// feature
+    Flag,
+    /// Leave them out
+    Drop,
+    /// Rebuild the polygons from the area their rings enclose, flagging
+    /// those that cannot be repaired
+    Repair,
+}
+
+/// Why a polygon is invalid
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A ring whose last point is not its first
+    UnclosedRing,
+    /// A ring with fewer than four points, or three distinct ones
+    TooFewPoints,
+    /// An outer ring running clockwise or an inner ring counterclockwise
+    WrongOrientation,
+    /// Two segments cross, or run along each other, at this point
+    SelfIntersection(Coord),
+    /// A ring touches itself at this vertex, as in a figure eight
+    RingSelfTouch(Coord),
+    /// Two polygons of a multipolygon overlap
+    OverlappingPolygons,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnclosedRing => f.write_str("unclosed ring"),
+            Problem::TooFewPoints => f.write_str("ring with fewer than 4 points"),
+            Problem::WrongOrientation => f.write_str("wrong ring orientation"),
+            Problem::SelfIntersection(c) => write!(f, "self-intersection at {} {}", c.x, c.y),
+            Problem::RingSelfTouch(c) => write!(f, "ring touching itself at {} {}", c.x, c.y),
+            Problem::OverlappingPolygons => f.write_str("overlapping polygons"),
+        }
+    }
+}
+
+// A segment of a ring, knowing its neighbours
+#[derive(Debug, Clone)]
+struct Segment {
+    line: Line,
+    ring: usize,
+    index: usize,
+    ring_segments: usize,
+}
+
+impl Segment {
+    fn adjacent(&self, other: &Segment) -> bool {
+        let last = self.ring_segments - 1;
+        self.ring == other.ring
+            && (self.index.abs_diff(other.index) == 1
+                || (self.index == 0 && other.index == last)
+                || (self.index == last && other.index == 0))
+    }
+}
+
+impl Cross for Segment {
+    type Scalar = f64;
+
+    fn line(&self) -> LineOrPoint<f64> {
+        self.line.into()
+    }
+}
+
+/// The first problem found with a polygon or multipolygon; other geometries
+/// are not checked and count as valid
+pub fn check(geometry: &Geometry) -> Option<Problem> {
+    let polygons: Vec<&Polygon> = match geometry {
+        Geometry::Polygon(polygon) => vec![polygon],
+        Geometry::MultiPolygon(multi) => multi.0.iter().collect(),
+        _ => return None,
+    };
+    let mut segments = Vec::new();
+    let rings = polygons.iter().flat_map(|p| std::iter::once(p.exterior()).chain(p.interiors()));
+    for (ring, line_string) in rings.clone().enumerate() {
+        if !line_string.is_closed() {
+            return Some(Problem::UnclosedRing);
+        }
+        let mut coords: Vec<Coord> = line_string.coords().copied().collect();
+        coords.dedup();
+        if coords.len() < 4 {
+            return Some(Problem::TooFewPoints);
+        }
+        let ring_segments = coords.len() - 1;
+        segments.extend(coords.windows(2).enumerate().map(|(index, pair)| Segment {
+            line: Line::new(pair[0], pair[1]),
+            ring,
+            index,
+            ring_segments,
+        }));
+    }
+
+    for polygon in &polygons {
+        if polygon.exterior().is_cw() || polygon.interiors().iter().any(|ring| ring.is_ccw()) {
+            return Some(Problem::WrongOrientation);
+        }
+    }
+
+    for (a, b, intersection) in Intersections::from_iter(segments) {
+        match intersection {
+            LineIntersection::SinglePoint { intersection, is_proper: true } => {
+                return Some(Problem::SelfIntersection(intersection));
+            }
+            LineIntersection::Collinear { intersection } => {
+                return Some(Problem::SelfIntersection(intersection.start));
+            }
+            // Rings may touch each other at a point, but not themselves
+            LineIntersection::SinglePoint { intersection, is_proper: false } => {
+                if a.ring == b.ring && !a.adjacent(&b) {
+                    return Some(Problem::RingSelfTouch(intersection));
+                }
+            }
+        }
+    }
+
+    // With no rings crossing, polygons overlap only if one lies within
+    // another, which a point on it tells
+    for (i, a) in polygons.iter().enumerate() {
+        for b in &polygons[i + 1..] {
+            let (Some(box_a), Some(box_b)) = (a.bounding_rect(), b.bounding_rect()) else {
+                continue;
+            };
+            if !box_a.intersects(&box_b) {
+                continue;
+            }
+            let inside = |outer: &Polygon, inner: &Polygon| inner.interior_point().is_some_and(|p| outer.contains(&p));
+            if inside(a, b) || inside(b, a) {
+                return Some(Problem::OverlappingPolygons);
+            }
+        }
+    }
+    None
+}
+
+/// A valid geometry covering what the rings of an invalid polygon or
+/// multipolygon enclose, in the manner of `buffer(0)`: closed, oriented,
+/// with crossing rings split where they cross and overlapping polygons
+/// merged. `None` if nothing valid is left of it.
+pub fn repair(geometry: &Geometry) -> Option<Geometry> {
+    let polygons: Vec<Polygon> = match geometry {
+        Geometry::Polygon(polygon) => vec![polygon.clone()],
+        Geometry::MultiPolygon(multi) => multi.0.clone(),
+        _ => return None,
+    };
+    // Rebuilding closes the rings
+    let polygons: Vec<Polygon> = polygons
+        .into_iter()
+        .map(|p| Polygon::new(p.exterior().clone(), p.interiors().to_vec()).orient(Direction::Default))
+        .collect();
+    let mut repaired = MultiPolygon(polygons.clone());
+    if check(&Geometry::MultiPolygon(repaired.clone())).is_some() {
+        let empty = Polygon::new(LineString(Vec::new()), Vec::new());
+        repaired = polygons
+            .iter()
+            .map(|polygon| polygon.union(&empty))
+            .reduce(|all, part| all.union(&part))
+            .unwrap_or_else(|| MultiPolygon(Vec::new()));
+    }
+    let mut repaired = repaired.orient(Direction::Default);
+    if repaired.0.is_empty() || check(&Geometry::MultiPolygon(repaired.clone())).is_some() {
+        return None;
+    }
+    if repaired.0.len() == 1 {
+        return repaired.0.pop().map(Geometry::Polygon);
+    }
+    Some(Geometry::MultiPolygon(repaired))
+}
+
+/// Checks the polygons of the feature and handles them as `mode` says;
+/// `false` if the feature is to be dropped. Features without polygons are
+/// kept as they are. Checking a feature again replaces the flags of the
+/// check before.
+pub fn apply(feature: &mut ExtractedFeature, mode: InvalidMode) -> bool {
+    if !matches!(feature.geometry(), Geometry::Polygon(_) | Geometry::MultiPolygon(_)) {
+        return true;
+    }
+    let Some(problem) = check(feature.geometry()) else {
+        if mode == InvalidMode::Flag {
+            feature.tags_mut().insert(VALID_TAG.into(), "true".into());
+            // From an earlier check, before the geometry changed
+            feature.tags_mut().remove(INVALID_REASON_TAG);
+        }
+        return true;
+    };
+    match mode {
+        InvalidMode::Drop => return false,
+        InvalidMode::Repair => {
+            if let Some(repaired) = repair(feature.geometry()) {
+                feature.set_geometry(repaired);
+                feature.tags_mut().insert(REPAIRED_TAG.into(), problem.to_string().into());
+                return true;
+            }
+        }
+        InvalidMode::Flag => {}
+    }
+    feature.tags_mut().insert(VALID_TAG.into(), "false".into());
+    feature.tags_mut().insert(INVALID_REASON_TAG.into(), problem.to_string().into());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Area;
+
+    fn polygon(coords: &[(f64, f64)]) -> Polygon {
+        Polygon::new(coords.to_vec().into(), vec![])
+    }
+
+    // A square from (x, y), counterclockwise
+    fn square(x: f64, y: f64, size: f64) -> Polygon {
+        polygon(&[(x, y), (x + size, y), (x + size, y + size), (x, y + size), (x, y)])
+    }
+
+    // What `repair` makes of the geometry, after checking it is valid
+    fn repaired_area(geometry: &Geometry) -> f64 {
+        let repaired = repair(geometry).expect("not repaired");
+        assert_eq!(check(&repaired), None);
+        repaired.unsigned_area()
+    }
+
+    #[test]
+    fn bow_ties_cross_themselves() {
+        let bow_tie = Geometry::Polygon(polygon(&[(0.0, 0.0), (2.0, 2.0), (2.0, 0.0), (0.0, 2.0), (0.0, 0.0)]));
+        assert_eq!(check(&bow_tie), Some(Problem::SelfIntersection(Coord { x: 1.0, y: 1.0 })));
+        assert_eq!(repaired_area(&bow_tie), 2.0);
+    }
+
+    #[test]
+    fn figure_eights_touch_themselves() {
+        let coords = [(0.0, 0.0), (2.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 2.0), (1.0, 1.0), (0.0, 0.0)];
+        let figure_eight = Geometry::Polygon(polygon(&coords));
+        assert_eq!(check(&figure_eight), Some(Problem::RingSelfTouch(Coord { x: 1.0, y: 1.0 })));
+        assert_eq!(repaired_area(&figure_eight), 2.0);
+    }
+
+    #[test]
+    fn rings_must_run_the_right_way() {
+        let mut clockwise = square(0.0, 0.0, 1.0);
+        clockwise.exterior_mut(|ring| ring.0.reverse());
+        let clockwise = Geometry::Polygon(clockwise);
+        assert_eq!(check(&clockwise), Some(Problem::WrongOrientation));
+        assert_eq!(repaired_area(&clockwise), 1.0);
+
+        let hole = square(1.0, 1.0, 1.0).exterior().clone();
+        let with_hole = Geometry::Polygon(Polygon::new(square(0.0, 0.0, 3.0).exterior().clone(), vec![hole]));
+        assert_eq!(check(&with_hole), Some(Problem::WrongOrientation));
+        assert_eq!(repaired_area(&with_hole), 8.0);
+    }
+
+    #[test]
+    fn parts_of_multipolygons_must_not_overlap() {
+        let nested = Geometry::MultiPolygon(MultiPolygon(vec![square(0.0, 0.0, 4.0), square(1.0, 1.0, 1.0)]));
+        assert_eq!(check(&nested), Some(Problem::OverlappingPolygons));
+        assert_eq!(repaired_area(&nested), 16.0);
+
+        let crossing = Geometry::MultiPolygon(MultiPolygon(vec![square(0.0, 0.0, 2.0), square(1.0, 1.0, 2.0)]));
+        assert!(matches!(check(&crossing), Some(Problem::SelfIntersection(_))));
+        assert_eq!(repaired_area(&crossing), 7.0);
+
+        // Touching at a corner is fine
+        let touching = Geometry::MultiPolygon(MultiPolygon(vec![square(0.0, 0.0, 1.0), square(1.0, 1.0, 1.0)]));
+        assert_eq!(check(&touching), None);
+    }
+}
@@ -0,0 +1,316 @@
+//! Reading of OSM XML (`.osm`) files, as exported by JOSM or the OSM API,
+//! and of osmChange (`.osc`) files.
+
+use crate::info::Header;
+use crate::metadata::{self, Metadata};
+use anyhow::{bail, Context, Result};
+use geo::Rect;
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// The block of an osmChange document (`.osc`) an object is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// Reads all objects of an OSM XML document with their metadata, sorted by
+/// type and id like the objects of a PBF, and the program that wrote it and
+/// its `<bounds>` as the header of a PBF would give them.
+///
+/// Objects JOSM marks for deletion (`action="delete"`) are left out. Of
+/// history files, which hold every version of an object, only the highest
+/// `version` is kept, and left out too if it is deleted (`visible="false"`).
+pub fn read<R: BufRead>(input: R) -> Result<(Header, Vec<(OsmObj, Metadata)>)> {
+    let mut versions = Vec::new();
+    let header = for_each_object(input, |obj, metadata, keep, _| {
+        versions.push((obj, metadata, keep));
+        Ok(())
+    })?;
+
+    versions.sort_by_key(|(obj, metadata, _)| (obj.id(), metadata.version));
+    let same = |a: &(OsmObj, Metadata, bool), b: &(OsmObj, Metadata, bool)| {
+        a.0.id() == b.0.id() && a.1.version == b.1.version
+    };
+    if let Some(pair) = versions.windows(2).find(|pair| same(&pair[0], &pair[1])) {
+        bail!("{:?} appears more than once in the OSM XML", pair[0].0.id());
+    }
+    let mut objects: Vec<(OsmObj, Metadata, bool)> = Vec::with_capacity(versions.len());
+    for version in versions {
+        if objects.last().is_some_and(|last| last.0.id() == version.0.id()) {
+            objects.pop();
+        }
+        objects.push(version);
+    }
+    let objects = objects.into_iter().filter(|(_, _, keep)| *keep).map(|(obj, metadata, _)| (obj, metadata));
+    Ok((header, objects.collect()))
+}
+
+/// Reads the objects of an osmChange document, as published by the OSM
+/// replication, in document order with the block they are in.
+///
+/// Deleted objects carry their id, and their version and metadata if the
+/// document has them, but no tags, nodes or members need to be given.
+pub fn read_changes<R: BufRead>(input: R) -> Result<Vec<(Action, OsmObj, Metadata)>> {
+    let mut changes = Vec::new();
+    for_each_object(input, |obj, metadata, _, action| match action {
+        Some(action) => {
+            changes.push((action, obj, metadata));
+            Ok(())
+        }
+        None => bail!("{:?} is outside of a create, modify or delete block", obj.id()),
+    })?;
+    Ok(changes)
+}
+
+/// Reads the tags of the `<changeset>` elements of a changeset document by
+/// changeset id
+pub fn read_changesets<R: BufRead>(input: R) -> Result<HashMap<i64, Tags>> {
+    let mut reader = Reader::from_reader(input);
+    let mut buf = Vec::new();
+    let mut changesets = HashMap::new();
+    // The changeset being read
+    let mut current: Option<(i64, Tags)> = None;
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("invalid changeset XML at byte {}", reader.buffer_position()))?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let attrs = attributes(e)?;
+                match e.name().as_ref() {
+                    "changeset" => {
+                        let changeset = (id(&attrs, "id")?, Tags::new());
+                        if matches!(event, Event::Empty(_)) {
+                            changesets.insert(changeset.0, changeset.1);
+                        } else {
+                            current = Some(changeset);
+                        }
+                    }
+                    "tag" => {
+                        if let Some((_, tags)) = &mut current {
+                            tags.insert(required(&attrs, "k")?.into(), required(&attrs, "v")?.into());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) if e.name().as_ref() == "changeset" => {
+                if let Some((id, tags)) = current.take() {
+                    changesets.insert(id, tags);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(changesets)
+}
+
+// Calls `handle` with every object of the document, its metadata, whether
+// it is visible rather than deleted and the osmChange block it is in, if
+// any; returns what the document says about itself
+fn for_each_object<R: BufRead>(
+    input: R,
+    mut handle: impl FnMut(OsmObj, Metadata, bool, Option<Action>) -> Result<()>,
+) -> Result<Header> {
+    let mut reader = Reader::from_reader(input);
+    let mut buf = Vec::new();
+    let mut header = Header::default();
+    let mut action = None;
+    // The object being read and whether it is visible
+    let mut current: Option<(OsmObj, Metadata, bool)> = None;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("invalid OSM XML at byte {}", reader.buffer_position()))?;
+        let (element, closed) = match &event {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(e) => {
+                match e.name().as_ref() {
+                    "node" | "way" | "relation" => {
+                        if let Some((obj, metadata, keep)) = current.take() {
+                            handle(obj, metadata, keep, action)?;
+                        }
+                    }
+                    "create" | "modify" | "delete" => action = None,
+                    _ => {}
+                }
+                buf.clear();
+                continue;
+            }
+            Event::Eof => break,
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+
+        let attrs = attributes(element)?;
+        match element.name().as_ref() {
+            "osm" | "osmChange" => header.writing_program = get(&attrs, "generator").map(str::to_string),
+            "bounds" => {
+                let min = (degrees(&attrs, "minlon")?, degrees(&attrs, "minlat")?);
+                header.bbox = Some(Rect::new(min, (degrees(&attrs, "maxlon")?, degrees(&attrs, "maxlat")?)));
+            }
+            "create" if !closed => action = Some(Action::Create),
+            "modify" if !closed => action = Some(Action::Modify),
+            "delete" if !closed => action = Some(Action::Delete),
+            "node" | "way" | "relation" => {
+                let keep = get(&attrs, "action") != Some("delete")
+                    && get(&attrs, "visible") != Some("false")
+                    && action != Some(Action::Delete);
+                let obj = object(element.name().as_ref(), &attrs, keep)?;
+                if closed {
+                    handle(obj, info(&attrs)?, keep, action)?;
+                } else {
+                    current = Some((obj, info(&attrs)?, keep));
+                }
+            }
+            "tag" => {
+                if let Some((obj, _, _)) = &mut current {
+                    let tags = match obj {
+                        OsmObj::Node(node) => &mut node.tags,
+                        OsmObj::Way(way) => &mut way.tags,
+                        OsmObj::Relation(relation) => &mut relation.tags,
+                    };
+                    tags.insert(required(&attrs, "k")?.into(), required(&attrs, "v")?.into());
+                }
+            }
+            "nd" => {
+                if let Some((OsmObj::Way(way), _, _)) = &mut current {
+                    way.nodes.push(NodeId(id(&attrs, "ref")?));
+                }
+            }
+            "member" => {
+                if let Some((OsmObj::Relation(relation), _, _)) = &mut current {
+                    let member_id = id(&attrs, "ref")?;
+                    let member = match required(&attrs, "type")? {
+                        "node" => OsmId::Node(NodeId(member_id)),
+                        "way" => OsmId::Way(WayId(member_id)),
+                        "relation" => OsmId::Relation(RelationId(member_id)),
+                        other => bail!("unknown member type {:?} in relation {}", other, relation.id.0),
+                    };
+                    let role = get(&attrs, "role").unwrap_or("").into();
+                    relation.refs.push(Ref { member, role });
+                }
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(header)
+}
+
+// Every metadata attribute is optional; JOSM leaves all of them out for
+// objects that were never uploaded
+fn info(attrs: &[(String, String)]) -> Result<Metadata> {
+    fn number<T: std::str::FromStr>(attrs: &[(String, String)], key: &str) -> Result<Option<T>> {
+        match get(attrs, key) {
+            Some(value) => match value.parse() {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => bail!("invalid {} {:?} in OSM XML", key, value),
+            },
+            None => Ok(None),
+        }
+    }
+    Ok(Metadata {
+        version: number(attrs, "version")?,
+        timestamp: get(attrs, "timestamp").map(metadata::parse_timestamp).transpose()?,
+        changeset: number(attrs, "changeset")?,
+        uid: number(attrs, "uid")?,
+        user: get(attrs, "user").map(str::to_string),
+    })
+}
+
+// Deleted nodes have no location, so it is only read for kept ones
+fn object(element: &str, attrs: &[(String, String)], keep: bool) -> Result<OsmObj> {
+    let tags = Tags::new();
+    Ok(match element {
+        "node" => OsmObj::Node(Node {
+            id: NodeId(id(attrs, "id")?),
+            tags,
+            decimicro_lat: if keep { coordinate(attrs, "lat")? } else { 0 },
+            decimicro_lon: if keep { coordinate(attrs, "lon")? } else { 0 },
+        }),
+        "way" => OsmObj::Way(Way {
+            id: WayId(id(attrs, "id")?),
+            tags,
+            nodes: Vec::new(),
+        }),
+        _ => OsmObj::Relation(Relation {
+            id: RelationId(id(attrs, "id")?),
+            tags,
+            refs: Vec::new(),
+        }),
+    })
+}
+
+fn attributes(element: &BytesStart) -> Result<Vec<(String, String)>> {
+    let mut attrs = Vec::new();
+    for attr in element.attributes() {
+        let attr = attr?;
+        let key = attr.key.as_ref().to_string();
+        attrs.push((key, attr.normalized_value(XmlVersion::Implicit1_0)?.into_owned()));
+    }
+    Ok(attrs)
+}
+
+fn get<'a>(attrs: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+fn required<'a>(attrs: &'a [(String, String)], key: &str) -> Result<&'a str> {
+    match get(attrs, key) {
+        Some(value) => Ok(value),
+        None => bail!("missing attribute {:?} in OSM XML", key),
+    }
+}
+
+fn id(attrs: &[(String, String)], key: &str) -> Result<i64> {
+    let value = required(attrs, key)?;
+    value.parse().with_context(|| format!("invalid id {:?} in OSM XML", value))
+}
+
+fn degrees(attrs: &[(String, String)], key: &str) -> Result<f64> {
+    let value = required(attrs, key)?;
+    value.parse().with_context(|| format!("invalid coordinate {:?} in OSM XML", value))
+}
+
+// Degrees to the 1e-7 degree units of a PBF
+fn coordinate(attrs: &[(String, String)], key: &str) -> Result<i32> {
+    Ok((degrees(attrs, key)? * 1e7).round() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_files_keep_the_latest_version() {
+        let xml = r#"<osm version="0.6">
+  <node id="1" version="1" visible="true" lat="1" lon="1"/>
+  <node id="1" version="2" visible="true" lat="2" lon="2"/>
+  <node id="2" version="1" visible="true" lat="3" lon="3"/>
+  <node id="2" version="2" visible="false"/>
+  <way id="1" version="2" visible="true"><nd ref="1"/><nd ref="2"/></way>
+  <way id="1" version="1" visible="true"><nd ref="1"/></way>
+</osm>"#;
+        let (_, objects) = read(xml.as_bytes()).unwrap();
+        let versions: Vec<(OsmId, Option<i32>)> = objects.iter().map(|(obj, metadata)| (obj.id(), metadata.version)).collect();
+        assert_eq!(versions, [(NodeId(1).into(), Some(2)), (WayId(1).into(), Some(2))]);
+        assert!(matches!(&objects[0].0, OsmObj::Node(node) if node.lat() == 2.0));
+        assert!(matches!(&objects[1].0, OsmObj::Way(way) if way.nodes.len() == 2));
+
+        let twice = r#"<osm><node id="1" lat="1" lon="1"/><node id="1" lat="2" lon="2"/></osm>"#;
+        assert!(read(twice.as_bytes()).is_err());
+    }
+}
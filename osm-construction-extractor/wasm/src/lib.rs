@@ -0,0 +1,76 @@
+//! WebAssembly bindings of the extractor, so web tools can filter a small
+//! PBF extract the user picked without uploading it anywhere.
+//!
+//! Everything happens in memory on the calling thread: the file comes in
+//! as bytes and the features go out as GeoJSON text.
+//!
+//! ```js
+//! import init, { Extractor } from "./fast_osm_extraction_wasm.js";
+//!
+//! await init();
+//! const data = new Uint8Array(await file.arrayBuffer());
+//! const geojson = new Extractor(data)
+//!     .filter("highway=* and construction=*")
+//!     .bbox(13.0, 52.3, 13.8, 52.7)
+//!     .geojson();
+//! map.addSource("construction", { type: "geojson", data: JSON.parse(geojson) });
+//! ```
+
+use osm_construction_extractor::output::geojson;
+use osm_construction_extractor::{spatial, Filter, OsmType};
+use wasm_bindgen::prelude::*;
+
+/// Extracts the OSM objects matching a tag filter from the bytes of a PBF,
+/// o5m or OSM XML file
+#[wasm_bindgen]
+pub struct Extractor {
+    inner: osm_construction_extractor::Extractor,
+}
+
+#[wasm_bindgen]
+impl Extractor {
+    /// Takes a copy of the file, with the default `highway=* and
+    /// construction=*` filter applied to ways
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Extractor {
+        Extractor { inner: osm_construction_extractor::Extractor::new(data) }
+    }
+
+    /// Sets the tag filter, in the syntax of the command line tool
+    pub fn filter(self, filter: &str) -> Result<Extractor, JsError> {
+        let filter: Filter = filter.parse().map_err(js_error)?;
+        Ok(Extractor { inner: self.inner.filter(filter) })
+    }
+
+    /// Sets the OSM types to extract, comma-separated, e.g. `"way,relation"`
+    pub fn types(self, types: &str) -> Result<Extractor, JsError> {
+        let types = types.split(',').map(|t| t.trim().parse()).collect::<anyhow::Result<Vec<OsmType>>>().map_err(js_error)?;
+        Ok(Extractor { inner: self.inner.types(&types) })
+    }
+
+    /// Only extracts objects within the bounding box, in degrees
+    pub fn bbox(self, min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> Result<Extractor, JsError> {
+        let bbox = spatial::parse_bbox(&format!("{},{},{},{}", min_lon, min_lat, max_lon, max_lat)).map_err(js_error)?;
+        Ok(Extractor { inner: self.inner.bbox(bbox) })
+    }
+
+    /// Adds the version, timestamp, changeset and author of each object as
+    /// `@version`, `@timestamp`, `@changeset`, `@uid` and `@user` properties
+    #[wasm_bindgen(js_name = withMetadata)]
+    pub fn with_metadata(self, with_metadata: bool) -> Extractor {
+        Extractor { inner: self.inner.with_metadata(with_metadata) }
+    }
+
+    /// Runs the extraction and returns the features as the text of a
+    /// GeoJSON FeatureCollection
+    pub fn geojson(&self) -> Result<String, JsError> {
+        let features = self.inner.run().map_err(js_error)?;
+        let mut out = Vec::new();
+        geojson::write_feature_collection_to(&mut out, &features, None).map_err(js_error)?;
+        String::from_utf8(out).map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", e))
+}